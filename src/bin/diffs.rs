@@ -1,8 +1,10 @@
 use clap::Parser;
 use std::fs;
+use std::path::Path;
 
 use osm_replication_rust::diffs;
 use osm_replication_rust::osm::OsmUpdate;
+use osm_replication_rust::osmcache::OsmCache;
 use osm_replication_rust::osmxml;
 
 #[derive(Parser, Debug)]
@@ -10,14 +12,23 @@ use osm_replication_rust::osmxml;
 struct Args {
     #[arg(long, help = "Polygon directory")]
     pub polygons: String,
-    #[arg(long, help = "Directory for osmbin database")]
-    pub osmbin: String,
     #[arg(
         long,
-        help = "Use OsmCache instead of OsmBin for recursive diffs",
+        help = "Directory for osmbin database",
+        required_unless_present = "cache"
+    )]
+    pub osmbin: Option<String>,
+    #[arg(
+        long,
+        help = "Use OsmCache filled by bbox pass for recursive diffs, reading missing elements from OsmBin",
         required = false
     )]
     pub use_osmcache: bool,
+    #[arg(
+        long,
+        help = "Use OsmCache saved by update next to the bbox diff, falling back to OsmBin if given"
+    )]
+    pub cache: Option<String>,
     #[arg(long, help = "Source osc file")]
     pub source: String,
     #[arg(long, help = "Source state.txt file")]
@@ -34,28 +45,56 @@ fn main() {
     let polys = diffs::Poly::get_poly_from_dir(&args.polygons);
     let dest_modified_time = fs::metadata(&args.source).unwrap().modified().unwrap();
 
-    let dest = String::from("/dev/null");
-    let mut osmxml = osmxml::bbox::OsmXmlBBox::new_osmbin(&dest, &args.osmbin).unwrap();
-    osmxml.update(&args.source).unwrap();
-
-    let diff = if args.use_osmcache {
-        let osmcache = osmxml.get_reader().get_cache();
-        diffs::Diff::new_osmcache(
-            osmcache,
-            &args.dest_dir,
-            &args.dest_suffix,
-            dest_modified_time,
-            &args.state,
-        )
+    let diff = if let Some(cache) = &args.cache {
+        let osmcache = OsmCache::from_file(Path::new(cache)).unwrap();
+        if let Some(dir_osmbin) = &args.osmbin {
+            diffs::Diff::new_osmcache_fallback(
+                osmcache,
+                dir_osmbin,
+                &args.dest_dir,
+                &args.dest_suffix,
+                dest_modified_time,
+                &args.state,
+            )
+        } else {
+            diffs::Diff::new_osmcache(
+                osmcache,
+                &args.dest_dir,
+                &args.dest_suffix,
+                dest_modified_time,
+                &args.state,
+            )
+        }
     } else {
-        diffs::Diff::new_osmbin(
-            &args.osmbin,
-            &args.dest_dir,
-            &args.dest_suffix,
-            dest_modified_time,
-            &args.state,
-        )
+        let dir_osmbin = args.osmbin.as_deref().unwrap();
+        let dest = String::from("/dev/null");
+        let mut osmxml = osmxml::bbox::OsmXmlBBox::new_osmbin(&dest, dir_osmbin).unwrap();
+        osmxml.update(&args.source).unwrap();
+
+        if args.use_osmcache {
+            let osmcache = osmxml.get_reader().get_cache();
+            diffs::Diff::new_osmcache_fallback(
+                osmcache,
+                dir_osmbin,
+                &args.dest_dir,
+                &args.dest_suffix,
+                dest_modified_time,
+                &args.state,
+            )
+        } else {
+            diffs::Diff::new_osmbin(
+                dir_osmbin,
+                &args.dest_dir,
+                &args.dest_suffix,
+                dest_modified_time,
+                &args.state,
+            )
+        }
     };
     diff.generate_diff_recursive(&polys, &args.source, 0)
         .unwrap();
+    let cache_misses = diff.take_cache_misses();
+    if !cache_misses.is_empty() {
+        println!("{cache_misses}");
+    }
 }
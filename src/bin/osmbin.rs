@@ -1,7 +1,10 @@
 use clap::Parser;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
 use osm_replication_rust::osm::{OsmReader, OsmUpdate, OsmWriter};
 use osm_replication_rust::osmbin;
+use osm_replication_rust::osmgeom;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -12,8 +15,141 @@ struct Args {
     command: Command,
     #[arg(long, help = "Verbose mode")]
     pub verbose: bool,
+    #[arg(
+        long,
+        help = "State file of imported or applied diff file (default: .state.txt next to diff)"
+    )]
+    pub state: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Apply diff even if it is not the next one expected by database"
+    )]
+    pub force: bool,
+    #[arg(long, help = "Diffs directory, to check state of database")]
+    pub diffs: Option<String>,
+    #[arg(long, help = "Polygon file to limit exported data, or for --extract")]
+    pub poly: Option<String>,
+    #[arg(long, help = "Size of node ids in way.data, for --init or --migrate")]
+    pub node_id_size: Option<usize>,
+    #[arg(long, help = "Size of pointers in way.idx, for --init or --migrate")]
+    pub way_ptr_size: Option<usize>,
+    #[arg(
+        long,
+        help = "Size of pointers in relation.idx, for --init or --migrate"
+    )]
+    pub relation_ptr_size: Option<usize>,
+    #[arg(
+        long,
+        help = "Encoding of coordinates in node.crd (offset or direct), for --init or --migrate"
+    )]
+    pub coordinates: Option<osmbin::CoordEncoding>,
+    #[arg(
+        long,
+        help = "Encoding of node ids in way.data (fixed or delta), for --init or --migrate"
+    )]
+    pub way_encoding: Option<osmbin::WayEncoding>,
+    #[arg(
+        long,
+        help = "Storage of node coordinates (direct or hash, for small regional databases), for --init or --migrate"
+    )]
+    pub node_store: Option<osmbin::NodeStore>,
+    #[arg(
+        long,
+        help = "Store reverse indexes from nodes to ways and from ways to relations (true or false), for --init or --migrate"
+    )]
+    pub parents: Option<bool>,
+    #[arg(
+        long,
+        help = "Store spatial index of nodes (true or false), for --init or --migrate"
+    )]
+    pub tiles: Option<bool>,
+    #[arg(
+        long,
+        help = "Store tags and metadata of nodes and ways (true or false), for --init or --migrate"
+    )]
+    pub metadata: Option<bool>,
+    #[arg(
+        long,
+        help = "Store version of elements, to detect diffs applied twice or out of order (true or false), for --init or --migrate"
+    )]
+    pub versions: Option<bool>,
+    #[arg(
+        long,
+        help = "Number of applied diffs which can be rolled back (0 to disable), for --init or --migrate"
+    )]
+    pub undo: Option<u32>,
+    #[arg(
+        long,
+        help = "When a diff has an element not newer than database, skip it (warn) or abort update (fail), for --update"
+    )]
+    pub version_check: Option<osmbin::VersionCheck>,
+    #[arg(
+        long,
+        help = "Exit instead of waiting when database is locked by another process"
+    )]
+    pub no_wait: bool,
+    #[arg(
+        long,
+        help = "Maximum size in MB of the cache of elements read (unbounded by default)"
+    )]
+    pub cache_size: Option<usize>,
 }
 
+impl Args {
+    /// Layout of database, with options given on command line
+    fn format(&self, mut format: osmbin::Format) -> osmbin::Format {
+        if let Some(node_id_size) = self.node_id_size {
+            format.node_id_size = node_id_size;
+        }
+        if let Some(way_ptr_size) = self.way_ptr_size {
+            format.way_ptr_size = way_ptr_size;
+        }
+        if let Some(relation_ptr_size) = self.relation_ptr_size {
+            format.relation_ptr_size = relation_ptr_size;
+        }
+        if let Some(coordinates) = self.coordinates {
+            format.coordinates = coordinates;
+        }
+        if let Some(way_encoding) = self.way_encoding {
+            format.way_encoding = way_encoding;
+        }
+        if let Some(node_store) = self.node_store {
+            format.node_store = node_store;
+        }
+        if let Some(parents) = self.parents {
+            format.parents = parents;
+        }
+        if let Some(tiles) = self.tiles {
+            format.tiles = tiles;
+        }
+        if let Some(metadata) = self.metadata {
+            format.metadata = metadata;
+        }
+        if let Some(versions) = self.versions {
+            format.versions = versions;
+        }
+        if let Some(undo) = self.undo {
+            format.undo = undo;
+        }
+        format.version = format.min_version();
+        format
+    }
+
+    /// Open database in read-only or read-write mode, waiting for lock unless `--no-wait`, with
+    /// cache bounded by `--cache-size`
+    fn open(&self, write: bool) -> osmbin::OsmBin {
+        let mut osmbin = or_exit(match (write, self.no_wait) {
+            (false, false) => osmbin::OsmBin::new(&self.dir),
+            (false, true) => osmbin::OsmBin::try_new(&self.dir),
+            (true, false) => osmbin::OsmBin::new_writer(&self.dir),
+            (true, true) => osmbin::OsmBin::try_new_writer(&self.dir),
+        });
+        osmbin.set_cache_limit(self.cache_size.map(|size| size << 20));
+        osmbin
+    }
+}
+
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Parser, Debug)]
 #[group(required = true, multiple = true)]
 struct Command {
@@ -23,58 +159,248 @@ struct Command {
     pub import: Option<String>,
     #[arg(long, help = "Apply diff file to database")]
     pub update: Option<String>,
-    #[arg(long, num_args=2, value_names=["ELEM", "ID"], help="Read node/way/relation id from database")]
+    #[arg(long, help = "Roll back the last N diffs applied to database")]
+    pub rollback: Option<usize>,
+    #[arg(long, num_args=2..=3, value_names=["ELEM", "ID"], help="Read node/way/relation id from database, or parents of node/way id")]
     pub read: Vec<String>,
     #[arg(long, help = "Check database")]
     pub check: Option<u64>,
+    #[arg(long, help = "Check consistency of whole database")]
+    pub check_all: bool,
+    #[arg(long, help = "Repair errors found by --check-all")]
+    pub repair: bool,
+    #[arg(
+        long,
+        help = "Convert relations from previous format, with one json file per relation"
+    )]
+    pub migrate_relations: bool,
+    #[arg(long, help = "Compact way.data by removing free space")]
+    pub compact: bool,
+    #[arg(long, help = "Convert database to the format given by other options")]
+    pub migrate: bool,
+    #[arg(
+        long,
+        help = "Copy database to an empty directory, consistent with its replication state"
+    )]
+    pub snapshot: Option<String>,
+    #[arg(long, help = "Export database to a .osm.pbf, .osm or .osm.gz file")]
+    pub export: Option<String>,
+    #[arg(
+        long,
+        help = "Print ids of ways and relations inside polygon given by --poly, with --tiles and --parents indexes"
+    )]
+    pub extract: bool,
 }
 
 fn main() {
     let args = Args::parse();
 
     if args.command.init {
-        osmbin::OsmBin::init(&args.dir);
+        or_exit(osmbin::OsmBin::init_with_format(
+            &args.dir,
+            &args.format(osmbin::Format::default()),
+        ));
+    }
+    if args.command.migrate_relations {
+        let num_relations = or_exit(osmbin::OsmBin::migrate_relations(&args.dir));
+        println!("{num_relations} relations migrated");
+    }
+    if args.command.migrate {
+        let cur_format = args.open(false).get_format();
+        let format = args.format(cur_format);
+        if let Err(e) = format.validate() {
+            eprintln!("Format is not valid: {e}");
+            std::process::exit(1);
+        }
+        if or_exit(osmbin::OsmBin::migrate(&args.dir, &format)) {
+            println!("Database migrated to {format:?}");
+        } else {
+            println!("Database already uses {format:?}");
+        }
     }
     if let Some(import) = &args.command.import {
-        let mut osmbin = osmbin::OsmBin::new_writer(&args.dir).unwrap();
-        osmbin.import(import).unwrap();
+        let mut osmbin = args.open(true);
+        or_exit(osmbin.import(import));
+        if let Some(state) = &args.state {
+            let state = or_exit(osmbin::ReplicationState::from_file(state));
+            or_exit(osmbin.set_state(&state));
+        }
     }
     if let Some(update) = &args.command.update {
-        let mut osmbin = osmbin::OsmBin::new_writer(&args.dir).unwrap();
-        osmbin.update(update).unwrap();
+        let mut osmbin = args.open(true);
+        if let Some(version_check) = args.version_check {
+            osmbin.set_version_check(version_check);
+        }
+        let state_file = args.state.clone().or_else(|| {
+            let prefix = update
+                .strip_suffix(".osc.gz")
+                .or_else(|| update.strip_suffix(".osc"))?;
+            let state_file = PathBuf::from(prefix.to_string() + ".state.txt");
+            state_file.exists().then_some(state_file)
+        });
+        if let Some(state_file) = state_file {
+            let state = or_exit(osmbin::ReplicationState::from_file(&state_file));
+            if let Err(e) = osmbin.update_with_state(update, &state, args.force) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        } else {
+            or_exit(osmbin.update(update));
+        }
+    }
+    if let Some(num) = args.command.rollback {
+        let mut osmbin = args.open(true);
+        or_exit(osmbin.rollback(num));
+        match or_exit(osmbin.get_state()) {
+            Some(state) => println!("Database rolled back to state {}", state.sequence_number),
+            None => println!("Database rolled back"),
+        }
     }
     if !args.command.read.is_empty() {
-        let elem = args.command.read[0].clone();
-        let id: u64 = args.command.read[1]
-            .trim()
-            .parse()
-            .expect("ID should be a number");
+        read(&args);
+    }
+    if args.command.compact {
+        let mut osmbin = args.open(true);
+        let reclaimed = or_exit(osmbin.compact());
+        println!("Compaction reclaimed {reclaimed} bytes from way.data");
+    }
+    if let Some(export) = &args.command.export {
+        let mut osmbin = args.open(false);
+        if let Err(e) = osmbin.export(export, args.poly.as_deref()) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+    if let Some(snapshot) = &args.command.snapshot {
+        let osmbin = args.open(false);
+        println!("{}", or_exit(osmbin.snapshot(snapshot)));
+    }
+    if args.command.extract {
+        extract(&args);
+    }
+    if let Some(start) = args.command.check {
+        check(&args, start);
+    }
+    if args.command.check_all || args.command.repair {
+        check_all(&args);
+    }
+}
+
+/// Read an element, for `--read`
+fn read(args: &Args) {
+    let elem = args.command.read[0].clone();
+    let id: u64 = args.command.read[args.command.read.len() - 1]
+        .trim()
+        .parse()
+        .expect("ID should be a number");
 
-        let mut osmbin = osmbin::OsmBin::new(&args.dir).unwrap();
-        match elem.as_str() {
-            "node" => println!("{:?}", osmbin.read_node(id)),
-            "way" => println!("{:?}", osmbin.read_way(id)),
-            "relation" => println!("{:?}", osmbin.read_relation(id)),
-            "relation_full" => {
-                let relation = osmbin.read_relation_full(id, &[]);
-                if let Some(relation) = relation {
-                    println!("{} members", relation.members.len());
-                    if args.verbose {
-                        println!("{relation:?}");
-                    }
-                    osmbin.print_stats();
-                } else {
-                    println!("Relation not found");
+    let mut osmbin = args.open(false);
+    if elem == "parents" {
+        let parents = match args.command.read[1].as_str() {
+            "node" if args.command.read.len() == 3 => osmbin.read_node_parents(id),
+            "way" if args.command.read.len() == 3 => osmbin.read_way_parents(id),
+            _ => panic!("--read parents should be followed by node/way and id"),
+        };
+        println!("{:?}", or_exit(parents));
+        return;
+    }
+    match elem.as_str() {
+        "node" => println!("{:?}", or_exit(osmbin.try_read_node(id))),
+        "way" => println!("{:?}", or_exit(osmbin.try_read_way(id))),
+        "relation" => println!("{:?}", or_exit(osmbin.try_read_relation(id))),
+        "relation_full" => {
+            let relation = osmbin.read_relation_full(id, &[]);
+            if let Some(relation) = relation {
+                println!("{} members", relation.members.len());
+                if args.verbose {
+                    println!("{relation:?}");
                 }
+                osmbin.print_stats();
+            } else {
+                println!("Relation not found");
             }
-            _ => panic!("--read option {elem} not recognized"),
         }
+        _ => panic!("--read option {elem} not recognized"),
     }
-    if let Some(check) = args.command.check {
-        let mut osmbin = osmbin::OsmBin::new(&args.dir).unwrap();
-        if let Err(e) = osmbin.check_database(check) {
-            eprintln!("{e}");
-            std::process::exit(1);
+}
+
+/// Print elements inside polygon, for `--extract`
+fn extract(args: &Args) {
+    let Some(poly) = &args.poly else {
+        eprintln!("--extract needs a polygon given by --poly");
+        std::process::exit(1);
+    };
+    let (_, poly) = or_exit(osmgeom::read_multipolygon(poly));
+    let mut osmbin = args.open(false);
+    let extract = or_exit(osmbin.extract(&poly));
+    if args.verbose {
+        for id in &extract.nodes {
+            println!("node {id}");
         }
     }
+    for id in &extract.ways {
+        println!("way {id}");
+    }
+    for id in &extract.relations {
+        println!("relation {id}");
+    }
+    println!(
+        "{} nodes, {} ways, {} relations",
+        extract.nodes.len(),
+        extract.ways.len(),
+        extract.relations.len()
+    );
+}
+
+/// Check database, and its state against diffs, for `--check`
+fn check(args: &Args, start: u64) {
+    let mut osmbin = args.open(false);
+    if let Some(diffs) = &args.diffs {
+        let state_file = Path::new(diffs).join("planet/minute/state.txt");
+        let diffs_state = or_exit(osmbin::ReplicationState::from_file(&state_file));
+        match or_exit(osmbin.get_state()) {
+            Some(state) if state.sequence_number == diffs_state.sequence_number => {
+                println!("Database state: {}", state.sequence_number);
+            }
+            Some(state) => {
+                eprintln!(
+                    "Database state {} doesn't match diffs state {} from {}",
+                    state.sequence_number,
+                    diffs_state.sequence_number,
+                    state_file.display()
+                );
+                std::process::exit(1);
+            }
+            None => println!("Database has no state"),
+        }
+    }
+    if let Err(e) = osmbin.check_database(start) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+/// Check consistency of whole database, for `--check-all` and `--repair`
+fn check_all(args: &Args) {
+    let mut osmbin = if args.command.repair {
+        args.open(true)
+    } else {
+        args.open(false)
+    };
+    let report = or_exit(osmbin.check());
+    println!("{report}");
+    if args.command.repair && !report.is_ok() {
+        let num_repaired = or_exit(osmbin.repair(&report));
+        println!("{num_repaired} errors repaired");
+    } else if !report.is_ok() {
+        std::process::exit(1);
+    }
+}
+
+/// Get result of an operation, or exit with its error message
+fn or_exit<T, E: fmt::Display>(res: Result<T, E>) -> T {
+    res.unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    })
 }
@@ -4,14 +4,15 @@ use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io::ErrorKind;
+use std::mem;
 use std::os::unix;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 
 use crate::osm::OsmUpdate;
-use crate::osmbin;
-use crate::osmcache::OsmCache;
+use crate::osmbin::shared::OsmBinShared;
+use crate::osmcache::{CacheMisses, OsmCache, OsmCacheFallback};
 use crate::osmxml;
 
 macro_rules! dprintln {
@@ -27,8 +28,10 @@ pub struct Poly {
 }
 
 pub struct Diff {
-    dir_osmbin: Option<String>,
-    osmcache: Arc<OsmCache>,
+    osmbin: Option<OsmBinShared>,
+    osmcache: Option<Arc<OsmCache>>,
+    /// Elements missing from `osmcache`, read from `osmbin`
+    cache_misses: Arc<Mutex<CacheMisses>>,
     dest_diff_dir: PathBuf,
     dest_diff_file: PathBuf,
     dest_diff_tmp_file: PathBuf,
@@ -54,8 +57,9 @@ impl Diff {
             panic!("Filename given should end with '.osc.gz': {dest_diff_file}");
         }
         Diff {
-            dir_osmbin: Some(dir_osmbin.to_string()),
-            osmcache: Arc::default(),
+            osmbin: Some(OsmBinShared::new(dir_osmbin).unwrap()),
+            osmcache: None,
+            cache_misses: Arc::default(),
             dest_diff_dir: PathBuf::from(dest_diff_dir),
             dest_diff_file: PathBuf::from(dest_diff_file),
             dest_diff_tmp_file,
@@ -80,8 +84,9 @@ impl Diff {
             panic!("Filename given should end with '.osc.gz': {dest_diff_file}");
         }
         Diff {
-            dir_osmbin: None,
-            osmcache: Arc::new(osmcache),
+            osmbin: None,
+            osmcache: Some(Arc::new(osmcache)),
+            cache_misses: Arc::default(),
             dest_diff_dir: PathBuf::from(dest_diff_dir),
             dest_diff_file: PathBuf::from(dest_diff_file),
             dest_diff_tmp_file,
@@ -91,6 +96,35 @@ impl Diff {
         }
     }
 
+    /// Generate diffs from `osmcache`, reading elements missing from it in OsmBin database
+    /// `dir_osmbin`, instead of panicking
+    ///
+    /// Elements missing from cache are given by [`Diff::take_cache_misses`].
+    pub fn new_osmcache_fallback(
+        osmcache: OsmCache,
+        dir_osmbin: &str,
+        dest_diff_dir: &str,
+        dest_diff_file: &str,
+        dest_modified_time: SystemTime,
+        orig_state_file: &str,
+    ) -> Diff {
+        let mut diff = Self::new_osmcache(
+            osmcache,
+            dest_diff_dir,
+            dest_diff_file,
+            dest_modified_time,
+            orig_state_file,
+        );
+        diff.osmbin = Some(OsmBinShared::new(dir_osmbin).unwrap());
+        diff
+    }
+
+    /// Take ids of elements missing from cache, since diff generation started or this function
+    /// was last called
+    pub fn take_cache_misses(&self) -> CacheMisses {
+        mem::take(&mut *self.cache_misses.lock().unwrap())
+    }
+
     pub fn generate_diff(
         &self,
         poly: &Poly,
@@ -110,24 +144,38 @@ impl Diff {
             r => r.unwrap(),
         }
         let dest_diff_tmp = dest_diff_tmp_path.to_str().unwrap();
-        if let Some(dir_osmbin) = &self.dir_osmbin {
-            let reader = osmbin::OsmBin::new(dir_osmbin).unwrap();
-            let mut osmxml = osmxml::filter::OsmXmlFilter::new_reader(
-                dest_diff_tmp,
-                reader,
-                poly_file.to_str().unwrap(),
-            )
-            .unwrap();
-            osmxml.update(orig_diff).unwrap();
-        } else {
-            let reader = self.osmcache.clone();
-            let mut osmxml = osmxml::filter::OsmXmlFilter::new_reader(
-                dest_diff_tmp,
-                reader,
-                poly_file.to_str().unwrap(),
-            )
-            .unwrap();
-            osmxml.update(orig_diff).unwrap();
+        match (&self.osmbin, &self.osmcache) {
+            (Some(osmbin), Some(osmcache)) => {
+                let reader =
+                    OsmCacheFallback::new(osmcache.clone(), osmbin, self.cache_misses.clone());
+                let mut osmxml = osmxml::filter::OsmXmlFilter::new_reader(
+                    dest_diff_tmp,
+                    reader,
+                    poly_file.to_str().unwrap(),
+                )
+                .unwrap();
+                osmxml.update(orig_diff).unwrap();
+            }
+            (Some(reader), None) => {
+                let mut osmxml = osmxml::filter::OsmXmlFilter::new_reader(
+                    dest_diff_tmp,
+                    reader,
+                    poly_file.to_str().unwrap(),
+                )
+                .unwrap();
+                osmxml.update(orig_diff).unwrap();
+            }
+            (None, Some(osmcache)) => {
+                let reader = osmcache.clone();
+                let mut osmxml = osmxml::filter::OsmXmlFilter::new_reader(
+                    dest_diff_tmp,
+                    reader,
+                    poly_file.to_str().unwrap(),
+                )
+                .unwrap();
+                osmxml.update(orig_diff).unwrap();
+            }
+            (None, None) => unreachable!("Diff has no reader"),
         }
 
         let dest_state_file = Path::new(&self.dest_diff_dir)
@@ -5,6 +5,7 @@
 
 mod bufreaderwriter;
 pub mod diffs;
+mod macros;
 pub mod osm;
 pub mod osmbin;
 pub mod osmcache;
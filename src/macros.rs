@@ -0,0 +1,10 @@
+//! Macros shared by modules
+
+/// Print a line prefixed by the local time
+macro_rules! printlnt {
+    ($($arg:tt)*) => {
+        println!("{} {}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"), format_args!($($arg)*));
+    };
+}
+
+pub(crate) use printlnt;
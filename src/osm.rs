@@ -12,7 +12,7 @@ use crate::osmpbf;
 use crate::osmxml;
 
 /// Node
-#[derive(Debug, Default, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Node {
     /// Node id
     pub id: u64,
@@ -45,7 +45,7 @@ impl Node {
 }
 
 /// Way
-#[derive(Debug, Default, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Way {
     /// Way id
     pub id: u64,
@@ -199,14 +199,22 @@ pub trait OsmReader {
     fn read_way(&mut self, id: u64) -> Option<Way>;
     fn read_relation(&mut self, id: u64) -> Option<Relation>;
 
+    /// Get nodes from a list of osm ids, in the same order
+    ///
+    /// Readers backed by files should read ids with [`read_sorted`], to avoid random seeks.
+    fn read_nodes(&mut self, ids: &[u64]) -> Vec<Option<Node>> {
+        ids.iter().map(|id| self.read_node(*id)).collect()
+    }
+    /// Get ways from a list of osm ids, in the same order
+    fn read_ways(&mut self, ids: &[u64]) -> Vec<Option<Way>> {
+        ids.iter().map(|id| self.read_way(*id)).collect()
+    }
+
     /// Get a way including all its nodes from an osm id
     fn read_way_full(&mut self, id: u64) -> Option<WayFull> {
         let way = self.read_way(id);
         if let Some(way) = way {
-            let mut nodes: Vec<Option<Node>> = Vec::with_capacity(way.nodes.len());
-            for n in &way.nodes {
-                nodes.push(self.read_node(*n));
-            }
+            let nodes = self.read_nodes(&way.nodes);
             Some(WayFull { way, nodes })
         } else {
             None
@@ -218,16 +226,23 @@ pub trait OsmReader {
     /// Note that a relation including itself is handled by dropping the inclusion.
     fn read_relation_full(&mut self, id: u64, prev_relations: &[u64]) -> Option<RelationFull> {
         if prev_relations.contains(&id) {
-            println!("Detected relation recursion on id={id} - {prev_relations:?}",);
+            println!("Detected relation recursion on id={id} - {prev_relations:?}");
             return None;
         }
         let relation = self.read_relation(id);
         if let Some(relation) = relation {
             let mut members: Vec<ElementFull> = Vec::with_capacity(relation.members.len());
+            let node_ids: Vec<u64> = relation
+                .members
+                .iter()
+                .filter(|m| m.type_ == "node")
+                .map(|m| m.ref_)
+                .collect();
+            let mut nodes = self.read_nodes(&node_ids).into_iter();
 
             for m in &relation.members {
                 match m.type_.as_str() {
-                    "node" => members.push(ElementFull::Node(self.read_node(m.ref_))),
+                    "node" => members.push(ElementFull::Node(nodes.next().unwrap())),
                     "way" => members.push(ElementFull::Way(self.read_way_full(m.ref_))),
                     "relation" => {
                         let mut prev_relations = prev_relations.to_owned();
@@ -246,6 +261,24 @@ pub trait OsmReader {
     }
 }
 
+/// Read elements with `read` by increasing ids, reading each id once, and return them in the
+/// order of `ids`
+///
+/// Ids are allocated in increasing order in files indexed by id, so that elements are read
+/// with less seeks than in the order they are used, like nodes of a way.
+pub fn read_sorted<T: Clone>(
+    ids: &[u64],
+    mut read: impl FnMut(u64) -> Option<T>,
+) -> Vec<Option<T>> {
+    let mut sorted_ids = ids.to_vec();
+    sorted_ids.sort_unstable();
+    sorted_ids.dedup();
+    let elements: Vec<Option<T>> = sorted_ids.iter().map(|id| read(*id)).collect();
+    ids.iter()
+        .map(|id| elements[sorted_ids.binary_search(id).unwrap()].clone())
+        .collect()
+}
+
 /// Writer writing a new node/way/relation
 pub trait OsmWriter {
     fn write_node(&mut self, node: &mut Node) -> Result<(), io::Error>;
@@ -259,6 +292,16 @@ pub trait OsmWriter {
         Ok(())
     }
 
+    /// Whether tags and metadata of nodes and ways are stored, so that readers can skip them
+    /// otherwise
+    fn stores_metadata(&self) -> bool {
+        true
+    }
+    /// Whether versions of elements are stored, so that readers can skip them otherwise
+    fn stores_versions(&self) -> bool {
+        true
+    }
+
     fn import(&mut self, filename: &str) -> Result<(), Box<dyn Error>>
     where
         Self: Sized,
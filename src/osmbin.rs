@@ -2,64 +2,166 @@
 
 use chrono;
 use serde_json;
-use std::borrow::Cow;
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fmt;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, ErrorKind};
 use std::io::{BufRead, Read, Seek, SeekFrom, Write};
 use std::io::{BufReader, BufWriter};
-use std::mem;
-use std::path::Path;
+use std::num::NonZeroU64;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
 
 use crate::bufreaderwriter;
-use crate::osm::{Action, Node, Relation, Way};
-use crate::osm::{OsmReader, OsmUpdate, OsmWriter};
+use crate::macros::printlnt;
+use crate::osm::{self, Action, Node, Relation, Way};
+use crate::osm::{NotSupportedFileType, OsmCopyTo, OsmReader, OsmUpdate, OsmWriter};
 use crate::osmcache::OsmCache;
+use crate::osmgeom;
+use crate::osmpbf::OsmPbf;
+use crate::osmxml::OsmXml;
+
+mod cache;
+mod check;
+mod diff;
+pub(crate) mod encoding;
+mod format;
+mod journal;
+mod lock;
+mod metadata;
+pub mod mmap;
+mod nodes;
+mod parents;
+pub mod shared;
+mod snapshot;
+mod sort;
+mod tiles;
+mod undo;
+mod versions;
+
+pub use check::{CheckError, CheckReport};
+pub use format::{CoordEncoding, Format, NodeStore, WayEncoding};
+pub use snapshot::Snapshot;
+pub use tiles::Extract;
+pub use versions::VersionCheck;
 
 const NODE_CRD: &str = "node.crd";
 const WAY_IDX: &str = "way.idx";
 const WAY_DATA: &str = "way.data";
 const WAY_FREE: &str = "way.free";
+const RELATION_IDX: &str = "relation.idx";
+const RELATION_DATA: &str = "relation.data";
+const RELATION_FREE: &str = "relation.free";
+const STATE: &str = "state.txt";
+const FORMAT: &str = "format.txt";
+const WAY_IDX_COMPACT: &str = "way.idx.compact";
+const WAY_DATA_COMPACT: &str = "way.data.compact";
+/// Present while compacted files replace current ones
+const COMPACT: &str = "compact";
+/// Directory where a database is rewritten with another format
+const MIGRATE_DIR: &str = "migrate.tmp";
+/// Present while migrated files replace current ones
+const MIGRATE: &str = "migrate";
+/// Directory used by previous versions to store one json file per relation
+const RELATION_LEGACY_DIR: &str = "relation";
 
-/// Size of a node-id stored in `node.crd` or `way.data`
+/// Default size of a node-id stored in `way.data`
 pub const NODE_ID_SIZE: usize = 5;
-/// Size of a way pointer in `way.idx` to `way.data`
+/// Default size of a way pointer in `way.idx` to `way.data`
 pub const WAY_PTR_SIZE: usize = 5;
+/// Default size of a relation pointer in `relation.idx` to `relation.data`
+pub const RELATION_PTR_SIZE: usize = 5;
+/// Size of the header of a relation in `relation.data`: allocated size and used size
+const RELATION_HEADER_SIZE: u64 = 8;
+/// Granularity of allocations in `relation.data`
+const RELATION_ALLOC_SIZE: u64 = 32;
 
 /// Simplified OpenStreetMap database
 ///
 /// Database used by `OsmBin` is stored in few files:
+/// - `format.txt`: layout of other files, as described by [`Format`]. A database without this
+///   file uses the default layout.
 /// - `node.crd`: stores latitude/longitude of node, as 2*4 bytes. File is directly indexed by node
 ///   id. Not allocated nodes are not written to file, so its size is smaller than `max(node_id) *
-///   8`, thanks to sparse files.
-/// - `way.idx`: stores a pointer into `way.data`, as [`WAY_PTR_SIZE`] bytes. File is directly
-///   indexed by way id.
+///   8`, thanks to sparse files. With [`NodeStore::Hash`], this file is empty and coordinates
+///   are stored in `node.hash`, a hash table only using space for stored nodes.
+/// - `way.idx`: stores a pointer into `way.data`, as [`WAY_PTR_SIZE`] bytes by default. File is
+///   directly indexed by way id.
 /// - `way.data`: stores a list of nodes id, as `number of nodes` (2-bytes, as OSM limit is 2000),
-///   followed by N node-id (each using [`NODE_ID_SIZE`] bytes). File is indexed by pointer given
-///   by `way.idx`.
+///   followed by N node-id (each using [`NODE_ID_SIZE`] bytes by default). File is indexed by
+///   pointer given by `way.idx`. Node ids can also be delta-encoded, as described by
+///   [`WayEncoding`].
 /// - `way.free`: stores pointer to `way.data` of free space, used to update or allocate a new way
 ///   without needing to allocate at the end of file. It is filled from ways that are deleted from
-///   database
+///   database, with the header of their entry
+/// - `relation.idx`: stores a pointer into `relation.data`, as [`RELATION_PTR_SIZE`] bytes by
+///   default. File is directly indexed by relation id.
+/// - `relation.data`: stores relations, as allocated size (4-bytes), used size (4-bytes), followed
+///   by the relation in a compact binary encoding. Allocated size is rounded to 32 bytes, so that
+///   space freed by a deleted relation can be reused by a relation of a slightly different size.
+/// - `relation.free`: stores pointer to `relation.data` of free space, with its allocated size.
+/// - `node.parents.*` and `way.parents.*`: optional reverse indexes, storing ways using each
+///   node and relations using each way, with the same layout as `relation.*` files.
+/// - `node.tiles.*`: optional spatial index, storing blocks of `node.crd` with nodes inside each
+///   tile, with the same layout as `relation.*` files.
+/// - `node.meta.*` and `way.meta.*`: optional tags, version, timestamp, user and changeset of
+///   nodes and ways, stored in append-only data files, as described by [`Format::metadata`].
+/// - `node.version`, `way.version` and `relation.version`: optional version of each element, as
+///   4 bytes directly indexed by element id, used to detect diffs applied twice or out of order.
+/// - `undo/`: optional inverse changesets of the last applied diffs, used to roll them back, as
+///   described by [`Format::undo`].
+/// - `state.txt`: replication state of the last diff applied to database, in the same format as
+///   `state.txt` files from planet replication.
+/// - `journal`: only present while a diff is being applied, stores previous content of all
+///   modified data, to be able to restore the database if the update is interrupted.
+/// - `sort.tmp/`: only present while importing a file not sorted by id, stores sorted runs of
+///   elements.
+/// - `lock`: locked by readers and writers, so that a database is not read while a diff is
+///   being applied.
 pub struct OsmBin {
     dir: String,
+    mode: OpenMode,
+    format: Format,
     node_crd: bufreaderwriter::BufReaderWriterRand<File>,
     way_idx: bufreaderwriter::BufReaderWriterRand<File>,
     way_data: bufreaderwriter::BufReaderWriterRand<File>,
     way_free_data: HashMap<u16, Vec<u64>>,
+    relation_idx: bufreaderwriter::BufReaderWriterRand<File>,
+    relation_data: bufreaderwriter::BufReaderWriterRand<File>,
+    relation_free_data: BTreeMap<u64, Vec<u64>>,
+    node_parents: Option<parents::Parents>,
+    way_parents: Option<parents::Parents>,
+    tiles: Option<tiles::Tiles>,
+    node_hash: Option<nodes::NodeHash>,
+    node_metadata: Option<metadata::Metadata>,
+    way_metadata: Option<metadata::Metadata>,
+    versions: Option<versions::Versions>,
+    version_check: VersionCheck,
+    undo: Option<undo::UndoLog>,
+    /// Entry of `undo/` applied by current update
+    rollback_entry: Option<u64>,
 
     node_crd_init_size: u64,
     way_idx_init_size: u64,
     way_data_size: u64,
+    relation_idx_init_size: u64,
+    relation_data_size: u64,
 
+    /// Highest ids written since start of import or update
     prev_node_id: u64,
     prev_way_id: u64,
 
-    cache: OsmCache,
+    journal: Option<journal::Journal>,
+    pending_state: Option<ReplicationState>,
+
+    cache: cache::ElementCache,
 
     stats: OsmBinStats,
+
+    /// Released after database is closed
+    lock: Option<lock::DbLock>,
 }
 
 #[allow(clippy::struct_field_names)]
@@ -71,37 +173,105 @@ struct OsmBinStats {
     num_seek_node_crd: u64,
     num_seek_way_idx: u64,
     num_seek_way_data: u64,
+    num_seek_relation_idx: u64,
+    num_seek_relation_data: u64,
     num_hit_nodes: u64,
     num_hit_ways: u64,
     num_hit_relations: u64,
+    /// Elements of a diff skipped, as they are not newer than database
+    num_stale: u64,
 }
 
+#[derive(Clone, Copy)]
 enum OpenMode {
     Read,
     Write,
 }
 
-macro_rules! printlnt {
-    ($($arg:tt)*) => {
-        println!("{} {}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"), format_args!($($arg)*));
-    };
+/// Replication state, as found in `state.txt` files from planet replication
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplicationState {
+    pub sequence_number: u64,
+    pub timestamp: Option<String>,
+}
+
+impl ReplicationState {
+    /// Read a `state.txt` file
+    pub fn from_file(filename: &Path) -> Result<ReplicationState, Box<dyn Error>> {
+        let content = fs::read_to_string(filename)?;
+        Self::parse(&content).ok_or_else(|| {
+            format!("State file {} has an incorrect format", filename.display()).into()
+        })
+    }
+
+    fn parse(content: &str) -> Option<ReplicationState> {
+        let mut sequence_number = None;
+        let mut timestamp = None;
+        for l in content.lines() {
+            if let Some(v) = l.strip_prefix("sequenceNumber=") {
+                sequence_number = v.trim().parse().ok();
+            } else if let Some(v) = l.strip_prefix("timestamp=") {
+                timestamp = Some(v.trim().to_string());
+            }
+        }
+        Some(ReplicationState {
+            sequence_number: sequence_number?,
+            timestamp,
+        })
+    }
+
+    fn to_content(&self) -> String {
+        match &self.timestamp {
+            Some(timestamp) => format!(
+                "sequenceNumber={}\ntimestamp={timestamp}\n",
+                self.sequence_number
+            ),
+            None => format!("sequenceNumber={}\n", self.sequence_number),
+        }
+    }
 }
 
 impl OsmBin {
     /// Access an OsmBin database in read-only mode
-    pub fn new(dir: &str) -> Result<OsmBin, io::Error> {
-        Self::new_any(dir, &OpenMode::Read)
+    ///
+    /// Waits while another process has opened database in read-write mode.
+    pub fn new(dir: &str) -> Result<OsmBin, OsmBinError> {
+        Self::new_any(dir, OpenMode::Read, true)
     }
     /// Access an OsmBin database in read-write mode
-    pub fn new_writer(dir: &str) -> Result<OsmBin, io::Error> {
-        Self::new_any(dir, &OpenMode::Write)
+    ///
+    /// Waits while another process has opened database.
+    pub fn new_writer(dir: &str) -> Result<OsmBin, OsmBinError> {
+        Self::new_any(dir, OpenMode::Write, true)
     }
-    fn new_any(dir: &str, mode: &OpenMode) -> Result<OsmBin, io::Error> {
+    /// Access an OsmBin database in read-only mode, or fail with [`OsmBinError::Locked`] if
+    /// database is opened in read-write mode
+    pub fn try_new(dir: &str) -> Result<OsmBin, OsmBinError> {
+        Self::new_any(dir, OpenMode::Read, false)
+    }
+    /// Access an OsmBin database in read-write mode, or fail with [`OsmBinError::Locked`] if
+    /// database is opened
+    pub fn try_new_writer(dir: &str) -> Result<OsmBin, OsmBinError> {
+        Self::new_any(dir, OpenMode::Write, false)
+    }
+    #[allow(clippy::too_many_lines)]
+    fn new_any(dir: &str, mode: OpenMode, wait: bool) -> Result<OsmBin, OsmBinError> {
+        let lock = lock::DbLock::new(dir, matches!(mode, OpenMode::Write), wait)?;
         let mut file_options = OpenOptions::new();
         file_options.read(true);
         if let OpenMode::Write = mode {
             file_options.write(true);
+            if journal::Journal::recover(Path::new(dir))? {
+                printlnt!("Rolled back interrupted update on {dir}");
+            }
+            if Self::finish_compact(Path::new(dir))? {
+                printlnt!("Finished interrupted compaction on {dir}");
+            }
+            if Self::finish_migrate(Path::new(dir))? {
+                printlnt!("Finished interrupted migration on {dir}");
+            }
         }
+        let format = Format::from_file(&Path::new(dir).join(FORMAT))?;
         let node_crd = file_options.open(Path::new(dir).join(NODE_CRD))?;
         let node_crd_init_size = node_crd.metadata()?.len();
         let node_crd = bufreaderwriter::BufReaderWriterRand::new_reader(node_crd);
@@ -113,89 +283,239 @@ impl OsmBin {
         let way_data_size = way_data.metadata()?.len();
         let way_data = bufreaderwriter::BufReaderWriterRand::new_reader(way_data);
 
-        let way_free = file_options.open(Path::new(dir).join(WAY_FREE))?;
-        let way_free = BufReader::new(way_free);
-        let mut way_free_data: HashMap<u16, Vec<u64>> = HashMap::new();
-
-        if let OpenMode::Write = mode {
-            for line in way_free.lines() {
-                let line = line.unwrap();
-                let mut s = line.split(';');
-                let pos: u64 = s.next().unwrap().parse().unwrap();
-                let num_nodes: u16 = s.next().unwrap().parse().unwrap();
-                way_free_data.entry(num_nodes).or_default().push(pos);
+        let relation_idx = match file_options.open(Path::new(dir).join(RELATION_IDX)) {
+            Err(e)
+                if e.kind() == ErrorKind::NotFound
+                    && Path::new(dir).join(RELATION_LEGACY_DIR).is_dir() =>
+            {
+                return Err(OsmBinError::FormatMismatch {
+                    dir: dir.to_string(),
+                    reason:
+                        "relations use a previous format, please run osmbin --migrate-relations"
+                            .to_string(),
+                });
             }
-        }
+            r => r?,
+        };
+        let relation_idx_init_size = relation_idx.metadata()?.len();
+        let relation_idx = bufreaderwriter::BufReaderWriterRand::new_reader(relation_idx);
+
+        let relation_data = file_options.open(Path::new(dir).join(RELATION_DATA))?;
+        let relation_data_size = relation_data.metadata()?.len();
+        let relation_data = bufreaderwriter::BufReaderWriterRand::new_reader(relation_data);
+
+        let (way_free_data, relation_free_data) = match mode {
+            OpenMode::Write => Self::read_free_data(dir)?,
+            OpenMode::Read => (HashMap::new(), BTreeMap::new()),
+        };
+
+        let write = matches!(mode, OpenMode::Write);
+        let node_parents = format
+            .parents
+            .then(|| {
+                parents::Parents::open(dir, &parents::NODE_PARENTS, format.way_ptr_size, write)
+            })
+            .transpose()?;
+        let way_parents = format
+            .parents
+            .then(|| {
+                parents::Parents::open(dir, &parents::WAY_PARENTS, format.relation_ptr_size, write)
+            })
+            .transpose()?;
+        let tiles = format
+            .tiles
+            .then(|| tiles::Tiles::open(dir, write))
+            .transpose()?;
+        let node_hash = (format.node_store == NodeStore::Hash)
+            .then(|| nodes::NodeHash::open(dir, write))
+            .transpose()?;
+        let node_metadata = format
+            .metadata
+            .then(|| metadata::Metadata::open(dir, &metadata::NODE_METADATA, write))
+            .transpose()?;
+        let way_metadata = format
+            .metadata
+            .then(|| metadata::Metadata::open(dir, &metadata::WAY_METADATA, write))
+            .transpose()?;
+        let versions = format
+            .versions
+            .then(|| versions::Versions::open(dir, write))
+            .transpose()?;
+        let undo = (format.undo > 0).then(|| undo::UndoLog::open(dir, format.undo));
 
         Ok(OsmBin {
             dir: dir.to_string(),
+            mode,
+            format,
             node_crd,
             way_idx,
             way_data,
             way_free_data,
+            relation_idx,
+            relation_data,
+            relation_free_data,
+            node_parents,
+            way_parents,
+            tiles,
+            node_hash,
+            node_metadata,
+            way_metadata,
+            versions,
+            version_check: VersionCheck::default(),
+            undo,
+            rollback_entry: None,
             node_crd_init_size,
             way_idx_init_size,
             way_data_size,
+            relation_idx_init_size,
+            relation_data_size,
             prev_node_id: 0,
             prev_way_id: 0,
-            cache: OsmCache::default(),
+            journal: None,
+            pending_state: None,
+            cache: cache::ElementCache::default(),
             stats: OsmBinStats {
                 ..Default::default()
             },
+            lock: Some(lock),
         })
     }
 
     /// Initialize an OsmBin database with all required files
-    pub fn init(dir: &str) {
-        match fs::create_dir_all(dir) {
-            Ok(()) => (),
-            Err(error) => match error.kind() {
-                ErrorKind::AlreadyExists => (),
-                _ => panic!("Error with directory {dir}: {error}"),
-            },
-        }
+    pub fn init(dir: &str) -> Result<(), OsmBinError> {
+        Self::init_with_format(dir, &Format::default())
+    }
 
-        for filename in [NODE_CRD, WAY_IDX, WAY_DATA, WAY_FREE] {
+    /// Initialize an OsmBin database with all required files, using given layout
+    ///
+    /// If database already exists, it must use the same layout.
+    pub fn init_with_format(dir: &str, format: &Format) -> Result<(), OsmBinError> {
+        format
+            .validate()
+            .map_err(|reason| OsmBinError::FormatMismatch {
+                dir: dir.to_string(),
+                reason: format!("requested format is not valid, {reason}"),
+            })?;
+        fs::create_dir_all(dir)?;
+
+        for filename in [
+            NODE_CRD,
+            WAY_IDX,
+            WAY_DATA,
+            WAY_FREE,
+            RELATION_IDX,
+            RELATION_DATA,
+            RELATION_FREE,
+        ] {
             let full_filename = Path::new(dir).join(filename);
-            let f = File::create_new(full_filename);
-            match f {
-                Ok(mut file) => {
-                    if filename == WAY_DATA && file.write_all(b"--").is_err() {
-                        panic!("Could not write to {filename}");
-                    }
+            match File::create_new(full_filename) {
+                // Pointer 0 is used for a missing element
+                Ok(mut file) if filename == WAY_DATA || filename == RELATION_DATA => {
+                    file.write_all(b"--")?;
                 }
-                Err(error) => match error.kind() {
-                    ErrorKind::AlreadyExists => (),
-                    _ => panic!("Error with file {filename}: {error}"),
-                },
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+                Err(e) => return Err(e.into()),
             }
         }
-        match fs::create_dir_all(Path::new(dir).join("relation")) {
-            Ok(()) => (),
-            Err(error) => match error.kind() {
-                ErrorKind::AlreadyExists => (),
-                _ => panic!("Error with directory {dir}: {error}"),
-            },
+
+        if format.parents {
+            for files in [&parents::NODE_PARENTS, &parents::WAY_PARENTS] {
+                parents::Parents::init(dir, files)?;
+            }
+        }
+        if format.tiles {
+            tiles::Tiles::init(dir)?;
+        }
+        if format.node_store == NodeStore::Hash {
+            nodes::NodeHash::init(dir)?;
+        }
+        if format.metadata {
+            for files in [&metadata::NODE_METADATA, &metadata::WAY_METADATA] {
+                metadata::Metadata::init(dir, files)?;
+            }
+        }
+        if format.versions {
+            versions::Versions::init(dir)?;
         }
+
+        let format_path = Path::new(dir).join(FORMAT);
+        if format_path.exists() {
+            if Format::from_file(&format_path)? != *format {
+                return Err(OsmBinError::FormatMismatch {
+                    dir: dir.to_string(),
+                    reason: "please run osmbin --migrate".to_string(),
+                });
+            }
+        } else {
+            fs::write(&format_path, format.to_content())?;
+        }
+        Ok(())
+    }
+
+    /// Convert relations stored by a previous version, as one json file per relation in
+    /// `relation/` directory, to `relation.idx` and `relation.data`
+    ///
+    /// Returns the number of converted relations. `relation/` directory is removed once all
+    /// relations were converted, and the conversion can be restarted if interrupted.
+    pub fn migrate_relations(dir: &str) -> Result<u64, Box<dyn Error>> {
+        let relation_dir = Path::new(dir).join(RELATION_LEGACY_DIR);
+        Self::init(dir)?;
+        let mut osmbin = Self::new_writer(dir)?;
+        let mut num_relations = 0;
+
+        for dir0 in Self::read_sorted_dir(&relation_dir)? {
+            for dir1 in Self::read_sorted_dir(&dir0)? {
+                printlnt!("{}", dir1.strip_prefix(&relation_dir)?.display());
+                for rel_path in Self::read_sorted_dir(&dir1)? {
+                    let id_str: String = [&dir0, &dir1, &rel_path]
+                        .iter()
+                        .map(|p| p.file_name().unwrap().to_string_lossy())
+                        .collect();
+                    let mut relation: Relation =
+                        serde_json::from_str(&fs::read_to_string(&rel_path)?).map_err(|e| {
+                            OsmBinError::corrupt(&rel_path.display().to_string(), 0, e.to_string())
+                        })?;
+                    relation.id = id_str.parse()?;
+                    osmbin.write_relation(&mut relation)?;
+                    num_relations += 1;
+                }
+            }
+        }
+        osmbin.flush()?;
+        osmbin.relation_idx.get_ref().sync_all()?;
+        osmbin.relation_data.get_ref().sync_all()?;
+        drop(osmbin);
+
+        fs::remove_dir_all(&relation_dir)?;
+        Ok(num_relations)
+    }
+
+    fn read_sorted_dir(dir: &Path) -> Result<Vec<PathBuf>, io::Error> {
+        let mut paths = fs::read_dir(dir)?
+            .map(|res| res.map(|e| e.path()))
+            .collect::<Result<Vec<_>, io::Error>>()?;
+        paths.sort();
+        Ok(paths)
     }
 
     pub fn print_stats(&mut self) {
-        self.stats.print_stats();
+        self.stats.print_stats(&self.cache);
     }
 
-    fn bytes5_to_int(d: [u8; 5]) -> u64 {
-        let mut arr: Vec<u8> = Vec::with_capacity(8);
-        arr.extend([0; 3]);
-        arr.extend(d);
-        u64::from_be_bytes(arr.as_slice().try_into().unwrap())
+    /// Convert big-endian bytes, from 1 to 8 bytes, to an integer
+    fn bytes_to_int(d: &[u8]) -> u64 {
+        let mut arr = [0u8; 8];
+        arr[8 - d.len()..].copy_from_slice(d);
+        u64::from_be_bytes(arr)
     }
-    fn int_to_bytes5(d: u64) -> [u8; 5] {
-        if d > 2_u64.pow(5 * 8) {
-            panic!("Integer {d:#x} do not fit on 5 bytes");
+    /// Convert an integer to big-endian bytes, filling the whole `buffer`
+    fn int_to_bytes(d: u64, buffer: &mut [u8]) {
+        let size = buffer.len();
+        if size < 8 && d >> (size * 8) != 0 {
+            panic!("Integer {d:#x} do not fit on {size} bytes");
         }
-        let v = d.to_be_bytes();
-        let arr: [u8; 5] = v[3..8].try_into().unwrap();
-        arr
+        buffer.copy_from_slice(&d.to_be_bytes()[8 - size..]);
     }
 
     fn bytes4_to_int(d: [u8; 4]) -> u32 {
@@ -205,18 +525,6 @@ impl OsmBin {
         d.to_be_bytes()
     }
 
-    #[allow(clippy::cast_possible_truncation)]
-    fn bytes4_to_coord(d: [u8; 4]) -> i32 {
-        // TODO: Store directly i32 instead of converting to a positive number
-        (i64::from(Self::bytes4_to_int(d)) - 1_800_000_000) as i32
-    }
-    fn coord_to_bytes4(d: i32) -> [u8; 4] {
-        // TODO: Store directly i32 instead of converting to a positive number
-        #[allow(clippy::cast_possible_truncation)]
-        #[allow(clippy::cast_sign_loss)]
-        Self::int_to_bytes4((i64::from(d) + 1_800_000_000) as u32)
-    }
-
     fn bytes2_to_int(d: [u8; 2]) -> u16 {
         u16::from_be_bytes(d)
     }
@@ -224,28 +532,345 @@ impl OsmBin {
         d.to_be_bytes()
     }
 
-    fn to_digits(v: u64) -> Vec<u8> {
-        let mut v = v;
-        let mut digits: Vec<u8> = Vec::with_capacity(10);
-        while v > 0 {
-            let n = (v % 10) as u8;
-            v /= 10;
-            digits.push(n);
+    /// Get ids of ways using node `id`, from reverse index
+    pub fn read_node_parents(&self, id: u64) -> Result<Vec<u64>, io::Error> {
+        self.node_parents
+            .as_ref()
+            .ok_or_else(|| self.no_parents())?
+            .read(id)
+    }
+
+    /// Get ids of relations using way `id`, from reverse index
+    pub fn read_way_parents(&self, id: u64) -> Result<Vec<u64>, io::Error> {
+        self.way_parents
+            .as_ref()
+            .ok_or_else(|| self.no_parents())?
+            .read(id)
+    }
+
+    /// Lists stored by optional indexes, with the same layout as `relation.*` files
+    fn list_indexes(&self) -> impl Iterator<Item = &parents::Parents> {
+        self.node_parents
+            .iter()
+            .chain(&self.way_parents)
+            .chain(self.tiles.as_ref().map(tiles::Tiles::lists))
+    }
+
+    /// Optional stores of tags and metadata
+    fn metadata_stores(&self) -> impl Iterator<Item = &metadata::Metadata> {
+        self.node_metadata.iter().chain(&self.way_metadata)
+    }
+
+    fn no_parents(&self) -> io::Error {
+        io::Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "Database {} has no reverse index, please run osmbin --migrate --parents true",
+                self.dir
+            ),
+        )
+    }
+
+    /// Set what to do when a diff has an element not newer than database, if versions are
+    /// stored
+    pub fn set_version_check(&mut self, version_check: VersionCheck) {
+        self.version_check = version_check;
+    }
+
+    /// Get layout of database files
+    pub fn get_format(&self) -> Format {
+        self.format
+    }
+
+    /// Set maximum size in bytes of the cache of elements read, or `None` for an unbounded cache
+    ///
+    /// Least recently read elements are evicted once the approximate size of cache exceeds
+    /// `limit`, so that long imports, checks or bulk reads use a bounded amount of memory.
+    pub fn set_cache_limit(&mut self, limit: Option<usize>) {
+        self.cache.set_limit(limit);
+    }
+
+    /// Keep elements evicted from a bounded cache, so that [`OsmBin::get_cache`] still returns
+    /// every element read
+    pub fn set_cache_record(&mut self, record: bool) {
+        self.cache.set_record(record);
+    }
+
+    /// Take all elements read since cache was last cleared
+    ///
+    /// With a cache limit, elements evicted from cache are only returned if recording was
+    /// enabled with [`OsmBin::set_cache_record`].
+    pub fn get_cache(&mut self) -> OsmCache {
+        self.cache.take()
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.node_crd.flush()?;
+        self.way_idx.flush()?;
+        self.way_data.flush()?;
+        self.relation_idx.flush()?;
+        self.relation_data.flush()
+    }
+
+    /// Read free space of `way.data` by header, and of `relation.data` by size
+    #[allow(clippy::type_complexity)]
+    fn read_free_data(
+        dir: &str,
+    ) -> Result<(HashMap<u16, Vec<u64>>, BTreeMap<u64, Vec<u64>>), OsmBinError> {
+        let mut way_free_data: HashMap<u16, Vec<u64>> = HashMap::new();
+        let mut relation_free_data: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        for (pos, header) in Self::read_free_list(dir, WAY_FREE)? {
+            let header = u16::try_from(header).map_err(|_| {
+                OsmBinError::corrupt(WAY_FREE, 0, format!("entry {pos};{header} is too big"))
+            })?;
+            way_free_data.entry(header).or_default().push(pos);
         }
-        if digits.len() < 9 {
-            digits.resize(9, 0);
+        for (pos, size) in Self::read_free_list(dir, RELATION_FREE)? {
+            relation_free_data.entry(size).or_default().push(pos);
+        }
+        Ok((way_free_data, relation_free_data))
+    }
+
+    /// Read a list of free space, as `pointer;size` lines
+    fn read_free_list(dir: &str, filename: &str) -> Result<Vec<(u64, u64)>, io::Error> {
+        let free = BufReader::new(File::open(Path::new(dir).join(filename))?);
+        let mut free_list = Vec::new();
+        let mut offset = 0;
+        for line in free.lines() {
+            let line = line?;
+            let entry = line
+                .split_once(';')
+                .and_then(|(pos, size)| Some((pos.parse().ok()?, size.parse().ok()?)));
+            let Some(entry) = entry else {
+                return Err(OsmBinError::corrupt(
+                    filename,
+                    offset,
+                    format!("incorrect entry \"{line}\""),
+                )
+                .into());
+            };
+            free_list.push(entry);
+            offset += line.len() as u64 + 1;
+        }
+        Ok(free_list)
+    }
+
+    fn free_list_content<'a, K: fmt::Display + 'a>(
+        free_data: impl IntoIterator<Item = (&'a K, &'a Vec<u64>)>,
+    ) -> Vec<u8> {
+        let mut content: Vec<u8> = Vec::new();
+        for (size, v) in free_data {
+            for pos in v {
+                writeln!(content, "{pos};{size}").unwrap();
+            }
         }
-        digits.reverse();
-        digits
+        content
     }
 
-    fn join_nums(nums: &[u8]) -> String {
-        let str_nums: Vec<String> = nums.iter().map(std::string::ToString::to_string).collect();
-        str_nums.join("")
+    fn way_free_content(&self) -> Vec<u8> {
+        Self::free_list_content(&self.way_free_data)
     }
 
-    pub fn get_cache(&mut self) -> OsmCache {
-        mem::take(&mut self.cache)
+    fn relation_free_content(&self) -> Vec<u8> {
+        Self::free_list_content(&self.relation_free_data)
+    }
+
+    /// Replace a file by writing a temporary file first
+    fn write_file_atomic(&self, filename: &str, content: &[u8]) -> Result<(), io::Error> {
+        let tmp_path = Path::new(&self.dir).join(filename.to_string() + ".tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, Path::new(&self.dir).join(filename))
+    }
+
+    /// Get replication state of the last diff applied to database
+    pub fn get_state(&self) -> Result<Option<ReplicationState>, OsmBinError> {
+        let content = match fs::read_to_string(Path::new(&self.dir).join(STATE)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            r => r?,
+        };
+        ReplicationState::parse(&content).map(Some).ok_or_else(|| {
+            OsmBinError::corrupt(STATE, 0, "replication state has an incorrect format".into())
+        })
+    }
+
+    /// Set replication state of database, for example after importing a planet file
+    pub fn set_state(&mut self, state: &ReplicationState) -> Result<(), io::Error> {
+        self.write_file_atomic(STATE, state.to_content().as_bytes())
+    }
+
+    /// Apply a diff file, and store its replication state in database
+    ///
+    /// The diff must be the one following the current state of database, unless `force` is set.
+    /// Replication state is written in the same transaction as the diff.
+    pub fn update_with_state(
+        &mut self,
+        filename: &str,
+        state: &ReplicationState,
+        force: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(cur_state) = self.get_state()?
+            && cur_state.sequence_number + 1 != state.sequence_number
+            && !force
+        {
+            return Err(Box::new(StateMismatch {
+                expected: cur_state.sequence_number + 1,
+                found: state.sequence_number,
+            }));
+        }
+        self.pending_state = Some(state.clone());
+        let res = self.update(filename);
+        self.pending_state = None;
+        res
+    }
+
+    /// Restore database to its state before the last `num` diffs were applied
+    ///
+    /// Inverse changesets are only recorded if [`Format::undo`] is set, and only for its number
+    /// of last diffs. Each diff is rolled back in its own transaction, with the replication state
+    /// database had before it.
+    pub fn rollback(&mut self, num: usize) -> Result<(), Box<dyn Error>> {
+        let Some(undo) = &self.undo else {
+            return Err(format!(
+                "Database {} doesn't record applied diffs, please run osmbin --migrate --undo N",
+                self.dir
+            )
+            .into());
+        };
+        let entries = undo.entries()?;
+        if entries.len() < num {
+            return Err(format!(
+                "Database {} can only roll back {} diffs",
+                self.dir,
+                entries.len()
+            )
+            .into());
+        }
+        let diff_paths: Vec<PathBuf> = entries
+            .iter()
+            .rev()
+            .take(num)
+            .map(|index| undo.diff_path(*index))
+            .collect();
+        for (index, diff_path) in entries.iter().rev().zip(diff_paths) {
+            self.rollback_entry = Some(*index);
+            let res = self.update(diff_path.to_str().unwrap());
+            self.rollback_entry = None;
+            res?;
+        }
+        Ok(())
+    }
+
+    /// Start journaling all modifications, until [`commit`](Self::commit) is called
+    fn begin(&mut self) -> Result<(), io::Error> {
+        self.flush()?;
+        // Table can't be resized while journaling, it needs room for new nodes of update
+        if let Some(node_hash) = self.node_hash.as_mut() {
+            node_hash.reserve()?;
+        }
+        let node_crd_size = self.node_crd.get_ref().metadata()?.len();
+        let way_idx_size = self.way_idx.get_ref().metadata()?.len();
+        let way_data_size = self.way_data.get_ref().metadata()?.len();
+        let relation_idx_size = self.relation_idx.get_ref().metadata()?.len();
+        let relation_data_size = self.relation_data.get_ref().metadata()?.len();
+        self.node_crd_init_size = node_crd_size;
+        self.way_idx_init_size = way_idx_size;
+        self.relation_idx_init_size = relation_idx_size;
+        let mut sizes = vec![
+            (NODE_CRD, node_crd_size),
+            (WAY_IDX, way_idx_size),
+            (WAY_DATA, way_data_size),
+            (RELATION_IDX, relation_idx_size),
+            (RELATION_DATA, relation_data_size),
+        ];
+        let mut snapshots = vec![
+            (WAY_FREE, Some(self.way_free_content())),
+            (RELATION_FREE, Some(self.relation_free_content())),
+            (STATE, fs::read(Path::new(&self.dir).join(STATE)).ok()),
+        ];
+        for parents in self.list_indexes() {
+            sizes.extend(parents.sizes()?);
+            snapshots.push((parents.files().free, Some(parents.free_content())));
+        }
+        if let Some(node_hash) = &self.node_hash {
+            sizes.extend(node_hash.sizes()?);
+        }
+        for metadata in self.metadata_stores() {
+            sizes.extend(metadata.sizes()?);
+        }
+        if let Some(versions) = &self.versions {
+            sizes.extend(versions.sizes()?);
+        }
+        let undo_snapshots = match self.undo.as_mut() {
+            Some(undo) => undo.begin(self.rollback_entry)?,
+            None => Vec::new(),
+        };
+        for (filename, content) in &undo_snapshots {
+            snapshots.push((filename, content.clone()));
+        }
+        self.journal = Some(journal::Journal::begin(
+            Path::new(&self.dir),
+            &sizes,
+            &snapshots,
+        )?);
+        Ok(())
+    }
+
+    /// Sync all modifications to disk, and remove journal
+    fn commit(&mut self) -> Result<(), io::Error> {
+        if let Some(journal) = self.journal.take() {
+            self.flush()?;
+            self.node_crd.get_ref().sync_all()?;
+            self.way_idx.get_ref().sync_all()?;
+            self.way_data.get_ref().sync_all()?;
+            self.relation_idx.get_ref().sync_all()?;
+            self.relation_data.get_ref().sync_all()?;
+            self.write_file_atomic(WAY_FREE, &self.way_free_content())?;
+            self.write_file_atomic(RELATION_FREE, &self.relation_free_content())?;
+            for parents in self.list_indexes() {
+                parents.sync_all()?;
+                self.write_file_atomic(parents.files().free, &parents.free_content())?;
+            }
+            if let Some(node_hash) = &self.node_hash {
+                node_hash.sync_all()?;
+            }
+            for metadata in self.metadata_stores() {
+                metadata.sync_all()?;
+            }
+            if let Some(versions) = &self.versions {
+                versions.sync_all()?;
+            }
+            if let Some(state) = self.pending_state.take() {
+                self.set_state(&state)?;
+            }
+            if let Some(undo) = self.undo.as_mut() {
+                undo.commit()?;
+            }
+            journal.commit()?;
+            if let Some(undo) = &self.undo {
+                undo.prune()?;
+            }
+            // Cached elements may have been modified by update
+            self.cache.clear();
+        }
+        Ok(())
+    }
+
+    /// Save previous content of a data file before it is overwritten
+    fn journal_range(&mut self, filename: &str, offset: u64, len: u64) -> Result<(), io::Error> {
+        if let Some(journal) = self.journal.as_mut() {
+            let file = match filename {
+                NODE_CRD => self.node_crd.get_ref(),
+                WAY_IDX => self.way_idx.get_ref(),
+                WAY_DATA => self.way_data.get_ref(),
+                RELATION_IDX => self.relation_idx.get_ref(),
+                RELATION_DATA => self.relation_data.get_ref(),
+                _ => panic!("File {filename} is not journaled"),
+            };
+            journal.record_range(filename, file, offset, len)?;
+        }
+        Ok(())
     }
 
     fn check_node(&mut self, id: u64) -> Result<(), ElementNotFound> {
@@ -259,7 +884,7 @@ impl OsmBin {
         Ok(())
     }
     fn check_way(&mut self, id: u64) -> Result<(), ElementNotFound> {
-        if self.cache.ways.contains_key(&id) {
+        if self.cache.contains_way(id) {
             return Ok(());
         }
         let way = self.read_way(id);
@@ -281,11 +906,11 @@ impl OsmBin {
         }
     }
     fn check_relation(&mut self, id: u64, prev_relations: &[u64]) -> Result<(), ElementNotFound> {
-        if self.cache.relations.contains_key(&id) {
+        if self.cache.contains_relation(id) {
             return Ok(());
         }
         if prev_relations.contains(&id) {
-            println!("Detected relation recursion on id={id} - {prev_relations:?}",);
+            println!("Detected relation recursion on id={id} - {prev_relations:?}");
             return Ok(());
         }
         let relation = self.read_relation(id);
@@ -325,46 +950,323 @@ impl OsmBin {
             })
         }
     }
-    pub fn check_database(&mut self, start: u64) -> Result<(), Box<dyn Error>> {
-        let s0: Cow<str> = format!("{:03}", start / 1_000_000).into();
-        let s1: Cow<str> = format!("{:03}", start / 1_000).into();
+    /// Rewrite `way.data` without free space, with ways ordered by id
+    ///
+    /// New `way.idx` and `way.data` are written to temporary files, which replace the current
+    /// ones when complete. `node.hash` is also rewritten without deleted nodes, and resized for
+    /// its current number of nodes. Returns the number of bytes reclaimed from `way.data`.
+    pub fn compact(&mut self) -> Result<u64, Box<dyn Error>> {
+        if self.journal.is_some() {
+            return Err(OsmBinError::UpdateInProgress {
+                dir: self.dir.clone(),
+            }
+            .into());
+        }
+        self.flush()?;
+        if let Some(node_hash) = self.node_hash.as_mut() {
+            node_hash.compact()?;
+        }
+        let dir = PathBuf::from(&self.dir);
+        let old_size = self.way_data.get_ref().metadata()?.len();
 
-        let relation_dir = Path::new(&self.dir).join("relation");
-        let mut dirs = fs::read_dir(relation_dir)?
-            .map(|res| res.map(|e| e.path()))
-            .collect::<Result<Vec<_>, io::Error>>()?;
-        dirs.sort();
-        for dir in dirs {
-            let part0 = dir.file_name().expect("Incorrect string").to_string_lossy();
-            if part0 < s0 {
-                continue;
+        let mut way_idx = BufReader::new(File::open(dir.join(WAY_IDX))?);
+        let mut way_data = BufReader::new(File::open(dir.join(WAY_DATA))?);
+        let new_way_idx = File::create(dir.join(WAY_IDX_COMPACT))?;
+        let mut new_way_data = BufWriter::new(File::create(dir.join(WAY_DATA_COMPACT))?);
+        new_way_data.write_all(b"--")?;
+        let mut way_data_pos: u64 = 0;
+        let mut new_size: u64 = 2;
+
+        // way.idx is processed by chunks, to quickly skip over large ranges without ways, and
+        // keep them as holes in new file
+        let way_ptr_size = self.format.way_ptr_size;
+        let mut chunk = vec![0u8; way_ptr_size * 65536];
+        let mut nodes: Vec<u8> = Vec::new();
+        let mut way_idx_pos: u64 = 0;
+        loop {
+            let mut len = 0;
+            while len < chunk.len() {
+                match way_idx.read(&mut chunk[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
             }
-            self.cache.clear();
-            let mut dirs = fs::read_dir(dir.as_path())?
-                .map(|res| res.map(|e| e.path()))
-                .collect::<Result<Vec<_>, io::Error>>()?;
-            dirs.sort();
-            for dir in dirs {
-                let part1 = dir.file_name().expect("Incorrect string").to_string_lossy();
-                if part1 < s1 {
+            if len == 0 {
+                break;
+            }
+            let chunk = &mut chunk[..len];
+            if chunk.iter().any(|b| *b != 0) {
+                for (i, buffer) in chunk.chunks_exact_mut(way_ptr_size).enumerate() {
+                    if buffer.iter().all(|b| *b == 0) {
+                        continue;
+                    }
+                    let way_data_addr = Self::bytes_to_int(buffer);
+                    way_data.seek_relative(
+                        i64::try_from(way_data_addr)? - i64::try_from(way_data_pos)?,
+                    )?;
+                    let mut header = [0u8; 2];
+                    way_data.read_exact(&mut header)?;
+                    if header == [0u8; 2] {
+                        let id = way_idx_pos / way_ptr_size as u64 + i as u64;
+                        return Err(format!("Way {id} points to free space in way.data").into());
+                    }
+                    let size = self.format.way_size(Self::bytes2_to_int(header)) - 2;
+                    nodes.resize(usize::try_from(size)?, 0);
+                    way_data.read_exact(&mut nodes)?;
+                    way_data_pos = way_data_addr + 2 + nodes.len() as u64;
+
+                    Self::int_to_bytes(new_size, buffer);
+                    new_way_data.write_all(&header)?;
+                    new_way_data.write_all(&nodes)?;
+                    new_size += 2 + nodes.len() as u64;
+                }
+                new_way_idx.write_all_at(chunk, way_idx_pos)?;
+            }
+            way_idx_pos += len as u64;
+        }
+        new_way_idx.set_len(way_idx_pos)?;
+        new_way_idx.sync_all()?;
+        new_way_data.into_inner()?.sync_all()?;
+
+        // From now on, an interrupted compaction is finished on next opening of database
+        File::create(dir.join(COMPACT))?.sync_all()?;
+        File::open(&dir)?.sync_all()?;
+        Self::finish_compact(&dir)?;
+
+        let mut file_options = OpenOptions::new();
+        file_options.read(true).write(true);
+        let way_idx = file_options.open(dir.join(WAY_IDX))?;
+        self.way_idx_init_size = way_idx.metadata()?.len();
+        self.way_idx = bufreaderwriter::BufReaderWriterRand::new_reader(way_idx);
+        let way_data = file_options.open(dir.join(WAY_DATA))?;
+        self.way_data_size = way_data.metadata()?.len();
+        self.way_data = bufreaderwriter::BufReaderWriterRand::new_reader(way_data);
+        self.way_free_data.clear();
+        self.cache.clear();
+
+        Ok(old_size - new_size)
+    }
+
+    /// Replace `way.idx` and `way.data` by compacted files, if compaction was complete
+    ///
+    /// Returns `true` if files were replaced.
+    fn finish_compact(dir: &Path) -> Result<bool, io::Error> {
+        if !dir.join(COMPACT).exists() {
+            // Compaction was interrupted before all files were written
+            for filename in [WAY_IDX_COMPACT, WAY_DATA_COMPACT] {
+                match fs::remove_file(dir.join(filename)) {
+                    Err(e) if e.kind() == ErrorKind::NotFound => (),
+                    r => r?,
+                }
+            }
+            return Ok(false);
+        }
+        for (compact_filename, filename) in
+            [(WAY_DATA_COMPACT, WAY_DATA), (WAY_IDX_COMPACT, WAY_IDX)]
+        {
+            if dir.join(compact_filename).exists() {
+                fs::rename(dir.join(compact_filename), dir.join(filename))?;
+            }
+        }
+        File::create(dir.join(WAY_FREE))?.sync_all()?;
+        fs::remove_file(dir.join(COMPACT))?;
+        File::open(dir)?.sync_all()?;
+        Ok(true)
+    }
+
+    /// Rewrite database with another layout, for example to store larger ids
+    ///
+    /// All elements are copied to a new database in a temporary directory, which replaces the
+    /// current files when complete. Returns `false` if database already uses this layout.
+    pub fn migrate(dir: &str, format: &Format) -> Result<bool, Box<dyn Error>> {
+        // Recover an interrupted update or compaction before reading database
+        let mut osmbin = Self::new_writer(dir)?;
+        if osmbin.format == *format {
+            return Ok(false);
+        }
+        // Keep database locked until migrated files replace current ones
+        let _lock = osmbin.lock.take();
+        drop(osmbin);
+
+        let migrate_dir = Path::new(dir).join(MIGRATE_DIR);
+        if migrate_dir.exists() {
+            fs::remove_dir_all(&migrate_dir)?;
+        }
+        let migrate_dir_str = migrate_dir.to_str().unwrap();
+        Self::init_with_format(migrate_dir_str, format)?;
+        let mut new_osmbin = Self::new_writer(migrate_dir_str)?;
+        mmap::OsmBinMmap::new_unlocked(dir)?.copy_to_poly(&mut new_osmbin, None)?;
+        new_osmbin.flush()?;
+        new_osmbin.node_crd.get_ref().sync_all()?;
+        new_osmbin.way_idx.get_ref().sync_all()?;
+        new_osmbin.way_data.get_ref().sync_all()?;
+        new_osmbin.relation_idx.get_ref().sync_all()?;
+        new_osmbin.relation_data.get_ref().sync_all()?;
+        if let Some(node_hash) = &new_osmbin.node_hash {
+            node_hash.sync_all()?;
+        }
+        for metadata in new_osmbin.metadata_stores() {
+            metadata.sync_all()?;
+        }
+        if let Some(versions) = &new_osmbin.versions {
+            versions.sync_all()?;
+        }
+        drop(new_osmbin);
+        match fs::read(Path::new(dir).join(STATE)) {
+            Ok(state) => fs::write(migrate_dir.join(STATE), state)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        File::open(&migrate_dir)?.sync_all()?;
+
+        // From now on, an interrupted migration is finished on next opening of database
+        File::create(Path::new(dir).join(MIGRATE))?.sync_all()?;
+        File::open(dir)?.sync_all()?;
+        Self::finish_migrate(Path::new(dir))?;
+        Ok(true)
+    }
+
+    /// Replace all files by migrated files, if migration was complete
+    ///
+    /// Returns `true` if files were replaced.
+    fn finish_migrate(dir: &Path) -> Result<bool, io::Error> {
+        let migrate_dir = dir.join(MIGRATE_DIR);
+        if !dir.join(MIGRATE).exists() {
+            // Migration was interrupted before all files were written
+            match fs::remove_dir_all(&migrate_dir) {
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                r => r?,
+            }
+            return Ok(false);
+        }
+        let files = |f: &[&parents::ParentsFiles]| -> Vec<&'static str> {
+            f.iter().flat_map(|f| [f.idx, f.data, f.free]).collect()
+        };
+        let parents_files = files(&[&parents::NODE_PARENTS, &parents::WAY_PARENTS]);
+        let tiles_files = files(&[&tiles::NODE_TILES]);
+        let node_hash_files = vec![nodes::NODE_HASH];
+        let metadata_files: Vec<&'static str> = [&metadata::NODE_METADATA, &metadata::WAY_METADATA]
+            .iter()
+            .flat_map(|f| [f.idx, f.data])
+            .collect();
+        let versions_files = vec![
+            versions::NODE_VERSION,
+            versions::WAY_VERSION,
+            versions::RELATION_VERSION,
+        ];
+        let migrate_format = migrate_dir.join(FORMAT);
+        if migrate_format.exists() {
+            let format = Format::from_file(&migrate_format)?;
+            // Optional indexes which are not kept by new format
+            for (enabled, files) in [
+                (format.parents, &parents_files),
+                (format.tiles, &tiles_files),
+                (format.node_store == NodeStore::Hash, &node_hash_files),
+                (format.metadata, &metadata_files),
+                (format.versions, &versions_files),
+            ] {
+                if enabled {
                     continue;
                 }
-                printlnt!("{part0}{part1}");
-                for f in fs::read_dir(dir.as_path())? {
-                    let filename = f?.file_name();
-                    let part2 = filename.to_string_lossy();
-                    let id_str = format!("{part0}{part1}{part2}");
-                    let id: u64 = id_str.parse()?;
-                    self.check_relation(id, &[])?;
+                for filename in files {
+                    match fs::remove_file(dir.join(filename)) {
+                        Err(e) if e.kind() == ErrorKind::NotFound => (),
+                        r => r?,
+                    }
+                }
+            }
+            if format.undo == 0 {
+                match fs::remove_dir_all(dir.join(undo::UNDO_DIR)) {
+                    Err(e) if e.kind() == ErrorKind::NotFound => (),
+                    r => r?,
                 }
             }
         }
+        // format.txt is moved last, so that a database is never read with a wrong layout
+        for filename in [
+            NODE_CRD,
+            WAY_IDX,
+            WAY_DATA,
+            WAY_FREE,
+            RELATION_IDX,
+            RELATION_DATA,
+            RELATION_FREE,
+            STATE,
+        ]
+        .into_iter()
+        .chain(parents_files)
+        .chain(tiles_files)
+        .chain(node_hash_files)
+        .chain(metadata_files)
+        .chain(versions_files)
+        .chain([FORMAT])
+        {
+            if migrate_dir.join(filename).exists() {
+                fs::rename(migrate_dir.join(filename), dir.join(filename))?;
+            }
+        }
+        fs::remove_dir_all(&migrate_dir)?;
+        fs::remove_file(dir.join(MIGRATE))?;
+        File::open(dir)?.sync_all()?;
+        Ok(true)
+    }
+
+    /// Write all nodes/ways/relations to an osm/osm.gz/pbf file
+    ///
+    /// If a polygon file is given, only nodes inside polygon are written, with ways having at
+    /// least one of these nodes and all their nodes, and relations with a written member.
+    pub fn export(
+        &mut self,
+        filename: &str,
+        poly_file: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        let poly = match poly_file {
+            Some(poly_file) => Some(osmgeom::read_multipolygon(poly_file)?.1),
+            None => None,
+        };
+        self.flush()?;
+        let osmbin = mmap::OsmBinMmap::new_unlocked(&self.dir)?;
+        if filename.ends_with(".pbf") {
+            osmbin.copy_to_poly(&mut OsmPbf::new(filename)?, poly.as_ref())
+        } else if filename.ends_with(".osm.gz") || filename.ends_with(".osm") {
+            osmbin.copy_to_poly(&mut OsmXml::new(filename)?, poly.as_ref())
+        } else {
+            Err(NotSupportedFileType {
+                filename: filename.to_string(),
+            }
+            .into())
+        }
+    }
+
+    pub fn check_database(&mut self, start: u64) -> Result<(), Box<dyn Error>> {
+        let relation_idx = File::open(Path::new(&self.dir).join(RELATION_IDX))?;
+        let mut relation_idx = BufReader::new(relation_idx);
+        let relation_ptr_size = self.format.relation_ptr_size;
+        relation_idx.seek(SeekFrom::Start(start * relation_ptr_size as u64))?;
+
+        let mut buffer = [0u8; 8];
+        let buffer = &mut buffer[..relation_ptr_size];
+        let mut id = start;
+        loop {
+            match relation_idx.read_exact(buffer) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                r => r?,
+            }
+            if id.is_multiple_of(1_000_000) {
+                printlnt!("{id}");
+                self.cache.clear();
+            }
+            if buffer.iter().any(|b| *b != 0) {
+                self.check_relation(id, &[])?;
+            }
+            id += 1;
+        }
         Ok(())
     }
 }
 
 impl OsmBinStats {
-    pub fn print_stats(&mut self) {
+    pub fn print_stats(&mut self, cache: &cache::ElementCache) {
         println!(
             "nodes:     {} ({} seeks) ({} hits)",
             self.num_nodes, self.num_seek_node_crd, self.num_hit_nodes,
@@ -374,300 +1276,908 @@ impl OsmBinStats {
             self.num_ways, self.num_seek_way_idx, self.num_seek_way_data, self.num_hit_ways,
         );
         println!(
-            "relations: {} ({} hits)",
-            self.num_relations, self.num_hit_relations
+            "relations: {} ({} + {} seeks) ({} hits)",
+            self.num_relations,
+            self.num_seek_relation_idx,
+            self.num_seek_relation_data,
+            self.num_hit_relations
         );
+        if let Some(limit) = cache.get_limit() {
+            let stats = &cache.stats;
+            println!(
+                "cache:     {} / {limit} bytes ({} nodes, {} ways, {} relations evicted)",
+                cache.get_size(),
+                stats.num_evicted_nodes,
+                stats.num_evicted_ways,
+                stats.num_evicted_relations
+            );
+        }
+        if self.num_stale > 0 {
+            println!(
+                "skipped:   {} elements not newer than database",
+                self.num_stale
+            );
+        }
     }
 }
 
-impl Drop for OsmBin {
-    fn drop(&mut self) {
-        let way_free = File::create(Path::new(&self.dir).join(WAY_FREE)).unwrap();
+impl OsmBin {
+    /// Roll back an unfinished update, or write free lists
+    fn close(&mut self) -> Result<(), io::Error> {
+        if let Some(journal) = self.journal.take() {
+            // Update was not finished, so restore database to its previous state
+            self.flush()?;
+            journal.rollback()?;
+            return Ok(());
+        }
+        if let OpenMode::Read = self.mode {
+            return Ok(());
+        }
+
+        let way_free = File::create(Path::new(&self.dir).join(WAY_FREE))?;
         let mut way_free = BufWriter::new(way_free);
+        way_free.write_all(&self.way_free_content())?;
 
-        for (num_nodes, v) in &self.way_free_data {
-            for pos in v {
-                writeln!(way_free, "{pos};{num_nodes}").unwrap();
-            }
+        let relation_free = File::create(Path::new(&self.dir).join(RELATION_FREE))?;
+        let mut relation_free = BufWriter::new(relation_free);
+        relation_free.write_all(&self.relation_free_content())?;
+
+        for parents in self.list_indexes() {
+            fs::write(
+                Path::new(&self.dir).join(parents.files().free),
+                parents.free_content(),
+            )?;
         }
+        Ok(())
     }
 }
 
-impl OsmReader for OsmBin {
-    fn read_node(&mut self, id: u64) -> Option<Node> {
+impl Drop for OsmBin {
+    fn drop(&mut self) {
+        // A journal which could not be rolled back is recovered on next opening of database
+        if let Err(e) = self.close() {
+            eprintln!("Error when closing database {}: {e}", self.dir);
+        }
+    }
+}
+
+impl<T> OsmCopyTo<T> for OsmBin
+where
+    T: OsmWriter,
+{
+    fn copy_to(&mut self, target: &mut T) -> Result<(), Box<dyn Error>> {
+        self.flush()?;
+        mmap::OsmBinMmap::new_unlocked(&self.dir)?.copy_to(target)
+    }
+}
+
+impl OsmBin {
+    /// Read node `id`, or an error if database can't be read
+    pub fn try_read_node(&mut self, id: u64) -> Result<Option<Node>, OsmBinError> {
         self.stats.num_nodes += 1;
 
-        if self.cache.nodes.contains_key(&id) {
+        if let Some(node) = self.cache.get_node(id) {
             self.stats.num_hit_nodes += 1;
-            return self.cache.read_node(id);
+            return self.with_node_metadata(node);
         }
 
-        let node_crd_addr = id * 8;
-
-        let cur_position = self.node_crd.stream_position().unwrap();
-        if cur_position != node_crd_addr {
-            let diff: i64 =
-                i64::try_from(node_crd_addr).unwrap() - i64::try_from(cur_position).unwrap();
-            if diff > 0 && diff < 4096 {
-                let mut vec: Vec<u8> = vec![0; usize::try_from(diff).unwrap()];
-                if self.node_crd.read_exact(&mut vec).is_err() {
-                    self.node_crd.seek_relative(diff).unwrap();
-                    self.stats.num_seek_node_crd += 1;
-                }
-            } else {
-                self.node_crd.seek_relative(diff).unwrap();
-                self.stats.num_seek_node_crd += 1;
-            }
-        }
-        let mut lat_buffer = [0u8; 4];
-        let mut lon_buffer = [0u8; 4];
-        self.node_crd.read_exact_allow_eof(&mut lat_buffer).unwrap();
-        self.node_crd.read_exact_allow_eof(&mut lon_buffer).unwrap();
+        let buffer = match &self.node_hash {
+            Some(node_hash) => node_hash.read(id)?,
+            None => self.read_node_crd(id)?,
+        };
 
-        if lat_buffer == [0u8; 4] && lon_buffer == [0u8; 4] {
-            self.cache.nodes.insert(id, None);
-            return None;
-        }
-        let decimicro_lat = Self::bytes4_to_coord(lat_buffer);
-        let decimicro_lon = Self::bytes4_to_coord(lon_buffer);
+        let Some((decimicro_lat, decimicro_lon)) = self.format.coordinates.decode(buffer) else {
+            self.cache.insert_node(id, None);
+            return Ok(None);
+        };
 
         self.cache
-            .nodes
-            .insert(id, Some((decimicro_lat, decimicro_lon)));
+            .insert_node(id, Some((decimicro_lat, decimicro_lon)));
 
-        Some(Node {
+        self.with_node_metadata(Some(Node {
             id,
             decimicro_lat,
             decimicro_lon,
             tags: None,
             ..Default::default()
-        })
+        }))
     }
-    fn read_way(&mut self, id: u64) -> Option<Way> {
+
+    /// Fill tags and metadata of a node, and its version, if they are stored
+    fn with_node_metadata(&self, mut node: Option<Node>) -> Result<Option<Node>, OsmBinError> {
+        if let (Some(node), Some(metadata)) = (node.as_mut(), &self.node_metadata) {
+            metadata.read_node(node)?;
+        }
+        if let (Some(node), Some(versions)) = (node.as_mut(), &self.versions)
+            && node.version.is_none()
+        {
+            node.version = versions.read("node", node.id)?;
+        }
+        Ok(node)
+    }
+
+    /// Read coordinates of node `id` from `node.crd`
+    fn read_node_crd(&mut self, id: u64) -> Result<[u8; 8], OsmBinError> {
+        let node_crd_addr = Self::idx_addr("node", id, 8)?;
+
+        let cur_position = self.node_crd.stream_position()?;
+        if cur_position != node_crd_addr {
+            let diff: i64 =
+                i64::try_from(node_crd_addr).unwrap() - i64::try_from(cur_position).unwrap();
+            if diff > 0 && diff < 4096 {
+                let mut vec: Vec<u8> = vec![0; usize::try_from(diff).unwrap()];
+                if self.node_crd.read_exact(&mut vec).is_err() {
+                    self.node_crd.seek_relative(diff)?;
+                    self.stats.num_seek_node_crd += 1;
+                }
+            } else {
+                self.node_crd.seek_relative(diff)?;
+                self.stats.num_seek_node_crd += 1;
+            }
+        }
+        let mut buffer = [0u8; 8];
+        self.node_crd.read_exact_allow_eof(&mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    /// Write coordinates of node `id`, with empty coordinates to delete it
+    fn write_node_crd(&mut self, id: u64, crd: [u8; 8]) -> Result<(), io::Error> {
+        if let Some(node_hash) = self.node_hash.as_mut() {
+            if id == 0 {
+                return Err(OsmBinError::IdOutOfRange { type_: "node", id }.into());
+            }
+            return node_hash.write(id, crd, self.journal.as_mut());
+        }
+        let node_crd_addr = Self::idx_addr("node", id, 8)?;
+
+        // Try not to seek if not necessary, as seeking flushes write buffer
+        let cur_position = self.node_crd.stream_position()?;
+        if cur_position != node_crd_addr {
+            let diff: i64 =
+                i64::try_from(node_crd_addr).unwrap() - i64::try_from(cur_position).unwrap();
+            // Skipped nodes are only filled with zeros after the last node written, as nodes may
+            // not be sorted
+            if self.node_crd_init_size < cur_position
+                && self.node_crd_init_size < node_crd_addr
+                && cur_position == (self.prev_node_id + 1) * 8
+                && diff > 0
+                && diff < 4096
+            {
+                self.journal_range(NODE_CRD, cur_position, diff.unsigned_abs())?;
+                let vec: Vec<u8> = vec![0; usize::try_from(diff).unwrap()];
+                self.node_crd.write_all(&vec)?;
+            } else {
+                self.node_crd.seek(SeekFrom::Start(node_crd_addr))?;
+                self.stats.num_seek_node_crd += 1;
+            }
+            debug_assert_eq!(self.node_crd.stream_position().unwrap(), node_crd_addr);
+        }
+        self.journal_range(NODE_CRD, node_crd_addr, 8)?;
+        self.node_crd.write_all(&crd)?;
+        Ok(())
+    }
+
+    /// Read way `id`, or an error if database can't be read or is corrupted
+    pub fn try_read_way(&mut self, id: u64) -> Result<Option<Way>, OsmBinError> {
         self.stats.num_ways += 1;
 
-        if self.cache.ways.contains_key(&id) {
+        if let Some(way) = self.cache.get_way(id) {
             self.stats.num_hit_ways += 1;
-            return self.cache.read_way(id);
+            return self.with_way_metadata(way);
         }
 
-        let way_idx_addr = id * (WAY_PTR_SIZE as u64);
+        let way_ptr_size = self.format.way_ptr_size;
+        let way_idx_addr = Self::idx_addr("way", id, way_ptr_size)?;
 
-        let cur_position = self.way_idx.stream_position().unwrap();
+        let cur_position = self.way_idx.stream_position()?;
         if cur_position != way_idx_addr {
             let diff: i64 =
                 i64::try_from(way_idx_addr).unwrap() - i64::try_from(cur_position).unwrap();
-            self.way_idx.seek_relative(diff).unwrap();
+            self.way_idx.seek_relative(diff)?;
             self.stats.num_seek_way_idx += 1;
         }
-        let mut buffer = [0u8; WAY_PTR_SIZE];
-        self.way_idx.read_exact_allow_eof(&mut buffer).unwrap();
+        let mut buffer = [0u8; 8];
+        let buffer = &mut buffer[..way_ptr_size];
+        self.way_idx.read_exact_allow_eof(buffer)?;
 
-        if buffer == [0u8; WAY_PTR_SIZE] {
-            self.cache.ways.insert(id, None);
-            return None;
+        if buffer.iter().all(|b| *b == 0) {
+            self.cache.insert_way(id, None);
+            return Ok(None);
         }
-        let way_data_addr = Self::bytes5_to_int(buffer);
+        let way_data_addr = Self::bytes_to_int(buffer);
 
-        let cur_position = self.way_data.stream_position().unwrap();
+        let cur_position = self.way_data.stream_position()?;
         if cur_position != way_data_addr {
             let diff: i64 =
                 i64::try_from(way_data_addr).unwrap() - i64::try_from(cur_position).unwrap();
-            self.way_data.seek_relative(diff).unwrap();
+            self.way_data.seek_relative(diff)?;
             self.stats.num_seek_way_data += 1;
         }
-        let mut buffer = [0u8; 2];
-        self.way_data.read_exact(&mut buffer).unwrap();
-        if buffer == [0u8; 2] {
-            panic!("Should have gotten way num_nodes for way_id={id}");
-        }
-        let num_nodes = Self::bytes2_to_int(buffer);
-
-        let mut buffer = [0u8; NODE_ID_SIZE];
-
-        let mut nodes: Vec<u64> = Vec::new();
-        for _ in 0..num_nodes {
-            self.way_data.read_exact(&mut buffer).unwrap();
-            if buffer == [0u8; NODE_ID_SIZE] {
-                panic!("Should have gotten way node id for way_id={id}");
-            }
-            nodes.push(Self::bytes5_to_int(buffer));
-        }
+        let header = self.read_way_header(id, way_data_addr)?;
+        let nodes = self.read_way_nodes(id, way_data_addr, header)?;
 
-        self.cache.ways.insert(id, Some(nodes.clone()));
+        self.cache.insert_way(id, Some(nodes.clone()));
 
-        Some(Way {
+        self.with_way_metadata(Some(Way {
             id,
             nodes,
             tags: None,
             ..Default::default()
+        }))
+    }
+
+    /// Fill tags and metadata of a way, and its version, if they are stored
+    fn with_way_metadata(&self, mut way: Option<Way>) -> Result<Option<Way>, OsmBinError> {
+        if let (Some(way), Some(metadata)) = (way.as_mut(), &self.way_metadata) {
+            metadata.read_way(way)?;
+        }
+        if let (Some(way), Some(versions)) = (way.as_mut(), &self.versions)
+            && way.version.is_none()
+        {
+            way.version = versions.read("way", way.id)?;
+        }
+        Ok(way)
+    }
+
+    /// Read header of way `id` stored at current position of `way.data`
+    fn read_way_header(&mut self, id: u64, way_data_addr: u64) -> Result<u16, OsmBinError> {
+        let mut buffer = [0u8; 2];
+        self.way_data
+            .read_exact(&mut buffer)
+            .map_err(|e| OsmBinError::from_read(e, WAY_DATA, way_data_addr, "way", id))?;
+        if buffer == [0u8; 2] {
+            return Err(OsmBinError::corrupt(
+                WAY_DATA,
+                way_data_addr,
+                format!("way {id} has no nodes"),
+            ));
+        }
+        Ok(Self::bytes2_to_int(buffer))
+    }
+
+    /// Read nodes of way `id` stored after its header in `way.data`
+    fn read_way_nodes(
+        &mut self,
+        id: u64,
+        way_data_addr: u64,
+        header: u16,
+    ) -> Result<Vec<u64>, OsmBinError> {
+        let size = self.format.way_size(header) - 2;
+        let mut data = vec![0u8; usize::try_from(size).unwrap()];
+        self.way_data
+            .read_exact(&mut data)
+            .map_err(|e| OsmBinError::from_read(e, WAY_DATA, way_data_addr, "way", id))?;
+        self.format.decode_way(&data).ok_or_else(|| {
+            OsmBinError::corrupt(
+                WAY_DATA,
+                way_data_addr,
+                format!("way {id} has invalid nodes"),
+            )
         })
     }
-    fn read_relation(&mut self, id: u64) -> Option<Relation> {
+
+    /// Read relation `id`, or an error if database can't be read or is corrupted
+    pub fn try_read_relation(&mut self, id: u64) -> Result<Option<Relation>, OsmBinError> {
         self.stats.num_relations += 1;
 
-        if self.cache.relations.contains_key(&id) {
+        if let Some(relation) = self.cache.get_relation(id) {
             self.stats.num_hit_relations += 1;
-            return self.cache.read_relation(id);
-        }
-
-        let relid_digits = Self::to_digits(id);
-        let relid_part0 = Self::join_nums(&relid_digits[0..3]);
-        let relid_part1 = Self::join_nums(&relid_digits[3..6]);
-        let relid_part2 = Self::join_nums(&relid_digits[6..9]);
-        let rel_path = Path::new(&self.dir)
-            .join("relation")
-            .join(relid_part0)
-            .join(relid_part1)
-            .join(relid_part2);
-        let rel_data = fs::read_to_string(&rel_path);
-        let rel_data = match rel_data {
-            Ok(d) => d,
-            Err(error) => match error.kind() {
-                ErrorKind::NotFound => {
-                    self.cache.relations.insert(id, None);
-                    return None;
-                }
-                _ => panic!("Error with file {}: {error}", rel_path.display()),
-            },
+            return Ok(relation);
+        }
+
+        let relation_ptr_size = self.format.relation_ptr_size;
+        let relation_idx_addr = Self::idx_addr("relation", id, relation_ptr_size)?;
+
+        let cur_position = self.relation_idx.stream_position()?;
+        if cur_position != relation_idx_addr {
+            let diff: i64 =
+                i64::try_from(relation_idx_addr).unwrap() - i64::try_from(cur_position).unwrap();
+            self.relation_idx.seek_relative(diff)?;
+            self.stats.num_seek_relation_idx += 1;
+        }
+        let mut buffer = [0u8; 8];
+        let buffer = &mut buffer[..relation_ptr_size];
+        self.relation_idx.read_exact_allow_eof(buffer)?;
+
+        if buffer.iter().all(|b| *b == 0) {
+            self.cache.insert_relation(id, None);
+            return Ok(None);
+        }
+        let relation_data_addr = Self::bytes_to_int(buffer);
+
+        let cur_position = self.relation_data.stream_position()?;
+        if cur_position != relation_data_addr {
+            let diff: i64 =
+                i64::try_from(relation_data_addr).unwrap() - i64::try_from(cur_position).unwrap();
+            self.relation_data.seek_relative(diff)?;
+            self.stats.num_seek_relation_data += 1;
+        }
+        let truncated =
+            |e| OsmBinError::from_read(e, RELATION_DATA, relation_data_addr, "relation", id);
+        let mut buffer = [0u8; 4];
+        self.relation_data
+            .read_exact(&mut buffer)
+            .map_err(truncated)?;
+        self.relation_data
+            .read_exact(&mut buffer)
+            .map_err(truncated)?;
+        if buffer == [0u8; 4] {
+            return Err(OsmBinError::corrupt(
+                RELATION_DATA,
+                relation_data_addr,
+                format!("relation {id} is empty"),
+            ));
+        }
+        let mut data = vec![0u8; Self::bytes4_to_int(buffer) as usize];
+        self.relation_data
+            .read_exact(&mut data)
+            .map_err(truncated)?;
+
+        let relation = encoding::decode_relation(id, &data).ok_or_else(|| {
+            OsmBinError::corrupt(
+                RELATION_DATA,
+                relation_data_addr,
+                format!("relation {id} can't be decoded"),
+            )
+        })?;
+
+        self.cache.insert_relation(id, Some(relation.clone()));
+
+        Ok(Some(relation))
+    }
+
+    /// Check that an element of a diff is newer than the one in database
+    ///
+    /// Returns `false` if element should be skipped, or an error with [`VersionCheck::Fail`].
+    /// Elements without version are always applied.
+    fn check_version(
+        &mut self,
+        type_: &'static str,
+        id: u64,
+        version: Option<NonZeroU64>,
+    ) -> Result<bool, OsmBinError> {
+        // An inverse changeset restores older versions
+        if self.rollback_entry.is_some() {
+            return Ok(true);
+        }
+        let (Some(versions), Some(version)) = (&self.versions, version) else {
+            return Ok(true);
+        };
+        let Some(stored) = versions.read(type_, id)? else {
+            return Ok(true);
+        };
+        if version > stored {
+            return Ok(true);
+        }
+        match self.version_check {
+            VersionCheck::Warn => {
+                self.stats.num_stale += 1;
+                Ok(false)
+            }
+            VersionCheck::Fail => Err(OsmBinError::StaleVersion {
+                type_,
+                id,
+                version: version.get(),
+                stored: stored.get(),
+            }),
+        }
+    }
+
+    /// Store version of an element, if versions are stored and element has a version
+    ///
+    /// When an inverse changeset is applied, an element without version gets back its previous
+    /// state of having no known version.
+    fn write_version(
+        &mut self,
+        type_: &'static str,
+        id: u64,
+        version: Option<NonZeroU64>,
+    ) -> Result<(), io::Error> {
+        if let Some(versions) = &self.versions
+            && (version.is_some() || self.rollback_entry.is_some())
+        {
+            versions.write(type_, id, version, self.journal.as_mut())?;
+        }
+        Ok(())
+    }
+
+    /// Read stored version of an element, even if it was deleted
+    fn read_version(&self, type_: &'static str, id: u64) -> Result<Option<NonZeroU64>, io::Error> {
+        match &self.versions {
+            Some(versions) => versions.read(type_, id),
+            None => Ok(None),
+        }
+    }
+
+    /// Inverse changeset recorded by current update
+    fn undo_diff(&mut self) -> Result<&mut diff::StagedDiff, OsmBinError> {
+        self.undo
+            .as_mut()
+            .and_then(undo::UndoLog::diff)
+            .ok_or_else(|| OsmBinError::NoUndoRecord {
+                dir: self.dir.clone(),
+            })
+    }
+
+    /// Record previous state of node `id` in inverse changeset, if it is the first time it is
+    /// modified by current update
+    fn record_undo_node(&mut self, id: u64) -> Result<(), io::Error> {
+        if !self
+            .undo
+            .as_mut()
+            .is_some_and(|undo| undo.record("node", id))
+        {
+            return Ok(());
+        }
+        let (mut node, action) = match self.try_read_node(id)? {
+            Some(node) => (node, Action::Modify()),
+            None => (
+                Node {
+                    id,
+                    version: self.read_version("node", id)?,
+                    ..Default::default()
+                },
+                Action::Delete(),
+            ),
+        };
+        // Element is modified next, so its previous state must not be read from cache
+        self.cache.remove_node(id);
+        let diff = self.undo_diff()?;
+        diff.update_node(&mut node, &action)
+    }
+
+    /// Record previous state of way `id` in inverse changeset, if it is the first time it is
+    /// modified by current update
+    fn record_undo_way(&mut self, id: u64) -> Result<(), io::Error> {
+        if !self
+            .undo
+            .as_mut()
+            .is_some_and(|undo| undo.record("way", id))
+        {
+            return Ok(());
+        }
+        let (mut way, action) = match self.try_read_way(id)? {
+            Some(way) => (way, Action::Modify()),
+            None => (
+                Way {
+                    id,
+                    version: self.read_version("way", id)?,
+                    ..Default::default()
+                },
+                Action::Delete(),
+            ),
+        };
+        // Element is modified next, so its previous state must not be read from cache
+        self.cache.remove_way(id);
+        let diff = self.undo_diff()?;
+        diff.update_way(&mut way, &action)
+    }
+
+    /// Record previous state of relation `id` in inverse changeset, if it is the first time it
+    /// is modified by current update
+    fn record_undo_relation(&mut self, id: u64) -> Result<(), io::Error> {
+        if !self
+            .undo
+            .as_mut()
+            .is_some_and(|undo| undo.record("relation", id))
+        {
+            return Ok(());
+        }
+        let (mut relation, action) = match self.try_read_relation(id)? {
+            Some(relation) => (relation, Action::Modify()),
+            None => (
+                Relation {
+                    id,
+                    version: self.read_version("relation", id)?,
+                    ..Default::default()
+                },
+                Action::Delete(),
+            ),
         };
-        let u: Relation = serde_json::from_str(rel_data.as_str()).unwrap();
+        // Element is modified next, so its previous state must not be read from cache
+        self.cache.remove_relation(id);
+        let diff = self.undo_diff()?;
+        diff.update_relation(&mut relation, &action)
+    }
+
+    /// Delete way `id`, keeping its space in `way.data` to be reused
+    fn delete_way(&mut self, id: u64) -> Result<(), io::Error> {
+        let way_ptr_size = self.format.way_ptr_size;
+        let way_idx_addr = Self::idx_addr("way", id, way_ptr_size)?;
+        self.way_idx.seek(SeekFrom::Start(way_idx_addr))?;
+        let mut buffer = [0u8; 8];
+        let buffer = &mut buffer[..way_ptr_size];
+        self.way_idx.read_exact_allow_eof(buffer)?;
+
+        if buffer.iter().all(|b| *b == 0) {
+            return Ok(());
+        }
+        let way_data_addr = Self::bytes_to_int(buffer);
+
+        self.way_data.seek(SeekFrom::Start(way_data_addr))?;
+        let header = self.read_way_header(id, way_data_addr)?;
+
+        if self.node_parents.is_some() {
+            let nodes = self.read_way_nodes(id, way_data_addr, header)?;
+            let node_parents = self.node_parents.as_mut().unwrap();
+            for n in nodes {
+                node_parents.remove(n, id, self.journal.as_mut())?;
+            }
+        }
+
+        self.way_free_data
+            .entry(header)
+            .or_default()
+            .push(way_data_addr);
+
+        self.journal_range(WAY_DATA, way_data_addr, 2)?;
+        self.way_data.seek(SeekFrom::Start(way_data_addr))?;
+        let empty = vec![0; 2];
+        self.way_data.write_all(&empty)?;
+
+        let buffer = vec![0; way_ptr_size];
+        self.journal_range(WAY_IDX, way_idx_addr, way_ptr_size as u64)?;
+        self.way_idx.seek(SeekFrom::Start(way_idx_addr))?;
+        self.way_idx.write_all(&buffer)?;
+
+        if let Some(metadata) = self.way_metadata.as_mut() {
+            metadata.remove(id, self.journal.as_mut())?;
+        }
+        Ok(())
+    }
 
-        self.cache.relations.insert(id, Some(u.clone()));
+    /// Delete relation `id`, keeping its space in `relation.data` to be reused
+    fn delete_relation(&mut self, id: u64) -> Result<(), io::Error> {
+        let relation_ptr_size = self.format.relation_ptr_size;
+        let relation_idx_addr = Self::idx_addr("relation", id, relation_ptr_size)?;
+        self.relation_idx.seek(SeekFrom::Start(relation_idx_addr))?;
+        let mut buffer = [0u8; 8];
+        let buffer = &mut buffer[..relation_ptr_size];
+        self.relation_idx.read_exact_allow_eof(buffer)?;
+
+        if buffer.iter().all(|b| *b == 0) {
+            return Ok(());
+        }
+        let relation_data_addr = Self::bytes_to_int(buffer);
+
+        self.relation_data
+            .seek(SeekFrom::Start(relation_data_addr))?;
+        let truncated =
+            |e| OsmBinError::from_read(e, RELATION_DATA, relation_data_addr, "relation", id);
+        let corrupt = |reason: &str| {
+            OsmBinError::corrupt(
+                RELATION_DATA,
+                relation_data_addr,
+                format!("relation {id} {reason}"),
+            )
+        };
+        let mut buffer = [0u8; 4];
+        self.relation_data
+            .read_exact(&mut buffer)
+            .map_err(truncated)?;
+        let alloc_size = Self::bytes4_to_int(buffer);
+        self.relation_data
+            .read_exact(&mut buffer)
+            .map_err(truncated)?;
+        if buffer == [0u8; 4] {
+            return Err(corrupt("is empty").into());
+        }
+
+        if let Some(way_parents) = self.way_parents.as_mut() {
+            let mut data = vec![0u8; Self::bytes4_to_int(buffer) as usize];
+            self.relation_data
+                .read_exact(&mut data)
+                .map_err(truncated)?;
+            let old_relation =
+                encoding::decode_relation(id, &data).ok_or_else(|| corrupt("can't be decoded"))?;
+            for m in old_relation.members.iter().filter(|m| m.type_ == "way") {
+                way_parents.remove(m.ref_, id, self.journal.as_mut())?;
+            }
+        }
+
+        self.relation_free_data
+            .entry(u64::from(alloc_size))
+            .or_default()
+            .push(relation_data_addr);
+
+        // Keep allocated size, so that space can be reused
+        self.journal_range(RELATION_DATA, relation_data_addr + 4, 4)?;
+        self.relation_data
+            .seek(SeekFrom::Start(relation_data_addr + 4))?;
+        let empty = vec![0; 4];
+        self.relation_data.write_all(&empty)?;
+
+        let buffer = vec![0; relation_ptr_size];
+        self.journal_range(RELATION_IDX, relation_idx_addr, relation_ptr_size as u64)?;
+        self.relation_idx.seek(SeekFrom::Start(relation_idx_addr))?;
+        self.relation_idx.write_all(&buffer)?;
+        Ok(())
+    }
+
+    /// Address of element `id` in an index file with entries of `size` bytes
+    fn idx_addr(type_: &'static str, id: u64, size: usize) -> Result<u64, OsmBinError> {
+        id.checked_mul(size as u64)
+            .filter(|addr| i64::try_from(*addr).is_ok())
+            .ok_or(OsmBinError::IdOutOfRange { type_, id })
+    }
+}
 
-        Some(u)
+/// Reader of elements, panicking with the error returned by [`OsmBin::try_read_node`],
+/// [`OsmBin::try_read_way`] or [`OsmBin::try_read_relation`] if database is corrupted
+impl OsmReader for OsmBin {
+    fn read_node(&mut self, id: u64) -> Option<Node> {
+        self.try_read_node(id).unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn read_way(&mut self, id: u64) -> Option<Way> {
+        self.try_read_way(id).unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn read_relation(&mut self, id: u64) -> Option<Relation> {
+        self.try_read_relation(id).unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn read_nodes(&mut self, ids: &[u64]) -> Vec<Option<Node>> {
+        osm::read_sorted(ids, |id| self.read_node(id))
+    }
+    fn read_ways(&mut self, ids: &[u64]) -> Vec<Option<Way>> {
+        osm::read_sorted(ids, |id| self.read_way(id))
     }
 }
 
 impl OsmWriter for OsmBin {
+    /// Import a file, sorting elements by id if they are not sorted
+    fn import(&mut self, filename: &str) -> Result<(), Box<dyn Error>> {
+        let sort_dir = Path::new(&self.dir).join(sort::SORT_DIR);
+        sort::SortedImport::new(self, sort_dir).import(filename)
+    }
+    fn stores_metadata(&self) -> bool {
+        self.format.metadata
+    }
+    fn stores_versions(&self) -> bool {
+        self.format.versions || self.format.metadata
+    }
     fn write_node(&mut self, node: &mut Node) -> Result<(), io::Error> {
-        debug_assert!(node.id >= self.prev_node_id);
-        self.prev_node_id = node.id;
-
-        let lat = Self::coord_to_bytes4(node.decimicro_lat);
-        let lon = Self::coord_to_bytes4(node.decimicro_lon);
-        let node_crd_addr = node.id * 8;
+        let crd = self
+            .format
+            .coordinates
+            .encode(node.decimicro_lat, node.decimicro_lon);
+        self.write_node_crd(node.id, crd)?;
+        self.prev_node_id = self.prev_node_id.max(node.id);
+        if let Some(metadata) = self.node_metadata.as_mut() {
+            metadata.write_node(node, self.journal.as_mut())?;
+        }
+        self.write_version("node", node.id, node.version)?;
 
-        // Try not to seek if not necessary, as seeking flushes write buffer
-        let cur_position = self.node_crd.stream_position().unwrap();
-        if cur_position != node_crd_addr {
-            let diff: i64 =
-                i64::try_from(node_crd_addr).unwrap() - i64::try_from(cur_position).unwrap();
-            if self.node_crd_init_size < cur_position
-                && self.node_crd_init_size < node_crd_addr
-                && diff > 0
-                && diff < 4096
-            {
-                let vec: Vec<u8> = vec![0; usize::try_from(diff).unwrap()];
-                self.node_crd.write_all(&vec).unwrap();
-            } else {
-                self.node_crd.seek(SeekFrom::Start(node_crd_addr)).unwrap();
-                self.stats.num_seek_node_crd += 1;
-            }
-            debug_assert_eq!(self.node_crd.stream_position().unwrap(), node_crd_addr);
+        if let Some(tiles) = self.tiles.as_mut() {
+            tiles.add(
+                node.id,
+                node.decimicro_lat,
+                node.decimicro_lon,
+                self.journal.as_mut(),
+            )?;
         }
-        self.node_crd.write_all(&lat).unwrap();
-        self.node_crd.write_all(&lon).unwrap();
 
         self.stats.num_nodes += 1;
 
         Ok(())
     }
     fn write_way(&mut self, way: &mut Way) -> Result<(), io::Error> {
-        debug_assert!(way.id >= self.prev_way_id);
-        self.prev_way_id = way.id;
-
-        let way_idx_addr = way.id * (WAY_PTR_SIZE as u64);
+        let way_ptr_size = self.format.way_ptr_size;
+        let node_id_size = self.format.node_id_size;
+        let way_idx_addr = Self::idx_addr("way", way.id, way_ptr_size)?;
+        #[allow(clippy::cast_possible_truncation)]
+        if let WayEncoding::Fixed = self.format.way_encoding
+            && let Some(n) = way.nodes.iter().find(|n| {
+                n.checked_shr(8 * node_id_size as u32)
+                    .is_some_and(|high| high != 0)
+            })
+        {
+            return Err(OsmBinError::IdOutOfRange {
+                type_: "node",
+                id: *n,
+            }
+            .into());
+        }
 
         // Only need to delete way if it could be inside file
         if way_idx_addr < self.way_idx_init_size {
-            self.update_way(way, &Action::Delete())?;
+            self.delete_way(way.id)?;
         }
-        #[allow(clippy::cast_possible_truncation)]
-        let num_nodes = way.nodes.len() as u16;
+        let (header, data) = self.format.encode_way(&way.nodes).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Way {} has too many nodes", way.id),
+            )
+        })?;
         let way_data_addr = self
             .way_free_data
-            .get_mut(&num_nodes)
+            .get_mut(&header)
             .unwrap_or(&mut Vec::new())
             .pop()
             .unwrap_or(self.way_data_size);
 
         // Try not to seek if not necessary, as seeking flushes write buffer
-        if self.way_data.stream_position().unwrap() != way_data_addr {
+        if self.way_data.stream_position()? != way_data_addr {
             self.way_data.seek(SeekFrom::Start(way_data_addr))?;
             self.stats.num_seek_way_data += 1;
         }
-        let num_nodes = Self::int_to_bytes2(num_nodes);
-        self.way_data.write_all(&num_nodes).unwrap();
-        for n in &way.nodes {
-            let node = Self::int_to_bytes5(*n);
-            self.way_data.write_all(&node).unwrap();
-        }
+        self.journal_range(WAY_DATA, way_data_addr, data.len() as u64)?;
+        self.way_data.write_all(&data)?;
 
         // Try not to seek if not necessary, as seeking flushes write buffer
-        let cur_position = self.way_idx.stream_position().unwrap();
+        let cur_position = self.way_idx.stream_position()?;
         if cur_position != way_idx_addr {
             let diff: i64 =
                 i64::try_from(way_idx_addr).unwrap() - i64::try_from(cur_position).unwrap();
+            // Skipped ways are only filled with zeros after the last way written, as ways may not
+            // be sorted
             if self.way_idx_init_size < cur_position
                 && self.way_idx_init_size < way_idx_addr
+                && cur_position == (self.prev_way_id + 1) * way_ptr_size as u64
                 && diff > 0
                 && diff < 4096
             {
+                self.journal_range(WAY_IDX, cur_position, diff.unsigned_abs())?;
                 let vec: Vec<u8> = vec![0; usize::try_from(diff).unwrap()];
-                self.way_idx.write_all(&vec).unwrap();
+                self.way_idx.write_all(&vec)?;
             } else {
-                self.way_idx.seek(SeekFrom::Start(way_idx_addr)).unwrap();
+                self.way_idx.seek(SeekFrom::Start(way_idx_addr))?;
                 self.stats.num_seek_way_idx += 1;
             }
             debug_assert_eq!(self.way_idx.stream_position().unwrap(), way_idx_addr);
         }
-        let buffer = Self::int_to_bytes5(way_data_addr);
-        self.way_idx.write_all(&buffer).unwrap();
+        self.journal_range(WAY_IDX, way_idx_addr, way_ptr_size as u64)?;
+        let mut buffer = [0u8; 8];
+        let buffer = &mut buffer[..way_ptr_size];
+        Self::int_to_bytes(way_data_addr, buffer);
+        self.way_idx.write_all(buffer)?;
+        self.prev_way_id = self.prev_way_id.max(way.id);
+
+        if let Some(node_parents) = self.node_parents.as_mut() {
+            for n in &way.nodes {
+                node_parents.add(*n, way.id, self.journal.as_mut())?;
+            }
+        }
+        if let Some(metadata) = self.way_metadata.as_mut() {
+            metadata.write_way(way, self.journal.as_mut())?;
+        }
+        self.write_version("way", way.id, way.version)?;
 
-        self.way_data_size = cmp::max(self.way_data_size, self.way_data.stream_position().unwrap());
+        self.way_data_size = cmp::max(self.way_data_size, self.way_data.stream_position()?);
         self.stats.num_ways += 1;
 
         Ok(())
     }
     fn write_relation(&mut self, relation: &mut Relation) -> Result<(), io::Error> {
-        let relid_digits = Self::to_digits(relation.id);
-        let relid_part0 = Self::join_nums(&relid_digits[0..3]);
-        let relid_part1 = Self::join_nums(&relid_digits[3..6]);
-        let relid_part2 = Self::join_nums(&relid_digits[6..9]);
-        let rel_path = Path::new(&self.dir)
-            .join("relation")
-            .join(relid_part0)
-            .join(relid_part1)
-            .join(relid_part2);
-        match fs::create_dir_all(rel_path.parent().unwrap()) {
-            Ok(()) => (),
-            Err(error) => match error.kind() {
-                ErrorKind::AlreadyExists => (),
-                _ => panic!("Error with directory: {error}"),
-            },
+        let relation_ptr_size = self.format.relation_ptr_size;
+        let relation_idx_addr = Self::idx_addr("relation", relation.id, relation_ptr_size)?;
+
+        // Only need to delete relation if it could be inside file
+        if relation_idx_addr < self.relation_idx_init_size {
+            self.delete_relation(relation.id)?;
+        }
+        let data = encoding::encode_relation(relation);
+        let alloc_size = (RELATION_HEADER_SIZE + data.len() as u64).div_ceil(RELATION_ALLOC_SIZE)
+            * RELATION_ALLOC_SIZE;
+
+        // Reuse the smallest free space large enough for relation
+        let free = self
+            .relation_free_data
+            .range_mut(alloc_size..)
+            .next()
+            .map(|(size, v)| (*size, v.pop().unwrap()));
+        let (alloc_size, relation_data_addr) = match free {
+            Some((size, addr)) => {
+                if self.relation_free_data[&size].is_empty() {
+                    self.relation_free_data.remove(&size);
+                }
+                (size, addr)
+            }
+            None => (alloc_size, self.relation_data_size),
+        };
+
+        // Try not to seek if not necessary, as seeking flushes write buffer
+        if self.relation_data.stream_position()? != relation_data_addr {
+            self.relation_data
+                .seek(SeekFrom::Start(relation_data_addr))?;
+            self.stats.num_seek_relation_data += 1;
+        }
+        self.journal_range(RELATION_DATA, relation_data_addr, alloc_size)?;
+        let padding = alloc_size - RELATION_HEADER_SIZE - data.len() as u64;
+        let too_big = |_| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Relation {} is too big", relation.id),
+            )
+        };
+        let alloc_size = u32::try_from(alloc_size).map_err(too_big)?;
+        let data_size = u32::try_from(data.len()).map_err(too_big)?;
+        self.relation_data
+            .write_all(&Self::int_to_bytes4(alloc_size))?;
+        self.relation_data
+            .write_all(&Self::int_to_bytes4(data_size))?;
+        self.relation_data.write_all(&data)?;
+        self.relation_data
+            .write_all(&vec![0; usize::try_from(padding).unwrap()])?;
+
+        // Try not to seek if not necessary, as seeking flushes write buffer
+        let cur_position = self.relation_idx.stream_position()?;
+        if cur_position != relation_idx_addr {
+            let diff: i64 =
+                i64::try_from(relation_idx_addr).unwrap() - i64::try_from(cur_position).unwrap();
+            if self.relation_idx_init_size < cur_position
+                && self.relation_idx_init_size < relation_idx_addr
+                && diff > 0
+                && diff < 4096
+            {
+                self.journal_range(RELATION_IDX, cur_position, diff.unsigned_abs())?;
+                let vec: Vec<u8> = vec![0; usize::try_from(diff).unwrap()];
+                self.relation_idx.write_all(&vec)?;
+            } else {
+                self.relation_idx.seek(SeekFrom::Start(relation_idx_addr))?;
+                self.stats.num_seek_relation_idx += 1;
+            }
+            debug_assert_eq!(
+                self.relation_idx.stream_position().unwrap(),
+                relation_idx_addr
+            );
         }
+        self.journal_range(RELATION_IDX, relation_idx_addr, relation_ptr_size as u64)?;
+        let mut buffer = [0u8; 8];
+        let buffer = &mut buffer[..relation_ptr_size];
+        Self::int_to_bytes(relation_data_addr, buffer);
+        self.relation_idx.write_all(buffer)?;
 
-        let json_data = serde_json::to_string(relation)?;
-        fs::write(&rel_path, json_data)?;
+        if let Some(way_parents) = self.way_parents.as_mut() {
+            for m in relation.members.iter().filter(|m| m.type_ == "way") {
+                way_parents.add(m.ref_, relation.id, self.journal.as_mut())?;
+            }
+        }
+        self.write_version("relation", relation.id, relation.version)?;
 
+        self.relation_data_size = cmp::max(
+            self.relation_data_size,
+            self.relation_data.stream_position()?,
+        );
         self.stats.num_relations += 1;
 
         Ok(())
     }
-    fn write_end(&mut self, _change: bool) -> Result<(), Box<dyn Error>> {
+    fn write_start(&mut self, change: bool) -> Result<(), Box<dyn Error>> {
+        self.prev_node_id = 0;
+        self.prev_way_id = 0;
+        if change {
+            self.begin()?;
+        }
+        Ok(())
+    }
+    fn write_end(&mut self, change: bool) -> Result<(), Box<dyn Error>> {
+        if change {
+            self.commit()?;
+        }
         println!("Osmbin import finished");
-        self.stats.print_stats();
+        self.stats.print_stats(&self.cache);
         Ok(())
     }
 }
 
 impl OsmUpdate for OsmBin {
+    /// Apply a diff, staged in memory and sorted by id to limit seeks
+    fn update(&mut self, filename: &str) -> Result<(), Box<dyn Error>> {
+        let mut diff = diff::StagedDiff::default();
+        diff.update(filename)?;
+        diff.apply_to(self)
+    }
     fn update_node(&mut self, node: &mut Node, action: &Action) -> Result<(), io::Error> {
+        if !self.check_version("node", node.id, node.version)? {
+            return Ok(());
+        }
+        self.record_undo_node(node.id)?;
         if *action == Action::Delete() {
-            let empty: Vec<u8> = vec![0; 8];
-            self.node_crd.seek(SeekFrom::Start(node.id * 8))?;
-            self.node_crd.write_all(&empty).unwrap();
+            self.write_node_crd(node.id, [0u8; 8])?;
+            if let Some(metadata) = self.node_metadata.as_mut() {
+                metadata.remove(node.id, self.journal.as_mut())?;
+            }
+            self.write_version("node", node.id, node.version)?;
         } else {
             self.write_node(node)?;
         }
@@ -675,41 +2185,13 @@ impl OsmUpdate for OsmBin {
         Ok(())
     }
     fn update_way(&mut self, way: &mut Way, action: &Action) -> Result<(), io::Error> {
+        if !self.check_version("way", way.id, way.version)? {
+            return Ok(());
+        }
+        self.record_undo_way(way.id)?;
         if *action == Action::Delete() {
-            let way_idx_addr = way.id * (WAY_PTR_SIZE as u64);
-            self.way_idx.seek(SeekFrom::Start(way_idx_addr))?;
-            let mut buffer = [0u8; WAY_PTR_SIZE];
-            self.way_idx.read_exact_allow_eof(&mut buffer).unwrap();
-
-            if buffer == [0u8; WAY_PTR_SIZE] {
-                return Ok(());
-            }
-            let way_data_addr = Self::bytes5_to_int(buffer);
-
-            self.way_data
-                .seek(SeekFrom::Start(way_data_addr))
-                .expect("Could not seek");
-            let mut buffer = [0u8; 2];
-            self.way_data.read_exact(&mut buffer).unwrap();
-            if buffer == [0u8; 2] {
-                panic!("Should have gotten way num_nodes for way_id={}", way.id);
-            }
-            let num_nodes = Self::bytes2_to_int(buffer);
-
-            self.way_free_data
-                .entry(num_nodes)
-                .or_default()
-                .push(way_data_addr);
-
-            self.way_data
-                .seek(SeekFrom::Start(way_data_addr))
-                .expect("Could not seek");
-            let empty = vec![0; 2];
-            self.way_data.write_all(&empty).unwrap();
-
-            let buffer = vec![0; WAY_PTR_SIZE];
-            self.way_idx.seek(SeekFrom::Start(way_idx_addr))?;
-            self.way_idx.write_all(&buffer).unwrap();
+            self.delete_way(way.id)?;
+            self.write_version("way", way.id, way.version)?;
         } else {
             self.write_way(way)?;
         }
@@ -720,33 +2202,35 @@ impl OsmUpdate for OsmBin {
         relation: &mut Relation,
         action: &Action,
     ) -> Result<(), io::Error> {
+        if !self.check_version("relation", relation.id, relation.version)? {
+            return Ok(());
+        }
+        self.record_undo_relation(relation.id)?;
         if *action == Action::Delete() {
-            let relid_digits = Self::to_digits(relation.id);
-            let relid_part0 = Self::join_nums(&relid_digits[0..3]);
-            let relid_part1 = Self::join_nums(&relid_digits[3..6]);
-            let relid_part2 = Self::join_nums(&relid_digits[6..9]);
-            let rel_path = Path::new(&self.dir)
-                .join("relation")
-                .join(relid_part0)
-                .join(relid_part1)
-                .join(relid_part2);
-            match fs::remove_file(&rel_path) {
-                Ok(o) => Ok(o),
-                Err(error) => match error.kind() {
-                    ErrorKind::NotFound => Ok(()),
-                    _ => panic!(
-                        "Couldn’t delete relation {} ({}): {error}",
-                        relation.id,
-                        rel_path.display()
-                    ),
-                },
-            }
+            self.delete_relation(relation.id)?;
+            self.write_version("relation", relation.id, relation.version)
         } else {
             self.write_relation(relation)
         }
     }
 }
 
+#[derive(Debug)]
+pub struct StateMismatch {
+    pub expected: u64,
+    pub found: u64,
+}
+impl Error for StateMismatch {}
+impl fmt::Display for StateMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Diff {} is not the next one expected by database ({})",
+            self.found, self.expected
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct ElementNotFound {
     type_: String,
@@ -771,15 +2255,95 @@ impl fmt::Display for ElementNotFound {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile;
+/// Error when reading or writing an OsmBin database
+#[derive(Debug, thiserror::Error)]
+pub enum OsmBinError {
+    #[error(transparent)]
+    Io(io::Error),
+    #[error("Corrupted record in {file} at offset {offset}: {reason}")]
+    CorruptRecord {
+        file: String,
+        offset: u64,
+        reason: String,
+    },
+    #[error("{type_} id {id} is too large to be stored in database")]
+    IdOutOfRange { type_: &'static str, id: u64 },
+    #[error("Database {dir} uses another format: {reason}")]
+    FormatMismatch { dir: String, reason: String },
+    #[error("Database {dir} is locked by {holder}")]
+    Locked { dir: String, holder: String },
+    #[error("Database {dir} has an update in progress")]
+    UpdateInProgress { dir: String },
+    #[error("Database {dir} is not recording an inverse changeset")]
+    NoUndoRecord { dir: String },
+    #[error("{type_} {id} has version {version}, but database already has version {stored}")]
+    StaleVersion {
+        type_: &'static str,
+        id: u64,
+        version: u64,
+        stored: u64,
+    },
+}
 
-    use crate::osm::Member;
+impl OsmBinError {
+    fn corrupt(file: &str, offset: u64, reason: String) -> OsmBinError {
+        OsmBinError::CorruptRecord {
+            file: file.to_string(),
+            offset,
+            reason,
+        }
+    }
 
-    const PBF_SAINT_BARTHELEMY: &str = "tests/resources/saint_barthelemy.osm.pbf";
-    const OSM_WAY_666412102: &str = "tests/resources/way-666412102.osm.gz";
+    /// Error when reading element `id` from `file`, with a short read being a truncated record
+    fn from_read(e: io::Error, file: &str, offset: u64, type_: &str, id: u64) -> OsmBinError {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            Self::corrupt(file, offset, format!("{type_} {id} is truncated"))
+        } else {
+            OsmBinError::Io(e)
+        }
+    }
+}
+
+impl From<io::Error> for OsmBinError {
+    fn from(e: io::Error) -> OsmBinError {
+        // Get back an OsmBinError which was converted to io::Error
+        match e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<OsmBinError>())
+        {
+            Some(_) => *e.into_inner().unwrap().downcast().unwrap(),
+            None => OsmBinError::Io(e),
+        }
+    }
+}
+
+/// Writers of [`OsmWriter`] and [`OsmUpdate`] return an [`io::Error`], which wraps the
+/// [`OsmBinError`]
+impl From<OsmBinError> for io::Error {
+    fn from(e: OsmBinError) -> io::Error {
+        match e {
+            OsmBinError::Io(e) => e,
+            OsmBinError::IdOutOfRange { .. } | OsmBinError::StaleVersion { .. } => {
+                io::Error::new(ErrorKind::InvalidInput, e)
+            }
+            OsmBinError::Locked { .. } | OsmBinError::UpdateInProgress { .. } => {
+                io::Error::new(ErrorKind::WouldBlock, e)
+            }
+            e => io::Error::new(ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+    use tempfile;
+
+    use crate::osm::Member;
+
+    const PBF_SAINT_BARTHELEMY: &str = "tests/resources/saint_barthelemy.osm.pbf";
+    const OSM_WAY_666412102: &str = "tests/resources/way-666412102.osm.gz";
     const OSM_RELATION_17170852: &str = "tests/resources/relation-17170852.osm.gz";
     const OSM_BOUNDARY_UPDATE: &str = "tests/resources/saint_barthelemy-boundary.osc.gz";
 
@@ -817,7 +2381,7 @@ mod tests {
     fn read_node() {
         let tmpdir_path = tempfile::tempdir().unwrap();
         let tmpdir = tmpdir_path.path().to_str().unwrap();
-        OsmBin::init(&tmpdir);
+        OsmBin::init(&tmpdir).unwrap();
         let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
         osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
         drop(osmbin);
@@ -882,7 +2446,7 @@ mod tests {
     fn read_way() {
         let tmpdir_path = tempfile::tempdir().unwrap();
         let tmpdir = tmpdir_path.path().to_str().unwrap();
-        OsmBin::init(&tmpdir);
+        OsmBin::init(&tmpdir).unwrap();
         let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
         osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
         drop(osmbin);
@@ -1118,7 +2682,7 @@ mod tests {
     fn read_relation() {
         let tmpdir_path = tempfile::tempdir().unwrap();
         let tmpdir = tmpdir_path.path().to_str().unwrap();
-        OsmBin::init(&tmpdir);
+        OsmBin::init(&tmpdir).unwrap();
         let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
         osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
 
@@ -1228,11 +2792,58 @@ mod tests {
         assert_eq!(true, rel.is_none());
     }
 
+    #[test]
+    fn cache_limit() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(osmbin);
+
+        let read_all = |osmbin: &mut OsmBin| {
+            for id in [47796, 529891, 1_000_000_000] {
+                osmbin.read_relation(id);
+            }
+            for id in 255316700..255316800 {
+                osmbin.read_way(id);
+            }
+            for id in 2619283300..2619283400 {
+                osmbin.read_node(id);
+            }
+        };
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        read_all(&mut osmbin);
+        let unbounded = osmbin.get_cache();
+
+        // Elements evicted from a bounded cache are still returned when recording
+        let limit = 1000;
+        osmbin.set_cache_limit(Some(limit));
+        osmbin.set_cache_record(true);
+        read_all(&mut osmbin);
+        assert!(osmbin.cache.get_size() <= limit);
+        assert!(osmbin.cache.stats.num_evicted_nodes > 0);
+        let recorded = osmbin.get_cache();
+        assert_eq!(unbounded.nodes, recorded.nodes);
+        assert_eq!(unbounded.ways, recorded.ways);
+        assert_eq!(unbounded.relations, recorded.relations);
+
+        osmbin.set_cache_record(false);
+        read_all(&mut osmbin);
+        assert!(osmbin.get_cache().nodes.len() < unbounded.nodes.len());
+
+        // Elements read again are found in cache
+        let num_hit_nodes = osmbin.stats.num_hit_nodes;
+        osmbin.read_node(2619283348);
+        osmbin.read_node(2619283348);
+        assert_eq!(num_hit_nodes + 1, osmbin.stats.num_hit_nodes);
+    }
+
     #[test]
     fn boundary_update() {
         let tmpdir_path = tempfile::tempdir().unwrap();
         let tmpdir = tmpdir_path.path().to_str().unwrap();
-        OsmBin::init(&tmpdir);
+        OsmBin::init(&tmpdir).unwrap();
         let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
         osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
 
@@ -1286,73 +2897,1339 @@ mod tests {
         }
     }
 
+    #[test]
+    fn interrupted_update() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(osmbin);
+
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        let node = osmbin.read_node(2619283351).unwrap();
+        let way = osmbin.read_way(255316718).unwrap();
+        let rel = osmbin.read_relation(529891).unwrap();
+
+        osmbin.write_start(true).unwrap();
+        osmbin
+            .update_node(
+                &mut Node {
+                    id: 2619283351,
+                    decimicro_lat: 123,
+                    decimicro_lon: 456,
+                    ..Default::default()
+                },
+                &Action::Modify(),
+            )
+            .unwrap();
+        osmbin
+            .update_node(
+                &mut Node {
+                    id: 2619283360,
+                    decimicro_lat: 123,
+                    decimicro_lon: 456,
+                    ..Default::default()
+                },
+                &Action::Create(),
+            )
+            .unwrap();
+        osmbin
+            .update_way(
+                &mut Way {
+                    id: 255316718,
+                    ..Default::default()
+                },
+                &Action::Delete(),
+            )
+            .unwrap();
+        osmbin
+            .update_way(
+                &mut Way {
+                    id: 255316740,
+                    nodes: vec![1, 2, 3, 4, 5],
+                    ..Default::default()
+                },
+                &Action::Create(),
+            )
+            .unwrap();
+        osmbin
+            .update_relation(&mut rel.clone(), &Action::Delete())
+            .unwrap();
+        osmbin
+            .update_relation(
+                &mut Relation {
+                    id: 2707694,
+                    ..Default::default()
+                },
+                &Action::Create(),
+            )
+            .unwrap();
+
+        // Simulate a process killed during update
+        osmbin.flush().unwrap();
+        assert_eq!(true, Path::new(&tmpdir).join(journal::JOURNAL).exists());
+        // Lock is released when a process is killed
+        drop(osmbin.lock.take());
+        mem::forget(osmbin);
+
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        assert_eq!(false, Path::new(&tmpdir).join(journal::JOURNAL).exists());
+        assert_eq!(Some(node), osmbin.read_node(2619283351));
+        assert_eq!(None, osmbin.read_node(2619283360));
+        assert_eq!(Some(way), osmbin.read_way(255316718));
+        assert_eq!(None, osmbin.read_way(255316740));
+        assert_eq!(Some(rel), osmbin.read_relation(529891));
+        assert_eq!(None, osmbin.read_relation(2707694));
+        assert_eq!(0, osmbin.way_free_data.values().flatten().count());
+
+        // A complete update removes journal
+        osmbin.update(OSM_BOUNDARY_UPDATE).unwrap();
+        assert_eq!(false, Path::new(&tmpdir).join(journal::JOURNAL).exists());
+        drop(osmbin);
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        assert_eq!(3, osmbin.read_way(255316716).unwrap().nodes.len());
+    }
+
+    #[test]
+    fn update_with_state() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        assert_eq!(None, osmbin.get_state().unwrap());
+
+        let state = |sequence_number| ReplicationState {
+            sequence_number,
+            timestamp: Some(String::from("2014-01-10T23\\:00\\:23Z")),
+        };
+        osmbin.set_state(&state(100)).unwrap();
+        assert_eq!(Some(state(100)), osmbin.get_state().unwrap());
+
+        let res = osmbin.update_with_state(OSM_BOUNDARY_UPDATE, &state(102), false);
+        assert_eq!(
+            "Diff 102 is not the next one expected by database (101)",
+            res.unwrap_err().to_string()
+        );
+        assert_eq!(true, osmbin.read_node(2619283348).is_none());
+        assert_eq!(Some(state(100)), osmbin.get_state().unwrap());
+
+        osmbin
+            .update_with_state(OSM_BOUNDARY_UPDATE, &state(101), false)
+            .unwrap();
+        assert_eq!(false, osmbin.read_node(2619283348).is_none());
+        assert_eq!(Some(state(101)), osmbin.get_state().unwrap());
+
+        osmbin
+            .update_with_state(OSM_BOUNDARY_UPDATE, &state(105), true)
+            .unwrap();
+        assert_eq!(Some(state(105)), osmbin.get_state().unwrap());
+    }
+
+    #[test]
+    fn read_nodes() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(osmbin);
+
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        let way_ids = [255316725, 1, 24473155, 255316718, 24473155];
+        let ways = osmbin.read_ways(&way_ids);
+        assert_eq!(way_ids.len(), ways.len());
+        assert_eq!(None, ways[1]);
+        assert_eq!(ways[2], ways[4]);
+        let mut node_ids: Vec<u64> = ways
+            .iter()
+            .flatten()
+            .flat_map(|w| w.nodes.clone())
+            .collect();
+        node_ids.reverse();
+        node_ids.push(1);
+        drop(osmbin);
+
+        // Same nodes as read one by one, with less seeks
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        let nodes: Vec<Option<Node>> = node_ids.iter().map(|id| osmbin.read_node(*id)).collect();
+        let num_seek = osmbin.stats.num_seek_node_crd;
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        assert_eq!(nodes, osmbin.read_nodes(&node_ids));
+        assert!(osmbin.stats.num_seek_node_crd < num_seek);
+        assert_eq!(None, nodes[nodes.len() - 1]);
+        assert!(nodes[0].is_some());
+    }
+
+    #[test]
+    fn update_sorted() {
+        use std::fmt::Write as _;
+
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        // Nodes and ways created by two interleaved changesets, and modified
+        let mut osc = String::from("<osmChange version=\"0.6\">\n<create>\n");
+        for changeset in 0..2 {
+            for i in 0..200 {
+                let id = 10_000_000_000u64 + 2 * i + changeset;
+                writeln!(osc, "<node id=\"{id}\" lat=\"18.{i}\" lon=\"-62.8\"/>").unwrap();
+            }
+        }
+        for i in (0..50).rev() {
+            let id = 1_000_000_000 + i;
+            writeln!(osc, "<way id=\"{id}\"><nd ref=\"2619283351\"/></way>").unwrap();
+        }
+        osc.push_str("</create>\n<modify>\n");
+        osc.push_str("<node id=\"10000000002\" lat=\"17.9\" lon=\"-62.8\"/>\n");
+        osc.push_str("</modify>\n<delete>\n<node id=\"10000000003\"/>\n");
+        osc.push_str("<way id=\"1000000001\"/>\n</delete>\n</osmChange>\n");
+        let osc_path = tmpdir_path.path().join("diff.osc");
+        fs::write(&osc_path, osc).unwrap();
+
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        osmbin.stats = OsmBinStats::default();
+        osmbin.update(osc_path.to_str().unwrap()).unwrap();
+
+        // Created elements are written sequentially, instead of seeking for each changeset. Nodes
+        // need one more seek to write again a node modified after its creation.
+        assert!(osmbin.stats.num_seek_node_crd <= 3);
+        assert!(osmbin.stats.num_seek_way_idx <= 2);
+        for i in 0..400 {
+            let node = osmbin.read_node(10_000_000_000 + i);
+            assert_eq!(i != 3, node.is_some(), "{i}");
+        }
+        assert_eq!(
+            179_000_000,
+            osmbin.read_node(10_000_000_002).unwrap().decimicro_lat
+        );
+        assert_eq!(
+            181_990_000,
+            osmbin.read_node(10_000_000_399).unwrap().decimicro_lat
+        );
+        for i in 0..50 {
+            let way = osmbin.read_way(1_000_000_000 + i);
+            assert_eq!(i != 1, way.is_some(), "{i}");
+        }
+    }
+
+    #[test]
+    fn locked() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+
+        let reader = OsmBin::try_new(&tmpdir).unwrap();
+        let _reader2 = OsmBin::try_new(&tmpdir).unwrap();
+        let e = OsmBin::try_new_writer(&tmpdir).err().unwrap();
+        assert!(matches!(e, OsmBinError::Locked { .. }), "{e}");
+        assert_eq!(ErrorKind::WouldBlock, io::Error::from(e).kind());
+        drop(reader);
+        drop(_reader2);
+
+        let mut writer = OsmBin::try_new_writer(&tmpdir).unwrap();
+        assert!(matches!(
+            OsmBin::try_new(&tmpdir),
+            Err(OsmBinError::Locked { .. })
+        ));
+        writer.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(writer);
+        assert!(
+            OsmBin::try_new(&tmpdir)
+                .unwrap()
+                .read_way(255316725)
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn corrupted() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(osmbin);
+
+        // Node ids must fit in way.data
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        let mut way = Way {
+            id: 1,
+            nodes: vec![1, 1 << 40],
+            ..Default::default()
+        };
+        let e = osmbin.write_way(&mut way).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, e.kind());
+        assert!(matches!(
+            e.into_inner().unwrap().downcast::<OsmBinError>().as_deref(),
+            Ok(OsmBinError::IdOutOfRange {
+                type_: "node",
+                id: 0x100_0000_0000
+            })
+        ));
+        drop(osmbin);
+
+        let mut way_ptr = [0u8; 5];
+        let way_idx = File::open(tmpdir_path.path().join(WAY_IDX)).unwrap();
+        way_idx.read_exact_at(&mut way_ptr, 255316725 * 5).unwrap();
+        let way_ptr = OsmBin::bytes_to_int(&way_ptr);
+        let way_data = OpenOptions::new()
+            .write(true)
+            .open(tmpdir_path.path().join(WAY_DATA))
+            .unwrap();
+        way_data.write_all_at(&[0, 0], way_ptr).unwrap();
+
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        match osmbin.try_read_way(255316725) {
+            Err(OsmBinError::CorruptRecord { file, offset, .. }) => {
+                assert_eq!((WAY_DATA, way_ptr), (file.as_str(), offset));
+            }
+            r => panic!("Unexpected result {r:?}"),
+        }
+        assert!(osmbin.try_read_way(24473155).unwrap().is_some());
+        // Updates fail without panicking
+        let e = osmbin
+            .update_way(
+                &mut Way {
+                    id: 255316725,
+                    ..Default::default()
+                },
+                &Action::Delete(),
+            )
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, e.kind());
+        assert!(e.to_string().contains("way 255316725 has no nodes"));
+        drop(osmbin);
+
+        fs::write(tmpdir_path.path().join(WAY_FREE), "2;1\nnot-a-number\n").unwrap();
+        match OsmBin::new_writer(&tmpdir) {
+            Err(OsmBinError::CorruptRecord { file, offset, .. }) => {
+                assert_eq!((WAY_FREE, 4), (file.as_str(), offset));
+            }
+            r => panic!("Unexpected result {:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn parse_state() {
+        let content = "#Sat Jan 11 00:00:00 UTC 2014\nsequenceNumber=1234\ntimestamp=2014-01-10T23\\:00\\:23Z\n";
+        assert_eq!(
+            Some(ReplicationState {
+                sequence_number: 1234,
+                timestamp: Some(String::from("2014-01-10T23\\:00\\:23Z")),
+            }),
+            ReplicationState::parse(content)
+        );
+        assert_eq!(
+            Some(ReplicationState {
+                sequence_number: 1234,
+                timestamp: Some(String::from("2014-01-10T23\\:00\\:23Z")),
+            }),
+            ReplicationState::parse(&ReplicationState::parse(content).unwrap().to_content())
+        );
+        assert_eq!(
+            None,
+            ReplicationState::parse("timestamp=2014-01-10T23\\:00\\:23Z\n")
+        );
+    }
+
+    fn int_to_bytes(d: u64, size: usize) -> Vec<u8> {
+        let mut buffer = vec![0u8; size];
+        OsmBin::int_to_bytes(d, &mut buffer);
+        buffer
+    }
+
     #[test]
     fn bytes5_to_int() {
-        assert_eq!(0x00_00_00_00_00, OsmBin::bytes5_to_int([0, 0, 0, 0, 0]));
+        assert_eq!(0x00_00_00_00_00, OsmBin::bytes_to_int(&[0, 0, 0, 0, 0]));
         assert_eq!(
             0x12_23_45_67_89,
-            OsmBin::bytes5_to_int([0x12, 0x23, 0x45, 0x67, 0x89])
+            OsmBin::bytes_to_int(&[0x12, 0x23, 0x45, 0x67, 0x89])
+        );
+        assert_eq!(
+            0x12_23_45_67_89_ab,
+            OsmBin::bytes_to_int(&[0x12, 0x23, 0x45, 0x67, 0x89, 0xab])
         );
     }
     #[test]
     fn int_to_bytes5() {
-        assert_eq!([0, 0, 0, 0, 0], OsmBin::int_to_bytes5(0));
+        assert_eq!([0, 0, 0, 0, 0], *int_to_bytes(0, 5));
         assert_eq!(
             [0x12, 0x23, 0x45, 0x67, 0x89],
-            OsmBin::int_to_bytes5(0x12_23_45_67_89)
+            *int_to_bytes(0x12_23_45_67_89, 5)
+        );
+        assert_eq!(
+            [0x12, 0x23, 0x45, 0x67, 0x89, 0xab],
+            *int_to_bytes(0x12_23_45_67_89_ab, 6)
         );
     }
     #[test]
     fn bytes5() {
         for n in 0_u64..100000_u64 {
-            assert_eq!(n, OsmBin::bytes5_to_int(OsmBin::int_to_bytes5(n)));
-            assert_eq!(14 * n, OsmBin::bytes5_to_int(OsmBin::int_to_bytes5(14 * n)));
-            assert_eq!(
-                1098 * n,
-                OsmBin::bytes5_to_int(OsmBin::int_to_bytes5(1098 * n))
-            );
-            assert_eq!(
-                4898481 * n,
-                OsmBin::bytes5_to_int(OsmBin::int_to_bytes5(4898481 * n))
-            );
+            for size in [5, 6, 8] {
+                assert_eq!(n, OsmBin::bytes_to_int(&int_to_bytes(n, size)));
+                assert_eq!(14 * n, OsmBin::bytes_to_int(&int_to_bytes(14 * n, size)));
+                assert_eq!(
+                    1098 * n,
+                    OsmBin::bytes_to_int(&int_to_bytes(1098 * n, size))
+                );
+                assert_eq!(
+                    4898481 * n,
+                    OsmBin::bytes_to_int(&int_to_bytes(4898481 * n, size))
+                );
+            }
         }
     }
     #[test]
     #[should_panic]
     fn int_to_bytes5_too_big() {
-        OsmBin::int_to_bytes5(0x99_12_23_45_67_89);
+        int_to_bytes(0x99_12_23_45_67_89, 5);
+    }
+    #[test]
+    #[should_panic]
+    fn int_to_bytes5_limit() {
+        int_to_bytes(1 << 40, 5);
     }
 
     #[test]
     fn coord() {
-        for n in (-1800000000_i32..1800000000_i32).step_by(100000) {
-            assert_eq!(n, OsmBin::bytes4_to_coord(OsmBin::coord_to_bytes4(n)));
-            assert_eq!(
-                n + 13198,
-                OsmBin::bytes4_to_coord(OsmBin::coord_to_bytes4(n + 13198))
-            );
-            assert_eq!(
-                n + 401,
-                OsmBin::bytes4_to_coord(OsmBin::coord_to_bytes4(n + 401))
-            );
-            assert_eq!(
-                n + 50014,
-                OsmBin::bytes4_to_coord(OsmBin::coord_to_bytes4(n + 50014))
-            );
+        for encoding in [CoordEncoding::Offset, CoordEncoding::Direct] {
+            for n in (-1800000000_i32..1800000000_i32).step_by(100000) {
+                for (lat, lon) in [(n / 2, n), (n / 2 + 13198, n + 401), (n / 2, n + 50014)] {
+                    assert_eq!(Some((lat, lon)), encoding.decode(encoding.encode(lat, lon)));
+                }
+            }
         }
     }
 
     #[test]
-    fn to_digits() {
-        assert_eq!(vec![0, 0, 0, 0, 0, 0, 0, 0, 0], OsmBin::to_digits(0));
-        assert_eq!(vec![0, 0, 0, 0, 0, 1, 2, 3, 4], OsmBin::to_digits(1234));
+    fn relation_free_space() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(osmbin);
+
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        let rel = osmbin.read_relation(529891).unwrap();
+        let relation_data_size = osmbin.relation_data_size;
+
+        osmbin.write_start(true).unwrap();
+        osmbin
+            .update_relation(&mut rel.clone(), &Action::Delete())
+            .unwrap();
+        let mut new_rel = Relation {
+            id: 2707694,
+            ..rel.clone()
+        };
+        osmbin
+            .update_relation(&mut new_rel, &Action::Create())
+            .unwrap();
+        osmbin.write_end(true).unwrap();
+
+        // Space of deleted relation was reused
+        assert_eq!(relation_data_size, osmbin.relation_data_size);
+        assert_eq!(None, osmbin.read_relation(529891));
+        assert_eq!(Some(new_rel.clone()), osmbin.read_relation(2707694));
+
+        osmbin.write_start(true).unwrap();
+        new_rel
+            .members
+            .extend(rel.members.iter().cloned().cycle().take(40));
+        osmbin
+            .update_relation(&mut new_rel, &Action::Modify())
+            .unwrap();
+        osmbin.write_end(true).unwrap();
+        drop(osmbin);
+
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        assert_eq!(Some(new_rel), osmbin.read_relation(2707694));
         assert_eq!(
-            vec![1, 2, 3, 4, 5, 6, 7, 8, 9],
-            OsmBin::to_digits(123456789)
+            1,
+            OsmBin::read_free_list(&tmpdir, RELATION_FREE)
+                .unwrap()
+                .len()
         );
+    }
+
+    /// Path of relation `id` in the legacy `relation/` directory, split into 3 directory levels
+    /// of 3 digits each like legacy databases did
+    fn legacy_relation_path(id: u64) -> PathBuf {
+        let mut digits = id.to_string().into_bytes();
+        if digits.len() < 9 {
+            digits.splice(0..0, vec![b'0'; 9 - digits.len()]);
+        }
+        let part = |r: std::ops::Range<usize>| String::from_utf8(digits[r].to_vec()).unwrap();
+        Path::new(&part(0..3)).join(part(3..6)).join(part(6..9))
+    }
+
+    #[test]
+    fn migrate_relations() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        let relations = [
+            Relation {
+                id: 47796,
+                members: vec![Member {
+                    ref_: 670634766,
+                    role: String::from("stop"),
+                    type_: String::from("node"),
+                }],
+                tags: Some(vec![(String::from("type"), String::from("route"))]),
+                ..Default::default()
+            },
+            Relation {
+                id: 789000000,
+                ..Default::default()
+            },
+        ];
+        for rel in &relations {
+            let rel_path = tmpdir_path
+                .path()
+                .join(RELATION_LEGACY_DIR)
+                .join(legacy_relation_path(rel.id));
+            fs::create_dir_all(rel_path.parent().unwrap()).unwrap();
+            fs::write(rel_path, serde_json::to_string(rel).unwrap()).unwrap();
+        }
+        for filename in [NODE_CRD, WAY_IDX, WAY_DATA, WAY_FREE] {
+            fs::write(tmpdir_path.path().join(filename), b"").unwrap();
+        }
+
+        let err = OsmBin::new(&tmpdir).err().unwrap();
+        assert!(matches!(err, OsmBinError::FormatMismatch { .. }));
+        assert!(err.to_string().contains("--migrate-relations"));
+
+        assert_eq!(2, OsmBin::migrate_relations(&tmpdir).unwrap());
+        assert_eq!(false, tmpdir_path.path().join(RELATION_LEGACY_DIR).exists());
+
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        for rel in relations {
+            assert_eq!(Some(rel.clone()), osmbin.read_relation(rel.id));
+        }
+        assert_eq!(None, osmbin.read_relation(47797));
+    }
+
+    #[test]
+    fn compact() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(osmbin);
+
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        let way = osmbin.read_way(255316725).unwrap();
+        let way_data_size = osmbin.way_data_size;
+
+        osmbin.write_start(true).unwrap();
+        osmbin
+            .update_way(
+                &mut Way {
+                    id: 255316718,
+                    ..Default::default()
+                },
+                &Action::Delete(),
+            )
+            .unwrap();
+        osmbin
+            .update_way(
+                &mut Way {
+                    id: 255316725,
+                    nodes: vec![1, 2, 3],
+                    ..Default::default()
+                },
+                &Action::Modify(),
+            )
+            .unwrap();
+        let err = osmbin.compact().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<OsmBinError>(),
+            Some(OsmBinError::UpdateInProgress { .. })
+        ));
+        osmbin.write_end(true).unwrap();
+        assert_eq!(way_data_size + 2 + 3 * 5, osmbin.way_data_size);
+        let modified_nodes = osmbin.read_way(255316725).unwrap().nodes;
+        let other_nodes = osmbin.read_way(24473155).unwrap().nodes;
+
+        let reclaimed = osmbin.compact().unwrap();
+        let deleted_size = 2 + 5 * way.nodes.len() as u64 + 2 + 5 * 5;
+        assert_eq!(deleted_size, reclaimed);
+        assert_eq!(way_data_size + 2 + 3 * 5 - reclaimed, osmbin.way_data_size);
+        assert_eq!(0, osmbin.way_free_content().len());
+        assert_eq!(true, osmbin.read_way(255316718).is_none());
+        assert_eq!(modified_nodes, osmbin.read_way(255316725).unwrap().nodes);
+        drop(osmbin);
+
+        assert_eq!(false, tmpdir_path.path().join(COMPACT).exists());
+        assert_eq!(false, tmpdir_path.path().join(WAY_DATA_COMPACT).exists());
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        assert_eq!(true, osmbin.read_way(255316718).is_none());
+        assert_eq!(modified_nodes, osmbin.read_way(255316725).unwrap().nodes);
+        assert_eq!(other_nodes, osmbin.read_way(24473155).unwrap().nodes);
+        assert_eq!(
+            0,
+            fs::read(tmpdir_path.path().join(WAY_FREE)).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn migrate() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        let state = ReplicationState {
+            sequence_number: 42,
+            timestamp: None,
+        };
+        osmbin.set_state(&state).unwrap();
+        drop(osmbin);
+        assert_eq!(
+            Format::default().to_content(),
+            fs::read_to_string(tmpdir_path.path().join(FORMAT)).unwrap()
+        );
+
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        let node = osmbin.read_node(2619283351);
+        let way = osmbin.read_way(255316725).unwrap().nodes;
+        let relation = osmbin.read_relation(47796);
+        drop(osmbin);
+
+        let format = Format {
+            node_id_size: 6,
+            way_ptr_size: 6,
+            relation_ptr_size: 4,
+            coordinates: CoordEncoding::Direct,
+            ..Default::default()
+        };
+        assert_eq!(false, OsmBin::migrate(&tmpdir, &Format::default()).unwrap());
+        assert_eq!(true, OsmBin::migrate(&tmpdir, &format).unwrap());
+        assert_eq!(false, tmpdir_path.path().join(MIGRATE).exists());
+        assert_eq!(false, tmpdir_path.path().join(MIGRATE_DIR).exists());
+
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        assert_eq!(format, osmbin.get_format());
+        assert_eq!(Some(state), osmbin.get_state().unwrap());
+        assert_eq!(node, osmbin.read_node(2619283351));
+        assert_eq!(way, osmbin.read_way(255316725).unwrap().nodes);
+        assert_eq!(relation, osmbin.read_relation(47796));
+
+        // Ids larger than 40 bits can be stored with 6 bytes
+        let big_id = 1 << 44;
+        osmbin.write_start(true).unwrap();
+        osmbin
+            .update_way(
+                &mut Way {
+                    id: 255316725,
+                    nodes: vec![big_id, big_id + 1],
+                    ..Default::default()
+                },
+                &Action::Modify(),
+            )
+            .unwrap();
+        osmbin.write_end(true).unwrap();
+        drop(osmbin);
+        let osmbin = mmap::OsmBinMmap::new(&tmpdir).unwrap();
+        assert_eq!(
+            vec![big_id, big_id + 1],
+            osmbin.try_read_way(255316725).unwrap().unwrap().nodes
+        );
+        assert_eq!(node, osmbin.read_node(2619283351));
+    }
+
+    #[test]
+    fn way_encoding_delta() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(osmbin);
+        let fixed_size = fs::metadata(tmpdir_path.path().join(WAY_DATA))
+            .unwrap()
+            .len();
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        let way_ids = [255316725, 24473155, 255316718];
+        let ways = osmbin.read_ways(&way_ids);
+        drop(osmbin);
+
+        let format = Format {
+            way_encoding: WayEncoding::Delta,
+            ..Default::default()
+        };
+        assert!(OsmBin::migrate(&tmpdir, &format).is_err());
+        let format = Format {
+            version: 2,
+            ..format
+        };
+        assert_eq!(true, OsmBin::migrate(&tmpdir, &format).unwrap());
+        let delta_size = fs::metadata(tmpdir_path.path().join(WAY_DATA))
+            .unwrap()
+            .len();
+        assert!(delta_size * 2 < fixed_size, "{delta_size} {fixed_size}");
+
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        assert_eq!(ways, osmbin.read_ways(&way_ids));
+        let mmap = mmap::OsmBinMmap::new_unlocked(&tmpdir).unwrap();
+        assert_eq!(ways[0], mmap.try_read_way(way_ids[0]).unwrap());
+        drop(mmap);
+
+        // Space of a deleted way is reused by a way of a similar size
+        osmbin.write_start(true).unwrap();
+        let mut way = ways[0].clone().unwrap();
+        osmbin.update_way(&mut way, &Action::Delete()).unwrap();
+        assert_eq!(1, osmbin.way_free_data.values().flatten().count());
+        way.id = 1;
+        way.nodes.swap(0, 1);
+        osmbin.update_way(&mut way, &Action::Create()).unwrap();
+        assert_eq!(0, osmbin.way_free_data.values().flatten().count());
+        way.id = 2;
+        way.nodes = vec![1, 1 << 50];
+        osmbin.update_way(&mut way, &Action::Create()).unwrap();
+        osmbin.write_end(true).unwrap();
+        osmbin.update(OSM_BOUNDARY_UPDATE).unwrap();
+        assert_eq!(vec![1, 1 << 50], osmbin.read_way(2).unwrap().nodes);
+        assert_eq!(3, osmbin.read_way(255316716).unwrap().nodes.len());
+
+        osmbin.compact().unwrap();
+        assert_eq!(ways[1], osmbin.read_way(way_ids[1]));
+        // Extract has ways and relations with missing nodes and members
+        let report = osmbin.check().unwrap();
+        assert!(
+            report.errors.iter().all(|e| matches!(
+                e,
+                CheckError::MissingNode { .. } | CheckError::MissingMember { .. }
+            )),
+            "{report}"
+        );
+        assert!(
+            report
+                .errors
+                .contains(&CheckError::MissingNode { way: 2, node: 1 })
+        );
+    }
+
+    #[test]
+    fn node_store_hash() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(osmbin);
+        let mmap = mmap::OsmBinMmap::new(&tmpdir).unwrap();
+        let node_ids: Vec<u64> = mmap.node_ids().collect();
+        drop(mmap);
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        let nodes = osmbin.read_nodes(&node_ids);
+        let way_nodes = osmbin.read_way_full(255316718).unwrap().nodes;
+        drop(osmbin);
+
+        let format = Format {
+            node_store: NodeStore::Hash,
+            ..Default::default()
+        };
+        assert!(OsmBin::migrate(&tmpdir, &format).is_err());
+        let format = Format {
+            version: 3,
+            ..format
+        };
+        assert_eq!(true, OsmBin::migrate(&tmpdir, &format).unwrap());
+        let file_size = |filename| {
+            fs::metadata(tmpdir_path.path().join(filename))
+                .unwrap()
+                .len()
+        };
+        assert_eq!(0, file_size(NODE_CRD));
+        assert!(file_size(nodes::NODE_HASH) < 16 * 4 * node_ids.len() as u64);
+
+        // Nodes are read from hash table by all readers
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        assert_eq!(nodes, osmbin.read_nodes(&node_ids));
+        assert_eq!(way_nodes, osmbin.read_way_full(255316718).unwrap().nodes);
+        assert_eq!(None, osmbin.read_node(1));
+        let mmap = mmap::OsmBinMmap::new_unlocked(&tmpdir).unwrap();
+        assert_eq!(node_ids, mmap.node_ids().collect::<Vec<u64>>());
+        assert_eq!(nodes[0], mmap.read_node(node_ids[0]));
+        drop(mmap);
+
+        // Update filling the table fails instead of growing it, and is rolled back
+        osmbin.write_start(true).unwrap();
+        let mut node = nodes[0].clone().unwrap();
+        osmbin.update_node(&mut node, &Action::Delete()).unwrap();
+        let res: Result<(), io::Error> = (1..=node_ids.len() as u64)
+            .map(|id| {
+                node.id = id << 20;
+                osmbin.update_node(&mut node, &Action::Create())
+            })
+            .collect();
+        assert_eq!(ErrorKind::StorageFull, res.unwrap_err().kind());
+        osmbin.flush().unwrap();
+        drop(osmbin.lock.take());
+        mem::forget(osmbin);
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        assert_eq!(nodes, osmbin.read_nodes(&node_ids));
+        assert_eq!(None, osmbin.read_node(1 << 20));
+
+        osmbin.update(OSM_BOUNDARY_UPDATE).unwrap();
+        assert_eq!(3, osmbin.read_way(255316716).unwrap().nodes.len());
+        let report = osmbin.check().unwrap();
+        assert!(
+            report.errors.iter().all(|e| matches!(
+                e,
+                CheckError::MissingNode { .. } | CheckError::MissingMember { .. }
+            )),
+            "{report}"
+        );
+        drop(osmbin);
+
+        // Hash table is removed when migrating back to node.crd
+        assert_eq!(true, OsmBin::migrate(&tmpdir, &Format::default()).unwrap());
+        assert!(!tmpdir_path.path().join(nodes::NODE_HASH).exists());
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        assert_eq!(3, osmbin.read_way(255316716).unwrap().nodes.len());
+    }
+
+    #[test]
+    fn metadata() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        let format = Format {
+            version: 4,
+            metadata: true,
+            ..Default::default()
+        };
+        OsmBin::init_with_format(&tmpdir, &format).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+
+        let tag = |k: &str, v: &str| (k.to_string(), v.to_string());
+        let node = osmbin.read_node(2619283351).unwrap();
+        assert_eq!(Some(vec![tag("name", "Supermarché Oasis")]), node.tags);
+        // Tags are also filled for elements found in cache
+        assert_eq!(node, osmbin.read_node(2619283351).unwrap());
+        let way = osmbin.read_way(255316718).unwrap();
+        assert!(way.tags.unwrap().contains(&tag("natural", "coastline")));
+        // Metadata are read from pbf
+        let way = osmbin.read_way(255316725).unwrap();
+        assert_eq!(NonZeroU64::new(1), way.version);
+        assert_eq!(Some("2014-01-04T21:56:26Z"), way.timestamp.as_deref());
+        assert_eq!(Some("RedFox"), way.user.as_deref());
+        assert_eq!(NonZeroU64::new(19813735), way.changeset);
+        assert_eq!(Some("Kobow"), node.user.as_deref());
+
+        // Metadata from diff replace previous ones
+        osmbin.update(OSM_BOUNDARY_UPDATE).unwrap();
+        let way = osmbin.read_way(255316716).unwrap();
+        assert_eq!(Some(vec![]), way.tags);
+        assert_eq!(NonZeroU64::new(1), way.version);
+        assert_eq!(Some("b"), way.user.as_deref());
+        assert_eq!(NonZeroU64::new(527), way.changeset);
+        osmbin.flush().unwrap();
+        let mmap = mmap::OsmBinMmap::new_unlocked(&tmpdir).unwrap();
+        assert_eq!(Some(node.clone()), mmap.read_node(2619283351));
+        assert_eq!(Some(way.clone()), mmap.try_read_way(255316716).unwrap());
+        drop(mmap);
+
+        // Tags and metadata are kept through a pbf export
+        let export_path = tmpdir_path.path().join("export.osm.pbf");
+        osmbin.export(export_path.to_str().unwrap(), None).unwrap();
+        let import_path = tempfile::tempdir().unwrap();
+        let import = import_path.path().to_str().unwrap();
+        OsmBin::init_with_format(&import, &format).unwrap();
+        let mut imported = OsmBin::new_writer(&import).unwrap();
+        imported.import(export_path.to_str().unwrap()).unwrap();
+        assert_eq!(Some(node.clone()), imported.read_node(2619283351));
+        // Pbf doesn't distinguish empty tags from missing ones
+        let exp_way = Way {
+            tags: None,
+            ..way.clone()
+        };
+        assert_eq!(Some(exp_way), imported.read_way(255316716));
+        drop(imported);
+
+        // Interrupted update is rolled back
+        osmbin.write_start(true).unwrap();
+        osmbin
+            .update_node(&mut node.clone(), &Action::Delete())
+            .unwrap();
+        osmbin
+            .update_way(
+                &mut Way {
+                    tags: Some(vec![tag("highway", "track")]),
+                    ..way.clone()
+                },
+                &Action::Modify(),
+            )
+            .unwrap();
+        osmbin.flush().unwrap();
+        drop(osmbin.lock.take());
+        mem::forget(osmbin);
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        assert_eq!(Some(node.clone()), osmbin.read_node(2619283351));
+        assert_eq!(Some(way), osmbin.read_way(255316716));
+
+        // Deleted node loses its tags if created again without them
+        osmbin
+            .update_node(&mut node.clone(), &Action::Delete())
+            .unwrap();
+        let mut node = Node { tags: None, ..node };
+        osmbin.update_node(&mut node, &Action::Create()).unwrap();
+        osmbin.cache.clear();
+        assert_eq!(Some(node), osmbin.read_node(2619283351));
+        drop(osmbin);
+
+        // Metadata files are removed when migrating to a format without them
+        assert_eq!(true, OsmBin::migrate(&tmpdir, &Format::default()).unwrap());
+        assert!(
+            !tmpdir_path
+                .path()
+                .join(metadata::NODE_METADATA.data)
+                .exists()
+        );
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        assert_eq!(None, osmbin.read_way(255316718).unwrap().tags);
+    }
+
+    #[test]
+    fn versions() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        let format = Format {
+            version: 5,
+            versions: true,
+            ..Default::default()
+        };
+        OsmBin::init_with_format(&tmpdir, &format).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        assert_eq!(
+            NonZeroU64::new(1),
+            osmbin.read_way(255316718).unwrap().version
+        );
+
+        osmbin.update(OSM_BOUNDARY_UPDATE).unwrap();
+        assert_eq!(0, osmbin.stats.num_stale);
+        let way = osmbin.read_way(255316716).unwrap();
+        assert_eq!(NonZeroU64::new(1), way.version);
+        assert_eq!(3, way.nodes.len());
+        assert_eq!(
+            NonZeroU64::new(1),
+            osmbin.read_node(2619283348).unwrap().version
+        );
+
+        // Diff applied twice is skipped
+        osmbin.update(OSM_BOUNDARY_UPDATE).unwrap();
+        assert_eq!(8, osmbin.stats.num_stale);
+
+        // Older version is skipped, and newer version is applied
+        let mut old_way = Way {
+            nodes: vec![266964103, 266964101],
+            ..way.clone()
+        };
+        osmbin.write_start(true).unwrap();
+        osmbin.update_way(&mut old_way, &Action::Modify()).unwrap();
+        let mut node = osmbin.read_node(2619283348).unwrap();
+        osmbin.update_node(&mut node, &Action::Delete()).unwrap();
+        osmbin.write_end(true).unwrap();
+        assert_eq!(10, osmbin.stats.num_stale);
+        assert_eq!(Some(way.clone()), osmbin.read_way(255316716));
+        assert!(osmbin.read_node(2619283348).is_some());
+
+        osmbin.write_start(true).unwrap();
+        old_way.version = NonZeroU64::new(3);
+        osmbin.update_way(&mut old_way, &Action::Modify()).unwrap();
+        node.version = NonZeroU64::new(2);
+        osmbin.update_node(&mut node, &Action::Delete()).unwrap();
+        osmbin.write_end(true).unwrap();
+        assert_eq!(Some(old_way.clone()), osmbin.read_way(255316716));
+        assert_eq!(None, osmbin.read_node(2619283348));
+
+        // Deleting again the same version is detected, and fails update
+        osmbin.set_version_check(VersionCheck::Fail);
+        osmbin.write_start(true).unwrap();
+        let err = osmbin
+            .update_node(&mut node, &Action::Delete())
+            .unwrap_err();
+        assert!(matches!(
+            OsmBinError::from(err),
+            OsmBinError::StaleVersion {
+                type_: "node",
+                id: 2619283348,
+                version: 2,
+                stored: 2
+            }
+        ));
+        drop(osmbin);
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.set_version_check(VersionCheck::Fail);
+        assert!(osmbin.update(OSM_BOUNDARY_UPDATE).is_err());
+        drop(osmbin);
+
+        // Versions are kept by migration
+        let format = Format {
+            version: 7,
+            tiles: true,
+            ..format
+        };
+        assert_eq!(true, OsmBin::migrate(&tmpdir, &format).unwrap());
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        assert_eq!(Some(old_way), osmbin.read_way(255316716));
+        drop(osmbin);
+        assert_eq!(true, OsmBin::migrate(&tmpdir, &Format::default()).unwrap());
+        assert!(!tmpdir_path.path().join(versions::WAY_VERSION).exists());
+    }
+
+    #[test]
+    fn rollback() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        let format = Format {
+            version: 6,
+            metadata: true,
+            versions: true,
+            undo: 2,
+            ..Default::default()
+        };
+        OsmBin::init_with_format(&tmpdir, &format).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        let state = |sequence_number| ReplicationState {
+            sequence_number,
+            timestamp: None,
+        };
+        osmbin.set_state(&state(10)).unwrap();
+        let orig_node = osmbin.read_node(2619283351).unwrap();
+        let orig_way = osmbin.read_way(255316718).unwrap();
+        assert_eq!(None, osmbin.read_way(255316716));
+
+        osmbin
+            .update_with_state(OSM_BOUNDARY_UPDATE, &state(11), false)
+            .unwrap();
+        assert!(osmbin.read_way(255316716).is_some());
+
+        osmbin.write_start(true).unwrap();
+        let mut node = Node {
+            decimicro_lat: orig_node.decimicro_lat + 1,
+            tags: None,
+            version: NonZeroU64::new(2),
+            ..orig_node.clone()
+        };
+        osmbin.update_node(&mut node, &Action::Modify()).unwrap();
+        node.decimicro_lat += 1;
+        node.version = NonZeroU64::new(3);
+        osmbin.update_node(&mut node, &Action::Modify()).unwrap();
+        assert_eq!(Some(node), osmbin.read_node(2619283351));
+        let mut way = Way {
+            version: NonZeroU64::new(3),
+            ..orig_way.clone()
+        };
+        osmbin.update_way(&mut way, &Action::Delete()).unwrap();
+        osmbin.write_end(true).unwrap();
+        assert_eq!(None, osmbin.read_way(255316718));
+        assert_eq!(2, osmbin.undo.as_ref().unwrap().entries().unwrap().len());
+        // Entries are renamed once written, and are not recorded outside of an update
+        let undo_dir = tmpdir_path.path().join(undo::UNDO_DIR);
+        assert_eq!(4, fs::read_dir(&undo_dir).unwrap().count());
+        assert!(matches!(
+            osmbin.undo_diff(),
+            Err(OsmBinError::NoUndoRecord { .. })
+        ));
+
+        assert!(osmbin.rollback(3).is_err());
+        assert_eq!(None, osmbin.read_way(255316718));
+
+        // Last diff is rolled back, with the state of elements before their first modification
+        osmbin.rollback(1).unwrap();
+        assert_eq!(Some(orig_node.clone()), osmbin.read_node(2619283351));
+        assert_eq!(Some(orig_way), osmbin.read_way(255316718));
+        assert!(osmbin.read_way(255316716).is_some());
+        assert_eq!(Some(state(11)), osmbin.get_state().unwrap());
+
+        // Created elements are removed, without their version
+        osmbin.rollback(1).unwrap();
+        assert_eq!(None, osmbin.read_way(255316716));
+        assert_eq!(None, osmbin.read_node(2619283348));
+        assert_eq!(None, osmbin.read_version("node", 2619283348).unwrap());
+        assert_eq!(Some(state(10)), osmbin.get_state().unwrap());
+        assert!(osmbin.rollback(1).is_err());
+
+        // Diff can be applied again, and only the last entries are kept
+        assert_eq!(0, osmbin.stats.num_stale);
+        for sequence_number in 11..14 {
+            osmbin
+                .update_with_state(OSM_BOUNDARY_UPDATE, &state(sequence_number), true)
+                .unwrap();
+        }
+        assert_eq!(16, osmbin.stats.num_stale);
+        assert_eq!(vec![2, 3], osmbin.undo.as_ref().unwrap().entries().unwrap());
+        osmbin.rollback(2).unwrap();
+        assert_eq!(Some(state(11)), osmbin.get_state().unwrap());
+        assert!(osmbin.read_way(255316716).is_some());
+        drop(osmbin);
+
+        assert_eq!(true, OsmBin::migrate(&tmpdir, &Format::default()).unwrap());
+        assert!(!tmpdir_path.path().join(undo::UNDO_DIR).exists());
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        assert!(osmbin.rollback(1).is_err());
+    }
+
+    #[test]
+    fn interrupted_migrate() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let format = Format {
+            node_id_size: 6,
+            ..Default::default()
+        };
+
+        // Migration not complete is discarded
+        OsmBin::init_with_format(
+            tmpdir_path.path().join(MIGRATE_DIR).to_str().unwrap(),
+            &format,
+        )
+        .unwrap();
+        let osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        assert_eq!(Format::default(), osmbin.get_format());
+        drop(osmbin);
+        assert_eq!(false, tmpdir_path.path().join(MIGRATE_DIR).exists());
+
+        // Complete migration is finished
+        OsmBin::init_with_format(
+            tmpdir_path.path().join(MIGRATE_DIR).to_str().unwrap(),
+            &format,
+        )
+        .unwrap();
+        File::create(tmpdir_path.path().join(MIGRATE)).unwrap();
+        let osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        assert_eq!(format, osmbin.get_format());
+        drop(osmbin);
+        assert_eq!(false, tmpdir_path.path().join(MIGRATE).exists());
+        assert_eq!(false, tmpdir_path.path().join(MIGRATE_DIR).exists());
+    }
+
+    #[test]
+    fn invalid_format() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        fs::write(
+            tmpdir_path.path().join(FORMAT),
+            Format::default()
+                .to_content()
+                .replace("version=1", "version=1000"),
+        )
+        .unwrap();
+        assert!(matches!(
+            OsmBin::new(&tmpdir),
+            Err(OsmBinError::FormatMismatch { .. })
+        ));
+        assert!(matches!(
+            mmap::OsmBinMmap::new(&tmpdir),
+            Err(OsmBinError::FormatMismatch { .. })
+        ));
+
+        // Database from a previous version has no format file
+        fs::remove_file(tmpdir_path.path().join(FORMAT)).unwrap();
+        assert_eq!(
+            Format::default(),
+            OsmBin::new(&tmpdir).unwrap().get_format()
+        );
+
+        // Database can't be initialized again with another format
+        OsmBin::init(&tmpdir).unwrap();
+        let format = Format {
+            node_id_size: 6,
+            ..Default::default()
+        };
+        assert!(matches!(
+            OsmBin::init_with_format(&tmpdir, &format),
+            Err(OsmBinError::FormatMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn parents() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
         assert_eq!(
-            vec![7, 8, 9, 0, 0, 0, 0, 0, 0],
-            OsmBin::to_digits(789000000)
+            ErrorKind::Unsupported,
+            osmbin.read_node_parents(1).unwrap_err().kind()
         );
+        let way = osmbin.read_way(255316725).unwrap();
+        let rel = osmbin.read_relation(47796).unwrap();
+        let rel_way = rel.members.iter().find(|m| m.type_ == "way").unwrap().ref_;
+        drop(osmbin);
+
+        // Reverse indexes are built by migration
+        let format = Format {
+            version: 7,
+            parents: true,
+            ..Default::default()
+        };
+        assert_eq!(true, OsmBin::migrate(&tmpdir, &format).unwrap());
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        for n in &way.nodes {
+            assert!(osmbin.read_node_parents(*n).unwrap().contains(&255316725));
+        }
+        assert!(osmbin.read_way_parents(rel_way).unwrap().contains(&47796));
+        assert_eq!(Vec::<u64>::new(), osmbin.read_node_parents(1).unwrap());
+
+        // Interrupted update restores reverse indexes
+        osmbin.write_start(true).unwrap();
+        osmbin
+            .update_way(
+                &mut Way {
+                    id: 255316725,
+                    nodes: vec![1, 2, 3],
+                    ..Default::default()
+                },
+                &Action::Modify(),
+            )
+            .unwrap();
+        osmbin
+            .update_relation(&mut rel.clone(), &Action::Delete())
+            .unwrap();
+        assert_eq!(vec![255316725], osmbin.read_node_parents(1).unwrap());
+        assert!(
+            !osmbin
+                .read_node_parents(way.nodes[0])
+                .unwrap()
+                .contains(&255316725)
+        );
+        assert!(!osmbin.read_way_parents(rel_way).unwrap().contains(&47796));
+        osmbin.flush().unwrap();
+        // Lock is released when a process is killed
+        drop(osmbin.lock.take());
+        mem::forget(osmbin);
+
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        assert_eq!(Vec::<u64>::new(), osmbin.read_node_parents(1).unwrap());
+        assert!(
+            osmbin
+                .read_node_parents(way.nodes[0])
+                .unwrap()
+                .contains(&255316725)
+        );
+        assert!(osmbin.read_way_parents(rel_way).unwrap().contains(&47796));
+
+        // Complete update
+        osmbin.write_start(true).unwrap();
+        osmbin
+            .update_way(
+                &mut Way {
+                    id: 255316725,
+                    nodes: vec![1, 2, 3],
+                    ..Default::default()
+                },
+                &Action::Modify(),
+            )
+            .unwrap();
+        osmbin
+            .update_relation(&mut rel.clone(), &Action::Delete())
+            .unwrap();
+        osmbin.write_end(true).unwrap();
+        drop(osmbin);
+        let osmbin = OsmBin::new(&tmpdir).unwrap();
+        assert_eq!(vec![255316725], osmbin.read_node_parents(2).unwrap());
+        assert!(
+            !osmbin
+                .read_node_parents(way.nodes[0])
+                .unwrap()
+                .contains(&255316725)
+        );
+        assert!(!osmbin.read_way_parents(rel_way).unwrap().contains(&47796));
+        drop(osmbin);
+
+        // Reverse indexes are removed by migration
+        assert_eq!(true, OsmBin::migrate(&tmpdir, &Format::default()).unwrap());
+        assert_eq!(
+            false,
+            tmpdir_path.path().join(parents::NODE_PARENTS.idx).exists()
+        );
+    }
+
+    #[test]
+    fn export() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(osmbin);
+
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        for filename in ["export.osm.pbf", "export.osm.gz"] {
+            let export_path = tmpdir_path.path().join(filename);
+            let export = export_path.to_str().unwrap();
+            osmbin.export(export, None).unwrap();
+
+            let dest_path = tmpdir_path.path().join(format!("{filename}-osmbin"));
+            let dest = dest_path.to_str().unwrap();
+            OsmBin::init(&dest).unwrap();
+            let mut dest_osmbin = OsmBin::new_writer(&dest).unwrap();
+            dest_osmbin.import(export).unwrap();
+            drop(dest_osmbin);
+
+            for filename in [WAY_DATA, RELATION_DATA] {
+                assert_eq!(
+                    fs::read(tmpdir_path.path().join(filename)).unwrap(),
+                    fs::read(dest_path.join(filename)).unwrap(),
+                    "{filename}"
+                );
+            }
+            let mut dest_osmbin = OsmBin::new(&dest).unwrap();
+            for id in [266053077, 2619283351, 6239222548] {
+                assert_eq!(osmbin.read_node(id), dest_osmbin.read_node(id));
+            }
+            for id in [47796, 529891, 2324452] {
+                assert_eq!(osmbin.read_relation(id), dest_osmbin.read_relation(id));
+            }
+        }
+
+        let poly_path = tmpdir_path.path().join("gustavia.poly");
+        fs::write(
+            &poly_path,
+            "gustavia\n1\n\t-62.86\t17.89\n\t-62.84\t17.89\n\t-62.84\t17.91\n\t-62.86\t17.91\nEND\nEND\n",
+        )
+        .unwrap();
+        let export_path = tmpdir_path.path().join("gustavia.osm.pbf");
+        let export = export_path.to_str().unwrap();
+        osmbin
+            .export(export, Some(poly_path.to_str().unwrap()))
+            .unwrap();
+
+        let read_ids = |filename: &str| {
+            let mut ids: Vec<Vec<u64>> = vec![Vec::new(); 3];
+            let pbf = File::open(filename).unwrap();
+            for obj in osmpbfreader::OsmPbfReader::new(pbf).iter() {
+                match obj.unwrap() {
+                    osmpbfreader::OsmObj::Node(n) => ids[0].push(n.id.0.try_into().unwrap()),
+                    osmpbfreader::OsmObj::Way(w) => ids[1].push(w.id.0.try_into().unwrap()),
+                    osmpbfreader::OsmObj::Relation(r) => ids[2].push(r.id.0.try_into().unwrap()),
+                }
+            }
+            ids
+        };
+        let ids = read_ids(export);
+        let all_ids = read_ids(tmpdir_path.path().join("export.osm.pbf").to_str().unwrap());
+        for i in 0..3 {
+            assert!(!ids[i].is_empty());
+            assert!(ids[i].len() < all_ids[i].len());
+        }
+        for id in &ids[1] {
+            for n in osmbin.read_way(*id).unwrap().nodes {
+                assert!(ids[0].contains(&n), "node {n} of way {id}");
+            }
+        }
     }
 }
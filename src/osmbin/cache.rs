@@ -0,0 +1,351 @@
+//! Cache of elements read from an OsmBin database, optionally bounded in size
+
+use rustc_hash::FxHashSet;
+use std::collections::VecDeque;
+use std::mem;
+
+use crate::osm::{Member, Node, Relation, Way};
+use crate::osmcache::OsmCache;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Kind {
+    Node,
+    Way,
+    Relation,
+}
+
+/// Number of elements evicted from cache, by type
+#[allow(clippy::struct_field_names)]
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    pub num_evicted_nodes: u64,
+    pub num_evicted_ways: u64,
+    pub num_evicted_relations: u64,
+}
+
+/// Cache of elements read from database, with the same content as an [`OsmCache`]
+///
+/// Cache is unbounded by default. With a size limit, elements are evicted with the CLOCK
+/// policy: cached elements are kept in a ring in insertion order, and an element read again
+/// since it was inserted is given a second chance before being evicted. Size of an element is
+/// an approximation of the memory it uses in cache.
+///
+/// [`ElementCache::take`] returns all elements read since cache was last cleared. When a limit
+/// is set, evicted elements are only kept for it if recording is enabled.
+#[derive(Default)]
+pub struct ElementCache {
+    cache: OsmCache,
+    limit: Option<usize>,
+    /// Size of elements in cache, only computed with a limit
+    size: usize,
+    clock: VecDeque<(Kind, u64)>,
+    referenced: FxHashSet<(Kind, u64)>,
+    record: bool,
+    evicted: OsmCache,
+    pub stats: CacheStats,
+}
+
+impl ElementCache {
+    /// Set maximum size of cache in bytes, or `None` for an unbounded cache
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+        self.size = 0;
+        self.clock.clear();
+        self.referenced.clear();
+        if limit.is_some() {
+            let mut keys: Vec<(Kind, u64)> = Vec::new();
+            keys.extend(self.cache.nodes.keys().map(|id| (Kind::Node, *id)));
+            keys.extend(self.cache.ways.keys().map(|id| (Kind::Way, *id)));
+            keys.extend(self.cache.relations.keys().map(|id| (Kind::Relation, *id)));
+            for key in keys {
+                self.size += self.entry_size(key).unwrap();
+                self.clock.push_back(key);
+            }
+            self.evict();
+        }
+    }
+
+    pub fn get_limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Approximate size of elements currently in cache, or 0 without limit
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    /// Keep evicted elements, so that they are still returned by [`ElementCache::take`]
+    pub fn set_record(&mut self, record: bool) {
+        self.record = record;
+        if !record {
+            self.evicted.clear();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.cache.clear();
+        self.evicted.clear();
+        self.size = 0;
+        self.clock.clear();
+        self.referenced.clear();
+    }
+
+    /// Take all elements read since cache was last cleared, leaving it empty
+    pub fn take(&mut self) -> OsmCache {
+        let mut cache = mem::take(&mut self.evicted);
+        let current = mem::take(&mut self.cache);
+        cache.nodes.extend(current.nodes);
+        cache.ways.extend(current.ways);
+        cache.relations.extend(current.relations);
+        self.clear();
+        cache
+    }
+
+    /// Get node `id`, or `None` if it is not in cache
+    #[allow(clippy::option_option)]
+    pub fn get_node(&mut self, id: u64) -> Option<Option<Node>> {
+        if !self.cache.nodes.contains_key(&id) {
+            return None;
+        }
+        self.touch((Kind::Node, id));
+        Some(self.cache.read_node(id))
+    }
+    /// Get way `id`, or `None` if it is not in cache
+    #[allow(clippy::option_option)]
+    pub fn get_way(&mut self, id: u64) -> Option<Option<Way>> {
+        if !self.cache.ways.contains_key(&id) {
+            return None;
+        }
+        self.touch((Kind::Way, id));
+        Some(self.cache.read_way(id))
+    }
+    /// Get relation `id`, or `None` if it is not in cache
+    #[allow(clippy::option_option)]
+    pub fn get_relation(&mut self, id: u64) -> Option<Option<Relation>> {
+        if !self.cache.relations.contains_key(&id) {
+            return None;
+        }
+        self.touch((Kind::Relation, id));
+        Some(self.cache.read_relation(id))
+    }
+
+    pub fn contains_way(&self, id: u64) -> bool {
+        self.cache.ways.contains_key(&id)
+    }
+    pub fn contains_relation(&self, id: u64) -> bool {
+        self.cache.relations.contains_key(&id)
+    }
+
+    /// Add coordinates of node `id`, or `None` if it doesn't exist in database
+    pub fn insert_node(&mut self, id: u64, crd: Option<(i32, i32)>) {
+        self.remove_node(id);
+        self.cache.nodes.insert(id, crd);
+        self.inserted((Kind::Node, id));
+    }
+    /// Add nodes of way `id`, or `None` if it doesn't exist in database
+    pub fn insert_way(&mut self, id: u64, nodes: Option<Vec<u64>>) {
+        self.remove_way(id);
+        self.cache.ways.insert(id, nodes);
+        self.inserted((Kind::Way, id));
+    }
+    /// Add relation `id`, or `None` if it doesn't exist in database
+    pub fn insert_relation(&mut self, id: u64, relation: Option<Relation>) {
+        self.remove_relation(id);
+        self.cache.relations.insert(id, relation);
+        self.inserted((Kind::Relation, id));
+    }
+
+    pub fn remove_node(&mut self, id: u64) {
+        self.remove((Kind::Node, id));
+    }
+    pub fn remove_way(&mut self, id: u64) {
+        self.remove((Kind::Way, id));
+    }
+    pub fn remove_relation(&mut self, id: u64) {
+        self.remove((Kind::Relation, id));
+    }
+
+    /// Mark an element as read again, so that it is not evicted on next pass of the clock
+    fn touch(&mut self, key: (Kind, u64)) {
+        if self.limit.is_some() {
+            self.referenced.insert(key);
+        }
+    }
+
+    fn inserted(&mut self, key: (Kind, u64)) {
+        if self.limit.is_some() {
+            self.size += self.entry_size(key).unwrap();
+            self.clock.push_back(key);
+            self.evict();
+        }
+    }
+
+    /// Remove an element from cache, leaving its position in the clock, which is skipped once
+    /// reached
+    ///
+    /// Evicted element is also removed, as it is outdated.
+    fn remove(&mut self, key: (Kind, u64)) {
+        if self.limit.is_some()
+            && let Some(size) = self.entry_size(key)
+        {
+            self.size -= size;
+            self.referenced.remove(&key);
+        }
+        let id = key.1;
+        match key.0 {
+            Kind::Node => {
+                self.cache.nodes.remove(&id);
+                self.evicted.nodes.remove(&id);
+            }
+            Kind::Way => {
+                self.cache.ways.remove(&id);
+                self.evicted.ways.remove(&id);
+            }
+            Kind::Relation => {
+                self.cache.relations.remove(&id);
+                self.evicted.relations.remove(&id);
+            }
+        }
+    }
+
+    /// Evict elements until cache fits in its limit
+    fn evict(&mut self) {
+        let Some(limit) = self.limit else {
+            return;
+        };
+        while self.size > limit {
+            let Some(key) = self.clock.pop_front() else {
+                break;
+            };
+            if self.referenced.remove(&key) {
+                self.clock.push_back(key);
+                continue;
+            }
+            let Some(size) = self.entry_size(key) else {
+                // Element was removed since it was inserted
+                continue;
+            };
+            self.size -= size;
+            let id = key.1;
+            match key.0 {
+                Kind::Node => {
+                    let crd = self.cache.nodes.remove(&id).unwrap();
+                    if self.record {
+                        self.evicted.nodes.insert(id, crd);
+                    }
+                    self.stats.num_evicted_nodes += 1;
+                }
+                Kind::Way => {
+                    let nodes = self.cache.ways.remove(&id).unwrap();
+                    if self.record {
+                        self.evicted.ways.insert(id, nodes);
+                    }
+                    self.stats.num_evicted_ways += 1;
+                }
+                Kind::Relation => {
+                    let relation = self.cache.relations.remove(&id).unwrap();
+                    if self.record {
+                        self.evicted.relations.insert(id, relation);
+                    }
+                    self.stats.num_evicted_relations += 1;
+                }
+            }
+        }
+    }
+
+    /// Approximate memory used by an element in cache, or `None` if it is not in cache
+    fn entry_size(&self, key: (Kind, u64)) -> Option<usize> {
+        let id = key.1;
+        match key.0 {
+            Kind::Node => {
+                self.cache.nodes.get(&id)?;
+                Some(mem::size_of::<(u64, Option<(i32, i32)>)>())
+            }
+            Kind::Way => {
+                let nodes = self.cache.ways.get(&id)?;
+                Some(
+                    mem::size_of::<(u64, Option<Vec<u64>>)>()
+                        + nodes
+                            .as_ref()
+                            .map_or(0, |n| n.len() * mem::size_of::<u64>()),
+                )
+            }
+            Kind::Relation => {
+                let relation = self.cache.relations.get(&id)?;
+                let members = relation.as_ref().map_or(0, |r| {
+                    r.members
+                        .iter()
+                        .map(|m| mem::size_of::<Member>() + m.role.len() + m.type_.len())
+                        .sum()
+                });
+                Some(mem::size_of::<(u64, Option<Relation>)>() + members)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODE_SIZE: usize = mem::size_of::<(u64, Option<(i32, i32)>)>();
+
+    #[test]
+    fn clock() {
+        let mut cache = ElementCache::default();
+        cache.set_limit(Some(3 * NODE_SIZE));
+        cache.insert_node(1, Some((1, 0)));
+        cache.insert_node(2, Some((2, 0)));
+        cache.insert_node(3, Some((3, 0)));
+        assert_eq!(3 * NODE_SIZE, cache.get_size());
+        // Node 1 is read again, so node 2 is evicted first
+        assert!(cache.get_node(1).is_some());
+        cache.insert_node(4, None);
+        assert_eq!(None, cache.get_node(2));
+        assert_eq!(Some(None), cache.get_node(4));
+        assert!(cache.get_node(1).is_some());
+        assert_eq!(1, cache.stats.num_evicted_nodes);
+        assert_eq!(3 * NODE_SIZE, cache.get_size());
+
+        // Removed elements are not counted
+        cache.remove_node(3);
+        cache.remove_node(3);
+        assert_eq!(2 * NODE_SIZE, cache.get_size());
+        // Nodes 4 and 1 were read again, so the way is evicted
+        cache.insert_way(1, Some(vec![1, 4]));
+        assert!(!cache.contains_way(1));
+        assert_eq!(1, cache.stats.num_evicted_ways);
+        assert_eq!(2 * NODE_SIZE, cache.get_size());
+
+        // Evicted elements are not kept without recording
+        let taken = cache.take();
+        assert_eq!(2, taken.nodes.len());
+        assert!(taken.ways.is_empty());
+    }
+
+    #[test]
+    fn record() {
+        let mut cache = ElementCache::default();
+        cache.set_record(true);
+        for id in 1..=10 {
+            cache.insert_node(id, Some((i32::try_from(id).unwrap(), 0)));
+        }
+        cache.insert_relation(1, None);
+        // Relation is larger than the limit, so everything is evicted
+        cache.set_limit(Some(2 * NODE_SIZE));
+        assert_eq!(0, cache.get_size());
+        assert_eq!(10, cache.stats.num_evicted_nodes);
+        assert_eq!(1, cache.stats.num_evicted_relations);
+        cache.insert_way(1, Some(vec![1, 2, 3]));
+        cache.insert_node(5, Some((5, 0)));
+        assert_eq!(NODE_SIZE, cache.get_size());
+
+        let taken = cache.take();
+        assert_eq!(10, taken.nodes.len());
+        assert_eq!(Some(&Some((5, 0))), taken.nodes.get(&5));
+        assert_eq!(Some(&Some(vec![1, 2, 3])), taken.ways.get(&1));
+        assert_eq!(Some(&None), taken.relations.get(&1));
+        assert_eq!(0, cache.get_size());
+        assert_eq!(None, cache.get_node(5));
+    }
+}
@@ -0,0 +1,672 @@
+//! Integrity checker of an OsmBin database
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, ErrorKind, Seek, SeekFrom, Write};
+
+use super::encoding;
+use super::mmap::OsmBinMmap;
+use super::{OpenMode, OsmBin, RELATION_FREE, RELATION_HEADER_SIZE, RELATION_IDX};
+use super::{WAY_FREE, WAY_IDX};
+use crate::macros::printlnt;
+use crate::osm::Relation;
+
+/// Inconsistency found by [`OsmBin::check`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum CheckError {
+    /// Pointer of way in `way.idx` is outside of `way.data`, or to corrupted nodes
+    InvalidWayPointer { way: u64, ptr: u64 },
+    /// Way points to an entry of `way.data` with a zero node count
+    EmptyWay { way: u64, ptr: u64 },
+    /// Way uses a node missing from `node.crd`
+    MissingNode { way: u64, node: u64 },
+    /// Ways are stored in overlapping parts of `way.data`
+    OverlappingWays { way: u64, other: u64 },
+    /// Entry of `way.free` is used by a way
+    ///
+    /// `num_nodes` is the header of the entry, which is a size with [`WayEncoding::Delta`].
+    ///
+    /// [`WayEncoding::Delta`]: super::WayEncoding::Delta
+    WayFreeUsed { ptr: u64, num_nodes: u64, way: u64 },
+    /// Entry of `way.free` is not a free entry of `way.data`, or is listed twice
+    InvalidWayFree { ptr: u64, num_nodes: u64 },
+    /// Pointer of relation in `relation.idx` is outside of `relation.data`, or to a corrupted
+    /// relation
+    InvalidRelationPointer { relation: u64, ptr: u64 },
+    /// Relation uses a missing node/way/relation
+    MissingMember {
+        relation: u64,
+        type_: String,
+        id: u64,
+    },
+    /// Relations are stored in overlapping parts of `relation.data`
+    OverlappingRelations { relation: u64, other: u64 },
+    /// Entry of `relation.free` is used by a relation
+    RelationFreeUsed { ptr: u64, size: u64, relation: u64 },
+    /// Entry of `relation.free` is not a free entry of `relation.data`, or is listed twice
+    InvalidRelationFree { ptr: u64, size: u64 },
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckError::InvalidWayPointer { way, ptr } => {
+                write!(f, "way {way} points outside of way.data at {ptr}")
+            }
+            CheckError::EmptyWay { way, ptr } => {
+                write!(f, "way {way} points to an entry without nodes at {ptr}")
+            }
+            CheckError::MissingNode { way, node } => {
+                write!(f, "way {way} uses missing node {node}")
+            }
+            CheckError::OverlappingWays { way, other } => {
+                write!(f, "way {way} overlaps way {other} in way.data")
+            }
+            CheckError::WayFreeUsed {
+                ptr,
+                num_nodes,
+                way,
+            } => write!(f, "way.free entry {ptr};{num_nodes} is used by way {way}"),
+            CheckError::InvalidWayFree { ptr, num_nodes } => {
+                write!(f, "way.free entry {ptr};{num_nodes} is not free")
+            }
+            CheckError::InvalidRelationPointer { relation, ptr } => {
+                write!(f, "relation {relation} points to invalid data at {ptr}")
+            }
+            CheckError::MissingMember {
+                relation,
+                type_,
+                id,
+            } => {
+                write!(f, "relation {relation} uses missing {type_} {id}")
+            }
+            CheckError::OverlappingRelations { relation, other } => {
+                write!(
+                    f,
+                    "relation {relation} overlaps relation {other} in relation.data"
+                )
+            }
+            CheckError::RelationFreeUsed {
+                ptr,
+                size,
+                relation,
+            } => write!(
+                f,
+                "relation.free entry {ptr};{size} is used by relation {relation}"
+            ),
+            CheckError::InvalidRelationFree { ptr, size } => {
+                write!(f, "relation.free entry {ptr};{size} is not free")
+            }
+        }
+    }
+}
+
+/// Result of [`OsmBin::check`], with all inconsistencies found
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub num_ways: u64,
+    pub num_relations: u64,
+    pub num_way_free: u64,
+    pub num_relation_free: u64,
+    pub errors: Vec<CheckError>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Checked {} ways, {} relations, {} way.free and {} relation.free entries: {} errors",
+            self.num_ways,
+            self.num_relations,
+            self.num_way_free,
+            self.num_relation_free,
+            self.errors.len()
+        )?;
+        for e in &self.errors {
+            write!(f, "\n  {e}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Space used in a data file by an element
+struct Allocation {
+    start: u64,
+    end: u64,
+    id: u64,
+}
+
+/// Sort allocations, and return pairs of ids of overlapping allocations
+fn find_overlaps(allocs: &mut [Allocation]) -> Vec<(u64, u64)> {
+    allocs.sort_by_key(|a| a.start);
+    let mut overlaps = Vec::new();
+    let mut last: Option<&Allocation> = None;
+    for a in allocs.iter() {
+        if let Some(last) = last
+            && a.start < last.end
+        {
+            overlaps.push((a.id, last.id));
+        }
+        if last.is_none_or(|l| a.end > l.end) {
+            last = Some(a);
+        }
+    }
+    overlaps
+}
+
+/// Find an allocation overlapping `start..end`, in allocations sorted by [`find_overlaps`]
+///
+/// Only the last allocation starting before `end` needs to be checked, as allocations don't
+/// overlap in a valid database.
+fn find_used(allocs: &[Allocation], start: u64, end: u64) -> Option<u64> {
+    let i = allocs.partition_point(|a| a.start < end);
+    allocs[..i].last().filter(|a| a.end > start).map(|a| a.id)
+}
+
+/// Bitmap of bytes of a data file used by elements
+///
+/// It takes one bit per byte of the data file, instead of keeping the allocation of every
+/// element. Allocations are only kept for the ranges where a conflict was found, to get the ids
+/// of conflicting elements in a second pass.
+struct UsedBytes {
+    bits: Vec<u64>,
+    conflicts: Vec<(u64, u64)>,
+}
+
+impl UsedBytes {
+    fn new(len: usize) -> UsedBytes {
+        UsedBytes {
+            bits: vec![0; len.div_ceil(64) + 1],
+            conflicts: Vec::new(),
+        }
+    }
+
+    /// Call `f` with the index and mask of every word of bitmap covering `start..end`
+    fn words(start: u64, end: u64, mut f: impl FnMut(usize, u64)) {
+        let mut pos = start;
+        while pos < end {
+            let bit = pos % 64;
+            let len = (end - pos).min(64 - bit);
+            let mask = if len == 64 {
+                u64::MAX
+            } else {
+                ((1 << len) - 1) << bit
+            };
+            f(usize::try_from(pos / 64).unwrap(), mask);
+            pos += len;
+        }
+    }
+
+    /// Mark `start..end` as used, recording a conflict if it was already partly used
+    fn insert(&mut self, start: u64, end: u64) {
+        let mut conflict = false;
+        UsedBytes::words(start, end, |i, mask| {
+            conflict |= self.bits[i] & mask != 0;
+            self.bits[i] |= mask;
+        });
+        if conflict {
+            self.conflicts.push((start, end));
+        }
+    }
+
+    /// Check if `start..end` is partly used, recording a conflict if so
+    fn check_free(&mut self, start: u64, end: u64) -> bool {
+        let mut used = false;
+        UsedBytes::words(start, end, |i, mask| {
+            used |= self.bits.get(i).is_some_and(|b| b & mask != 0);
+        });
+        if used {
+            self.conflicts.push((start, end));
+        }
+        used
+    }
+
+    /// Get allocations overlapping a conflict, sorted by [`find_overlaps`], and the overlapping
+    /// pairs of ids among them
+    fn resolve(
+        &mut self,
+        allocs: impl Iterator<Item = Allocation>,
+    ) -> (Vec<Allocation>, Vec<(u64, u64)>) {
+        if self.conflicts.is_empty() {
+            return (Vec::new(), Vec::new());
+        }
+        self.conflicts.sort_unstable();
+        let mut conflicts: Vec<Allocation> = Vec::new();
+        for &(start, end) in &self.conflicts {
+            match conflicts.last_mut() {
+                Some(last) if start <= last.end => last.end = last.end.max(end),
+                _ => conflicts.push(Allocation { start, end, id: 0 }),
+            }
+        }
+        let mut allocs: Vec<Allocation> = allocs
+            .filter(|a| find_used(&conflicts, a.start, a.end).is_some())
+            .collect();
+        let overlaps = find_overlaps(&mut allocs);
+        (allocs, overlaps)
+    }
+}
+
+/// Get `len` bytes at `addr`, or `None` if they are outside of file or before its header
+fn get(data: &[u8], addr: u64, len: u64) -> Option<&[u8]> {
+    if addr < 2 {
+        return None;
+    }
+    let addr = usize::try_from(addr).ok()?;
+    data.get(addr..addr.checked_add(usize::try_from(len).ok()?)?)
+}
+
+impl OsmBin {
+    /// Check consistency of the whole database
+    ///
+    /// All ways are checked to point to valid data with existing nodes, and all relations to
+    /// point to valid data with existing members. Entries of `way.free` and `relation.free` are
+    /// checked to really be free. All errors are returned, instead of stopping on the first one.
+    pub fn check(&mut self) -> Result<CheckReport, Box<dyn Error>> {
+        self.flush()?;
+        let osmbin = OsmBinMmap::new_unlocked(&self.dir)?;
+        let mut report = CheckReport::default();
+        self.check_ways(&osmbin, &mut report)?;
+        self.check_relations(&osmbin, &mut report)?;
+        Ok(report)
+    }
+
+    /// Get the end of the entry of way `id` in `way.data`, and its nodes
+    fn way_entry(&self, osmbin: &OsmBinMmap, id: u64) -> Result<(u64, Vec<u64>), CheckError> {
+        let data = osmbin.way_data();
+        let ptr = osmbin.way_ptr(id);
+        let Some(header) = get(data, ptr, 2) else {
+            return Err(CheckError::InvalidWayPointer { way: id, ptr });
+        };
+        let header = OsmBin::bytes2_to_int(header.try_into().unwrap());
+        if header == 0 {
+            return Err(CheckError::EmptyWay { way: id, ptr });
+        }
+        let size = self.format.way_size(header);
+        let nodes = get(data, ptr + 2, size - 2).and_then(|d| self.format.decode_way(d));
+        let nodes = nodes.ok_or(CheckError::InvalidWayPointer { way: id, ptr })?;
+        Ok((ptr + size, nodes))
+    }
+
+    fn check_ways(&self, osmbin: &OsmBinMmap, report: &mut CheckReport) -> Result<(), io::Error> {
+        let data = osmbin.way_data();
+        let mut used = UsedBytes::new(data.len());
+        for id in osmbin.way_ids() {
+            report.num_ways += 1;
+            if report.num_ways.is_multiple_of(10_000_000) {
+                printlnt!("{} ways", report.num_ways);
+            }
+            let (end, nodes) = match self.way_entry(osmbin, id) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    report.errors.push(e);
+                    continue;
+                }
+            };
+            for n in nodes {
+                if osmbin.read_node(n).is_none() {
+                    report
+                        .errors
+                        .push(CheckError::MissingNode { way: id, node: n });
+                }
+            }
+            used.insert(osmbin.way_ptr(id), end);
+        }
+
+        let free_list = Self::read_free_list(&self.dir, WAY_FREE)?;
+        let free_list: Vec<(u64, u64, u64, bool)> = free_list
+            .into_iter()
+            .map(|(ptr, num_nodes)| {
+                let end = ptr
+                    + self
+                        .format
+                        .way_size(u16::try_from(num_nodes).unwrap_or(u16::MAX));
+                (ptr, num_nodes, end, used.check_free(ptr, end))
+            })
+            .collect();
+        let (allocs, overlaps) = used.resolve(osmbin.way_ids().filter_map(|id| {
+            let (end, _) = self.way_entry(osmbin, id).ok()?;
+            let start = osmbin.way_ptr(id);
+            Some(Allocation { start, end, id })
+        }));
+        for (way, other) in overlaps {
+            report
+                .errors
+                .push(CheckError::OverlappingWays { way, other });
+        }
+
+        let mut free: HashSet<u64> = HashSet::new();
+        for (ptr, num_nodes, end, is_used) in free_list {
+            report.num_way_free += 1;
+            if let Some(way) = find_used(&allocs, ptr, end).filter(|_| is_used) {
+                report.errors.push(CheckError::WayFreeUsed {
+                    ptr,
+                    num_nodes,
+                    way,
+                });
+            } else if !free.insert(ptr)
+                || num_nodes == 0
+                || get(data, ptr, end - ptr).is_none_or(|d| d[0..2] != [0u8; 2])
+            {
+                report
+                    .errors
+                    .push(CheckError::InvalidWayFree { ptr, num_nodes });
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the allocated size of the entry of relation `id` in `relation.data`, and its content
+    fn relation_entry(osmbin: &OsmBinMmap, id: u64) -> Result<(u64, Relation), CheckError> {
+        let data = osmbin.relation_data();
+        let ptr = osmbin.relation_ptr(id);
+        let relation = get(data, ptr, RELATION_HEADER_SIZE).and_then(|header| {
+            let alloc_size = OsmBin::bytes4_to_int(header[0..4].try_into().unwrap());
+            let size = OsmBin::bytes4_to_int(header[4..8].try_into().unwrap());
+            if size == 0 || RELATION_HEADER_SIZE + u64::from(size) > u64::from(alloc_size) {
+                return None;
+            }
+            get(data, ptr, u64::from(alloc_size))?;
+            let data = get(data, ptr + RELATION_HEADER_SIZE, u64::from(size))?;
+            Some((u64::from(alloc_size), encoding::decode_relation(id, data)?))
+        });
+        relation.ok_or(CheckError::InvalidRelationPointer { relation: id, ptr })
+    }
+
+    fn check_relations(
+        &self,
+        osmbin: &OsmBinMmap,
+        report: &mut CheckReport,
+    ) -> Result<(), io::Error> {
+        let data = osmbin.relation_data();
+        let mut used = UsedBytes::new(data.len());
+        for id in osmbin.relation_ids() {
+            report.num_relations += 1;
+            let (alloc_size, relation) = match Self::relation_entry(osmbin, id) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    report.errors.push(e);
+                    continue;
+                }
+            };
+            for m in &relation.members {
+                let exists = match m.type_.as_str() {
+                    "node" => osmbin.read_node(m.ref_).is_some(),
+                    "way" => osmbin.way_ptr(m.ref_) != 0,
+                    _ => osmbin.relation_ptr(m.ref_) != 0,
+                };
+                if !exists {
+                    report.errors.push(CheckError::MissingMember {
+                        relation: id,
+                        type_: m.type_.clone(),
+                        id: m.ref_,
+                    });
+                }
+            }
+            let ptr = osmbin.relation_ptr(id);
+            used.insert(ptr, ptr + alloc_size);
+        }
+
+        let free_list = Self::read_free_list(&self.dir, RELATION_FREE)?;
+        let free_list: Vec<(u64, u64, bool)> = free_list
+            .into_iter()
+            .map(|(ptr, size)| (ptr, size, used.check_free(ptr, ptr + size)))
+            .collect();
+        let (allocs, overlaps) = used.resolve(osmbin.relation_ids().filter_map(|id| {
+            let (alloc_size, _) = Self::relation_entry(osmbin, id).ok()?;
+            let start = osmbin.relation_ptr(id);
+            Some(Allocation {
+                start,
+                end: start + alloc_size,
+                id,
+            })
+        }));
+        for (relation, other) in overlaps {
+            report
+                .errors
+                .push(CheckError::OverlappingRelations { relation, other });
+        }
+
+        let mut free: HashSet<u64> = HashSet::new();
+        for (ptr, size, is_used) in free_list {
+            report.num_relation_free += 1;
+            if let Some(relation) = find_used(&allocs, ptr, ptr + size).filter(|_| is_used) {
+                report.errors.push(CheckError::RelationFreeUsed {
+                    ptr,
+                    size,
+                    relation,
+                });
+            } else if !free.insert(ptr)
+                || get(data, ptr, size.max(RELATION_HEADER_SIZE)).is_none_or(|header| {
+                    u64::from(OsmBin::bytes4_to_int(header[0..4].try_into().unwrap())) != size
+                        || header[4..8] != [0u8; 4]
+                })
+            {
+                report
+                    .errors
+                    .push(CheckError::InvalidRelationFree { ptr, size });
+            }
+        }
+        Ok(())
+    }
+
+    /// Fix inconsistencies of indexes and free lists found by [`check`](Self::check)
+    ///
+    /// Ways and relations pointing to invalid or overlapping data are removed from indexes, as
+    /// their content can't be trusted, and entries of free lists that are not free are removed.
+    /// Missing nodes and members can't be repaired. Returns the number of repaired errors.
+    pub fn repair(&mut self, report: &CheckReport) -> Result<u64, Box<dyn Error>> {
+        if let OpenMode::Read = self.mode {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "Database must be opened in read-write mode to be repaired",
+            )
+            .into());
+        }
+        let mut num_repaired = 0;
+        self.begin()?;
+        for e in &report.errors {
+            match e {
+                CheckError::InvalidWayPointer { way, .. } | CheckError::EmptyWay { way, .. } => {
+                    self.clear_ptr(WAY_IDX, *way)?;
+                }
+                CheckError::OverlappingWays { way, other } => {
+                    self.clear_ptr(WAY_IDX, *way)?;
+                    self.clear_ptr(WAY_IDX, *other)?;
+                }
+                CheckError::WayFreeUsed { ptr, num_nodes, .. }
+                | CheckError::InvalidWayFree { ptr, num_nodes } => {
+                    let num_nodes = u16::try_from(*num_nodes).unwrap_or(u16::MAX);
+                    if let Some(v) = self.way_free_data.get_mut(&num_nodes)
+                        && let Some(pos) = v.iter().position(|p| p == ptr)
+                    {
+                        v.remove(pos);
+                    }
+                }
+                CheckError::InvalidRelationPointer { relation, .. } => {
+                    self.clear_ptr(RELATION_IDX, *relation)?;
+                }
+                CheckError::OverlappingRelations { relation, other } => {
+                    self.clear_ptr(RELATION_IDX, *relation)?;
+                    self.clear_ptr(RELATION_IDX, *other)?;
+                }
+                CheckError::RelationFreeUsed { ptr, size, .. }
+                | CheckError::InvalidRelationFree { ptr, size } => {
+                    if let Some(v) = self.relation_free_data.get_mut(size)
+                        && let Some(pos) = v.iter().position(|p| p == ptr)
+                    {
+                        v.remove(pos);
+                    }
+                }
+                CheckError::MissingNode { .. } | CheckError::MissingMember { .. } => continue,
+            }
+            num_repaired += 1;
+        }
+        self.way_free_data.retain(|_, v| !v.is_empty());
+        self.relation_free_data.retain(|_, v| !v.is_empty());
+        self.commit()?;
+        Ok(num_repaired)
+    }
+
+    /// Remove element `id` from `way.idx` or `relation.idx`
+    fn clear_ptr(&mut self, filename: &str, id: u64) -> Result<(), io::Error> {
+        let ptr_size = match filename {
+            WAY_IDX => self.format.way_ptr_size,
+            _ => self.format.relation_ptr_size,
+        };
+        let addr = id * ptr_size as u64;
+        self.journal_range(filename, addr, ptr_size as u64)?;
+        let index = match filename {
+            WAY_IDX => &mut self.way_idx,
+            _ => &mut self.relation_idx,
+        };
+        index.seek(SeekFrom::Start(addr))?;
+        index.write_all(&vec![0; ptr_size])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+
+    use crate::osm::{OsmReader, OsmWriter};
+    use crate::osmbin::WAY_DATA;
+
+    const PBF_SAINT_BARTHELEMY: &str = "tests/resources/saint_barthelemy.osm.pbf";
+
+    fn is_missing(e: &CheckError) -> bool {
+        matches!(
+            e,
+            CheckError::MissingNode { .. } | CheckError::MissingMember { .. }
+        )
+    }
+
+    fn write_at(dir: &str, filename: &str, data: &[u8], offset: u64) {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(Path::new(dir).join(filename))
+            .unwrap();
+        file.write_all_at(data, offset).unwrap();
+    }
+
+    fn append(dir: &str, filename: &str, content: &str) {
+        let path = Path::new(dir).join(filename);
+        let prev = fs::read_to_string(&path).unwrap();
+        fs::write(&path, prev + content).unwrap();
+    }
+
+    #[test]
+    fn used_bytes() {
+        let mut used = UsedBytes::new(200);
+        used.insert(10, 70);
+        used.insert(70, 192);
+        assert!(used.conflicts.is_empty());
+        assert!(!used.check_free(2, 10));
+        assert!(!used.check_free(192, 300));
+        assert!(used.check_free(60, 64));
+        used.insert(191, 200);
+        assert_eq!(vec![(60, 64), (191, 200)], used.conflicts);
+
+        let allocs = [(10, 70, 1), (70, 192, 2), (191, 200, 3)]
+            .map(|(start, end, id)| Allocation { start, end, id });
+        let (allocs, overlaps) = used.resolve(allocs.into_iter());
+        assert_eq!(3, allocs.len());
+        assert_eq!(vec![(3, 2)], overlaps);
+    }
+
+    #[test]
+    fn check_repair() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        let report = osmbin.check().unwrap();
+        assert!(report.num_ways > 500);
+        assert!(report.num_relations > 10);
+        // Extract of a small area has relations with members outside of it
+        assert!(report.errors.iter().all(is_missing));
+        let num_errors = report.errors.len();
+        drop(osmbin);
+
+        let mmap = OsmBinMmap::new(&tmpdir).unwrap();
+        let empty_ptr = mmap.way_ptr(255316725);
+        let used_ptr = mmap.way_ptr(255316718);
+        let used_num_nodes = mmap.try_read_way(255316718).unwrap().unwrap().nodes.len();
+        let relation_ptr = mmap.relation_ptr(47796);
+        let way_data_size = mmap.way_data().len();
+        let relation_data_size = mmap.relation_data().len();
+        drop(mmap);
+
+        write_at(&tmpdir, WAY_DATA, &[0, 0], empty_ptr);
+        let mut ptr = [0u8; 5];
+        OsmBin::int_to_bytes(used_ptr, &mut ptr);
+        write_at(&tmpdir, WAY_IDX, &ptr, 24473155 * 5);
+        OsmBin::int_to_bytes(relation_data_size as u64 + 64, &mut ptr);
+        write_at(&tmpdir, RELATION_IDX, &ptr, 529891 * 5);
+        append(
+            &tmpdir,
+            WAY_FREE,
+            &format!("{used_ptr};{used_num_nodes}\n{way_data_size};3\n"),
+        );
+        append(&tmpdir, RELATION_FREE, &format!("{relation_ptr};32\n"));
+
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        let report = osmbin.check().unwrap();
+        let errors: Vec<&CheckError> = report.errors.iter().filter(|e| !is_missing(e)).collect();
+        assert_eq!(
+            vec![
+                &CheckError::EmptyWay {
+                    way: 255316725,
+                    ptr: empty_ptr
+                },
+                &CheckError::OverlappingWays {
+                    way: 255316718,
+                    other: 24473155
+                },
+                &CheckError::WayFreeUsed {
+                    ptr: used_ptr,
+                    num_nodes: used_num_nodes as u64,
+                    way: 255316718
+                },
+                &CheckError::InvalidWayFree {
+                    ptr: way_data_size as u64,
+                    num_nodes: 3
+                },
+                &CheckError::InvalidRelationPointer {
+                    relation: 529891,
+                    ptr: relation_data_size as u64 + 64
+                },
+                &CheckError::RelationFreeUsed {
+                    ptr: relation_ptr,
+                    size: 32,
+                    relation: 47796
+                },
+            ],
+            errors
+        );
+
+        // Missing nodes and members are not repaired
+        assert_eq!(6, osmbin.repair(&report).unwrap());
+        let report = osmbin.check().unwrap();
+        assert!(report.errors.iter().all(is_missing));
+        assert!(report.errors.len() >= num_errors);
+        assert_eq!(None, osmbin.read_way(255316725));
+        assert_eq!(None, osmbin.read_relation(529891));
+        assert_eq!(true, osmbin.read_relation(47796).is_some());
+        drop(osmbin);
+
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        assert!(osmbin.check().unwrap().errors.iter().all(is_missing));
+        assert!(osmbin.repair(&report).is_err());
+    }
+}
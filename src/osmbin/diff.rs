@@ -0,0 +1,130 @@
+//! Diff staged in memory, to be applied to an OsmBin database sorted by id
+
+use std::error::Error;
+use std::io;
+
+use crate::osm::{Action, Node, OsmUpdate, OsmWriter, Relation, Way};
+
+/// Elements of a diff with their action, in diff order
+///
+/// Diffs list elements by changeset, so applying them directly jumps back and forth in database
+/// files, and each seek flushes write buffers. Once the whole diff is read, elements are applied
+/// sorted by id for each type, so that consecutive elements, like nodes created by the same diff,
+/// are written in sequential runs.
+#[derive(Default)]
+pub struct StagedDiff {
+    change: bool,
+    nodes: Vec<(Node, Action)>,
+    ways: Vec<(Way, Action)>,
+    relations: Vec<(Relation, Action)>,
+}
+
+impl StagedDiff {
+    /// Apply all elements to `target`, sorted by id
+    ///
+    /// Sort is stable, so that an element present several times in diff gets the same actions in
+    /// the same order, and ends with the same state as with a diff applied in its order.
+    pub fn apply_to<T: OsmUpdate>(mut self, target: &mut T) -> Result<(), Box<dyn Error>> {
+        self.nodes.sort_by_key(|(node, _)| node.id);
+        self.ways.sort_by_key(|(way, _)| way.id);
+        self.relations.sort_by_key(|(relation, _)| relation.id);
+
+        target.write_start(self.change)?;
+        for (mut node, action) in self.nodes {
+            target.update_node(&mut node, &action)?;
+        }
+        for (mut way, action) in self.ways {
+            target.update_way(&mut way, &action)?;
+        }
+        for (mut relation, action) in self.relations {
+            target.update_relation(&mut relation, &action)?;
+        }
+        target.write_end(self.change)
+    }
+}
+
+impl OsmWriter for StagedDiff {
+    fn write_node(&mut self, node: &mut Node) -> Result<(), io::Error> {
+        self.update_node(node, &Action::None)
+    }
+    fn write_way(&mut self, way: &mut Way) -> Result<(), io::Error> {
+        self.update_way(way, &Action::None)
+    }
+    fn write_relation(&mut self, relation: &mut Relation) -> Result<(), io::Error> {
+        self.update_relation(relation, &Action::None)
+    }
+    fn write_start(&mut self, change: bool) -> Result<(), Box<dyn Error>> {
+        self.change = change;
+        Ok(())
+    }
+}
+
+impl OsmUpdate for StagedDiff {
+    fn update_node(&mut self, node: &mut Node, action: &Action) -> Result<(), io::Error> {
+        self.nodes.push((node.clone(), action.clone()));
+        Ok(())
+    }
+    fn update_way(&mut self, way: &mut Way, action: &Action) -> Result<(), io::Error> {
+        self.ways.push((way.clone(), action.clone()));
+        Ok(())
+    }
+    fn update_relation(
+        &mut self,
+        relation: &mut Relation,
+        action: &Action,
+    ) -> Result<(), io::Error> {
+        self.relations.push((relation.clone(), action.clone()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::osm::OsmReader;
+    use crate::osmbin::OsmBin;
+
+    #[test]
+    fn apply_sorted() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+
+        let node = |id, decimicro_lat| Node {
+            id,
+            decimicro_lat,
+            decimicro_lon: 1,
+            ..Default::default()
+        };
+        let mut diff = StagedDiff::default();
+        diff.write_start(true).unwrap();
+        diff.update_node(&mut node(5, 1), &Action::Create())
+            .unwrap();
+        diff.update_node(&mut node(3, 1), &Action::Create())
+            .unwrap();
+        diff.update_node(&mut node(5, 2), &Action::Modify())
+            .unwrap();
+        diff.update_node(&mut node(3, 1), &Action::Delete())
+            .unwrap();
+        diff.update_node(&mut node(4, 1), &Action::Create())
+            .unwrap();
+        diff.update_way(
+            &mut Way {
+                id: 1,
+                nodes: vec![4, 5],
+                ..Default::default()
+            },
+            &Action::Create(),
+        )
+        .unwrap();
+        diff.write_end(true).unwrap();
+        diff.apply_to(&mut osmbin).unwrap();
+
+        assert_eq!(None, osmbin.read_node(3));
+        assert_eq!(1, osmbin.read_node(4).unwrap().decimicro_lat);
+        assert_eq!(2, osmbin.read_node(5).unwrap().decimicro_lat);
+        assert_eq!(vec![4, 5], osmbin.read_way(1).unwrap().nodes);
+    }
+}
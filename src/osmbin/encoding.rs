@@ -0,0 +1,368 @@
+//! Compact binary encoding of elements stored in OsmBin
+
+use std::num::NonZeroU64;
+
+use crate::osm::{BoundingBox, Member, Relation};
+
+const FLAG_TAGS: u8 = 1 << 0;
+const FLAG_VERSION: u8 = 1 << 1;
+const FLAG_TIMESTAMP: u8 = 1 << 2;
+const FLAG_UID: u8 = 1 << 3;
+const FLAG_USER: u8 = 1 << 4;
+const FLAG_CHANGESET: u8 = 1 << 5;
+const FLAG_BBOX: u8 = 1 << 6;
+
+/// Append an unsigned integer using LEB128 variable-length encoding
+pub fn write_varint(buf: &mut Vec<u8>, v: u64) {
+    let mut v = v;
+    while v >= 0x80 {
+        #[allow(clippy::cast_possible_truncation)]
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    buf.push(v as u8);
+}
+
+/// Read an unsigned integer written by [`write_varint`]
+pub fn read_varint(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut v: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let b = *data.get(*pos)?;
+        *pos += 1;
+        if shift >= 64 {
+            return None;
+        }
+        v |= u64::from(b & 0x7f) << shift;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+        shift += 7;
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    write_varint(buf, s.len() as u64);
+    buf.extend(s.as_bytes());
+}
+
+fn read_str(data: &[u8], pos: &mut usize) -> Option<String> {
+    let len = usize::try_from(read_varint(data, pos)?).ok()?;
+    let s = data.get(*pos..pos.checked_add(len)?)?;
+    *pos += len;
+    String::from_utf8(s.to_vec()).ok()
+}
+
+fn read_i32(data: &[u8], pos: &mut usize) -> Option<i32> {
+    let v = data.get(*pos..*pos + 4)?;
+    *pos += 4;
+    Some(i32::from_be_bytes(v.try_into().unwrap()))
+}
+
+/// Encode a relation
+///
+/// Layout is a byte of flags giving which optional fields are present, followed by members (as
+/// type, ref, role), and then optional fields.
+pub fn encode_relation(relation: &Relation) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::new();
+    let mut flags = 0;
+    for (flag, present) in [
+        (FLAG_TAGS, relation.tags.is_some()),
+        (FLAG_VERSION, relation.version.is_some()),
+        (FLAG_TIMESTAMP, relation.timestamp.is_some()),
+        (FLAG_UID, relation.uid.is_some()),
+        (FLAG_USER, relation.user.is_some()),
+        (FLAG_CHANGESET, relation.changeset.is_some()),
+        (FLAG_BBOX, relation.bbox.is_some()),
+    ] {
+        if present {
+            flags |= flag;
+        }
+    }
+    buf.push(flags);
+
+    write_varint(&mut buf, relation.members.len() as u64);
+    for m in &relation.members {
+        let t = match m.type_.as_str() {
+            "node" => b'n',
+            "way" => b'w',
+            "relation" => b'r',
+            t => panic!("{t} not expected"),
+        };
+        buf.push(t);
+        write_varint(&mut buf, m.ref_);
+        write_str(&mut buf, &m.role);
+    }
+    if let Some(tags) = &relation.tags {
+        write_varint(&mut buf, tags.len() as u64);
+        for (k, v) in tags {
+            write_str(&mut buf, k);
+            write_str(&mut buf, v);
+        }
+    }
+    if let Some(version) = relation.version {
+        write_varint(&mut buf, version.get());
+    }
+    if let Some(timestamp) = &relation.timestamp {
+        write_str(&mut buf, timestamp);
+    }
+    if let Some(uid) = relation.uid {
+        write_varint(&mut buf, uid.get());
+    }
+    if let Some(user) = &relation.user {
+        write_str(&mut buf, user);
+    }
+    if let Some(changeset) = relation.changeset {
+        write_varint(&mut buf, changeset.get());
+    }
+    if let Some(bbox) = &relation.bbox {
+        for v in [
+            bbox.decimicro_minlat,
+            bbox.decimicro_maxlat,
+            bbox.decimicro_minlon,
+            bbox.decimicro_maxlon,
+        ] {
+            buf.extend(v.to_be_bytes());
+        }
+    }
+    buf
+}
+
+/// Decode a relation written by [`encode_relation`]
+///
+/// Returns `None` if data is corrupted.
+pub fn decode_relation(id: u64, data: &[u8]) -> Option<Relation> {
+    let mut pos = 0;
+    let flags = *data.first()?;
+    pos += 1;
+
+    let num_members = read_varint(data, &mut pos)?;
+    let mut members: Vec<Member> = Vec::new();
+    for _ in 0..num_members {
+        let type_ = match data.get(pos)? {
+            b'n' => "node",
+            b'w' => "way",
+            b'r' => "relation",
+            _ => return None,
+        };
+        pos += 1;
+        let ref_ = read_varint(data, &mut pos)?;
+        let role = read_str(data, &mut pos)?;
+        members.push(Member {
+            ref_,
+            role,
+            type_: String::from(type_),
+        });
+    }
+
+    let mut relation = Relation {
+        id,
+        members,
+        ..Default::default()
+    };
+    if flags & FLAG_TAGS != 0 {
+        let num_tags = read_varint(data, &mut pos)?;
+        let mut tags: Vec<(String, String)> = Vec::new();
+        for _ in 0..num_tags {
+            tags.push((read_str(data, &mut pos)?, read_str(data, &mut pos)?));
+        }
+        relation.tags = Some(tags);
+    }
+    if flags & FLAG_VERSION != 0 {
+        relation.version = NonZeroU64::new(read_varint(data, &mut pos)?);
+    }
+    if flags & FLAG_TIMESTAMP != 0 {
+        relation.timestamp = Some(read_str(data, &mut pos)?);
+    }
+    if flags & FLAG_UID != 0 {
+        relation.uid = NonZeroU64::new(read_varint(data, &mut pos)?);
+    }
+    if flags & FLAG_USER != 0 {
+        relation.user = Some(read_str(data, &mut pos)?);
+    }
+    if flags & FLAG_CHANGESET != 0 {
+        relation.changeset = NonZeroU64::new(read_varint(data, &mut pos)?);
+    }
+    if flags & FLAG_BBOX != 0 {
+        relation.bbox = Some(BoundingBox {
+            decimicro_minlat: read_i32(data, &mut pos)?,
+            decimicro_maxlat: read_i32(data, &mut pos)?,
+            decimicro_minlon: read_i32(data, &mut pos)?,
+            decimicro_maxlon: read_i32(data, &mut pos)?,
+        });
+    }
+    Some(relation)
+}
+
+/// Encode a sorted list of ids, as the number of ids followed by the difference with the
+/// previous id
+pub fn encode_ids(ids: &[u64]) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::new();
+    write_varint(&mut buf, ids.len() as u64);
+    let mut prev = 0;
+    for id in ids {
+        write_varint(&mut buf, id - prev);
+        prev = *id;
+    }
+    buf
+}
+
+/// Decode a list of ids written by [`encode_ids`]
+///
+/// Returns `None` if data is corrupted.
+pub fn decode_ids(data: &[u8]) -> Option<Vec<u64>> {
+    let mut pos = 0;
+    let num_ids = read_varint(data, &mut pos)?;
+    let mut ids: Vec<u64> = Vec::new();
+    let mut prev: u64 = 0;
+    for _ in 0..num_ids {
+        prev = prev.checked_add(read_varint(data, &mut pos)?)?;
+        ids.push(prev);
+    }
+    Some(ids)
+}
+
+/// Encode node ids of a way, as the number of nodes followed by the difference with the
+/// previous node, zigzag-encoded as it can be negative
+pub fn encode_way_nodes(nodes: &[u64]) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::with_capacity(2 * nodes.len() + 4);
+    write_varint(&mut buf, nodes.len() as u64);
+    let mut prev: u64 = 0;
+    for n in nodes {
+        #[allow(clippy::cast_possible_wrap)]
+        let delta = n.wrapping_sub(prev) as i64;
+        #[allow(clippy::cast_sign_loss)]
+        write_varint(&mut buf, ((delta << 1) ^ (delta >> 63)) as u64);
+        prev = *n;
+    }
+    buf
+}
+
+/// Decode node ids of a way written by [`encode_way_nodes`], ignoring following bytes
+///
+/// Returns `None` if data is corrupted.
+pub fn decode_way_nodes(data: &[u8]) -> Option<Vec<u64>> {
+    let mut pos = 0;
+    let num_nodes = usize::try_from(read_varint(data, &mut pos)?).ok()?;
+    let mut nodes: Vec<u64> = Vec::with_capacity(num_nodes.min(data.len()));
+    let mut prev: u64 = 0;
+    for _ in 0..num_nodes {
+        let v = read_varint(data, &mut pos)?;
+        #[allow(clippy::cast_possible_wrap)]
+        let delta = (v >> 1) as i64 ^ -((v & 1) as i64);
+        #[allow(clippy::cast_sign_loss)]
+        let n = prev.wrapping_add(delta as u64);
+        nodes.push(n);
+        prev = n;
+    }
+    Some(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint() {
+        for v in [
+            0,
+            1,
+            127,
+            128,
+            300,
+            16383,
+            16384,
+            u64::from(u32::MAX),
+            u64::MAX,
+        ] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, v);
+            let mut pos = 0;
+            assert_eq!(Some(v), read_varint(&buf, &mut pos));
+            assert_eq!(buf.len(), pos);
+        }
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300);
+        assert_eq!(vec![0xac, 0x02], buf);
+        let mut pos = 0;
+        assert_eq!(None, read_varint(&buf[0..1], &mut pos));
+    }
+
+    #[test]
+    fn relation() {
+        let relation = Relation {
+            id: 2324452,
+            members: vec![
+                Member {
+                    type_: String::from("node"),
+                    ref_: 279149652,
+                    role: String::from("admin_centre"),
+                },
+                Member {
+                    type_: String::from("way"),
+                    ref_: 174027472,
+                    role: String::from("outer"),
+                },
+                Member {
+                    type_: String::from("relation"),
+                    ref_: 1,
+                    role: String::from(""),
+                },
+            ],
+            tags: Some(vec![
+                (String::from("name"), String::from("Sint Eustatius")),
+                (String::from("name:el"), String::from("Άγιος Ευστάθιος")),
+            ]),
+            version: NonZeroU64::new(4),
+            timestamp: Some(String::from("2014-01-10T23:00:23Z")),
+            uid: NonZeroU64::new(1811738),
+            user: Some(String::from("47NOE")),
+            changeset: NonZeroU64::new(19926891),
+            bbox: Some(BoundingBox {
+                decimicro_minlat: 174800000,
+                decimicro_maxlat: 175200000,
+                decimicro_minlon: -630200000,
+                decimicro_maxlon: -629400000,
+            }),
+        };
+        let data = encode_relation(&relation);
+        assert_eq!(Some(relation), decode_relation(2324452, &data));
+        assert_eq!(None, decode_relation(2324452, &data[0..data.len() - 1]));
+
+        let relation = Relation {
+            id: 3,
+            ..Default::default()
+        };
+        let data = encode_relation(&relation);
+        assert_eq!(vec![0, 0], data);
+        assert_eq!(Some(relation), decode_relation(3, &data));
+    }
+
+    #[test]
+    fn ids() {
+        let ids = vec![1, 2, 300, 255316725, 10_000_000_000];
+        let data = encode_ids(&ids);
+        assert_eq!(Some(ids), decode_ids(&data));
+        assert_eq!(None, decode_ids(&data[0..data.len() - 1]));
+        assert_eq!(vec![0], encode_ids(&[]));
+        assert_eq!(Some(vec![]), decode_ids(&[0]));
+    }
+
+    #[test]
+    fn way_nodes() {
+        let nodes = vec![266053077, 266053076, 2619283351, 266053077, 1, u64::MAX, 3];
+        let data = encode_way_nodes(&nodes);
+        assert_eq!(Some(nodes.clone()), decode_way_nodes(&data));
+        assert_eq!(None, decode_way_nodes(&data[0..data.len() - 1]));
+        // Padding is ignored
+        let mut padded = data.clone();
+        padded.extend([0, 0, 0]);
+        assert_eq!(Some(nodes), decode_way_nodes(&padded));
+
+        // Close nodes use one byte
+        let data = encode_way_nodes(&[1000, 1001, 1003, 1002]);
+        assert_eq!(vec![4, 0xd0, 0x0f, 2, 4, 1], data);
+        assert_eq!(Some(vec![]), decode_way_nodes(&[0]));
+    }
+}
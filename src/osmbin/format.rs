@@ -0,0 +1,574 @@
+//! Layout of files of an OsmBin database, stored in `format.txt`
+
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::encoding;
+use super::{FORMAT, NODE_ID_SIZE, OsmBin, OsmBinError, RELATION_PTR_SIZE, WAY_PTR_SIZE};
+
+/// First line of `format.txt`, to recognize an OsmBin database
+const MAGIC: &str = "osmbin-format";
+/// Latest version of the layout of files
+pub const FORMAT_VERSION: u32 = 7;
+/// Offset added to coordinates by [`CoordEncoding::Offset`]
+const COORD_OFFSET: i64 = 1_800_000_000;
+/// Granularity of allocations of ways encoded by [`WayEncoding::Delta`]
+const WAY_ALLOC_SIZE: usize = 4;
+
+/// Encoding of latitude/longitude in `node.crd`
+///
+/// A node with both coordinates stored as 0 is a missing node, as not allocated parts of a sparse
+/// file are read as 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoordEncoding {
+    /// Coordinates are converted to a positive number by adding 1.8e9, so that a node at 0,0 is
+    /// not confused with a missing node
+    Offset,
+    /// Coordinates are stored directly as i32. A node at 0,0 is stored with `i32::MIN` as
+    /// latitude, which is never a valid latitude.
+    Direct,
+}
+
+/// Encoding of nodes of a way in `way.data`
+///
+/// A way is stored as a 2-bytes header followed by its nodes, with a header of 0 for free space.
+/// The header gives the size of the entry, and is kept in `way.free` when a way is deleted, so
+/// that free space is reused by a way with the same header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WayEncoding {
+    /// Header is the number of nodes, followed by each node id using `node_id_size` bytes
+    Fixed,
+    /// Header is the size of nodes encoded by [`encoding::encode_way_nodes`], rounded to 4 bytes
+    /// so that free space can be reused by a way of a slightly different size. Needs version 2.
+    Delta,
+}
+
+/// Storage of node coordinates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeStore {
+    /// `node.crd` is directly indexed by node id, and relies on sparse files to skip ids which
+    /// are not stored
+    Direct,
+    /// `node.hash` is a hash table from node id to coordinates, only using space for stored
+    /// nodes, for small regional databases. `node.crd` is kept empty. Needs version 3.
+    Hash,
+}
+
+/// Layout of files of an OsmBin database
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Format {
+    /// Version of osmbin needed to read this layout
+    pub version: u32,
+    /// Size of a node-id stored in `way.data`
+    pub node_id_size: usize,
+    /// Size of a way pointer in `way.idx` to `way.data`
+    pub way_ptr_size: usize,
+    /// Size of a relation pointer in `relation.idx` to `relation.data`
+    pub relation_ptr_size: usize,
+    pub coordinates: CoordEncoding,
+    pub way_encoding: WayEncoding,
+    pub node_store: NodeStore,
+    /// Reverse indexes from nodes to ways, and from ways to relations, are stored. Needs version
+    /// 7.
+    pub parents: bool,
+    /// Spatial index from tiles to nodes is stored. Needs version 7.
+    pub tiles: bool,
+    /// Tags, version, timestamp, user and changeset of nodes and ways are stored, so that they
+    /// are returned complete by readers. Needs version 4.
+    pub metadata: bool,
+    /// Version of each element is stored, so that updates with an element not newer than
+    /// database are detected. Needs version 5.
+    pub versions: bool,
+    /// Number of applied diffs whose inverse changeset is kept in `undo/`, so that they can be
+    /// rolled back, or 0 to keep none. Needs version 6.
+    pub undo: u32,
+}
+
+/// Layout used by databases created before `format.txt` was introduced
+impl Default for Format {
+    fn default() -> Format {
+        Format {
+            version: 1,
+            node_id_size: NODE_ID_SIZE,
+            way_ptr_size: WAY_PTR_SIZE,
+            relation_ptr_size: RELATION_PTR_SIZE,
+            coordinates: CoordEncoding::Offset,
+            way_encoding: WayEncoding::Fixed,
+            node_store: NodeStore::Direct,
+            parents: false,
+            tiles: false,
+            metadata: false,
+            versions: false,
+            undo: 0,
+        }
+    }
+}
+
+impl Format {
+    /// Read `format.txt` from a database
+    ///
+    /// A database without `format.txt` was created by a previous version, and uses the default
+    /// layout.
+    pub fn from_file(filename: &Path) -> Result<Format, OsmBinError> {
+        let content = match fs::read_to_string(filename) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Format::default()),
+            r => r?,
+        };
+        Self::parse(&content).map_err(|reason| OsmBinError::FormatMismatch {
+            dir: filename.parent().unwrap_or(filename).display().to_string(),
+            reason: format!("{FORMAT} is not valid, {reason}"),
+        })
+    }
+
+    fn parse(content: &str) -> Result<Format, String> {
+        let mut lines = content.lines();
+        if lines.next() != Some(MAGIC) {
+            return Err("not an osmbin database".to_string());
+        }
+        let mut version = None;
+        let mut node_id_size = None;
+        let mut way_ptr_size = None;
+        let mut relation_ptr_size = None;
+        let mut coordinates = None;
+        let mut way_encoding = WayEncoding::Fixed;
+        let mut node_store = NodeStore::Direct;
+        let mut parents = false;
+        let mut tiles = false;
+        let mut metadata = false;
+        let mut versions = false;
+        let mut undo = 0;
+        for l in lines {
+            let Some((key, value)) = l.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            let invalid = || format!("incorrect value {value} for {key}");
+            match key {
+                "version" => version = Some(value.parse().map_err(|_| invalid())?),
+                "node_id_size" => node_id_size = Some(value.parse().map_err(|_| invalid())?),
+                "way_ptr_size" => way_ptr_size = Some(value.parse().map_err(|_| invalid())?),
+                "relation_ptr_size" => {
+                    relation_ptr_size = Some(value.parse().map_err(|_| invalid())?);
+                }
+                "coordinates" => coordinates = Some(value.parse()?),
+                "way_encoding" => way_encoding = value.parse()?,
+                "node_store" => node_store = value.parse()?,
+                "parents" => parents = value.parse().map_err(|_| invalid())?,
+                "tiles" => tiles = value.parse().map_err(|_| invalid())?,
+                "metadata" => metadata = value.parse().map_err(|_| invalid())?,
+                "versions" => versions = value.parse().map_err(|_| invalid())?,
+                "undo" => undo = value.parse().map_err(|_| invalid())?,
+                // Layout may need files that this version doesn't know how to update
+                _ => return Err(format!("unknown key {key}")),
+            }
+        }
+        let format = Format {
+            version: version.ok_or("missing version")?,
+            node_id_size: node_id_size.ok_or("missing node_id_size")?,
+            way_ptr_size: way_ptr_size.ok_or("missing way_ptr_size")?,
+            relation_ptr_size: relation_ptr_size.ok_or("missing relation_ptr_size")?,
+            coordinates: coordinates.ok_or("missing coordinates")?,
+            way_encoding,
+            node_store,
+            parents,
+            tiles,
+            metadata,
+            versions,
+            undo,
+        };
+        format.validate()?;
+        Ok(format)
+    }
+
+    pub(super) fn to_content(self) -> String {
+        format!(
+            "{MAGIC}\nversion={}\nnode_id_size={}\nway_ptr_size={}\nrelation_ptr_size={}\ncoordinates={}\nway_encoding={}\nnode_store={}\nparents={}\ntiles={}\nmetadata={}\nversions={}\nundo={}\n",
+            self.version,
+            self.node_id_size,
+            self.way_ptr_size,
+            self.relation_ptr_size,
+            self.coordinates,
+            self.way_encoding,
+            self.node_store,
+            self.parents,
+            self.tiles,
+            self.metadata,
+            self.versions,
+            self.undo,
+        )
+    }
+
+    /// Oldest version able to read this layout, which should be used as `version`
+    pub fn min_version(&self) -> u32 {
+        let way_version = match self.way_encoding {
+            WayEncoding::Fixed => 1,
+            WayEncoding::Delta => 2,
+        };
+        let node_version = match self.node_store {
+            NodeStore::Direct => 1,
+            NodeStore::Hash => 3,
+        };
+        let metadata_version = if self.metadata { 4 } else { 1 };
+        let versions_version = if self.versions { 5 } else { 1 };
+        let undo_version = if self.undo > 0 { 6 } else { 1 };
+        // Previous versions ignore these indexes, and wouldn't keep them up to date
+        let indexes_version = if self.parents || self.tiles { 7 } else { 1 };
+        way_version
+            .max(node_version)
+            .max(metadata_version)
+            .max(versions_version)
+            .max(undo_version)
+            .max(indexes_version)
+    }
+
+    /// Check that this version of osmbin is able to use this layout
+    pub fn validate(&self) -> Result<(), String> {
+        if self.version > FORMAT_VERSION {
+            return Err(format!(
+                "version {} is newer than supported version {FORMAT_VERSION}",
+                self.version
+            ));
+        }
+        if self.version < self.min_version() {
+            return Err(format!(
+                "layout needs version {}, not {}",
+                self.min_version(),
+                self.version
+            ));
+        }
+        for (name, size) in [
+            ("node_id_size", self.node_id_size),
+            ("way_ptr_size", self.way_ptr_size),
+            ("relation_ptr_size", self.relation_ptr_size),
+        ] {
+            if !(4..=8).contains(&size) {
+                return Err(format!("{name} should be between 4 and 8, not {size}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Format {
+    /// Encode nodes of a way as stored in `way.data`, with its header
+    ///
+    /// Returns `None` if way has too many nodes to be stored.
+    pub(super) fn encode_way(&self, nodes: &[u64]) -> Option<(u16, Vec<u8>)> {
+        let (header, mut data) = match self.way_encoding {
+            WayEncoding::Fixed => {
+                let mut data = vec![0u8; 2 + nodes.len() * self.node_id_size];
+                for (n, buffer) in nodes
+                    .iter()
+                    .zip(data[2..].chunks_exact_mut(self.node_id_size))
+                {
+                    OsmBin::int_to_bytes(*n, buffer);
+                }
+                (u16::try_from(nodes.len()).ok()?, data)
+            }
+            WayEncoding::Delta => {
+                let encoded = encoding::encode_way_nodes(nodes);
+                let size = encoded.len().div_ceil(WAY_ALLOC_SIZE) * WAY_ALLOC_SIZE;
+                let mut data = Vec::with_capacity(2 + size);
+                data.extend([0u8; 2]);
+                data.extend(encoded);
+                data.resize(2 + size, 0);
+                (u16::try_from(size).ok()?, data)
+            }
+        };
+        data[0..2].copy_from_slice(&OsmBin::int_to_bytes2(header));
+        Some((header, data))
+    }
+
+    /// Size of an entry of `way.data` with given header, including header
+    pub(super) fn way_size(&self, header: u16) -> u64 {
+        match self.way_encoding {
+            WayEncoding::Fixed => 2 + u64::from(header) * self.node_id_size as u64,
+            WayEncoding::Delta => 2 + u64::from(header),
+        }
+    }
+
+    /// Decode nodes of a way stored after its header in `way.data`
+    ///
+    /// Returns `None` if data is corrupted, or has a node without id.
+    pub(super) fn decode_way(&self, data: &[u8]) -> Option<Vec<u64>> {
+        let nodes: Vec<u64> = match self.way_encoding {
+            WayEncoding::Fixed => data
+                .chunks_exact(self.node_id_size)
+                .map(OsmBin::bytes_to_int)
+                .collect(),
+            WayEncoding::Delta => encoding::decode_way_nodes(data)?,
+        };
+        (!nodes.contains(&0)).then_some(nodes)
+    }
+}
+
+impl CoordEncoding {
+    /// Convert latitude/longitude to the 8 bytes stored in `node.crd`
+    pub(super) fn encode(self, lat: i32, lon: i32) -> [u8; 8] {
+        let (lat, lon) = match self {
+            #[allow(clippy::cast_possible_truncation)]
+            #[allow(clippy::cast_sign_loss)]
+            CoordEncoding::Offset => (
+                ((i64::from(lat) + COORD_OFFSET) as u32).to_be_bytes(),
+                ((i64::from(lon) + COORD_OFFSET) as u32).to_be_bytes(),
+            ),
+            CoordEncoding::Direct if lat == 0 && lon == 0 => {
+                (i32::MIN.to_be_bytes(), 0i32.to_be_bytes())
+            }
+            CoordEncoding::Direct => (lat.to_be_bytes(), lon.to_be_bytes()),
+        };
+        let mut d = [0u8; 8];
+        d[0..4].copy_from_slice(&lat);
+        d[4..8].copy_from_slice(&lon);
+        d
+    }
+
+    /// Convert 8 bytes from `node.crd` to latitude/longitude, or `None` for a missing node
+    pub(super) fn decode(self, d: [u8; 8]) -> Option<(i32, i32)> {
+        if d == [0u8; 8] {
+            return None;
+        }
+        let lat: [u8; 4] = d[0..4].try_into().unwrap();
+        let lon: [u8; 4] = d[4..8].try_into().unwrap();
+        match self {
+            #[allow(clippy::cast_possible_truncation)]
+            CoordEncoding::Offset => Some((
+                (i64::from(u32::from_be_bytes(lat)) - COORD_OFFSET) as i32,
+                (i64::from(u32::from_be_bytes(lon)) - COORD_OFFSET) as i32,
+            )),
+            CoordEncoding::Direct => {
+                let lat = i32::from_be_bytes(lat);
+                let lon = i32::from_be_bytes(lon);
+                if lat == i32::MIN {
+                    Some((0, lon))
+                } else {
+                    Some((lat, lon))
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for CoordEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoordEncoding::Offset => write!(f, "offset"),
+            CoordEncoding::Direct => write!(f, "direct"),
+        }
+    }
+}
+
+impl fmt::Display for WayEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WayEncoding::Fixed => write!(f, "fixed"),
+            WayEncoding::Delta => write!(f, "delta"),
+        }
+    }
+}
+
+impl FromStr for WayEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<WayEncoding, String> {
+        match s {
+            "fixed" => Ok(WayEncoding::Fixed),
+            "delta" => Ok(WayEncoding::Delta),
+            _ => Err(format!("unknown way encoding {s}")),
+        }
+    }
+}
+
+impl fmt::Display for NodeStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NodeStore::Direct => write!(f, "direct"),
+            NodeStore::Hash => write!(f, "hash"),
+        }
+    }
+}
+
+impl FromStr for NodeStore {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<NodeStore, String> {
+        match s {
+            "direct" => Ok(NodeStore::Direct),
+            "hash" => Ok(NodeStore::Hash),
+            _ => Err(format!("unknown node store {s}")),
+        }
+    }
+}
+
+impl FromStr for CoordEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CoordEncoding, String> {
+        match s {
+            "offset" => Ok(CoordEncoding::Offset),
+            "direct" => Ok(CoordEncoding::Direct),
+            _ => Err(format!("unknown coordinates encoding {s}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let format = Format::default();
+        assert_eq!(Ok(format), Format::parse(&format.to_content()));
+
+        let format = Format {
+            node_id_size: 6,
+            way_ptr_size: 6,
+            coordinates: CoordEncoding::Direct,
+            ..Default::default()
+        };
+        assert_eq!(Ok(format), Format::parse(&format.to_content()));
+        let format_indexes = Format {
+            version: 7,
+            parents: true,
+            tiles: true,
+            ..format
+        };
+        assert_eq!(
+            Ok(format_indexes),
+            Format::parse(&format_indexes.to_content())
+        );
+        assert_eq!(7, format_indexes.min_version());
+        for index in ["parents", "tiles"] {
+            let format_index = format
+                .to_content()
+                .replace(&format!("{index}=false"), &format!("{index}=true"));
+            assert!(Format::parse(&format_index).is_err());
+            assert!(Format::parse(&format_index.replace("version=1", "version=7")).is_ok());
+        }
+        // Keys of a newer version are not ignored
+        assert!(Format::parse(&(format.to_content() + "way_tiles=true\n")).is_err());
+        // Reverse indexes, spatial index, metadata, versions and undo are optional
+        assert_eq!(
+            Ok(Format::default()),
+            Format::parse(&Format::default().to_content().replace(
+                "parents=false\ntiles=false\nmetadata=false\nversions=false\nundo=0\n",
+                ""
+            ))
+        );
+
+        let format_delta = Format {
+            version: 2,
+            way_encoding: WayEncoding::Delta,
+            ..format
+        };
+        assert_eq!(Ok(format_delta), Format::parse(&format_delta.to_content()));
+        // Older versions can't read delta-encoded ways
+        assert!(
+            Format::parse(&format_delta.to_content().replace("version=2", "version=1")).is_err()
+        );
+        // Previous layout without way encoding
+        assert_eq!(
+            Ok(format),
+            Format::parse(
+                &format
+                    .to_content()
+                    .replace("way_encoding=fixed\nnode_store=direct\n", "")
+            )
+        );
+
+        let format_hash = Format {
+            version: 3,
+            node_store: NodeStore::Hash,
+            ..format
+        };
+        assert_eq!(Ok(format_hash), Format::parse(&format_hash.to_content()));
+        assert_eq!(3, format_hash.min_version());
+        assert!(
+            Format::parse(&format_hash.to_content().replace("version=3", "version=2")).is_err()
+        );
+        assert!(Format::parse(&format_hash.to_content().replace("hash", "btree")).is_err());
+
+        let format_metadata = Format {
+            version: 4,
+            metadata: true,
+            ..format
+        };
+        assert_eq!(
+            Ok(format_metadata),
+            Format::parse(&format_metadata.to_content())
+        );
+        assert_eq!(4, format_metadata.min_version());
+        let format_versions = Format {
+            version: 5,
+            versions: true,
+            ..format
+        };
+        assert_eq!(
+            Ok(format_versions),
+            Format::parse(&format_versions.to_content())
+        );
+        assert_eq!(5, format_versions.min_version());
+        let format_undo = Format {
+            version: 6,
+            undo: 10,
+            ..format
+        };
+        assert_eq!(Ok(format_undo), Format::parse(&format_undo.to_content()));
+        assert_eq!(6, format_undo.min_version());
+        assert!(Format::parse(&format_undo.to_content().replace("undo=10", "undo=-1")).is_err());
+        assert!(
+            Format::parse(
+                &format_metadata
+                    .to_content()
+                    .replace("version=4", "version=3")
+            )
+            .is_err()
+        );
+
+        assert!(Format::parse("sequenceNumber=1\n").is_err());
+        assert!(Format::parse(&format.to_content().replace("version=1", "version=999")).is_err());
+        assert!(Format::parse(&format.to_content().replace("node_id_size=6", "")).is_err());
+        assert!(
+            Format::parse(
+                &format
+                    .to_content()
+                    .replace("node_id_size=6", "node_id_size=9")
+            )
+            .is_err()
+        );
+        assert!(Format::parse(&format.to_content().replace("direct", "float")).is_err());
+    }
+
+    #[test]
+    fn missing_file() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            Format::default(),
+            Format::from_file(&tmpdir.path().join("format.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn coord() {
+        for encoding in [CoordEncoding::Offset, CoordEncoding::Direct] {
+            for (lat, lon) in [
+                (0, 0),
+                (0, 1),
+                (1, 0),
+                (-900_000_000, -1_800_000_000),
+                (900_000_000, 1_800_000_000),
+                (179_005_000, -628_495_000),
+            ] {
+                let d = encoding.encode(lat, lon);
+                assert_ne!([0u8; 8], d);
+                assert_eq!(Some((lat, lon)), encoding.decode(d));
+            }
+            assert_eq!(None, encoding.decode([0u8; 8]));
+        }
+    }
+}
@@ -0,0 +1,423 @@
+//! Write-ahead undo journal for OsmBin updates
+//!
+//! Before any byte of a database file is overwritten during an update, its previous content is
+//! appended to the journal. When the update is complete, data files are synced to disk and the
+//! journal is removed: this removal is the commit point. If the process is interrupted before,
+//! the journal is still present on the next opening of the database, and is used to restore all
+//! files to their state before the update.
+//!
+//! Journal file format:
+//! - header: magic, then initial size of each journaled file, then a full copy of small files
+//!   (like `way.free`) that are rewritten as a whole on commit.
+//! - records, in write order: `R`, filename, offset, length, and previous content of a byte range
+//!   of a file.
+//!
+//! A record is always written to the journal before the corresponding data is written to the
+//! database, so an incomplete trailing record can safely be ignored. The journal is synced to
+//! disk each time a record is written, before the caller writes data protected by this record:
+//! otherwise the kernel could write back data pages before the journal, and a power loss would
+//! leave overwritten bytes that cannot be restored. Ranges already recorded, and ranges beyond the
+//! initial size of files, don't need a record, and so don't cost a sync.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+pub const JOURNAL: &str = "journal";
+
+const MAGIC: &[u8; 8] = b"OSMBINJ\x01";
+const RECORD_RANGE: u8 = b'R';
+
+/// Journal of an update in progress on an OsmBin database
+pub struct Journal {
+    dir: PathBuf,
+    file: File,
+    sizes: Vec<(String, u64)>,
+    recorded_ranges: HashSet<(String, u64, u64)>,
+    /// Length of journal known to be synced to disk
+    synced_len: u64,
+}
+
+impl Journal {
+    /// Start a new journal
+    ///
+    /// `sizes` gives the current size of files that will be modified in place, and `snapshots`
+    /// the current content of small files that will be rewritten on commit.
+    pub fn begin(
+        dir: &Path,
+        sizes: &[(&str, u64)],
+        snapshots: &[(&str, Option<Vec<u8>>)],
+    ) -> Result<Journal, io::Error> {
+        let mut header: Vec<u8> = Vec::new();
+        header.extend(MAGIC);
+        Self::push_len(&mut header, sizes.len());
+        for (filename, size) in sizes {
+            Self::push_str(&mut header, filename);
+            header.extend(size.to_be_bytes());
+        }
+        Self::push_len(&mut header, snapshots.len());
+        for (filename, content) in snapshots {
+            Self::push_str(&mut header, filename);
+            Self::push_content(&mut header, content.as_deref());
+        }
+
+        let mut file = File::create(dir.join(JOURNAL))?;
+        file.write_all(&header)?;
+        file.sync_all()?;
+        Self::sync_dir(dir)?;
+
+        Ok(Journal {
+            dir: dir.to_path_buf(),
+            file,
+            sizes: sizes.iter().map(|(f, s)| ((*f).to_string(), *s)).collect(),
+            recorded_ranges: HashSet::new(),
+            synced_len: header.len() as u64,
+        })
+    }
+
+    /// Save the content of a byte range of `file`, before it is overwritten
+    ///
+    /// Only the part of the range that was present in the file when the journal was started is
+    /// saved, as the rest is removed by truncating the file on rollback. The record is synced to
+    /// disk when this returns, so the range can be overwritten right away.
+    pub fn record_range(
+        &mut self,
+        filename: &str,
+        file: &File,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), io::Error> {
+        let init_size = self
+            .sizes
+            .iter()
+            .find(|(f, _)| f == filename)
+            .map_or(0, |(_, s)| *s);
+        if offset >= init_size {
+            return Ok(());
+        }
+        let len = len.min(init_size - offset);
+        if !self
+            .recorded_ranges
+            .insert((filename.to_string(), offset, len))
+        {
+            return Ok(());
+        }
+        let mut data = vec![0; usize::try_from(len).unwrap()];
+        file.read_exact_at(&mut data, offset)?;
+
+        let mut record: Vec<u8> = Vec::with_capacity(data.len() + 32);
+        record.push(RECORD_RANGE);
+        Self::push_str(&mut record, filename);
+        record.extend(offset.to_be_bytes());
+        Self::push_content(&mut record, Some(&data));
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        self.synced_len += record.len() as u64;
+        Ok(())
+    }
+
+    /// Validate all modifications, by removing the journal
+    ///
+    /// All database files must have already been synced to disk.
+    pub fn commit(self) -> Result<(), io::Error> {
+        fs::remove_file(self.dir.join(JOURNAL))?;
+        Self::sync_dir(&self.dir)
+    }
+
+    /// Cancel all modifications done since the journal was started
+    pub fn rollback(self) -> Result<(), io::Error> {
+        let dir = self.dir.clone();
+        drop(self);
+        Self::recover(&dir).map(|_| ())
+    }
+
+    /// Restore database files if a journal is present in `dir`
+    ///
+    /// Returns `true` if an interrupted update was rolled back.
+    pub fn recover(dir: &Path) -> Result<bool, io::Error> {
+        let journal_path = dir.join(JOURNAL);
+        let data = match fs::read(&journal_path) {
+            Ok(d) => d,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        let mut reader = JournalReader {
+            data: &data,
+            pos: 0,
+        };
+        // A journal with an incomplete header was interrupted before any database file was
+        // modified.
+        if let Some((sizes, snapshots)) = reader.read_header() {
+            let mut ranges: Vec<(String, u64, &[u8])> = Vec::new();
+            while let Some(t) = reader.read_u8() {
+                match t {
+                    RECORD_RANGE => {
+                        let Some(r) = reader.read_range() else { break };
+                        ranges.push(r);
+                    }
+                    _ => break,
+                }
+            }
+
+            // Restore in reverse order, so that the oldest content of a range is kept
+            for (filename, offset, content) in ranges.iter().rev() {
+                let file = OpenOptions::new().write(true).open(dir.join(filename))?;
+                file.write_all_at(content, *offset)?;
+            }
+            for (filename, size) in &sizes {
+                let file = OpenOptions::new().write(true).open(dir.join(filename))?;
+                file.set_len(*size)?;
+                file.sync_all()?;
+            }
+            for (filename, content) in &snapshots {
+                Self::restore_file(dir, filename, *content)?;
+            }
+        }
+
+        fs::remove_file(&journal_path)?;
+        Self::sync_dir(dir)?;
+        Ok(true)
+    }
+
+    fn restore_file(dir: &Path, filename: &str, content: Option<&[u8]>) -> Result<(), io::Error> {
+        let path = dir.join(filename);
+        if let Some(content) = content {
+            let mut file = File::create(&path)?;
+            file.write_all(content)?;
+            file.sync_all()
+        } else {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                r => r,
+            }
+        }
+    }
+
+    fn sync_dir(dir: &Path) -> Result<(), io::Error> {
+        File::open(dir)?.sync_all()
+    }
+
+    fn push_len(buf: &mut Vec<u8>, len: usize) {
+        buf.extend(u32::try_from(len).unwrap().to_be_bytes());
+    }
+    fn push_str(buf: &mut Vec<u8>, s: &str) {
+        Self::push_len(buf, s.len());
+        buf.extend(s.as_bytes());
+    }
+    fn push_content(buf: &mut Vec<u8>, content: Option<&[u8]>) {
+        if let Some(content) = content {
+            buf.push(1);
+            Self::push_len(buf, content.len());
+            buf.extend(content);
+        } else {
+            buf.push(0);
+        }
+    }
+}
+
+type Header<'a> = (Vec<(String, u64)>, Vec<(String, Option<&'a [u8]>)>);
+
+struct JournalReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JournalReader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(len)?;
+        let bytes = self.data.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+    fn read_u8(&mut self) -> Option<u8> {
+        self.read_bytes(1).map(|b| b[0])
+    }
+    fn read_u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+    fn read_len(&mut self) -> Option<usize> {
+        let len = u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap());
+        usize::try_from(len).ok()
+    }
+    fn read_str(&mut self) -> Option<String> {
+        let len = self.read_len()?;
+        String::from_utf8(self.read_bytes(len)?.to_vec()).ok()
+    }
+    // Outer option is None when journal is truncated, inner one when file didn't exist
+    #[allow(clippy::option_option)]
+    fn read_content(&mut self) -> Option<Option<&'a [u8]>> {
+        if self.read_u8()? == 0 {
+            return Some(None);
+        }
+        let len = self.read_len()?;
+        Some(Some(self.read_bytes(len)?))
+    }
+
+    fn read_header(&mut self) -> Option<Header<'a>> {
+        if self.read_bytes(MAGIC.len())? != MAGIC {
+            return None;
+        }
+        let mut sizes = Vec::new();
+        for _ in 0..self.read_len()? {
+            sizes.push((self.read_str()?, self.read_u64()?));
+        }
+        let mut snapshots = Vec::new();
+        for _ in 0..self.read_len()? {
+            snapshots.push((self.read_str()?, self.read_content()?));
+        }
+        Some((sizes, snapshots))
+    }
+    fn read_range(&mut self) -> Option<(String, u64, &'a [u8])> {
+        let filename = self.read_str()?;
+        let offset = self.read_u64()?;
+        let content = self.read_content()??;
+        Some((filename, offset, content))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    #[test]
+    fn rollback() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path();
+        fs::write(tmpdir.join("data"), b"0123456789").unwrap();
+        fs::write(tmpdir.join("free"), b"free list").unwrap();
+
+        let mut journal = Journal::begin(
+            tmpdir,
+            &[("data", 10)],
+            &[("free", Some(b"free list".to_vec()))],
+        )
+        .unwrap();
+
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(tmpdir.join("data"))
+            .unwrap();
+        journal.record_range("data", &data, 2, 3).unwrap();
+        data.write_all_at(b"abc", 2).unwrap();
+        journal.record_range("data", &data, 3, 2).unwrap();
+        data.write_all_at(b"de", 3).unwrap();
+        journal.record_range("data", &data, 8, 4).unwrap();
+        data.write_all_at(b"wxyz", 8).unwrap();
+        journal.record_range("data", &data, 12, 4).unwrap();
+        data.write_all_at(b"ABCD", 12).unwrap();
+
+        fs::write(tmpdir.join("free"), b"new free list").unwrap();
+
+        assert_eq!(
+            b"01ade567wxyzABCD".to_vec(),
+            fs::read(tmpdir.join("data")).unwrap()
+        );
+
+        journal.rollback().unwrap();
+
+        assert_eq!(
+            b"0123456789".to_vec(),
+            fs::read(tmpdir.join("data")).unwrap()
+        );
+        assert_eq!(
+            b"free list".to_vec(),
+            fs::read(tmpdir.join("free")).unwrap()
+        );
+        assert_eq!(false, tmpdir.join(JOURNAL).exists());
+        assert_eq!(false, Journal::recover(tmpdir).unwrap());
+    }
+
+    #[test]
+    fn record_synced_before_write() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path();
+        fs::write(tmpdir.join("data"), b"0123456789").unwrap();
+
+        let mut journal = Journal::begin(tmpdir, &[("data", 10)], &[]).unwrap();
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(tmpdir.join("data"))
+            .unwrap();
+        // Every record, not only the first one, is on disk before its range is overwritten
+        for (offset, new) in [(0, b"ab"), (4, b"cd"), (8, b"ef")] {
+            journal.record_range("data", &data, offset, 2).unwrap();
+            let journal_len = fs::metadata(tmpdir.join(JOURNAL)).unwrap().len();
+            assert_eq!(journal_len, journal.synced_len);
+            data.write_all_at(new, offset).unwrap();
+        }
+
+        // Ranges already recorded, or beyond initial size, don't need a sync
+        let synced_len = journal.synced_len;
+        journal.record_range("data", &data, 4, 2).unwrap();
+        journal.record_range("data", &data, 10, 2).unwrap();
+        assert_eq!(synced_len, journal.synced_len);
+
+        journal.rollback().unwrap();
+        assert_eq!(
+            b"0123456789".to_vec(),
+            fs::read(tmpdir.join("data")).unwrap()
+        );
+    }
+
+    #[test]
+    fn commit() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path();
+        fs::write(tmpdir.join("data"), b"0123456789").unwrap();
+
+        let mut journal = Journal::begin(tmpdir, &[("data", 10)], &[]).unwrap();
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(tmpdir.join("data"))
+            .unwrap();
+        journal.record_range("data", &data, 0, 2).unwrap();
+        data.write_all_at(b"ab", 0).unwrap();
+        assert_eq!(true, tmpdir.join(JOURNAL).exists());
+        journal.commit().unwrap();
+
+        assert_eq!(false, tmpdir.join(JOURNAL).exists());
+        assert_eq!(
+            b"ab23456789".to_vec(),
+            fs::read(tmpdir.join("data")).unwrap()
+        );
+    }
+
+    #[test]
+    fn truncated_journal() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path();
+        fs::write(tmpdir.join("data"), b"0123456789").unwrap();
+
+        let mut journal = Journal::begin(tmpdir, &[("data", 10)], &[]).unwrap();
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(tmpdir.join("data"))
+            .unwrap();
+        journal.record_range("data", &data, 0, 2).unwrap();
+        data.write_all_at(b"ab", 0).unwrap();
+        journal.record_range("data", &data, 4, 2).unwrap();
+        drop(journal);
+
+        // Simulate an interruption while writing the last record
+        let journal_file = OpenOptions::new()
+            .write(true)
+            .open(tmpdir.join(JOURNAL))
+            .unwrap();
+        let len = journal_file.metadata().unwrap().len();
+        journal_file.set_len(len - 1).unwrap();
+
+        assert_eq!(true, Journal::recover(tmpdir).unwrap());
+        assert_eq!(
+            b"0123456789".to_vec(),
+            fs::read(tmpdir.join("data")).unwrap()
+        );
+    }
+}
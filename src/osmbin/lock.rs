@@ -0,0 +1,122 @@
+//! Locking of an OsmBin database between processes
+
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use super::OsmBinError;
+
+/// Lock file, inside database directory
+pub const LOCK: &str = "lock";
+
+/// Lock on a database, released when dropped
+///
+/// Readers take a shared lock and writers an exclusive lock with `flock()` on [`LOCK`] file, so
+/// that a database is never read while a diff is applied. As `flock()` locks are attached to
+/// an open file, a process which opens a writer while holding a reader on the same database
+/// waits for itself.
+pub struct DbLock {
+    _file: File,
+}
+
+impl DbLock {
+    /// Take a lock on database in `dir`, waiting for other processes unless `wait` is false
+    pub fn new(dir: &str, exclusive: bool, wait: bool) -> Result<DbLock, OsmBinError> {
+        let path = Path::new(dir).join(LOCK);
+        let file = File::options().append(true).create(true).open(&path)?;
+        let mut operation = if exclusive {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_SH
+        };
+        if !wait {
+            operation |= libc::LOCK_NB;
+        }
+        loop {
+            // SAFETY: file descriptor is valid while `file` is alive
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(DbLock { _file: file });
+            }
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                ErrorKind::Interrupted => (),
+                ErrorKind::WouldBlock => {
+                    return Err(OsmBinError::Locked {
+                        dir: dir.to_string(),
+                        holder: Self::holder(&file).unwrap_or_else(|| "another process".into()),
+                    });
+                }
+                _ => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Describe processes holding a lock on `file`, as found in `/proc/locks`
+    fn holder(file: &File) -> Option<String> {
+        let metadata = file.metadata().ok()?;
+        let dev = metadata.dev();
+        // Device is given as major:minor:inode, with major/minor in hexadecimal
+        let file_id = format!(
+            "{:02x}:{:02x}:{}",
+            libc::major(dev),
+            libc::minor(dev),
+            metadata.ino()
+        );
+        let locks = fs::read_to_string("/proc/locks").ok()?;
+        let holders: Vec<String> = locks
+            .lines()
+            .filter_map(|l| {
+                // Waiting processes are given with "->" after lock number
+                let fields: Vec<&str> = l.split_whitespace().collect();
+                match fields[..] {
+                    [_, "FLOCK", _, _, pid, id, ..] if id == file_id => Some(pid),
+                    _ => None,
+                }
+            })
+            .map(
+                |pid| match fs::read_to_string(format!("/proc/{pid}/comm")) {
+                    Ok(name) => format!("process {pid} ({})", name.trim()),
+                    Err(_) => format!("process {pid}"),
+                },
+            )
+            .collect();
+        (!holders.is_empty()).then(|| holders.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_exclusive() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+
+        let reader1 = DbLock::new(tmpdir, false, false).unwrap();
+        let reader2 = DbLock::new(tmpdir, false, false).unwrap();
+        match DbLock::new(tmpdir, true, false) {
+            Err(OsmBinError::Locked { dir, holder }) => {
+                assert_eq!(tmpdir, dir);
+                let pid = std::process::id();
+                if Path::new("/proc/locks").exists() {
+                    assert!(holder.contains(&format!("process {pid}")), "{holder}");
+                }
+            }
+            Err(e) => panic!("{e}"),
+            Ok(_) => panic!("Exclusive lock taken while database is read"),
+        }
+        drop(reader1);
+        drop(reader2);
+
+        let writer = DbLock::new(tmpdir, true, false).unwrap();
+        assert!(matches!(
+            DbLock::new(tmpdir, false, false),
+            Err(OsmBinError::Locked { .. })
+        ));
+        drop(writer);
+        DbLock::new(tmpdir, false, false).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+//! Tags and metadata of nodes and ways, stored by an OsmBin database in full mode
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use super::OsmBin;
+use super::encoding;
+use super::journal::Journal;
+use crate::osm::{Node, Relation, Way};
+
+/// Size of a pointer in index file to data file
+const PTR_SIZE: usize = 6;
+/// Size of the header of an entry in data file: used size
+const HEADER_SIZE: usize = 4;
+
+/// Names of files used by a metadata store
+pub struct MetadataFiles {
+    pub idx: &'static str,
+    pub data: &'static str,
+}
+
+/// Tags and metadata of nodes
+pub const NODE_METADATA: MetadataFiles = MetadataFiles {
+    idx: "node.meta.idx",
+    data: "node.meta.data",
+};
+/// Tags and metadata of ways
+pub const WAY_METADATA: MetadataFiles = MetadataFiles {
+    idx: "way.meta.idx",
+    data: "way.meta.data",
+};
+
+/// Store of tags, version, timestamp, user and changeset of elements
+///
+/// The index file is directly indexed by element id and stores a pointer to the data file, which
+/// stores entries as used size (4-bytes) followed by the element encoded as a relation without
+/// members by [`encoding::encode_relation`]. An element without tags nor metadata has no entry.
+///
+/// Data file is only appended to: an updated element gets a new entry at the end of file, and
+/// the space of its previous entry is not reused. As tags of an element usually change with each
+/// version, updating in place would seldom be possible, and appending keeps writes sequential.
+///
+/// Files are accessed without buffering, as entries are read and written one at a time.
+pub struct Metadata {
+    files: &'static MetadataFiles,
+    idx: File,
+    data: File,
+    data_size: u64,
+}
+
+impl Metadata {
+    pub fn open(
+        dir: &str,
+        files: &'static MetadataFiles,
+        write: bool,
+    ) -> Result<Metadata, io::Error> {
+        let mut file_options = OpenOptions::new();
+        file_options.read(true).write(write);
+        let idx = file_options.open(Path::new(dir).join(files.idx))?;
+        let data = file_options.open(Path::new(dir).join(files.data))?;
+        let data_size = data.metadata()?.len();
+        Ok(Metadata {
+            files,
+            idx,
+            data,
+            data_size,
+        })
+    }
+
+    /// Create empty files of a metadata store, if they don't exist
+    pub fn init(dir: &str, files: &MetadataFiles) -> Result<(), io::Error> {
+        for filename in [files.idx, files.data] {
+            match File::create_new(Path::new(dir).join(filename)) {
+                // Pointer 0 is used for a missing entry
+                Ok(file) if filename == files.data => file.write_all_at(b"--", 0)?,
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Current size of index and data files, to start a journal
+    pub fn sizes(&self) -> Result<[(&'static str, u64); 2], io::Error> {
+        Ok([
+            (self.files.idx, self.idx.metadata()?.len()),
+            (self.files.data, self.data_size),
+        ])
+    }
+
+    pub fn sync_all(&self) -> Result<(), io::Error> {
+        self.idx.sync_all()?;
+        self.data.sync_all()
+    }
+
+    /// Read pointer to data file of element `id`, or 0 if element has no entry
+    fn read_ptr(&self, id: u64) -> Result<u64, io::Error> {
+        let mut buffer = [0u8; PTR_SIZE];
+        match self.idx.read_exact_at(&mut buffer, id * PTR_SIZE as u64) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+            r => r?,
+        }
+        Ok(OsmBin::bytes_to_int(&buffer))
+    }
+
+    /// Read entry of element `id`, decoded as a relation without members
+    fn read(&self, id: u64) -> Result<Option<Relation>, io::Error> {
+        let ptr = self.read_ptr(id)?;
+        if ptr == 0 {
+            return Ok(None);
+        }
+        let corrupted = || {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Corrupted data in {} for id={id}", self.files.data),
+            )
+        };
+        let mut header = [0u8; HEADER_SIZE];
+        self.data.read_exact_at(&mut header, ptr)?;
+        let mut data = vec![0u8; OsmBin::bytes4_to_int(header) as usize];
+        self.data
+            .read_exact_at(&mut data, ptr + HEADER_SIZE as u64)
+            .map_err(|_| corrupted())?;
+        encoding::decode_relation(id, &data)
+            .map(Some)
+            .ok_or_else(corrupted)
+    }
+
+    /// Append entry of element `id` at the end of data file, and return its pointer
+    fn append(&mut self, id: u64, element: &Relation) -> Result<u64, io::Error> {
+        let data = encoding::encode_relation(element);
+        let size = u32::try_from(data.len()).map_err(|_| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Element {id} has too many tags for {}", self.files.data),
+            )
+        })?;
+        let mut entry = Vec::with_capacity(HEADER_SIZE + data.len());
+        entry.extend(OsmBin::int_to_bytes4(size));
+        entry.extend(data);
+        let ptr = self.data_size;
+        self.data.write_all_at(&entry, ptr)?;
+        self.data_size += entry.len() as u64;
+        Ok(ptr)
+    }
+
+    /// Write entry of element `id`, or remove it if `element` is `None`
+    fn write(
+        &mut self,
+        id: u64,
+        element: Option<&Relation>,
+        journal: Option<&mut Journal>,
+    ) -> Result<(), io::Error> {
+        let ptr = if let Some(element) = element {
+            self.append(id, element)?
+        } else if self.read_ptr(id)? == 0 {
+            return Ok(());
+        } else {
+            0
+        };
+        let mut buffer = [0u8; PTR_SIZE];
+        OsmBin::int_to_bytes(ptr, &mut buffer);
+        let addr = OsmBin::idx_addr("element", id, PTR_SIZE)?;
+        if let Some(journal) = journal {
+            journal.record_range(self.files.idx, &self.idx, addr, PTR_SIZE as u64)?;
+        }
+        self.idx.write_all_at(&buffer, addr)
+    }
+
+    /// Fill tags and metadata of `node`
+    pub fn read_node(&self, node: &mut Node) -> Result<(), io::Error> {
+        if let Some(element) = self.read(node.id)? {
+            fill_node(node, element);
+        }
+        Ok(())
+    }
+
+    /// Fill tags and metadata of `way`
+    pub fn read_way(&self, way: &mut Way) -> Result<(), io::Error> {
+        if let Some(element) = self.read(way.id)? {
+            fill_way(way, element);
+        }
+        Ok(())
+    }
+
+    /// Store tags and metadata of `node`
+    pub fn write_node(
+        &mut self,
+        node: &Node,
+        journal: Option<&mut Journal>,
+    ) -> Result<(), io::Error> {
+        self.write(node.id, from_node(node).as_ref(), journal)
+    }
+
+    /// Store tags and metadata of `way`
+    pub fn write_way(&mut self, way: &Way, journal: Option<&mut Journal>) -> Result<(), io::Error> {
+        self.write(way.id, from_way(way).as_ref(), journal)
+    }
+
+    /// Remove tags and metadata of element `id`
+    pub fn remove(&mut self, id: u64, journal: Option<&mut Journal>) -> Result<(), io::Error> {
+        self.write(id, None, journal)
+    }
+
+    /// Get entry of element `id` from files in memory, decoded as a relation without members
+    ///
+    /// Returns `None` if element has no entry, or if data is corrupted.
+    pub fn read_from(idx: &[u8], data: &[u8], id: u64) -> Option<Relation> {
+        let addr = usize::try_from(id).ok()?.checked_mul(PTR_SIZE)?;
+        let ptr = OsmBin::bytes_to_int(idx.get(addr..addr + PTR_SIZE)?);
+        if ptr == 0 {
+            return None;
+        }
+        let ptr = usize::try_from(ptr).ok()?;
+        let header = data.get(ptr..ptr + HEADER_SIZE)?;
+        let size = OsmBin::bytes4_to_int(header.try_into().unwrap()) as usize;
+        let start = ptr + HEADER_SIZE;
+        encoding::decode_relation(id, data.get(start..start.checked_add(size)?)?)
+    }
+}
+
+/// Tags and metadata of a node as a relation without members, or `None` if it has none
+pub fn from_node(node: &Node) -> Option<Relation> {
+    let element = Relation {
+        id: node.id,
+        tags: node.tags.clone(),
+        version: node.version,
+        timestamp: node.timestamp.clone(),
+        uid: node.uid,
+        user: node.user.clone(),
+        changeset: node.changeset,
+        ..Default::default()
+    };
+    (element
+        != Relation {
+            id: node.id,
+            ..Default::default()
+        })
+    .then_some(element)
+}
+
+/// Tags and metadata of a way as a relation without members, or `None` if it has none
+pub fn from_way(way: &Way) -> Option<Relation> {
+    let element = Relation {
+        id: way.id,
+        tags: way.tags.clone(),
+        version: way.version,
+        timestamp: way.timestamp.clone(),
+        uid: way.uid,
+        user: way.user.clone(),
+        changeset: way.changeset,
+        ..Default::default()
+    };
+    (element
+        != Relation {
+            id: way.id,
+            ..Default::default()
+        })
+    .then_some(element)
+}
+
+/// Set tags and metadata of `node` from an entry
+pub fn fill_node(node: &mut Node, element: Relation) {
+    node.tags = element.tags;
+    node.version = element.version;
+    node.timestamp = element.timestamp;
+    node.uid = element.uid;
+    node.user = element.user;
+    node.changeset = element.changeset;
+}
+
+/// Set tags and metadata of `way` from an entry
+pub fn fill_way(way: &mut Way, element: Relation) {
+    way.tags = element.tags;
+    way.version = element.version;
+    way.timestamp = element.timestamp;
+    way.uid = element.uid;
+    way.user = element.user;
+    way.changeset = element.changeset;
+}
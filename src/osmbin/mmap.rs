@@ -0,0 +1,649 @@
+//! Read-only access to an OsmBin database through memory mapped files
+
+use geo::{Intersects, LineString, MultiPolygon, coord, point};
+use memmap2::Mmap;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::encoding;
+use super::lock::DbLock;
+use super::metadata::{self, Metadata, MetadataFiles};
+use super::nodes::{NODE_HASH, NodeHash};
+use super::versions::{NODE_VERSION, Versions, WAY_VERSION};
+use super::{
+    FORMAT, Format, NODE_CRD, NodeStore, OsmBin, OsmBinError, RELATION_DATA, RELATION_IDX,
+    WAY_DATA, WAY_IDX,
+};
+use crate::osm::{Node, Relation, Way};
+use crate::osm::{OsmCopyTo, OsmReader, OsmWriter};
+
+/// Read-only OsmBin database, with files mapped in memory
+///
+/// Lookups don't need any system call, and only take `&self`, so that a single database can be
+/// shared between threads with an [`Arc`].
+///
+/// Files are mapped with their size when the database is opened: elements added afterwards are
+/// not visible. Database is locked like an [`OsmBin`] reader while it is mapped, as an update,
+/// a compaction or a rollback of an interrupted update rewrites or truncates files.
+pub struct OsmBinMmap {
+    format: Format,
+    node_crd: Mmap,
+    node_hash: Option<Mmap>,
+    /// Index and data files of tags and metadata, in full mode
+    node_metadata: Option<(Mmap, Mmap)>,
+    way_metadata: Option<(Mmap, Mmap)>,
+    /// Versions of nodes and ways, if they are stored
+    node_version: Option<Mmap>,
+    way_version: Option<Mmap>,
+    way_idx: Mmap,
+    way_data: Mmap,
+    relation_idx: Mmap,
+    relation_data: Mmap,
+    /// Ranges of index files containing data, as they are usually sparse
+    node_crd_ranges: Vec<Range<usize>>,
+    way_idx_ranges: Vec<Range<usize>>,
+    relation_idx_ranges: Vec<Range<usize>>,
+    /// Released after files are unmapped, or `None` if database is locked by an [`OsmBin`]
+    _lock: Option<DbLock>,
+}
+
+impl OsmBinMmap {
+    /// Map an OsmBin database in memory
+    ///
+    /// Waits while another process has opened database in read-write mode.
+    pub fn new(dir: &str) -> Result<OsmBinMmap, OsmBinError> {
+        let lock = DbLock::new(dir, false, true)?;
+        Ok(Self::open(dir, Some(lock))?)
+    }
+
+    /// Map a database already locked by an [`OsmBin`] of this process, which must not modify
+    /// it while it is mapped
+    pub(super) fn new_unlocked(dir: &str) -> Result<OsmBinMmap, io::Error> {
+        Self::open(dir, None)
+    }
+
+    fn open(dir: &str, lock: Option<DbLock>) -> Result<OsmBinMmap, io::Error> {
+        let (node_crd, node_crd_ranges) = Self::map(dir, NODE_CRD)?;
+        let (way_idx, way_idx_ranges) = Self::map(dir, WAY_IDX)?;
+        let (relation_idx, relation_idx_ranges) = Self::map(dir, RELATION_IDX)?;
+        let format = Format::from_file(&Path::new(dir).join(FORMAT))?;
+        let node_hash = (format.node_store == NodeStore::Hash)
+            .then(|| Self::map(dir, NODE_HASH).map(|(mmap, _)| mmap))
+            .transpose()?;
+        let map_metadata = |files: &MetadataFiles| -> Result<(Mmap, Mmap), io::Error> {
+            Ok((Self::map(dir, files.idx)?.0, Self::map(dir, files.data)?.0))
+        };
+        let node_metadata = format
+            .metadata
+            .then(|| map_metadata(&metadata::NODE_METADATA))
+            .transpose()?;
+        let way_metadata = format
+            .metadata
+            .then(|| map_metadata(&metadata::WAY_METADATA))
+            .transpose()?;
+        let map_versions = |filename| {
+            format
+                .versions
+                .then(|| Self::map(dir, filename).map(|(mmap, _)| mmap))
+                .transpose()
+        };
+        Ok(OsmBinMmap {
+            format,
+            node_crd,
+            node_hash,
+            node_metadata,
+            way_metadata,
+            node_version: map_versions(NODE_VERSION)?,
+            way_version: map_versions(WAY_VERSION)?,
+            way_idx,
+            way_data: Self::map(dir, WAY_DATA)?.0,
+            relation_idx,
+            relation_data: Self::map(dir, RELATION_DATA)?.0,
+            node_crd_ranges,
+            way_idx_ranges,
+            relation_idx_ranges,
+            _lock: lock,
+        })
+    }
+
+    fn map(dir: &str, filename: &str) -> Result<(Mmap, Vec<Range<usize>>), io::Error> {
+        let file = File::open(Path::new(dir).join(filename))?;
+        // SAFETY: files are only modified by an OsmBin writer, which can't run while the
+        // database is locked.
+        let mmap = unsafe { Mmap::map(&file) }?;
+        let ranges = Self::data_ranges(&file, mmap.len());
+        Ok((mmap, ranges))
+    }
+
+    /// Ranges of a file containing data, skipping holes of a sparse file
+    ///
+    /// The whole file is returned if holes can't be found on this filesystem.
+    #[allow(clippy::single_range_in_vec_init)]
+    pub(super) fn data_ranges(file: &File, len: usize) -> Vec<Range<usize>> {
+        let fd = file.as_raw_fd();
+        let mut ranges = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            let Ok(offset) = libc::off_t::try_from(pos) else {
+                return vec![0..len];
+            };
+            // SAFETY: lseek only changes the offset of the file descriptor
+            let start = unsafe { libc::lseek(fd, offset, libc::SEEK_DATA) };
+            if start < 0 {
+                if io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) {
+                    // No more data after pos
+                    break;
+                }
+                return vec![0..len];
+            }
+            // SAFETY: lseek only changes the offset of the file descriptor
+            let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+            let (Ok(start), Ok(end)) = (usize::try_from(start), usize::try_from(end)) else {
+                return vec![0..len];
+            };
+            let end = end.min(len);
+            ranges.push(start..end);
+            pos = end;
+        }
+        ranges
+    }
+
+    /// Get `len` bytes at `addr`, or `None` if they are past the end of the file
+    fn get(data: &Mmap, addr: u64, len: usize) -> Option<&[u8]> {
+        let addr = usize::try_from(addr).ok()?;
+        data.get(addr..addr.checked_add(len)?)
+    }
+
+    pub fn read_node(&self, id: u64) -> Option<Node> {
+        let crd = match &self.node_hash {
+            Some(node_hash) => NodeHash::read_from(node_hash, id)?,
+            None => Self::get(&self.node_crd, id * 8, 8)?.try_into().unwrap(),
+        };
+        let (decimicro_lat, decimicro_lon) = self.format.coordinates.decode(crd)?;
+        let mut node = Node {
+            id,
+            decimicro_lat,
+            decimicro_lon,
+            tags: None,
+            ..Default::default()
+        };
+        if let Some((idx, data)) = &self.node_metadata
+            && let Some(element) = Metadata::read_from(idx, data, id)
+        {
+            metadata::fill_node(&mut node, element);
+        }
+        if let Some(node_version) = &self.node_version
+            && node.version.is_none()
+        {
+            node.version = Versions::read_from(node_version, id);
+        }
+        Some(node)
+    }
+
+    /// Read way `id`, or an error if database is corrupted
+    pub fn try_read_way(&self, id: u64) -> Result<Option<Way>, OsmBinError> {
+        let way_ptr_size = self.format.way_ptr_size;
+        let Some(ptr) = Self::get(&self.way_idx, id * (way_ptr_size as u64), way_ptr_size) else {
+            return Ok(None);
+        };
+        if ptr.iter().all(|b| *b == 0) {
+            return Ok(None);
+        }
+        let way_data_addr = OsmBin::bytes_to_int(ptr);
+        let corrupt = |reason: &str| {
+            OsmBinError::corrupt(WAY_DATA, way_data_addr, format!("way {id} {reason}"))
+        };
+
+        let header =
+            Self::get(&self.way_data, way_data_addr, 2).ok_or_else(|| corrupt("is truncated"))?;
+        let header = OsmBin::bytes2_to_int(header.try_into().unwrap());
+        if header == 0 {
+            return Err(corrupt("has no nodes"));
+        }
+        let data = Self::get(
+            &self.way_data,
+            way_data_addr + 2,
+            usize::try_from(self.format.way_size(header) - 2).unwrap(),
+        )
+        .ok_or_else(|| corrupt("is truncated"))?;
+
+        let nodes = self
+            .format
+            .decode_way(data)
+            .ok_or_else(|| corrupt("has invalid nodes"))?;
+        let mut way = Way {
+            id,
+            nodes,
+            tags: None,
+            ..Default::default()
+        };
+        if let Some((idx, data)) = &self.way_metadata
+            && let Some(element) = Metadata::read_from(idx, data, id)
+        {
+            metadata::fill_way(&mut way, element);
+        }
+        if let Some(way_version) = &self.way_version
+            && way.version.is_none()
+        {
+            way.version = Versions::read_from(way_version, id);
+        }
+        Ok(Some(way))
+    }
+
+    /// Read relation `id`, or an error if database is corrupted
+    pub fn try_read_relation(&self, id: u64) -> Result<Option<Relation>, OsmBinError> {
+        let relation_ptr_size = self.format.relation_ptr_size;
+        let Some(ptr) = Self::get(
+            &self.relation_idx,
+            id * (relation_ptr_size as u64),
+            relation_ptr_size,
+        ) else {
+            return Ok(None);
+        };
+        if ptr.iter().all(|b| *b == 0) {
+            return Ok(None);
+        }
+        let relation_data_addr = OsmBin::bytes_to_int(ptr);
+        let corrupt = |reason: &str| {
+            OsmBinError::corrupt(
+                RELATION_DATA,
+                relation_data_addr,
+                format!("relation {id} {reason}"),
+            )
+        };
+
+        let size = Self::get(&self.relation_data, relation_data_addr + 4, 4)
+            .ok_or_else(|| corrupt("is truncated"))?;
+        let size = OsmBin::bytes4_to_int(size.try_into().unwrap());
+        if size == 0 {
+            return Err(corrupt("is empty"));
+        }
+        let data = Self::get(&self.relation_data, relation_data_addr + 8, size as usize)
+            .ok_or_else(|| corrupt("is truncated"))?;
+        let relation =
+            encoding::decode_relation(id, data).ok_or_else(|| corrupt("can't be decoded"))?;
+        Ok(Some(relation))
+    }
+}
+
+/// Elements selected inside a polygon, to be exported or extracted
+#[derive(Default)]
+pub(super) struct Selection {
+    pub nodes: HashSet<u64>,
+    pub ways: HashSet<u64>,
+    pub relations: HashSet<u64>,
+}
+
+impl OsmBinMmap {
+    /// Ids of elements present in an index file
+    ///
+    /// Index is scanned by chunks, to quickly skip over large ranges without any element.
+    fn ids<'a>(
+        index: &'a Mmap,
+        ranges: &'a [Range<usize>],
+        ptr_size: usize,
+    ) -> impl Iterator<Item = u64> + 'a {
+        const CHUNK_SIZE: usize = 4096;
+        ranges.iter().flat_map(move |range| {
+            // Align range on elements
+            let start = range.start / ptr_size;
+            let end = range.end.div_ceil(ptr_size).min(index.len() / ptr_size);
+            index[start * ptr_size..end * ptr_size]
+                .chunks(ptr_size * CHUNK_SIZE)
+                .enumerate()
+                .filter(|(_, chunk)| chunk.iter().any(|b| *b != 0))
+                .flat_map(move |(i, chunk)| {
+                    chunk
+                        .chunks_exact(ptr_size)
+                        .enumerate()
+                        .filter(|(_, ptr)| ptr.iter().any(|b| *b != 0))
+                        .map(move |(j, _)| (start + i * CHUNK_SIZE + j) as u64)
+                })
+        })
+    }
+
+    /// Ids of all nodes
+    pub(super) fn node_ids(&self) -> Box<dyn Iterator<Item = u64> + '_> {
+        match &self.node_hash {
+            Some(node_hash) => Box::new(NodeHash::ids_from(node_hash).into_iter()),
+            None => Box::new(Self::ids(&self.node_crd, &self.node_crd_ranges, 8)),
+        }
+    }
+
+    /// Ids of all ways
+    pub(super) fn way_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        Self::ids(
+            &self.way_idx,
+            &self.way_idx_ranges,
+            self.format.way_ptr_size,
+        )
+    }
+
+    /// Ids of all relations
+    pub(super) fn relation_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        Self::ids(
+            &self.relation_idx,
+            &self.relation_idx_ranges,
+            self.format.relation_ptr_size,
+        )
+    }
+
+    /// Pointer of way `id` into `way.data`, or 0 if way is missing
+    pub(super) fn way_ptr(&self, id: u64) -> u64 {
+        let way_ptr_size = self.format.way_ptr_size;
+        Self::get(&self.way_idx, id * (way_ptr_size as u64), way_ptr_size)
+            .map_or(0, OsmBin::bytes_to_int)
+    }
+
+    /// Pointer of relation `id` into `relation.data`, or 0 if relation is missing
+    pub(super) fn relation_ptr(&self, id: u64) -> u64 {
+        let relation_ptr_size = self.format.relation_ptr_size;
+        Self::get(
+            &self.relation_idx,
+            id * (relation_ptr_size as u64),
+            relation_ptr_size,
+        )
+        .map_or(0, OsmBin::bytes_to_int)
+    }
+
+    pub(super) fn way_data(&self) -> &[u8] {
+        &self.way_data
+    }
+
+    pub(super) fn relation_data(&self) -> &[u8] {
+        &self.relation_data
+    }
+
+    /// Check if node `id` exists and is inside polygon
+    pub(super) fn node_in_poly(&self, id: u64, poly: &MultiPolygon<i64>) -> bool {
+        self.read_node(id).is_some_and(|node| {
+            point!(x: i64::from(node.decimicro_lon), y: i64::from(node.decimicro_lat))
+                .intersects(poly)
+        })
+    }
+
+    /// Select nodes inside polygon, ways with at least one of these nodes and all their nodes,
+    /// and relations with a selected member
+    fn select_in_poly(&self, poly: &MultiPolygon<i64>) -> Result<Selection, OsmBinError> {
+        let nodes = self
+            .node_ids()
+            .filter(|id| self.node_in_poly(*id, poly))
+            .collect();
+        let mut selection = self.select_from_nodes(nodes)?;
+        let mut way_nodes: HashSet<u64> = HashSet::new();
+        for id in &selection.ways {
+            way_nodes.extend(self.try_read_way(*id)?.map(|w| w.nodes).unwrap_or_default());
+        }
+        selection.nodes.extend(way_nodes);
+        Ok(selection)
+    }
+
+    /// Select given nodes, ways with at least one of these nodes, and relations with a selected
+    /// member
+    pub(super) fn select_from_nodes(&self, nodes: HashSet<u64>) -> Result<Selection, OsmBinError> {
+        let mut selection = Selection {
+            nodes,
+            ..Default::default()
+        };
+        for id in self.way_ids() {
+            let Some(way) = self.try_read_way(id)? else {
+                continue;
+            };
+            if way.nodes.iter().any(|n| selection.nodes.contains(n)) {
+                selection.ways.insert(id);
+            }
+        }
+        self.select_relations(&mut selection)?;
+        Ok(selection)
+    }
+
+    /// Check if a way crosses polygon, even between its nodes
+    pub(super) fn way_in_poly(&self, way: &Way, poly: &MultiPolygon<i64>) -> bool {
+        let line: LineString<i64> = way
+            .nodes
+            .iter()
+            .filter_map(|n| self.read_node(*n))
+            .map(|n| coord! {x: i64::from(n.decimicro_lon), y: i64::from(n.decimicro_lat)})
+            .collect();
+        line.intersects(poly)
+    }
+
+    /// Add to selection relations with a selected member, and their parent relations
+    pub(super) fn select_relations(&self, selection: &mut Selection) -> Result<(), OsmBinError> {
+        let mut parents: HashMap<u64, Vec<u64>> = HashMap::new();
+        let mut selected: Vec<u64> = Vec::new();
+        for id in Self::ids(
+            &self.relation_idx,
+            &self.relation_idx_ranges,
+            self.format.relation_ptr_size,
+        ) {
+            let Some(relation) = self.try_read_relation(id)? else {
+                continue;
+            };
+            let mut in_poly = false;
+            for m in &relation.members {
+                match m.type_.as_str() {
+                    "node" => in_poly |= selection.nodes.contains(&m.ref_),
+                    "way" => in_poly |= selection.ways.contains(&m.ref_),
+                    "relation" => parents.entry(m.ref_).or_default().push(id),
+                    t => {
+                        return Err(OsmBinError::corrupt(
+                            RELATION_DATA,
+                            self.relation_ptr(id),
+                            format!("relation {id} has a member of unknown type {t}"),
+                        ));
+                    }
+                }
+            }
+            if in_poly {
+                selected.push(id);
+            }
+        }
+        // Parents of a selected relation are also selected
+        while let Some(id) = selected.pop() {
+            if selection.relations.insert(id) {
+                selected.extend(parents.get(&id).into_iter().flatten());
+            }
+        }
+        Ok(())
+    }
+
+    /// Write all nodes/ways/relations to `target`, optionally limited to a polygon
+    pub fn copy_to_poly<T: OsmWriter>(
+        &self,
+        target: &mut T,
+        poly: Option<&MultiPolygon<i64>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let selection = poly.map(|poly| self.select_in_poly(poly)).transpose()?;
+
+        target.write_start(false)?;
+        for id in self.node_ids() {
+            if selection.as_ref().is_none_or(|s| s.nodes.contains(&id)) {
+                target.write_node(&mut self.read_node(id).unwrap())?;
+            }
+        }
+        for id in Self::ids(
+            &self.way_idx,
+            &self.way_idx_ranges,
+            self.format.way_ptr_size,
+        ) {
+            if selection.as_ref().is_none_or(|s| s.ways.contains(&id))
+                && let Some(mut way) = self.try_read_way(id)?
+            {
+                target.write_way(&mut way)?;
+            }
+        }
+        for id in Self::ids(
+            &self.relation_idx,
+            &self.relation_idx_ranges,
+            self.format.relation_ptr_size,
+        ) {
+            if selection.as_ref().is_none_or(|s| s.relations.contains(&id))
+                && let Some(mut relation) = self.try_read_relation(id)?
+            {
+                target.write_relation(&mut relation)?;
+            }
+        }
+        target.write_end(false)?;
+        Ok(())
+    }
+}
+
+impl<T> OsmCopyTo<T> for OsmBinMmap
+where
+    T: OsmWriter,
+{
+    fn copy_to(&mut self, target: &mut T) -> Result<(), Box<dyn Error>> {
+        self.copy_to_poly(target, None)
+    }
+}
+
+impl OsmReader for OsmBinMmap {
+    fn read_node(&mut self, id: u64) -> Option<Node> {
+        OsmBinMmap::read_node(self, id)
+    }
+    fn read_way(&mut self, id: u64) -> Option<Way> {
+        self.try_read_way(id).unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn read_relation(&mut self, id: u64) -> Option<Relation> {
+        self.try_read_relation(id).unwrap_or_else(|e| panic!("{e}"))
+    }
+}
+
+impl OsmReader for Arc<OsmBinMmap> {
+    fn read_node(&mut self, id: u64) -> Option<Node> {
+        OsmBinMmap::read_node(self.as_ref(), id)
+    }
+    fn read_way(&mut self, id: u64) -> Option<Way> {
+        self.try_read_way(id).unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn read_relation(&mut self, id: u64) -> Option<Relation> {
+        self.try_read_relation(id).unwrap_or_else(|e| panic!("{e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::prelude::*;
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::FileExt;
+    use tempfile;
+
+    const PBF_SAINT_BARTHELEMY: &str = "tests/resources/saint_barthelemy.osm.pbf";
+
+    #[test]
+    fn read() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(osmbin);
+
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        let osmbin_mmap = OsmBinMmap::new(&tmpdir).unwrap();
+
+        let node_ids = [266053077, 2619283351, 1, 266053076, 10_000_000_000];
+        let way_ids = [24473155, 255316725, 1, 24473154, 10_000_000_000];
+        let relation_ids = [47796, 529891, 1, 47795, 10_000_000_000];
+        for id in node_ids {
+            assert_eq!(
+                osmbin
+                    .read_node(id)
+                    .map(|n| (n.decimicro_lat, n.decimicro_lon)),
+                osmbin_mmap
+                    .read_node(id)
+                    .map(|n| (n.decimicro_lat, n.decimicro_lon))
+            );
+        }
+        for id in way_ids {
+            assert_eq!(
+                osmbin.read_way(id).map(|w| w.nodes),
+                osmbin_mmap.try_read_way(id).unwrap().map(|w| w.nodes)
+            );
+        }
+        for id in relation_ids {
+            assert_eq!(
+                osmbin.read_relation(id),
+                osmbin_mmap.try_read_relation(id).unwrap()
+            );
+        }
+
+        let osmbin_mmap = Arc::new(osmbin_mmap);
+        let num_members: usize = relation_ids
+            .par_iter()
+            .map(|id| {
+                let mut reader = osmbin_mmap.clone();
+                reader
+                    .read_relation_full(*id, &[])
+                    .map_or(0, |r| r.members.len())
+            })
+            .sum();
+        let exp_num_members: usize = relation_ids
+            .iter()
+            .map(|id| {
+                osmbin
+                    .read_relation_full(*id, &[])
+                    .map_or(0, |r| r.members.len())
+            })
+            .sum();
+        assert_eq!(exp_num_members, num_members);
+    }
+
+    #[test]
+    fn lock_and_corrupt() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(osmbin);
+
+        // Database can't be modified while it is mapped
+        let mmap = OsmBinMmap::new(&tmpdir).unwrap();
+        let way_data_size = mmap.way_data().len() as u64;
+        let relation_data_size = mmap.relation_data().len() as u64;
+        assert!(matches!(
+            OsmBin::try_new_writer(&tmpdir),
+            Err(OsmBinError::Locked { .. })
+        ));
+        drop(mmap);
+
+        let write_ptr = |filename: &str, ptr: u64, offset: u64| {
+            let mut data = [0u8; 5];
+            OsmBin::int_to_bytes(ptr, &mut data);
+            let file = OpenOptions::new()
+                .write(true)
+                .open(tmpdir_path.path().join(filename))
+                .unwrap();
+            file.write_all_at(&data, offset).unwrap();
+        };
+        write_ptr(WAY_IDX, way_data_size, 24473155 * 5);
+        write_ptr(RELATION_IDX, relation_data_size + 64, 47796 * 5);
+
+        let mmap = OsmBinMmap::new(&tmpdir).unwrap();
+        assert!(matches!(
+            mmap.try_read_way(24473155),
+            Err(OsmBinError::CorruptRecord { offset, .. }) if offset == way_data_size
+        ));
+        assert!(matches!(
+            mmap.try_read_relation(47796),
+            Err(OsmBinError::CorruptRecord { offset, .. }) if offset == relation_data_size + 64
+        ));
+        assert!(mmap.try_read_way(255316725).unwrap().is_some());
+
+        // Copy stops at first corrupted element
+        let target_path = tempfile::tempdir().unwrap();
+        let target = target_path.path().to_str().unwrap();
+        OsmBin::init(&target).unwrap();
+        let mut target = OsmBin::new_writer(&target).unwrap();
+        assert!(mmap.copy_to_poly(&mut target, None).is_err());
+    }
+}
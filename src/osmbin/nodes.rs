@@ -0,0 +1,386 @@
+//! Hash table of node coordinates, for databases with few nodes spread over a large id range
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use super::OsmBin;
+use super::journal::Journal;
+
+/// File storing the hash table
+pub const NODE_HASH: &str = "node.hash";
+/// Temporary file where the table is rewritten with another number of slots
+const NODE_HASH_TMP: &str = "node.hash.tmp";
+
+/// Size of a slot: node id (8-bytes) and coordinates (8-bytes)
+const SLOT_SIZE: usize = 16;
+/// Number of slots of an empty table
+const INIT_CAPACITY: u64 = 1024;
+/// Number of slots read at once while probing
+const PROBE_SLOTS: usize = 8;
+
+/// Hash table from node id to coordinates, stored in [`NODE_HASH`]
+///
+/// File starts with a header of one slot, storing the number of used slots, followed by a
+/// power-of-two number of slots. A slot stores the node id, with 0 for a free slot, and the
+/// coordinates as in `node.crd`. Collisions are resolved by linear probing. A deleted node keeps
+/// its slot with empty coordinates, as a tombstone that probing goes through. The first
+/// tombstone found while probing is reused by a new node, so that slots of deleted nodes don't
+/// accumulate.
+///
+/// Table is rewritten without tombstones in a temporary file replacing the current one, so it is
+/// never resized while a journal is active: before an update when more than half of slots are
+/// used, when database is compacted, or during an import when 3/4 of slots are used. An update
+/// fails if it fills 7/8 of slots. Files are accessed without buffering, as nodes are read and
+/// written one at a time.
+pub struct NodeHash {
+    path: PathBuf,
+    file: File,
+    capacity: u64,
+    used: u64,
+}
+
+impl NodeHash {
+    pub fn open(dir: &str, write: bool) -> Result<NodeHash, io::Error> {
+        let path = Path::new(dir).join(NODE_HASH);
+        let file = OpenOptions::new().read(true).write(write).open(&path)?;
+        let capacity = Self::capacity(file.metadata()?.len())?;
+        let mut header = [0u8; 8];
+        file.read_exact_at(&mut header, 0)?;
+        Ok(NodeHash {
+            path,
+            file,
+            capacity,
+            used: u64::from_be_bytes(header),
+        })
+    }
+
+    /// Create an empty table, if it doesn't exist
+    pub fn init(dir: &str) -> Result<(), io::Error> {
+        match File::create_new(Path::new(dir).join(NODE_HASH)) {
+            Ok(file) => file.set_len(SLOT_SIZE as u64 * (INIT_CAPACITY + 1)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Number of slots of a table stored in a file of size `len`
+    fn capacity(len: u64) -> Result<u64, io::Error> {
+        let capacity = (len / SLOT_SIZE as u64).saturating_sub(1);
+        if !capacity.is_power_of_two() || !len.is_multiple_of(SLOT_SIZE as u64) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{NODE_HASH} has an invalid size {len}"),
+            ));
+        }
+        Ok(capacity)
+    }
+
+    /// Current size of file, to start a journal
+    pub fn sizes(&self) -> Result<[(&'static str, u64); 1], io::Error> {
+        Ok([(NODE_HASH, self.file.metadata()?.len())])
+    }
+
+    pub fn sync_all(&self) -> Result<(), io::Error> {
+        self.file.sync_all()
+    }
+
+    /// First slot to probe for node `id`
+    fn slot(id: u64, capacity: u64) -> u64 {
+        // Fibonacci hashing, so that consecutive ids are spread over the table
+        id.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - capacity.trailing_zeros())
+    }
+
+    /// Find slot of node `id`, or the slot where it should be added
+    ///
+    /// `read_at` fills a buffer with the content of table at a position. Returns the slot and its
+    /// coordinates, or `None` if node is not stored. In this case, the slot is the first
+    /// tombstone found while probing, or the free slot ending probing, with `true` if it is free.
+    fn find(
+        id: u64,
+        capacity: u64,
+        mut read_at: impl FnMut(&mut [u8], u64) -> Result<(), io::Error>,
+    ) -> Result<(u64, Option<[u8; 8]>, bool), io::Error> {
+        let start = Self::slot(id, capacity);
+        let mut buffer = [0u8; PROBE_SLOTS * SLOT_SIZE];
+        let mut slot = start;
+        let mut tombstone = None;
+        loop {
+            // Read up to the end of table, probing continues at its start
+            let num_slots = (PROBE_SLOTS as u64).min(capacity - slot);
+            let chunk = &mut buffer[..usize::try_from(num_slots).unwrap() * SLOT_SIZE];
+            read_at(chunk, (slot + 1) * SLOT_SIZE as u64)?;
+            for entry in chunk.chunks_exact(SLOT_SIZE) {
+                let entry_id = OsmBin::bytes_to_int(&entry[0..8]);
+                if entry_id == id {
+                    return Ok((slot, Some(entry[8..16].try_into().unwrap()), false));
+                }
+                if entry_id == 0 {
+                    return Ok(match tombstone {
+                        Some(tombstone) => (tombstone, None, false),
+                        None => (slot, None, true),
+                    });
+                }
+                if tombstone.is_none() && entry[8..16] == [0u8; 8] {
+                    tombstone = Some(slot);
+                }
+                slot = (slot + 1) % capacity;
+                if slot == start {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{NODE_HASH} has no free slot"),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Get coordinates of node `id` from a table in memory, or `None` if node is not stored
+    pub fn read_from(data: &[u8], id: u64) -> Option<[u8; 8]> {
+        let capacity = Self::capacity(data.len() as u64).ok()?;
+        let read_at = |buffer: &mut [u8], pos: u64| {
+            let pos = usize::try_from(pos).unwrap();
+            buffer.copy_from_slice(&data[pos..pos + buffer.len()]);
+            Ok(())
+        };
+        Self::find(id, capacity, read_at).ok()?.1
+    }
+
+    /// Sorted ids of nodes stored in a table in memory
+    pub fn ids_from(data: &[u8]) -> Vec<u64> {
+        let mut ids: Vec<u64> = data
+            .chunks_exact(SLOT_SIZE)
+            .skip(1)
+            .filter(|entry| entry[8..16].iter().any(|b| *b != 0))
+            .map(|entry| OsmBin::bytes_to_int(&entry[0..8]))
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Get coordinates of node `id`, as stored in `node.crd`
+    ///
+    /// Empty coordinates are returned for a missing node.
+    pub fn read(&self, id: u64) -> Result<[u8; 8], io::Error> {
+        let (_, crd, _) = Self::find(id, self.capacity, |buffer, pos| {
+            self.file.read_exact_at(buffer, pos)
+        })?;
+        Ok(crd.unwrap_or_default())
+    }
+
+    /// Set coordinates of node `id`, with empty coordinates to delete it
+    ///
+    /// Table is only resized if there is no `journal`.
+    pub fn write(
+        &mut self,
+        id: u64,
+        crd: [u8; 8],
+        mut journal: Option<&mut Journal>,
+    ) -> Result<(), io::Error> {
+        let (mut slot, found, mut free) = Self::find(id, self.capacity, |buffer, pos| {
+            self.file.read_exact_at(buffer, pos)
+        })?;
+        if found.is_none() && crd == [0u8; 8] {
+            return Ok(());
+        }
+        if free && journal.is_none() && (self.used + 1) * 4 > self.capacity * 3 {
+            self.rehash(2)?;
+            (slot, _, free) = Self::find(id, self.capacity, |buffer, pos| {
+                self.file.read_exact_at(buffer, pos)
+            })?;
+        }
+        if free {
+            if (self.used + 1) * 8 > self.capacity * 7 {
+                return Err(io::Error::new(
+                    ErrorKind::StorageFull,
+                    format!("{NODE_HASH} is full, database must be compacted"),
+                ));
+            }
+            self.used += 1;
+            if let Some(journal) = journal.as_deref_mut() {
+                journal.record_range(NODE_HASH, &self.file, 0, 8)?;
+            }
+            self.file.write_all_at(&self.used.to_be_bytes(), 0)?;
+        }
+
+        let mut entry = [0u8; SLOT_SIZE];
+        entry[0..8].copy_from_slice(&id.to_be_bytes());
+        entry[8..16].copy_from_slice(&crd);
+        let pos = (slot + 1) * SLOT_SIZE as u64;
+        if let Some(journal) = journal {
+            journal.record_range(NODE_HASH, &self.file, pos, SLOT_SIZE as u64)?;
+        }
+        self.file.write_all_at(&entry, pos)
+    }
+
+    /// Rewrite table before an update if more than half of slots are used
+    ///
+    /// Must be called before the journal is started.
+    pub fn reserve(&mut self) -> Result<(), io::Error> {
+        if self.used * 2 > self.capacity {
+            self.rehash(2)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite table, keeping 3/4 of slots free
+    pub fn compact(&mut self) -> Result<(), io::Error> {
+        self.rehash(4)
+    }
+
+    /// Rewrite table without tombstones, with the smallest number of slots keeping at least
+    /// `slots_per_node` slots for each node
+    ///
+    /// New table is written to a temporary file, which atomically replaces the current one.
+    fn rehash(&mut self, slots_per_node: u64) -> Result<(), io::Error> {
+        let len = SLOT_SIZE as u64 * (self.capacity + 1);
+        let mut data = vec![0u8; usize::try_from(len).unwrap()];
+        self.file.read_exact_at(&mut data, 0)?;
+        let num_nodes = data
+            .chunks_exact(SLOT_SIZE)
+            .skip(1)
+            .filter(|entry| entry[8..16] != [0u8; 8])
+            .count() as u64;
+        let capacity = (num_nodes * slots_per_node)
+            .next_power_of_two()
+            .max(INIT_CAPACITY);
+
+        let mut new_data = vec![0u8; SLOT_SIZE * usize::try_from(capacity + 1).unwrap()];
+        let mut used: u64 = 0;
+        for entry in data.chunks_exact(SLOT_SIZE).skip(1) {
+            if entry[8..16] == [0u8; 8] {
+                continue;
+            }
+            let id = OsmBin::bytes_to_int(&entry[0..8]);
+            let read_at = |buffer: &mut [u8], pos: u64| {
+                let pos = usize::try_from(pos).unwrap();
+                buffer.copy_from_slice(&new_data[pos..pos + buffer.len()]);
+                Ok(())
+            };
+            let (slot, _, _) = Self::find(id, capacity, read_at)?;
+            let pos = usize::try_from(slot + 1).unwrap() * SLOT_SIZE;
+            new_data[pos..pos + SLOT_SIZE].copy_from_slice(entry);
+            used += 1;
+        }
+        new_data[0..8].copy_from_slice(&used.to_be_bytes());
+
+        let tmp_path = self.path.with_file_name(NODE_HASH_TMP);
+        let file = File::create(&tmp_path)?;
+        file.write_all_at(&new_data, 0)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        File::open(self.path.parent().unwrap())?.sync_all()?;
+        self.file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.capacity = capacity;
+        self.used = used;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_write() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        NodeHash::init(&tmpdir).unwrap();
+        let mut nodes = NodeHash::open(&tmpdir, true).unwrap();
+
+        let crd = |id: u64| (id * 3 + 1).to_be_bytes();
+        assert_eq!([0u8; 8], nodes.read(12).unwrap());
+        // Enough nodes to grow table several times, with ids far apart
+        let ids: Vec<u64> = (1..5000).map(|i| i * 1_000_003).collect();
+        for id in &ids {
+            nodes.write(*id, crd(*id), None).unwrap();
+        }
+        assert_eq!(8192, nodes.capacity);
+        assert_eq!(ids.len() as u64, nodes.used);
+        for id in &ids {
+            assert_eq!(crd(*id), nodes.read(*id).unwrap());
+            assert_eq!([0u8; 8], nodes.read(*id + 1).unwrap());
+        }
+
+        // Deleted node keeps its slot
+        nodes.write(ids[10], [0u8; 8], None).unwrap();
+        nodes.write(12, [0u8; 8], None).unwrap();
+        assert_eq!([0u8; 8], nodes.read(ids[10]).unwrap());
+        assert_eq!(ids.len() as u64, nodes.used);
+        nodes.write(ids[10], crd(1), None).unwrap();
+        assert_eq!(crd(1), nodes.read(ids[10]).unwrap());
+        assert_eq!(ids.len() as u64, nodes.used);
+
+        // Same content is read from memory
+        drop(nodes);
+        let data = std::fs::read(Path::new(tmpdir).join(NODE_HASH)).unwrap();
+        assert_eq!(ids, NodeHash::ids_from(&data));
+        assert_eq!(Some(crd(ids[20])), NodeHash::read_from(&data, ids[20]));
+        assert_eq!(None, NodeHash::read_from(&data, 12));
+        let nodes = NodeHash::open(&tmpdir, false).unwrap();
+        assert_eq!(ids.len() as u64, nodes.used);
+        assert_eq!(crd(ids[30]), nodes.read(ids[30]).unwrap());
+    }
+
+    #[test]
+    fn grow_with_deletes() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        NodeHash::init(&tmpdir).unwrap();
+        let mut nodes = NodeHash::open(&tmpdir, true).unwrap();
+
+        // Slots of deleted nodes are reused by other nodes
+        let crd = |id: u64| (id * 3 + 1).to_be_bytes();
+        for round in 0..20 {
+            let ids: Vec<u64> = (1..500).map(|i| i * 1_000_003 + round).collect();
+            for id in &ids {
+                nodes.write(*id, crd(*id), None).unwrap();
+            }
+            for id in &ids {
+                assert_eq!(crd(*id), nodes.read(*id).unwrap());
+                nodes.write(*id, [0u8; 8], None).unwrap();
+            }
+        }
+        assert_eq!(INIT_CAPACITY, nodes.capacity);
+        assert!(nodes.used <= INIT_CAPACITY * 3 / 4);
+
+        // Table is not resized while journaling, but before next update
+        nodes.write(12, crd(12), None).unwrap();
+        nodes.compact().unwrap();
+        assert_eq!(1, nodes.used);
+        let mut journal = Journal::begin(tmpdir_path.path(), &nodes.sizes().unwrap(), &[]).unwrap();
+        let ids: Vec<u64> = (1..).map(|i| i * 7_000_001).take(600).collect();
+        for id in &ids {
+            nodes.write(*id, crd(*id), Some(&mut journal)).unwrap();
+        }
+        assert_eq!(INIT_CAPACITY, nodes.capacity);
+        journal.commit().unwrap();
+        nodes.reserve().unwrap();
+        assert_eq!(INIT_CAPACITY * 2, nodes.capacity);
+        assert_eq!(ids.len() as u64 + 1, nodes.used);
+
+        // Full table fails an update instead of growing
+        let mut journal = Journal::begin(tmpdir_path.path(), &nodes.sizes().unwrap(), &[]).unwrap();
+        let res: Result<(), io::Error> = (1..2000)
+            .map(|i| nodes.write(i * 5_000_011, crd(i), Some(&mut journal)))
+            .collect();
+        assert_eq!(ErrorKind::StorageFull, res.unwrap_err().kind());
+        journal.rollback().unwrap();
+        let mut nodes = NodeHash::open(&tmpdir, true).unwrap();
+        assert_eq!(ids.len() as u64 + 1, nodes.used);
+
+        // Compaction drops tombstones
+        for id in &ids {
+            nodes.write(*id, [0u8; 8], None).unwrap();
+        }
+        nodes.compact().unwrap();
+        assert_eq!(INIT_CAPACITY, nodes.capacity);
+        assert_eq!(1, nodes.used);
+        assert_eq!(crd(12), nodes.read(12).unwrap());
+        assert_eq!([0u8; 8], nodes.read(ids[0]).unwrap());
+        drop(nodes);
+        let data = std::fs::read(Path::new(tmpdir).join(NODE_HASH)).unwrap();
+        assert_eq!(vec![12], NodeHash::ids_from(&data));
+    }
+}
@@ -0,0 +1,312 @@
+//! Reverse indexes of an OsmBin database, from an element to the elements using it
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use super::OsmBin;
+use super::encoding;
+use super::journal::Journal;
+
+/// Size of the header of a list in data file: allocated size and used size
+const HEADER_SIZE: u64 = 8;
+/// Granularity of allocations in data file
+const ALLOC_SIZE: u64 = 16;
+
+/// Names of files used by a reverse index
+pub struct ParentsFiles {
+    pub idx: &'static str,
+    pub data: &'static str,
+    pub free: &'static str,
+}
+
+/// Ways using a node
+pub const NODE_PARENTS: ParentsFiles = ParentsFiles {
+    idx: "node.parents.idx",
+    data: "node.parents.data",
+    free: "node.parents.free",
+};
+/// Relations using a way
+pub const WAY_PARENTS: ParentsFiles = ParentsFiles {
+    idx: "way.parents.idx",
+    data: "way.parents.data",
+    free: "way.parents.free",
+};
+
+/// Reverse index, storing the sorted list of parents of each element
+///
+/// Files use the same layout as `relation.idx` and `relation.data`: the index file is directly
+/// indexed by element id and stores a pointer to the data file, which stores lists of ids as
+/// allocated size (4-bytes), used size (4-bytes) and the list encoded by
+/// [`encoding::encode_ids`]. A list is updated in place when it fits in its allocated size.
+///
+/// Files are accessed without buffering, as lists are read and written one at a time.
+pub struct Parents {
+    files: &'static ParentsFiles,
+    ptr_size: usize,
+    idx: File,
+    data: File,
+    data_size: u64,
+    free_data: BTreeMap<u64, Vec<u64>>,
+}
+
+impl Parents {
+    pub fn open(
+        dir: &str,
+        files: &'static ParentsFiles,
+        ptr_size: usize,
+        write: bool,
+    ) -> Result<Parents, io::Error> {
+        let mut file_options = OpenOptions::new();
+        file_options.read(true).write(write);
+        let idx = file_options.open(Path::new(dir).join(files.idx))?;
+        let data = file_options.open(Path::new(dir).join(files.data))?;
+        let data_size = data.metadata()?.len();
+        let mut free_data: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        if write {
+            for (pos, size) in OsmBin::read_free_list(dir, files.free)? {
+                free_data.entry(size).or_default().push(pos);
+            }
+        }
+        Ok(Parents {
+            files,
+            ptr_size,
+            idx,
+            data,
+            data_size,
+            free_data,
+        })
+    }
+
+    /// Create empty files of a reverse index, if they don't exist
+    pub fn init(dir: &str, files: &ParentsFiles) -> Result<(), io::Error> {
+        for filename in [files.idx, files.data, files.free] {
+            match File::create_new(Path::new(dir).join(filename)) {
+                // Pointer 0 is used for a missing list
+                Ok(file) if filename == files.data => file.write_all_at(b"--", 0)?,
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn files(&self) -> &'static ParentsFiles {
+        self.files
+    }
+
+    /// Current size of index and data files, to start a journal
+    pub fn sizes(&self) -> Result<[(&'static str, u64); 2], io::Error> {
+        Ok([
+            (self.files.idx, self.idx.metadata()?.len()),
+            (self.files.data, self.data.metadata()?.len()),
+        ])
+    }
+
+    pub fn sync_all(&self) -> Result<(), io::Error> {
+        self.idx.sync_all()?;
+        self.data.sync_all()
+    }
+
+    pub fn free_content(&self) -> Vec<u8> {
+        OsmBin::free_list_content(&self.free_data)
+    }
+
+    /// Read pointer to data file of element `id`, or 0 if element has no parents
+    fn read_ptr(&self, id: u64) -> Result<u64, io::Error> {
+        let mut buffer = [0u8; 8];
+        let buffer = &mut buffer[..self.ptr_size];
+        match self.idx.read_exact_at(buffer, id * self.ptr_size as u64) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+            r => r?,
+        }
+        Ok(OsmBin::bytes_to_int(buffer))
+    }
+
+    /// Read allocated size and list stored at `ptr`
+    fn read_at(&self, id: u64, ptr: u64) -> Result<(u64, Vec<u64>), io::Error> {
+        let mut header = [0u8; 8];
+        self.data.read_exact_at(&mut header, ptr)?;
+        let alloc_size = u64::from(OsmBin::bytes4_to_int(header[0..4].try_into().unwrap()));
+        let size = OsmBin::bytes4_to_int(header[4..8].try_into().unwrap());
+        let mut data = vec![0u8; size as usize];
+        self.data.read_exact_at(&mut data, ptr + HEADER_SIZE)?;
+        let ids = encoding::decode_ids(&data).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Corrupted data in {} for id={id}", self.files.data),
+            )
+        })?;
+        Ok((alloc_size, ids))
+    }
+
+    /// Get sorted list of parents of element `id`
+    pub fn read(&self, id: u64) -> Result<Vec<u64>, io::Error> {
+        match self.read_ptr(id)? {
+            0 => Ok(Vec::new()),
+            ptr => Ok(self.read_at(id, ptr)?.1),
+        }
+    }
+
+    /// Add `parent` to the parents of element `id`
+    pub fn add(
+        &mut self,
+        id: u64,
+        parent: u64,
+        journal: Option<&mut Journal>,
+    ) -> Result<(), io::Error> {
+        let ptr = self.read_ptr(id)?;
+        let (alloc_size, mut ids) = match ptr {
+            0 => (0, Vec::new()),
+            ptr => self.read_at(id, ptr)?,
+        };
+        match ids.binary_search(&parent) {
+            Ok(_) => Ok(()),
+            Err(pos) => {
+                ids.insert(pos, parent);
+                self.write(id, ptr, alloc_size, &ids, journal)
+            }
+        }
+    }
+
+    /// Remove `parent` from the parents of element `id`
+    pub fn remove(
+        &mut self,
+        id: u64,
+        parent: u64,
+        journal: Option<&mut Journal>,
+    ) -> Result<(), io::Error> {
+        let ptr = self.read_ptr(id)?;
+        if ptr == 0 {
+            return Ok(());
+        }
+        let (alloc_size, mut ids) = self.read_at(id, ptr)?;
+        match ids.binary_search(&parent) {
+            Ok(pos) => {
+                ids.remove(pos);
+                self.write(id, ptr, alloc_size, &ids, journal)
+            }
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Write list of parents of element `id`, currently stored at `ptr` with `alloc_size`
+    fn write(
+        &mut self,
+        id: u64,
+        ptr: u64,
+        alloc_size: u64,
+        ids: &[u64],
+        mut journal: Option<&mut Journal>,
+    ) -> Result<(), io::Error> {
+        let data = encoding::encode_ids(ids);
+        let needed_size = (HEADER_SIZE + data.len() as u64).div_ceil(ALLOC_SIZE) * ALLOC_SIZE;
+
+        if ptr != 0 && (ids.is_empty() || needed_size > alloc_size) {
+            // Keep allocated size, so that space can be reused
+            if let Some(journal) = journal.as_deref_mut() {
+                journal.record_range(self.files.data, &self.data, ptr + 4, 4)?;
+            }
+            self.data.write_all_at(&[0u8; 4], ptr + 4)?;
+            self.free_data.entry(alloc_size).or_default().push(ptr);
+        }
+
+        let new_ptr = if ids.is_empty() {
+            0
+        } else if ptr != 0 && needed_size <= alloc_size {
+            self.write_data(ptr, alloc_size, &data, journal.as_deref_mut())?;
+            ptr
+        } else {
+            // Reuse the smallest free space large enough for list
+            let free = self
+                .free_data
+                .range_mut(needed_size..)
+                .next()
+                .map(|(size, v)| (*size, v.pop().unwrap()));
+            let (alloc_size, new_ptr) = if let Some((size, addr)) = free {
+                if self.free_data[&size].is_empty() {
+                    self.free_data.remove(&size);
+                }
+                (size, addr)
+            } else {
+                self.data_size += needed_size;
+                (needed_size, self.data_size - needed_size)
+            };
+            self.write_data(new_ptr, alloc_size, &data, journal.as_deref_mut())?;
+            new_ptr
+        };
+
+        if new_ptr != ptr {
+            let mut buffer = [0u8; 8];
+            let buffer = &mut buffer[..self.ptr_size];
+            OsmBin::int_to_bytes(new_ptr, buffer);
+            let idx_addr = id * self.ptr_size as u64;
+            if let Some(journal) = journal {
+                journal.record_range(self.files.idx, &self.idx, idx_addr, self.ptr_size as u64)?;
+            }
+            self.idx.write_all_at(buffer, idx_addr)?;
+        }
+        Ok(())
+    }
+
+    fn write_data(
+        &self,
+        ptr: u64,
+        alloc_size: u64,
+        data: &[u8],
+        journal: Option<&mut Journal>,
+    ) -> Result<(), io::Error> {
+        if let Some(journal) = journal {
+            journal.record_range(self.files.data, &self.data, ptr, alloc_size)?;
+        }
+        let mut buffer: Vec<u8> = Vec::with_capacity(usize::try_from(alloc_size).unwrap());
+        buffer.extend(OsmBin::int_to_bytes4(u32::try_from(alloc_size).unwrap()));
+        buffer.extend(OsmBin::int_to_bytes4(u32::try_from(data.len()).unwrap()));
+        buffer.extend(data);
+        buffer.resize(usize::try_from(alloc_size).unwrap(), 0);
+        self.data.write_all_at(&buffer, ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_remove() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        Parents::init(&tmpdir, &NODE_PARENTS).unwrap();
+        let mut parents = Parents::open(&tmpdir, &NODE_PARENTS, 5, true).unwrap();
+
+        assert_eq!(Vec::<u64>::new(), parents.read(12).unwrap());
+        parents.add(12, 1000, None).unwrap();
+        parents.add(12, 10, None).unwrap();
+        parents.add(12, 1000, None).unwrap();
+        parents.add(13, 10, None).unwrap();
+        assert_eq!(vec![10, 1000], parents.read(12).unwrap());
+        assert_eq!(vec![10], parents.read(13).unwrap());
+
+        // List is moved when it doesn't fit anymore in its allocated size
+        for parent in 2000..2010 {
+            parents.add(12, parent, None).unwrap();
+        }
+        assert_eq!(12, parents.read(12).unwrap().len());
+        assert_eq!(1, parents.free_data.len());
+        // Freed space is reused by another list
+        parents.add(14, 1, None).unwrap();
+        assert_eq!(0, parents.free_data.len());
+
+        parents.remove(12, 10, None).unwrap();
+        parents.remove(12, 11, None).unwrap();
+        assert_eq!(11, parents.read(12).unwrap().len());
+        parents.remove(13, 10, None).unwrap();
+        assert_eq!(Vec::<u64>::new(), parents.read(13).unwrap());
+        assert_eq!(0, parents.read_ptr(13).unwrap());
+        assert_eq!(1, parents.free_data.len());
+        assert_eq!(vec![1], parents.read(14).unwrap());
+    }
+}
@@ -0,0 +1,113 @@
+//! OsmBin reader that can be shared between threads
+
+use super::OsmBinError;
+use super::mmap::OsmBinMmap;
+use crate::osm::OsmReader;
+use crate::osm::{Node, Relation, Way};
+use crate::osmcache::OsmCacheShared;
+
+/// Read-only OsmBin database with a cache, that can be shared between threads
+///
+/// All methods only take `&self`, and [`OsmReader`] is implemented on `&OsmBinShared`, so that
+/// the same database and cache can be used by reference from several threads, like when
+/// generating diffs for a tree of polygons. Database is locked like an [`OsmBin`] reader.
+///
+/// [`OsmBin`]: super::OsmBin
+pub struct OsmBinShared {
+    osmbin: OsmBinMmap,
+    cache: OsmCacheShared,
+}
+
+impl OsmBinShared {
+    pub fn new(dir: &str) -> Result<OsmBinShared, OsmBinError> {
+        Ok(OsmBinShared {
+            osmbin: OsmBinMmap::new(dir)?,
+            cache: OsmCacheShared::default(),
+        })
+    }
+
+    pub fn read_node(&self, id: u64) -> Option<Node> {
+        self.cache.read_node(id, || self.osmbin.read_node(id))
+    }
+    pub fn read_way(&self, id: u64) -> Option<Way> {
+        self.cache.read_way(id, || {
+            self.osmbin
+                .try_read_way(id)
+                .unwrap_or_else(|e| panic!("{e}"))
+        })
+    }
+    pub fn read_relation(&self, id: u64) -> Option<Relation> {
+        self.cache.read_relation(id, || {
+            self.osmbin
+                .try_read_relation(id)
+                .unwrap_or_else(|e| panic!("{e}"))
+        })
+    }
+}
+
+impl OsmReader for &OsmBinShared {
+    fn read_node(&mut self, id: u64) -> Option<Node> {
+        OsmBinShared::read_node(self, id)
+    }
+    fn read_way(&mut self, id: u64) -> Option<Way> {
+        OsmBinShared::read_way(self, id)
+    }
+    fn read_relation(&mut self, id: u64) -> Option<Relation> {
+        OsmBinShared::read_relation(self, id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osm::OsmWriter;
+    use crate::osmbin::OsmBin;
+    use rayon::prelude::*;
+    use tempfile;
+
+    const PBF_SAINT_BARTHELEMY: &str = "tests/resources/saint_barthelemy.osm.pbf";
+
+    #[test]
+    fn read_parallel() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(osmbin);
+
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        let osmbin_shared = OsmBinShared::new(&tmpdir).unwrap();
+
+        let relation_ids = [47796, 529891, 1, 47795, 10_000_000_000];
+        let exp_num_members: Vec<usize> = relation_ids
+            .iter()
+            .map(|id| {
+                osmbin
+                    .read_relation_full(*id, &[])
+                    .map_or(0, |r| r.members.len())
+            })
+            .collect();
+
+        // Read each relation from several threads, so that some reads are served by cache
+        let num_members: Vec<usize> = relation_ids
+            .par_iter()
+            .flat_map(|id| [*id; 4])
+            .map(|id| {
+                let mut reader = &osmbin_shared;
+                reader
+                    .read_relation_full(id, &[])
+                    .map_or(0, |r| r.members.len())
+            })
+            .collect();
+        let exp_num_members: Vec<usize> =
+            exp_num_members.into_iter().flat_map(|n| [n; 4]).collect();
+        assert_eq!(exp_num_members, num_members);
+
+        assert_eq!(
+            osmbin.read_way(255316725).map(|w| w.nodes),
+            osmbin_shared.read_way(255316725).map(|w| w.nodes)
+        );
+        assert_eq!(None, osmbin_shared.read_node(1));
+    }
+}
@@ -0,0 +1,312 @@
+//! Point-in-time copy of an OsmBin database
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use super::journal::JOURNAL;
+use super::lock::LOCK;
+use super::mmap::OsmBinMmap;
+use super::undo::UNDO_DIR;
+use super::{COMPACT, MIGRATE, OpenMode, OsmBin, ReplicationState, STATE};
+
+/// Size of blocks copied when a file can't be cloned
+const COPY_BUFFER_SIZE: usize = 1 << 20;
+
+/// Result of [`OsmBin::snapshot`]
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    /// Replication state of database when it was copied
+    pub state: Option<ReplicationState>,
+    /// Files sharing their blocks with database, on filesystems supporting reflinks
+    pub num_cloned: u64,
+    /// Files which are never modified once written, hard-linked to database
+    pub num_linked: u64,
+    /// Files fully copied
+    pub num_copied: u64,
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.state {
+            Some(state) => write!(f, "Snapshot at state {}", state.sequence_number)?,
+            None => write!(f, "Snapshot without state")?,
+        }
+        write!(
+            f,
+            ": {} files cloned, {} hard-linked, {} copied",
+            self.num_cloned, self.num_linked, self.num_copied
+        )
+    }
+}
+
+impl OsmBin {
+    /// Copy all database files to directory `dest`, which must be empty or not exist
+    ///
+    /// Database must be opened in read-only mode, so that its lock prevents any diff from being
+    /// applied while files are copied, and the copy is consistent with the replication state it
+    /// contains. Files are cloned with reflinks when the filesystem supports it, so that the copy
+    /// is almost instantaneous and only uses space for blocks modified afterwards. Otherwise,
+    /// they are copied keeping holes of sparse files. Inverse changesets in `undo/` are never
+    /// modified once written, so they are hard-linked. The `lock` file is not copied. Once
+    /// copied, sizes of files and replication state of the snapshot are checked against database.
+    ///
+    /// The lock is held by `self`, so writers wait until it is dropped: this is only a fraction
+    /// of a second with reflinks, but a full copy blocks updates for as long as it takes to read
+    /// the whole database. `self` should be dropped as soon as the snapshot is done.
+    pub fn snapshot(&self, dest: &str) -> Result<Snapshot, Box<dyn Error>> {
+        if let OpenMode::Write = self.mode {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "Database must be opened in read-only mode to take a snapshot",
+            )
+            .into());
+        }
+        let dir = Path::new(&self.dir);
+        // Files are only consistent once an interrupted operation is finished by a writer
+        for marker in [JOURNAL, COMPACT, MIGRATE] {
+            if dir.join(marker).exists() {
+                return Err(format!(
+                    "Database {} has an interrupted update, please open it in read-write mode first",
+                    self.dir
+                )
+                .into());
+            }
+        }
+        let dest = Path::new(dest);
+        fs::create_dir_all(dest)?;
+        if fs::read_dir(dest)?.next().is_some() {
+            return Err(format!("Snapshot directory {} is not empty", dest.display()).into());
+        }
+
+        let mut snapshot = Snapshot {
+            state: self.get_state()?,
+            ..Default::default()
+        };
+        let mut manifest = Vec::new();
+        Self::snapshot_dir(dir, dest, false, &mut snapshot, &mut manifest)?;
+        if dir.join(UNDO_DIR).is_dir() {
+            fs::create_dir(dest.join(UNDO_DIR))?;
+            Self::snapshot_dir(
+                &dir.join(UNDO_DIR),
+                &dest.join(UNDO_DIR),
+                true,
+                &mut snapshot,
+                &mut manifest,
+            )?;
+            File::open(dest.join(UNDO_DIR))?.sync_all()?;
+        }
+        File::open(dest)?.sync_all()?;
+        Self::verify_snapshot(dest, &manifest, snapshot.state.as_ref())?;
+        Ok(snapshot)
+    }
+
+    /// Check that files of a snapshot have the sizes listed in `manifest`, and that its
+    /// replication state is `state`
+    fn verify_snapshot(
+        dest: &Path,
+        manifest: &[(PathBuf, u64)],
+        state: Option<&ReplicationState>,
+    ) -> Result<(), Box<dyn Error>> {
+        for (path, size) in manifest {
+            let len = fs::metadata(path)?.len();
+            if len != *size {
+                return Err(format!(
+                    "Snapshot file {} has {len} bytes instead of {size}",
+                    path.display()
+                )
+                .into());
+            }
+        }
+        let state_path = dest.join(STATE);
+        let dest_state = if state_path.exists() {
+            Some(ReplicationState::from_file(&state_path)?)
+        } else {
+            None
+        };
+        if dest_state.as_ref() != state {
+            return Err(format!(
+                "Snapshot {} has state {dest_state:?} instead of {state:?}",
+                dest.display()
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Copy all files of `dir` to `dest`, hard-linking them if they are `immutable`
+    ///
+    /// Subdirectories, like temporary directories of an interrupted import, are skipped. Copied
+    /// files are added to `manifest` with the size of the original.
+    fn snapshot_dir(
+        dir: &Path,
+        dest: &Path,
+        immutable: bool,
+        snapshot: &mut Snapshot,
+        manifest: &mut Vec<(PathBuf, u64)>,
+    ) -> Result<(), io::Error> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() || entry.file_name() == LOCK {
+                continue;
+            }
+            let dest_path = dest.join(entry.file_name());
+            manifest.push((dest_path.clone(), entry.metadata()?.len()));
+            if immutable && fs::hard_link(entry.path(), &dest_path).is_ok() {
+                snapshot.num_linked += 1;
+                continue;
+            }
+            let src = File::open(entry.path())?;
+            let copy = File::create_new(&dest_path)?;
+            if clone_file(&src, &copy).is_ok() {
+                snapshot.num_cloned += 1;
+            } else {
+                copy_sparse(&src, &copy)?;
+                snapshot.num_copied += 1;
+            }
+            copy.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Make `dst` share all blocks of `src`, with a reflink
+fn clone_file(src: &File, dst: &File) -> Result<(), io::Error> {
+    // SAFETY: both file descriptors are valid while files are alive
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Copy content of `src` to an empty `dst`, only writing parts of `src` which are not holes
+fn copy_sparse(src: &File, dst: &File) -> Result<(), io::Error> {
+    let size = src.metadata()?.len();
+    dst.set_len(size)?;
+    let size = usize::try_from(size).map_err(|_| io::Error::from(ErrorKind::InvalidInput))?;
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    for range in OsmBinMmap::data_ranges(src, size) {
+        let mut offset = range.start;
+        while offset < range.end {
+            let len = (range.end - offset).min(buffer.len());
+            src.read_exact_at(&mut buffer[..len], offset as u64)?;
+            dst.write_all_at(&buffer[..len], offset as u64)?;
+            offset += len;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::MetadataExt;
+
+    use crate::osm::{OsmReader, OsmWriter};
+    use crate::osmbin::Format;
+
+    const PBF_SAINT_BARTHELEMY: &str = "tests/resources/saint_barthelemy.osm.pbf";
+    const OSM_BOUNDARY_UPDATE: &str = "tests/resources/saint_barthelemy-boundary.osc.gz";
+
+    #[test]
+    fn snapshot() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        let format = Format {
+            version: 6,
+            undo: 2,
+            ..Default::default()
+        };
+        OsmBin::init_with_format(&tmpdir, &format).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        let state = |sequence_number| ReplicationState {
+            sequence_number,
+            timestamp: None,
+        };
+        osmbin.set_state(&state(10)).unwrap();
+        osmbin
+            .update_with_state(OSM_BOUNDARY_UPDATE, &state(11), false)
+            .unwrap();
+        let dest_path = tempfile::tempdir().unwrap();
+        let dest = dest_path.path().join("snapshot");
+        let dest = dest.to_str().unwrap();
+        assert!(osmbin.snapshot(dest).is_err());
+        drop(osmbin);
+
+        let mut osmbin = OsmBin::new(&tmpdir).unwrap();
+        let snapshot = osmbin.snapshot(dest).unwrap();
+        assert_eq!(Some(state(11)), snapshot.state);
+        assert_eq!(2, snapshot.num_linked);
+        assert!(!Path::new(dest).join(LOCK).exists());
+        let undo_file = |dir: &str| {
+            fs::metadata(Path::new(dir).join(UNDO_DIR).join("000000001.osc.gz")).unwrap()
+        };
+        assert_eq!(undo_file(tmpdir).ino(), undo_file(dest).ino());
+        // Snapshot can't overwrite files
+        assert!(osmbin.snapshot(dest).is_err());
+        let way = osmbin.read_way(255316716);
+        let errors = osmbin.check().unwrap().errors;
+        drop(osmbin);
+
+        // Snapshot is not modified by updates of database
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.rollback(1).unwrap();
+        assert_eq!(None, osmbin.read_way(255316716));
+        drop(osmbin);
+        let mut snapshot_osmbin = OsmBin::new(dest).unwrap();
+        assert_eq!(Some(state(11)), snapshot_osmbin.get_state().unwrap());
+        assert_eq!(way, snapshot_osmbin.read_way(255316716));
+        // Extract of a small area has relations with members outside of it
+        assert_eq!(errors, snapshot_osmbin.check().unwrap().errors);
+        drop(snapshot_osmbin);
+
+        // Copied database can be rolled back too
+        let mut snapshot_osmbin = OsmBin::new_writer(dest).unwrap();
+        snapshot_osmbin.rollback(1).unwrap();
+        assert_eq!(Some(state(10)), snapshot_osmbin.get_state().unwrap());
+    }
+
+    #[test]
+    fn verify() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let dest = tmpdir_path.path();
+        let path = dest.join("node.crd");
+        fs::write(&path, b"data").unwrap();
+        let state = ReplicationState {
+            sequence_number: 12,
+            timestamp: None,
+        };
+        let manifest = vec![(path.clone(), 4)];
+        OsmBin::verify_snapshot(dest, &manifest, None).unwrap();
+        assert!(OsmBin::verify_snapshot(dest, &manifest, Some(&state)).is_err());
+        fs::write(dest.join(STATE), state.to_content()).unwrap();
+        OsmBin::verify_snapshot(dest, &manifest, Some(&state)).unwrap();
+        assert!(OsmBin::verify_snapshot(dest, &manifest, None).is_err());
+        // Truncated copy
+        fs::write(&path, b"dat").unwrap();
+        assert!(OsmBin::verify_snapshot(dest, &manifest, Some(&state)).is_err());
+    }
+
+    #[test]
+    fn sparse() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let src_path = tmpdir_path.path().join("src");
+        let src = File::create(&src_path).unwrap();
+        src.write_all_at(b"start", 0).unwrap();
+        src.write_all_at(b"middle", 10 << 20).unwrap();
+        src.set_len(30 << 20).unwrap();
+        let dst = File::create(tmpdir_path.path().join("dst")).unwrap();
+        copy_sparse(&File::open(&src_path).unwrap(), &dst).unwrap();
+        assert_eq!(
+            fs::read(&src_path).unwrap(),
+            fs::read(tmpdir_path.path().join("dst")).unwrap()
+        );
+    }
+}
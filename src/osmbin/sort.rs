@@ -0,0 +1,470 @@
+//! External sort of elements, to import files which are not sorted by id
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+
+use super::encoding;
+use super::metadata;
+use crate::macros::printlnt;
+use crate::osm::{Node, OsmWriter, Relation, Way};
+
+/// Directory storing sorted runs during an import, inside database directory
+pub const SORT_DIR: &str = "sort.tmp";
+/// Size of encoded elements kept in memory before being written to a sorted run
+const MAX_BUFFER_SIZE: usize = 256 * 1024 * 1024;
+
+/// Type of element, in the order of a sorted file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ElementType {
+    Node,
+    Way,
+    Relation,
+}
+
+/// Element encoded with the data stored by OsmBin
+struct Record {
+    type_: ElementType,
+    id: u64,
+    data: Vec<u8>,
+}
+
+/// Writer forwarding elements to `target` while they are sorted by type and id, and sorting all
+/// following elements once an element is out of order
+///
+/// Elements to sort are kept in memory up to [`MAX_BUFFER_SIZE`], and then written as sorted
+/// runs in [`SORT_DIR`]. Runs are merged when input is complete. Sort is stable, so that an
+/// element present several times is written in input order. Only data stored by OsmBin is kept:
+/// tags and metadata of nodes and ways are dropped, unless database stores them, and their
+/// version is kept if database stores versions.
+pub struct SortedImport<'a, T: OsmWriter> {
+    target: &'a mut T,
+    dir: PathBuf,
+    /// Tags and metadata of nodes and ways are kept, before the data of element
+    metadata: bool,
+    /// Versions of nodes and ways are kept, alone if `metadata` is not set
+    versions: bool,
+    prev: Option<(ElementType, u64)>,
+    sorting: bool,
+    buffer: Vec<Record>,
+    buffer_size: usize,
+    max_buffer_size: usize,
+    runs: Vec<PathBuf>,
+}
+
+impl<'a, T: OsmWriter> SortedImport<'a, T> {
+    /// Write sorted elements to `target`, using `dir` for sorted runs, and keeping tags,
+    /// metadata and versions of nodes and ways if `target` stores them
+    pub fn new(target: &'a mut T, dir: PathBuf) -> SortedImport<'a, T> {
+        SortedImport {
+            metadata: target.stores_metadata(),
+            versions: target.stores_versions(),
+            target,
+            dir,
+            prev: None,
+            sorting: false,
+            buffer: Vec::new(),
+            buffer_size: 0,
+            max_buffer_size: MAX_BUFFER_SIZE,
+            runs: Vec::new(),
+        }
+    }
+
+    /// Check if elements are still sorted, including element `id`
+    fn is_sorted(&mut self, type_: ElementType, id: u64) -> bool {
+        if !self.sorting && self.prev.is_some_and(|prev| (type_, id) < prev) {
+            printlnt!("Input is not sorted at {type_:?} {id}, sorting following elements");
+            self.sorting = true;
+        }
+        self.prev = Some((type_, id));
+        !self.sorting
+    }
+
+    fn push(&mut self, record: Record) -> Result<(), io::Error> {
+        self.buffer_size += record.data.len() + size_of::<Record>();
+        self.buffer.push(record);
+        if self.buffer_size > self.max_buffer_size {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    /// Write elements kept in memory to a new sorted run
+    fn write_run(&mut self) -> Result<(), io::Error> {
+        if self.runs.is_empty() {
+            match fs::remove_dir_all(&self.dir) {
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                r => r?,
+            }
+            fs::create_dir_all(&self.dir)?;
+        }
+        self.buffer.sort_by_key(|r| (r.type_, r.id));
+        let path = self.dir.join(format!("run-{}", self.runs.len()));
+        let mut file = BufWriter::new(File::create(&path)?);
+        for record in self.buffer.drain(..) {
+            file.write_all(&[record.type_ as u8])?;
+            file.write_all(&record.id.to_be_bytes())?;
+            file.write_all(&u32::try_from(record.data.len()).unwrap().to_be_bytes())?;
+            file.write_all(&record.data)?;
+        }
+        file.flush()?;
+        self.buffer_size = 0;
+        self.runs.push(path);
+        Ok(())
+    }
+
+    /// Read next element of a sorted run, or `None` at end of run
+    fn read_record(run: &mut impl Read) -> Result<Option<Record>, io::Error> {
+        let mut header = [0u8; 13];
+        match run.read_exact(&mut header) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            r => r?,
+        }
+        let type_ = match header[0] {
+            0 => ElementType::Node,
+            1 => ElementType::Way,
+            2 => ElementType::Relation,
+            t => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown element type {t} in sorted run"),
+                ));
+            }
+        };
+        let id = u64::from_be_bytes(header[1..9].try_into().unwrap());
+        let len = u32::from_be_bytes(header[9..13].try_into().unwrap());
+        let mut data = vec![0u8; len as usize];
+        run.read_exact(&mut data)?;
+        Ok(Some(Record { type_, id, data }))
+    }
+
+    /// Write all sorted elements to target, merging sorted runs
+    fn write_sorted(&mut self) -> Result<(), io::Error> {
+        if self.runs.is_empty() {
+            self.buffer.sort_by_key(|r| (r.type_, r.id));
+            for record in mem::take(&mut self.buffer) {
+                self.write_record(&record)?;
+            }
+            return Ok(());
+        }
+        self.write_run()?;
+        printlnt!("Merging {} sorted runs", self.runs.len());
+
+        let mut runs = Vec::new();
+        // Next record of each run
+        let mut records = Vec::new();
+        // Smallest element first, and the one from the first run for the same id
+        let mut heap = BinaryHeap::new();
+        for (i, path) in self.runs.iter().enumerate() {
+            let mut run = BufReader::new(File::open(path)?);
+            let record = Self::read_record(&mut run)?;
+            if let Some(r) = &record {
+                heap.push(Reverse((r.type_, r.id, i)));
+            }
+            runs.push(run);
+            records.push(record);
+        }
+        while let Some(Reverse((_, _, i))) = heap.pop() {
+            let next = Self::read_record(&mut runs[i])?;
+            if let Some(r) = &next {
+                heap.push(Reverse((r.type_, r.id, i)));
+            }
+            let record = mem::replace(&mut records[i], next).unwrap();
+            self.write_record(&record)?;
+        }
+        drop(runs);
+        fs::remove_dir_all(&self.dir)
+    }
+
+    /// Encode tags and metadata of an element, as their size followed by a relation without
+    /// members, or nothing if they are not kept. Only version is kept if database stores
+    /// versions without metadata.
+    fn encode_metadata(&self, element: Option<Relation>) -> Vec<u8> {
+        let mut data = Vec::new();
+        if self.metadata || self.versions {
+            let element = if self.metadata {
+                element
+            } else {
+                element.and_then(|e| {
+                    e.version.map(|version| Relation {
+                        id: e.id,
+                        version: Some(version),
+                        ..Default::default()
+                    })
+                })
+            };
+            let encoded = element
+                .map(|e| encoding::encode_relation(&e))
+                .unwrap_or_default();
+            encoding::write_varint(&mut data, encoded.len() as u64);
+            data.extend(encoded);
+        }
+        data
+    }
+
+    /// Split data of a record into tags, metadata and version, if they are kept, and data of
+    /// element
+    fn decode_metadata<'r>(&self, record: &'r Record) -> Option<(Option<Relation>, &'r [u8])> {
+        if !self.metadata && !self.versions {
+            return Some((None, &record.data));
+        }
+        let mut pos = 0;
+        let len = usize::try_from(encoding::read_varint(&record.data, &mut pos)?).ok()?;
+        let encoded = record.data.get(pos..pos.checked_add(len)?)?;
+        let element = if len == 0 {
+            None
+        } else {
+            Some(encoding::decode_relation(record.id, encoded)?)
+        };
+        Some((element, &record.data[pos + len..]))
+    }
+
+    fn write_record(&mut self, record: &Record) -> Result<(), io::Error> {
+        let corrupted = || {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Corrupted {:?} {} in sorted run", record.type_, record.id),
+            )
+        };
+        match record.type_ {
+            ElementType::Node => {
+                let (element, data) = self.decode_metadata(record).ok_or_else(corrupted)?;
+                let crd: [u8; 8] = data.try_into().map_err(|_| corrupted())?;
+                let mut node = Node {
+                    id: record.id,
+                    decimicro_lat: i32::from_be_bytes(crd[0..4].try_into().unwrap()),
+                    decimicro_lon: i32::from_be_bytes(crd[4..8].try_into().unwrap()),
+                    ..Default::default()
+                };
+                if let Some(element) = element {
+                    metadata::fill_node(&mut node, element);
+                }
+                self.target.write_node(&mut node)
+            }
+            ElementType::Way => {
+                let (element, data) = self.decode_metadata(record).ok_or_else(corrupted)?;
+                let nodes = encoding::decode_way_nodes(data).ok_or_else(corrupted)?;
+                let mut way = Way {
+                    id: record.id,
+                    nodes,
+                    ..Default::default()
+                };
+                if let Some(element) = element {
+                    metadata::fill_way(&mut way, element);
+                }
+                self.target.write_way(&mut way)
+            }
+            ElementType::Relation => {
+                let mut relation =
+                    encoding::decode_relation(record.id, &record.data).ok_or_else(corrupted)?;
+                self.target.write_relation(&mut relation)
+            }
+        }
+    }
+}
+
+impl<T: OsmWriter> OsmWriter for SortedImport<'_, T> {
+    fn write_node(&mut self, node: &mut Node) -> Result<(), io::Error> {
+        if self.is_sorted(ElementType::Node, node.id) {
+            return self.target.write_node(node);
+        }
+        let mut data = self.encode_metadata(metadata::from_node(node));
+        data.extend(node.decimicro_lat.to_be_bytes());
+        data.extend(node.decimicro_lon.to_be_bytes());
+        self.push(Record {
+            type_: ElementType::Node,
+            id: node.id,
+            data,
+        })
+    }
+    fn write_way(&mut self, way: &mut Way) -> Result<(), io::Error> {
+        if self.is_sorted(ElementType::Way, way.id) {
+            return self.target.write_way(way);
+        }
+        let mut data = self.encode_metadata(metadata::from_way(way));
+        data.extend(encoding::encode_way_nodes(&way.nodes));
+        self.push(Record {
+            type_: ElementType::Way,
+            id: way.id,
+            data,
+        })
+    }
+    fn write_relation(&mut self, relation: &mut Relation) -> Result<(), io::Error> {
+        if self.is_sorted(ElementType::Relation, relation.id) {
+            return self.target.write_relation(relation);
+        }
+        self.push(Record {
+            type_: ElementType::Relation,
+            id: relation.id,
+            data: encoding::encode_relation(relation),
+        })
+    }
+    fn write_start(&mut self, change: bool) -> Result<(), Box<dyn Error>> {
+        self.target.write_start(change)
+    }
+    fn stores_metadata(&self) -> bool {
+        self.metadata
+    }
+    fn stores_versions(&self) -> bool {
+        self.target.stores_versions()
+    }
+    fn write_end(&mut self, change: bool) -> Result<(), Box<dyn Error>> {
+        if self.sorting {
+            self.write_sorted()?;
+        }
+        self.target.write_end(change)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::osm::OsmReader;
+    use crate::osmbin::OsmBin;
+    use crate::osmbin::format::Format;
+    use crate::osmbin::mmap::OsmBinMmap;
+
+    const PBF_SAINT_BARTHELEMY: &str = "tests/resources/saint_barthelemy.osm.pbf";
+
+    /// Writer keeping elements in memory
+    #[derive(Default)]
+    struct Elements {
+        nodes: Vec<Node>,
+        ways: Vec<Way>,
+        relations: Vec<Relation>,
+    }
+
+    impl OsmWriter for Elements {
+        fn write_node(&mut self, node: &mut Node) -> Result<(), io::Error> {
+            self.nodes.push(node.clone());
+            Ok(())
+        }
+        fn write_way(&mut self, way: &mut Way) -> Result<(), io::Error> {
+            self.ways.push(way.clone());
+            Ok(())
+        }
+        fn write_relation(&mut self, relation: &mut Relation) -> Result<(), io::Error> {
+            self.relations.push(relation.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn import_unsorted() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let mut elements = Elements::default();
+        elements.import(PBF_SAINT_BARTHELEMY).unwrap();
+
+        // Reverse order of nodes and ways, and put relations first
+        let mut target = Elements::default();
+        let mut import = SortedImport::new(&mut target, tmpdir_path.path().join(SORT_DIR));
+        import.max_buffer_size = 100_000;
+        import.write_start(false).unwrap();
+        for relation in &elements.relations {
+            import.write_relation(&mut relation.clone()).unwrap();
+        }
+        for node in elements.nodes.iter().rev() {
+            import.write_node(&mut node.clone()).unwrap();
+        }
+        for way in elements.ways.iter().rev() {
+            import.write_way(&mut way.clone()).unwrap();
+        }
+        assert!(import.runs.len() > 2);
+        import.write_end(false).unwrap();
+        assert!(!tmpdir_path.path().join(SORT_DIR).exists());
+
+        // Relations written before input was found unsorted are kept
+        assert_eq!(
+            elements
+                .relations
+                .iter()
+                .map(|r| r.id)
+                .collect::<Vec<u64>>(),
+            target.relations.iter().map(|r| r.id).collect::<Vec<u64>>()
+        );
+        // Tags and metadata are kept
+        assert_eq!(elements.nodes, target.nodes);
+        assert_eq!(elements.ways, target.ways);
+
+        // Unsorted file imported into OsmBin gives the same database
+        let osm_path = tmpdir_path.path().join("unsorted.osm");
+        let mut osmxml = crate::osmxml::OsmXml::new(osm_path.to_str().unwrap()).unwrap();
+        osmxml.write_start(false).unwrap();
+        for node in elements.nodes.iter().rev() {
+            osmxml.write_node(&mut node.clone()).unwrap();
+        }
+        for way in elements.ways.iter().rev() {
+            osmxml.write_way(&mut way.clone()).unwrap();
+        }
+        for relation in elements.relations.iter().rev() {
+            osmxml.write_relation(&mut relation.clone()).unwrap();
+        }
+        osmxml.write_end(false).unwrap();
+        drop(osmxml);
+
+        let dir = tmpdir_path.path().join("db");
+        let dir = dir.to_str().unwrap();
+        OsmBin::init(dir).unwrap();
+        let mut osmbin = OsmBin::new_writer(dir).unwrap();
+        osmbin.import(osm_path.to_str().unwrap()).unwrap();
+        osmbin.flush().unwrap();
+        let mmap = OsmBinMmap::new_unlocked(dir).unwrap();
+        assert_eq!(
+            elements.nodes.iter().map(|n| n.id).collect::<Vec<u64>>(),
+            mmap.node_ids().collect::<Vec<u64>>()
+        );
+        for way in &elements.ways {
+            assert_eq!(way.nodes, osmbin.read_way(way.id).unwrap().nodes);
+        }
+        for relation in &elements.relations {
+            assert_eq!(
+                relation.members,
+                osmbin.read_relation(relation.id).unwrap().members
+            );
+        }
+        assert!(!tmpdir_path.path().join("db").join(SORT_DIR).exists());
+    }
+
+    #[test]
+    fn import_unsorted_versions() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let mut elements = Elements::default();
+        elements.import(PBF_SAINT_BARTHELEMY).unwrap();
+
+        // Only versions are kept when database doesn't store metadata
+        let dir = tmpdir_path.path().join("db");
+        let dir = dir.to_str().unwrap();
+        let format = Format {
+            version: 5,
+            versions: true,
+            ..Default::default()
+        };
+        OsmBin::init_with_format(dir, &format).unwrap();
+        let mut osmbin = OsmBin::new_writer(dir).unwrap();
+        let mut import = SortedImport::new(&mut osmbin, tmpdir_path.path().join(SORT_DIR));
+        import.write_start(false).unwrap();
+        for node in elements.nodes.iter().rev() {
+            import.write_node(&mut node.clone()).unwrap();
+        }
+        for way in elements.ways.iter().rev() {
+            import.write_way(&mut way.clone()).unwrap();
+        }
+        import.write_end(false).unwrap();
+        assert!(import.runs.is_empty());
+        for node in &elements.nodes {
+            assert!(node.version.is_some());
+            assert_eq!(node.version, osmbin.read_node(node.id).unwrap().version);
+        }
+        for way in &elements.ways {
+            let stored = osmbin.read_way(way.id).unwrap();
+            assert!(way.version.is_some());
+            assert_eq!(way.version, stored.version);
+            assert_eq!(None, stored.user);
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! Spatial index of an OsmBin database, from tiles to nodes
+
+use geo::{BoundingRect, Intersects, MultiPolygon, Rect, coord, point};
+use std::collections::{BTreeSet, HashSet};
+use std::error::Error;
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+
+use super::OsmBin;
+use super::journal::Journal;
+use super::mmap::{OsmBinMmap, Selection};
+use super::parents::{Parents, ParentsFiles};
+
+/// Files used by the spatial index
+pub const NODE_TILES: ParentsFiles = ParentsFiles {
+    idx: "node.tiles.idx",
+    data: "node.tiles.data",
+    free: "node.tiles.free",
+};
+
+/// Size of a tile, as 0.1 degree
+const TILE_SIZE: i64 = 1_000_000;
+const NUM_TILES_LON: i64 = 3600;
+const NUM_TILES_LAT: i64 = 1800;
+/// Number of consecutive node ids in a block of `node.crd`
+const BLOCK_SIZE: u64 = 512;
+/// Size of a pointer in `node.tiles.idx`
+const TILE_PTR_SIZE: usize = 5;
+
+/// Coarse spatial index, storing for each tile the blocks of `node.crd` with a node inside it
+///
+/// Lists of blocks use the same files layout as reverse indexes, indexed by tile number. Blocks
+/// are only added to a tile, and never removed when a node is moved or deleted, so nodes found
+/// from the index must be checked against their current coordinates.
+pub struct Tiles {
+    lists: Parents,
+    /// Tiles already known to contain a node of `block`, to avoid reading a list for each node
+    block: u64,
+    block_known: HashSet<u64>,
+}
+
+impl Tiles {
+    pub fn open(dir: &str, write: bool) -> Result<Tiles, io::Error> {
+        Ok(Tiles {
+            lists: Parents::open(dir, &NODE_TILES, TILE_PTR_SIZE, write)?,
+            block: u64::MAX,
+            block_known: HashSet::new(),
+        })
+    }
+
+    /// Create empty files of spatial index, if they don't exist
+    pub fn init(dir: &str) -> Result<(), io::Error> {
+        Parents::init(dir, &NODE_TILES)
+    }
+
+    pub fn lists(&self) -> &Parents {
+        &self.lists
+    }
+
+    /// Get position of tile containing coordinates
+    fn tile_xy(lat: i64, lon: i64) -> (i64, i64) {
+        (
+            ((lon + 1_800_000_000) / TILE_SIZE).clamp(0, NUM_TILES_LON - 1),
+            ((lat + 900_000_000) / TILE_SIZE).clamp(0, NUM_TILES_LAT - 1),
+        )
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    fn tile(x: i64, y: i64) -> u64 {
+        (y * NUM_TILES_LON + x) as u64
+    }
+
+    /// Ids of nodes in a block
+    fn block_ids(block: u64) -> Range<u64> {
+        block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE
+    }
+
+    /// Add node `id` to the tile containing its coordinates
+    pub fn add(
+        &mut self,
+        id: u64,
+        lat: i32,
+        lon: i32,
+        journal: Option<&mut Journal>,
+    ) -> Result<(), io::Error> {
+        let block = id / BLOCK_SIZE;
+        if block != self.block {
+            self.block = block;
+            self.block_known.clear();
+        }
+        let (x, y) = Self::tile_xy(i64::from(lat), i64::from(lon));
+        let tile = Self::tile(x, y);
+        if self.block_known.insert(tile) {
+            self.lists.add(tile, block, journal)?;
+        }
+        Ok(())
+    }
+
+    /// Get tiles intersecting polygon
+    fn tiles_in_poly(poly: &MultiPolygon<i64>) -> Vec<u64> {
+        let Some(bbox) = poly.bounding_rect() else {
+            return Vec::new();
+        };
+        let (min_x, min_y) = Self::tile_xy(bbox.min().y, bbox.min().x);
+        let (max_x, max_y) = Self::tile_xy(bbox.max().y, bbox.max().x);
+        let mut tiles = Vec::new();
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                let rect = Rect::new(
+                    coord! {x: x * TILE_SIZE - 1_800_000_000, y: y * TILE_SIZE - 900_000_000},
+                    coord! {x: (x + 1) * TILE_SIZE - 1_800_000_000, y: (y + 1) * TILE_SIZE - 900_000_000},
+                );
+                if rect.intersects(poly) {
+                    tiles.push(Self::tile(x, y));
+                }
+            }
+        }
+        tiles
+    }
+
+    /// Get blocks of `node.crd` which may contain a node inside one of `tiles`
+    fn blocks_in_tiles(&self, tiles: &HashSet<u64>) -> Result<BTreeSet<u64>, io::Error> {
+        let mut blocks = BTreeSet::new();
+        for tile in tiles {
+            blocks.extend(self.lists.read(*tile)?);
+        }
+        Ok(blocks)
+    }
+}
+
+/// Ids of elements found by [`OsmBin::extract`], sorted
+#[derive(Debug, Default, PartialEq)]
+pub struct Extract {
+    pub nodes: Vec<u64>,
+    pub ways: Vec<u64>,
+    pub relations: Vec<u64>,
+}
+
+impl OsmBin {
+    /// Get ids of nodes inside polygon, ways crossing polygon, and relations with one of these
+    /// nodes/ways or such a relation as member
+    ///
+    /// Nodes are found with the spatial index, and ways with the reverse index of the nodes of
+    /// tiles intersecting polygon, so both indexes must be enabled in the database layout. A way
+    /// is found if it has a node inside polygon, or if one of its segments crosses polygon and
+    /// has a node in such a tile: a segment longer than a tile crossing polygon is missed.
+    /// Relations are found by reading all relations, as no index gives their node and relation
+    /// members. A bounding-box can be given with [`osmgeom::bounding_box_to_polygon`].
+    ///
+    /// [`osmgeom::bounding_box_to_polygon`]: crate::osmgeom::bounding_box_to_polygon
+    pub fn extract(&mut self, poly: &MultiPolygon<i64>) -> Result<Extract, Box<dyn Error>> {
+        self.flush()?;
+        let (Some(tiles), Some(node_parents)) = (&self.tiles, &self.node_parents) else {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "Database {} has no spatial or reverse index, please run osmbin --migrate --tiles true --parents true",
+                    self.dir
+                ),
+            )
+            .into());
+        };
+        let poly_tiles: HashSet<u64> = Tiles::tiles_in_poly(poly).into_iter().collect();
+        let blocks = tiles.blocks_in_tiles(&poly_tiles)?;
+        let osmbin = OsmBinMmap::new_unlocked(&self.dir)?;
+
+        let mut selection = Selection::default();
+        let mut ways = HashSet::new();
+        for id in blocks.into_iter().flat_map(Tiles::block_ids) {
+            let Some(node) = osmbin.read_node(id) else {
+                continue;
+            };
+            let lat = i64::from(node.decimicro_lat);
+            let lon = i64::from(node.decimicro_lon);
+            if point!(x: lon, y: lat).intersects(poly) {
+                selection.nodes.insert(id);
+            } else {
+                let (x, y) = Tiles::tile_xy(lat, lon);
+                if !poly_tiles.contains(&Tiles::tile(x, y)) {
+                    continue;
+                }
+            }
+            ways.extend(node_parents.read(id)?);
+        }
+        for id in ways {
+            let Some(way) = osmbin.try_read_way(id)? else {
+                continue;
+            };
+            if way.nodes.iter().any(|n| selection.nodes.contains(n))
+                || osmbin.way_in_poly(&way, poly)
+            {
+                selection.ways.insert(id);
+            }
+        }
+        osmbin.select_relations(&mut selection)?;
+
+        let sorted = |ids: HashSet<u64>| {
+            let mut ids: Vec<u64> = ids.into_iter().collect();
+            ids.sort_unstable();
+            ids
+        };
+        Ok(Extract {
+            nodes: sorted(selection.nodes),
+            ways: sorted(selection.ways),
+            relations: sorted(selection.relations),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::polygon;
+    use std::path::Path;
+
+    use crate::osm::{Node, OsmReader, OsmWriter, Way};
+    use crate::osmbin::Format;
+
+    const PBF_SAINT_BARTHELEMY: &str = "tests/resources/saint_barthelemy.osm.pbf";
+
+    fn gustavia() -> MultiPolygon<i64> {
+        MultiPolygon::new(vec![polygon![
+            (x: -628_600_000, y: 178_900_000),
+            (x: -628_400_000, y: 178_900_000),
+            (x: -628_400_000, y: 179_100_000),
+            (x: -628_600_000, y: 179_100_000),
+        ]])
+    }
+
+    #[test]
+    fn tiles_in_poly() {
+        // Gustavia is across latitude 17.9
+        assert_eq!(2, Tiles::tiles_in_poly(&gustavia()).len());
+        let poly = MultiPolygon::new(vec![polygon![
+            (x: -629_500_000, y: 178_500_000),
+            (x: -627_500_000, y: 178_500_000),
+            (x: -627_500_000, y: 180_500_000),
+            (x: -629_500_000, y: 180_500_000),
+        ]]);
+        assert_eq!(9, Tiles::tiles_in_poly(&poly).len());
+        let (x, y) = Tiles::tile_xy(0, 0);
+        assert_eq!((1800, 900), (x, y));
+        assert_eq!((3599, 1799), Tiles::tile_xy(900_000_000, 1_800_000_000));
+        assert_eq!((0, 0), Tiles::tile_xy(-900_000_000, -1_800_000_000));
+    }
+
+    #[test]
+    fn extract() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path().to_str().unwrap();
+        OsmBin::init(&tmpdir).unwrap();
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        assert_eq!(
+            ErrorKind::Unsupported,
+            osmbin
+                .extract(&gustavia())
+                .unwrap_err()
+                .downcast::<io::Error>()
+                .unwrap()
+                .kind()
+        );
+        drop(osmbin);
+
+        // Spatial index is built by migration, and reverse indexes are also needed
+        let format = Format {
+            version: 7,
+            tiles: true,
+            ..Default::default()
+        };
+        assert_eq!(true, OsmBin::migrate(&tmpdir, &format).unwrap());
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        assert!(osmbin.extract(&gustavia()).is_err());
+        drop(osmbin);
+        let format = Format {
+            parents: true,
+            ..format
+        };
+        assert_eq!(true, OsmBin::migrate(&tmpdir, &format).unwrap());
+        let mut osmbin = OsmBin::new_writer(&tmpdir).unwrap();
+        let extract = osmbin.extract(&gustavia()).unwrap();
+
+        // Same elements are found by scanning all nodes
+        let mmap = OsmBinMmap::new_unlocked(&tmpdir).unwrap();
+        let nodes = mmap
+            .node_ids()
+            .filter(|id| mmap.node_in_poly(*id, &gustavia()))
+            .collect();
+        let selection = mmap.select_from_nodes(nodes).unwrap();
+        drop(mmap);
+        assert!(!extract.nodes.is_empty());
+        assert!(!extract.ways.is_empty());
+        assert!(!extract.relations.is_empty());
+        assert_eq!(selection.nodes.len(), extract.nodes.len());
+        assert_eq!(selection.ways.len(), extract.ways.len());
+        assert_eq!(selection.relations.len(), extract.relations.len());
+        assert!(extract.ways.iter().all(|id| selection.ways.contains(id)));
+
+        // Moved nodes are only found at their new position
+        let mut moved = osmbin.read_node(extract.nodes[0]).unwrap();
+        moved.decimicro_lat = 0;
+        moved.decimicro_lon = 0;
+        osmbin.write_node(&mut moved).unwrap();
+        let mut added = Node {
+            id: 20_000_000_000,
+            decimicro_lat: 179_000_000,
+            decimicro_lon: -628_500_000,
+            ..Default::default()
+        };
+        osmbin.write_node(&mut added).unwrap();
+        let new_extract = osmbin.extract(&gustavia()).unwrap();
+        assert!(!new_extract.nodes.contains(&moved.id));
+        assert!(new_extract.nodes.contains(&added.id));
+        assert_eq!(extract.nodes.len(), new_extract.nodes.len());
+
+        // Way crossing polygon without any node inside it is found
+        let mut west = Node {
+            id: 20_000_000_001,
+            decimicro_lat: 179_000_000,
+            decimicro_lon: -628_700_000,
+            ..Default::default()
+        };
+        let mut east = Node {
+            id: 20_000_000_002,
+            decimicro_lon: -628_300_000,
+            ..west.clone()
+        };
+        osmbin.write_node(&mut west).unwrap();
+        osmbin.write_node(&mut east).unwrap();
+        let mut crossing = Way {
+            id: 2_000_000_000,
+            nodes: vec![west.id, east.id],
+            ..Default::default()
+        };
+        osmbin.write_way(&mut crossing).unwrap();
+        let new_extract = osmbin.extract(&gustavia()).unwrap();
+        assert!(new_extract.ways.contains(&crossing.id));
+        assert!(!new_extract.nodes.contains(&west.id));
+        assert!(!new_extract.nodes.contains(&east.id));
+        drop(osmbin);
+
+        // Spatial index is removed by migration
+        assert_eq!(true, OsmBin::migrate(&tmpdir, &Format::default()).unwrap());
+        assert!(!Path::new(tmpdir).join(NODE_TILES.idx).exists());
+    }
+}
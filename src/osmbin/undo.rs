@@ -0,0 +1,225 @@
+//! Inverse changesets of diffs applied to an OsmBin database, to roll them back
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use super::STATE;
+use super::diff::StagedDiff;
+use crate::osm::OsmWriter;
+use crate::osmxml::OsmXml;
+
+/// Directory storing inverse changesets
+pub const UNDO_DIR: &str = "undo";
+
+/// Extension of an inverse changeset
+const DIFF_EXT: &str = ".osc.gz";
+/// Extension of the replication state before a diff was applied
+const STATE_EXT: &str = ".state.txt";
+/// Extension of files being written
+const TMP_EXT: &str = ".tmp";
+
+/// Update in progress on database
+enum Pending {
+    /// A diff is applied, and previous state of elements it modifies is recorded as entry
+    /// `index`
+    Record {
+        index: u64,
+        prev_state: Option<Vec<u8>>,
+        diff: StagedDiff,
+        recorded: HashSet<(&'static str, u64)>,
+    },
+    /// Inverse changeset of entry `index` is applied
+    Rollback { index: u64 },
+}
+
+/// Inverse changesets of the last diffs applied to database
+///
+/// Each entry of `undo/` is numbered in the order diffs were applied, and is made of:
+/// - `NNNNNNNNN.osc.gz`: an osmChange file with the state of all elements modified by the diff,
+///   before it was applied. An element created by the diff is deleted by this file.
+/// - `NNNNNNNNN.state.txt`: replication state of database before the diff was applied, only
+///   present if database had one.
+///
+/// Entries are written and removed in the same journaled update as the diff, or its rollback, so
+/// that they always match the content of database. Only the last `limit` entries are kept.
+pub struct UndoLog {
+    dir: PathBuf,
+    limit: u32,
+    pending: Option<Pending>,
+}
+
+impl UndoLog {
+    pub fn open(dir: &str, limit: u32) -> UndoLog {
+        UndoLog {
+            dir: PathBuf::from(dir),
+            limit,
+            pending: None,
+        }
+    }
+
+    /// Name of inverse changeset of entry `index`, relative to database directory
+    fn diff_filename(index: u64) -> String {
+        format!("{UNDO_DIR}/{index:09}{DIFF_EXT}")
+    }
+
+    /// Name of replication state of entry `index`, relative to database directory
+    fn state_filename(index: u64) -> String {
+        format!("{UNDO_DIR}/{index:09}{STATE_EXT}")
+    }
+
+    /// List entries, from the oldest to the newest
+    pub fn entries(&self) -> Result<Vec<u64>, io::Error> {
+        let read_dir = match fs::read_dir(self.dir.join(UNDO_DIR)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            r => r?,
+        };
+        let mut entries = Vec::new();
+        for entry in read_dir {
+            let filename = entry?.file_name();
+            if let Some(index) = filename
+                .to_str()
+                .and_then(|f| f.strip_suffix(DIFF_EXT))
+                .and_then(|f| f.parse().ok())
+            {
+                entries.push(index);
+            }
+        }
+        entries.sort_unstable();
+        Ok(entries)
+    }
+
+    /// Path of inverse changeset of entry `index`
+    pub fn diff_path(&self, index: u64) -> PathBuf {
+        self.dir.join(Self::diff_filename(index))
+    }
+
+    /// Start recording a new entry, or applying entry `rollback`
+    ///
+    /// Returns the current content of files of the entry, to be restored if update is
+    /// interrupted.
+    #[allow(clippy::type_complexity)]
+    pub fn begin(
+        &mut self,
+        rollback: Option<u64>,
+    ) -> Result<Vec<(String, Option<Vec<u8>>)>, io::Error> {
+        let read = |filename: &str| match fs::read(self.dir.join(filename)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            r => r.map(Some),
+        };
+        let index = match rollback {
+            Some(index) => index,
+            None => self.entries()?.last().map_or(1, |i| i + 1),
+        };
+        let snapshots = vec![
+            (
+                Self::diff_filename(index),
+                read(&Self::diff_filename(index))?,
+            ),
+            (
+                Self::state_filename(index),
+                read(&Self::state_filename(index))?,
+            ),
+        ];
+        self.pending = Some(match rollback {
+            Some(index) => Pending::Rollback { index },
+            None => Pending::Record {
+                index,
+                prev_state: read(STATE)?,
+                diff: StagedDiff::default(),
+                recorded: HashSet::new(),
+            },
+        });
+        Ok(snapshots)
+    }
+
+    /// Check if previous state of element `id` of `type_` must be recorded by current update
+    ///
+    /// Only the first state of an element during an update is recorded, as it is the one before
+    /// the diff was applied.
+    pub fn record(&mut self, type_: &'static str, id: u64) -> bool {
+        match &mut self.pending {
+            Some(Pending::Record { recorded, .. }) => recorded.insert((type_, id)),
+            _ => false,
+        }
+    }
+
+    /// Inverse changeset recorded by current update
+    pub fn diff(&mut self) -> Option<&mut StagedDiff> {
+        match &mut self.pending {
+            Some(Pending::Record { diff, .. }) => Some(diff),
+            _ => None,
+        }
+    }
+
+    /// Write the recorded entry, or restore replication state from the rolled back entry and
+    /// remove it
+    ///
+    /// Must be called before the journal is committed.
+    pub fn commit(&mut self) -> Result<(), io::Error> {
+        match self.pending.take() {
+            Some(Pending::Record {
+                index,
+                prev_state,
+                mut diff,
+                ..
+            }) => {
+                fs::create_dir_all(self.dir.join(UNDO_DIR))?;
+                // Files are written under a temporary name, which is not listed as an entry, so
+                // that an entry is never seen partially written
+                let diff_path = self.diff_path(index);
+                let tmp_path = self
+                    .dir
+                    .join(format!("{UNDO_DIR}/{index:09}{TMP_EXT}{DIFF_EXT}"));
+                let mut writer = OsmXml::new(tmp_path.to_str().unwrap())
+                    .map_err(|e| io::Error::other(e.to_string()))?;
+                diff.write_start(true)
+                    .and_then(|()| diff.apply_to(&mut writer))
+                    .map_err(|e| io::Error::other(e.to_string()))?;
+                drop(writer);
+                File::open(&tmp_path)?.sync_all()?;
+                fs::rename(&tmp_path, &diff_path)?;
+                if let Some(prev_state) = prev_state {
+                    let state_path = self.dir.join(Self::state_filename(index));
+                    let mut tmp_path = state_path.as_os_str().to_owned();
+                    tmp_path.push(TMP_EXT);
+                    let mut file = File::create(&tmp_path)?;
+                    file.write_all(&prev_state)?;
+                    file.sync_all()?;
+                    fs::rename(&tmp_path, &state_path)?;
+                }
+            }
+            Some(Pending::Rollback { index }) => {
+                let state_path = self.dir.join(Self::state_filename(index));
+                if state_path.exists() {
+                    fs::rename(&state_path, self.dir.join(STATE))?;
+                } else {
+                    Self::remove_file(&self.dir.join(STATE))?;
+                }
+                fs::remove_file(self.diff_path(index))?;
+                File::open(&self.dir)?.sync_all()?;
+            }
+            None => return Ok(()),
+        }
+        File::open(self.dir.join(UNDO_DIR))?.sync_all()
+    }
+
+    /// Remove the oldest entries, so that only the last `limit` entries are kept
+    pub fn prune(&self) -> Result<(), io::Error> {
+        let entries = self.entries()?;
+        let num_removed = entries.len().saturating_sub(self.limit as usize);
+        for index in &entries[..num_removed] {
+            Self::remove_file(&self.dir.join(Self::state_filename(*index)))?;
+            fs::remove_file(self.diff_path(*index))?;
+        }
+        Ok(())
+    }
+
+    fn remove_file(path: &Path) -> Result<(), io::Error> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            r => r,
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Versions of elements stored by an OsmBin database, to detect diffs applied out of order
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::num::NonZeroU64;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::OsmBin;
+use super::journal::Journal;
+
+/// Versions of nodes
+pub const NODE_VERSION: &str = "node.version";
+/// Versions of ways
+pub const WAY_VERSION: &str = "way.version";
+/// Versions of relations
+pub const RELATION_VERSION: &str = "relation.version";
+
+/// Size of a version
+const VERSION_SIZE: usize = 4;
+
+/// What to do when a diff has an element with a version not newer than the stored version
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VersionCheck {
+    /// Skip element, keeping the stored element, and count it in statistics
+    #[default]
+    Warn,
+    /// Fail update, so that it is rolled back
+    Fail,
+}
+
+/// Last version of each element, in files directly indexed by element id
+///
+/// A version is stored as 4 bytes, with 0 for an element without known version, so that ids
+/// which were never written only use space in sparse files. Version of a deleted element is
+/// kept, so that applying the same deletion twice is detected.
+///
+/// Files are accessed without buffering, as versions are read and written one at a time.
+pub struct Versions {
+    node: File,
+    way: File,
+    relation: File,
+}
+
+impl Versions {
+    pub fn open(dir: &str, write: bool) -> Result<Versions, io::Error> {
+        let mut file_options = OpenOptions::new();
+        file_options.read(true).write(write);
+        Ok(Versions {
+            node: file_options.open(Path::new(dir).join(NODE_VERSION))?,
+            way: file_options.open(Path::new(dir).join(WAY_VERSION))?,
+            relation: file_options.open(Path::new(dir).join(RELATION_VERSION))?,
+        })
+    }
+
+    /// Create empty files, if they don't exist
+    pub fn init(dir: &str) -> Result<(), io::Error> {
+        for filename in [NODE_VERSION, WAY_VERSION, RELATION_VERSION] {
+            match File::create_new(Path::new(dir).join(filename)) {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Current size of files, to start a journal
+    pub fn sizes(&self) -> Result<[(&'static str, u64); 3], io::Error> {
+        Ok([
+            (NODE_VERSION, self.node.metadata()?.len()),
+            (WAY_VERSION, self.way.metadata()?.len()),
+            (RELATION_VERSION, self.relation.metadata()?.len()),
+        ])
+    }
+
+    pub fn sync_all(&self) -> Result<(), io::Error> {
+        self.node.sync_all()?;
+        self.way.sync_all()?;
+        self.relation.sync_all()
+    }
+
+    /// File storing versions of `type_` elements, and its name
+    fn file(&self, type_: &str) -> (&File, &'static str) {
+        match type_ {
+            "node" => (&self.node, NODE_VERSION),
+            "way" => (&self.way, WAY_VERSION),
+            "relation" => (&self.relation, RELATION_VERSION),
+            _ => panic!("{type_} has no version"),
+        }
+    }
+
+    /// Read version of element `id` of `type_`, or `None` if it is not known
+    pub fn read(&self, type_: &'static str, id: u64) -> Result<Option<NonZeroU64>, io::Error> {
+        let addr = OsmBin::idx_addr(type_, id, VERSION_SIZE)?;
+        let mut buffer = [0u8; VERSION_SIZE];
+        match self.file(type_).0.read_exact_at(&mut buffer, addr) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            r => r?,
+        }
+        Ok(NonZeroU64::new(u64::from(OsmBin::bytes4_to_int(buffer))))
+    }
+
+    /// Store version of element `id` of `type_`, or clear it if `version` is `None`
+    pub fn write(
+        &self,
+        type_: &'static str,
+        id: u64,
+        version: Option<NonZeroU64>,
+        journal: Option<&mut Journal>,
+    ) -> Result<(), io::Error> {
+        let addr = OsmBin::idx_addr(type_, id, VERSION_SIZE)?;
+        let version = version.map_or(0, NonZeroU64::get);
+        let version = u32::try_from(version).map_err(|_| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{type_} {id} has a too large version {version}"),
+            )
+        })?;
+        let (file, filename) = self.file(type_);
+        if let Some(journal) = journal {
+            journal.record_range(filename, file, addr, VERSION_SIZE as u64)?;
+        }
+        file.write_all_at(&OsmBin::int_to_bytes4(version), addr)
+    }
+
+    /// Get version of element `id` from a file in memory, or `None` if it is not known
+    pub fn read_from(data: &[u8], id: u64) -> Option<NonZeroU64> {
+        let addr = usize::try_from(id).ok()?.checked_mul(VERSION_SIZE)?;
+        let buffer = data.get(addr..addr.checked_add(VERSION_SIZE)?)?;
+        NonZeroU64::new(u64::from(OsmBin::bytes4_to_int(buffer.try_into().unwrap())))
+    }
+}
+
+impl fmt::Display for VersionCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VersionCheck::Warn => write!(f, "warn"),
+            VersionCheck::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+impl FromStr for VersionCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<VersionCheck, String> {
+        match s {
+            "warn" => Ok(VersionCheck::Warn),
+            "fail" => Ok(VersionCheck::Fail),
+            _ => Err(format!("unknown version check {s}")),
+        }
+    }
+}
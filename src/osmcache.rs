@@ -1,13 +1,29 @@
 //! Cache for nodes/ways/relations
 
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 use rustc_hash::FxHashMap;
-use std::sync::Arc;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 
 use crate::osm::OsmReader;
 use crate::osm::{Node, Relation, Way};
+use crate::osmbin::encoding;
 
 type OsmCacheHashMap<K, V> = FxHashMap<K, V>;
 
+const NUM_SHARDS: usize = 16;
+
+/// First bytes of a file written by [`OsmCache::write_file`]
+const FILE_MAGIC: &[u8; 8] = b"OSMCACH\x01";
+/// Suffix of a cache file saved next to a diff, replacing `.osc.gz`
+pub const FILE_SUFFIX: &str = ".cache.gz";
+
 /// Cache for nodes/ways/relations
 ///
 /// This cache is filled when reading a diff file the first time by
@@ -41,7 +57,122 @@ impl OsmCache {
         self.relations.clear();
     }
 
-    fn read_node(&self, id: u64) -> Option<Node> {
+    /// Name of the cache file saved next to diff `filename`, or `None` if it is not a `.osc.gz`
+    /// file
+    pub fn file_for_diff(filename: &Path) -> Option<PathBuf> {
+        let prefix = filename.to_str()?.strip_suffix(".osc.gz")?;
+        Some(PathBuf::from(prefix.to_string() + FILE_SUFFIX))
+    }
+
+    /// Save cache to a gzip-compressed file, which can be loaded by [`OsmCache::from_file`]
+    ///
+    /// Nodes, ways and relations are written in turn, as their number followed by each element
+    /// sorted by id: difference with the previous id, size of the encoded element, and encoded
+    /// element. Size is 0 for an element known to be missing. A node is encoded as 2*4 bytes of
+    /// coordinates, a way as its delta-encoded nodes and a relation with the compact encoding
+    /// used by OsmBin, so that a cache only takes a fraction of the size of its diff.
+    ///
+    /// Cache is first written to a temporary file, so that an interrupted write doesn't leave a
+    /// truncated cache.
+    pub fn write_file(&self, filename: &Path) -> Result<(), io::Error> {
+        let mut data: Vec<u8> = Vec::new();
+        data.extend(FILE_MAGIC);
+        Self::encode_elements(&mut data, &self.nodes, |(lat, lon)| {
+            [lat.to_be_bytes(), lon.to_be_bytes()].concat()
+        });
+        Self::encode_elements(&mut data, &self.ways, |nodes| {
+            encoding::encode_way_nodes(nodes)
+        });
+        Self::encode_elements(&mut data, &self.relations, encoding::encode_relation);
+
+        let mut tmp_filename = filename.as_os_str().to_owned();
+        tmp_filename.push(".tmp");
+        let file = File::create(&tmp_filename)?;
+        let mut writer = GzEncoder::new(BufWriter::new(file), Compression::default());
+        writer.write_all(&data)?;
+        let file = writer.finish()?.into_inner().map_err(io::Error::other)?;
+        file.sync_all()?;
+        fs::rename(&tmp_filename, filename)
+    }
+
+    /// Load a cache saved by [`OsmCache::write_file`]
+    pub fn from_file(filename: &Path) -> Result<OsmCache, io::Error> {
+        let mut data: Vec<u8> = Vec::new();
+        GzDecoder::new(BufReader::new(File::open(filename)?)).read_to_end(&mut data)?;
+        let corrupted = || {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Cache file {} is corrupted", filename.display()),
+            )
+        };
+        let data = data.strip_prefix(FILE_MAGIC).ok_or_else(corrupted)?;
+        let mut pos = 0;
+        let nodes = Self::decode_elements(data, &mut pos, |_, d| {
+            let d: [u8; 8] = d.try_into().ok()?;
+            Some((
+                i32::from_be_bytes(d[0..4].try_into().unwrap()),
+                i32::from_be_bytes(d[4..8].try_into().unwrap()),
+            ))
+        })
+        .ok_or_else(corrupted)?;
+        let ways = Self::decode_elements(data, &mut pos, |_, d| encoding::decode_way_nodes(d))
+            .ok_or_else(corrupted)?;
+        let relations = Self::decode_elements(data, &mut pos, encoding::decode_relation)
+            .ok_or_else(corrupted)?;
+        if pos != data.len() {
+            return Err(corrupted());
+        }
+        Ok(OsmCache::new(nodes, ways, relations))
+    }
+
+    fn encode_elements<V>(
+        data: &mut Vec<u8>,
+        elements: &OsmCacheHashMap<u64, Option<V>>,
+        encode: impl Fn(&V) -> Vec<u8>,
+    ) {
+        let mut ids: Vec<u64> = elements.keys().copied().collect();
+        ids.sort_unstable();
+        encoding::write_varint(data, ids.len() as u64);
+        let mut prev = 0;
+        for id in ids {
+            encoding::write_varint(data, id - prev);
+            prev = id;
+            if let Some(element) = &elements[&id] {
+                let encoded = encode(element);
+                encoding::write_varint(data, encoded.len() as u64);
+                data.extend(encoded);
+            } else {
+                encoding::write_varint(data, 0);
+            }
+        }
+    }
+
+    /// Decode elements written by [`encode_elements`](Self::encode_elements), or `None` if data
+    /// is corrupted
+    fn decode_elements<V>(
+        data: &[u8],
+        pos: &mut usize,
+        decode: impl Fn(u64, &[u8]) -> Option<V>,
+    ) -> Option<OsmCacheHashMap<u64, Option<V>>> {
+        let num_elements = encoding::read_varint(data, pos)?;
+        let mut elements = OsmCacheHashMap::default();
+        let mut id: u64 = 0;
+        for _ in 0..num_elements {
+            id = id.checked_add(encoding::read_varint(data, pos)?)?;
+            let len = usize::try_from(encoding::read_varint(data, pos)?).ok()?;
+            let element = if len == 0 {
+                None
+            } else {
+                let encoded = data.get(*pos..pos.checked_add(len)?)?;
+                *pos += len;
+                Some(decode(id, encoded)?)
+            };
+            elements.insert(id, element);
+        }
+        Some(elements)
+    }
+
+    pub(crate) fn read_node(&self, id: u64) -> Option<Node> {
         if let Some(node) = self.nodes.get(&id) {
             if let Some((decimicro_lat, decimicro_lon)) = node {
                 return Some(Node {
@@ -56,7 +187,7 @@ impl OsmCache {
         }
         panic!("Node {id} not found ");
     }
-    fn read_way(&self, id: u64) -> Option<Way> {
+    pub(crate) fn read_way(&self, id: u64) -> Option<Way> {
         if let Some(nodes) = self.ways.get(&id) {
             if let Some(nodes) = nodes {
                 return Some(Way {
@@ -70,7 +201,7 @@ impl OsmCache {
         }
         panic!("Way {id} not found ");
     }
-    fn read_relation(&self, id: u64) -> Option<Relation> {
+    pub(crate) fn read_relation(&self, id: u64) -> Option<Relation> {
         if let Some(relation) = self.relations.get(&id) {
             return relation.clone();
         }
@@ -102,6 +233,162 @@ impl OsmReader for Arc<OsmCache> {
     }
 }
 
+/// Ids of elements missing from an [`OsmCache`], read by [`OsmCacheFallback`] from its fallback
+/// reader
+#[derive(Debug, Default, PartialEq)]
+pub struct CacheMisses {
+    pub nodes: BTreeSet<u64>,
+    pub ways: BTreeSet<u64>,
+    pub relations: BTreeSet<u64>,
+}
+
+impl CacheMisses {
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.ways.is_empty() && self.relations.is_empty()
+    }
+}
+
+impl fmt::Display for CacheMisses {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} nodes, {} ways, {} relations missing from cache",
+            self.nodes.len(),
+            self.ways.len(),
+            self.relations.len()
+        )
+    }
+}
+
+/// Reader answering from an [`OsmCache`], and from another reader for elements missing from it
+///
+/// A cache filled by [`OsmXmlBBox`](crate::osmxml::bbox::OsmXmlBBox) should contain all elements
+/// needed to filter its diff, but an element it didn't predict is read from `fallback`, usually
+/// an [`OsmBin`](crate::osmbin::OsmBin) database, instead of panicking. Ids of these elements are
+/// recorded in `misses`, which can be shared by readers of several threads, so that they can be
+/// reported.
+pub struct OsmCacheFallback<T> {
+    cache: Arc<OsmCache>,
+    fallback: T,
+    misses: Arc<Mutex<CacheMisses>>,
+}
+
+impl<T> OsmCacheFallback<T>
+where
+    T: OsmReader,
+{
+    pub fn new(
+        cache: Arc<OsmCache>,
+        fallback: T,
+        misses: Arc<Mutex<CacheMisses>>,
+    ) -> OsmCacheFallback<T> {
+        OsmCacheFallback {
+            cache,
+            fallback,
+            misses,
+        }
+    }
+}
+
+impl<T> OsmReader for OsmCacheFallback<T>
+where
+    T: OsmReader,
+{
+    fn read_node(&mut self, id: u64) -> Option<Node> {
+        if self.cache.nodes.contains_key(&id) {
+            return self.cache.read_node(id);
+        }
+        self.misses.lock().unwrap().nodes.insert(id);
+        self.fallback.read_node(id)
+    }
+    fn read_way(&mut self, id: u64) -> Option<Way> {
+        if self.cache.ways.contains_key(&id) {
+            return self.cache.read_way(id);
+        }
+        self.misses.lock().unwrap().ways.insert(id);
+        self.fallback.read_way(id)
+    }
+    fn read_relation(&mut self, id: u64) -> Option<Relation> {
+        if self.cache.relations.contains_key(&id) {
+            return self.cache.read_relation(id);
+        }
+        self.misses.lock().unwrap().relations.insert(id);
+        self.fallback.read_relation(id)
+    }
+}
+
+/// Cache for nodes/ways/relations that can be shared between threads
+///
+/// Elements are spread over several shards according to their id, each one behind its own lock,
+/// so that threads reading different elements rarely wait on each other.
+#[derive(Default)]
+pub struct OsmCacheShared {
+    shards: [RwLock<OsmCache>; NUM_SHARDS],
+}
+
+impl OsmCacheShared {
+    fn shard(&self, id: u64) -> &RwLock<OsmCache> {
+        #[allow(clippy::cast_possible_truncation)]
+        &self.shards[(id % NUM_SHARDS as u64) as usize]
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.write().unwrap().clear();
+        }
+    }
+
+    /// Get a node from cache, or read it with `read` and add it to cache
+    pub fn read_node(&self, id: u64, read: impl FnOnce() -> Option<Node>) -> Option<Node> {
+        let shard = self.shard(id);
+        {
+            let cache = shard.read().unwrap();
+            if cache.nodes.contains_key(&id) {
+                return cache.read_node(id);
+            }
+        }
+        let node = read();
+        let crd = node.as_ref().map(|n| (n.decimicro_lat, n.decimicro_lon));
+        shard.write().unwrap().nodes.insert(id, crd);
+        node
+    }
+    /// Get a way from cache, or read it with `read` and add it to cache
+    pub fn read_way(&self, id: u64, read: impl FnOnce() -> Option<Way>) -> Option<Way> {
+        let shard = self.shard(id);
+        {
+            let cache = shard.read().unwrap();
+            if cache.ways.contains_key(&id) {
+                return cache.read_way(id);
+            }
+        }
+        let way = read();
+        let nodes = way.as_ref().map(|w| w.nodes.clone());
+        shard.write().unwrap().ways.insert(id, nodes);
+        way
+    }
+    /// Get a relation from cache, or read it with `read` and add it to cache
+    pub fn read_relation(
+        &self,
+        id: u64,
+        read: impl FnOnce() -> Option<Relation>,
+    ) -> Option<Relation> {
+        let shard = self.shard(id);
+        {
+            let cache = shard.read().unwrap();
+            if cache.relations.contains_key(&id) {
+                return cache.read_relation(id);
+            }
+        }
+        let relation = read();
+        shard
+            .write()
+            .unwrap()
+            .relations
+            .insert(id, relation.clone());
+        relation
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -240,6 +527,87 @@ mod tests {
         osmcache.read_relation(24);
     }
 
+    #[test]
+    fn write_file() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let diff = tmpdir_path.path().join("123.osc.gz");
+        let filename = OsmCache::file_for_diff(&diff).unwrap();
+        assert_eq!(tmpdir_path.path().join("123.cache.gz"), filename);
+        assert_eq!(None, OsmCache::file_for_diff(Path::new("123.osm.pbf")));
+
+        let osmcache = init_osmcache();
+        osmcache.write_file(&filename).unwrap();
+        let mut loaded = OsmCache::from_file(&filename).unwrap();
+        assert_eq!(osmcache.nodes, loaded.nodes);
+        assert_eq!(osmcache.ways, loaded.ways);
+        assert_eq!(osmcache.relations, loaded.relations);
+        assert_eq!(Some(rel_23()), OsmReader::read_relation(&mut loaded, 23));
+
+        OsmCache::default().write_file(&filename).unwrap();
+        assert!(OsmCache::from_file(&filename).unwrap().nodes.is_empty());
+
+        // Truncated file is detected
+        let mut data = Vec::new();
+        osmcache.write_file(&filename).unwrap();
+        GzDecoder::new(File::open(&filename).unwrap())
+            .read_to_end(&mut data)
+            .unwrap();
+        let mut writer = GzEncoder::new(File::create(&filename).unwrap(), Compression::default());
+        writer.write_all(&data[..data.len() - 1]).unwrap();
+        writer.finish().unwrap();
+        let err = OsmCache::from_file(&filename).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn fallback() {
+        let osmcache = init_osmcache();
+        let fallback = OsmCache::new(
+            OsmCacheHashMap::from_iter([(4, Some((1, 2))), (5, None)]),
+            OsmCacheHashMap::from_iter([(14, Some(vec![4]))]),
+            OsmCacheHashMap::from_iter([(24, None)]),
+        );
+        let misses = Arc::new(Mutex::new(CacheMisses::default()));
+        let mut reader =
+            OsmCacheFallback::new(Arc::new(osmcache.clone()), fallback, misses.clone());
+
+        // Elements found in cache, even if they don't exist
+        assert_eq!(osmcache.read_node(2), reader.read_node(2));
+        assert_eq!(None, reader.read_node(1));
+        assert_eq!(osmcache.read_way(12), reader.read_way(12));
+        assert_eq!(Some(rel_23()), reader.read_relation(23));
+        assert!(misses.lock().unwrap().is_empty());
+
+        // Elements missing from cache are read from fallback
+        assert_eq!(
+            Some(Node {
+                id: 4,
+                decimicro_lat: 1,
+                decimicro_lon: 2,
+                ..Default::default()
+            }),
+            reader.read_node(4)
+        );
+        assert_eq!(None, reader.read_node(5));
+        assert_eq!(Some(vec![4]), reader.read_way(14).map(|w| w.nodes));
+        assert_eq!(None, reader.read_relation(24));
+        assert_eq!(None, reader.read_node(5));
+
+        let misses = misses.lock().unwrap();
+        assert_eq!(
+            CacheMisses {
+                nodes: BTreeSet::from([4, 5]),
+                ways: BTreeSet::from([14]),
+                relations: BTreeSet::from([24]),
+            },
+            *misses
+        );
+        assert_eq!(
+            "2 nodes, 1 ways, 1 relations missing from cache",
+            misses.to_string()
+        );
+    }
+
     #[test]
     fn clear() {
         let mut osmcache = init_osmcache();
@@ -253,4 +621,48 @@ mod tests {
         assert!(osmcache.ways.is_empty());
         assert!(osmcache.relations.is_empty());
     }
+
+    #[test]
+    fn shared() {
+        let osmcache = OsmCacheShared::default();
+
+        let node = osmcache.read_node(2, || {
+            Some(Node {
+                id: 2,
+                decimicro_lat: 4,
+                decimicro_lon: 5,
+                ..Default::default()
+            })
+        });
+        assert_eq!(Some(2), node.map(|n| n.id));
+        let node = osmcache.read_node(2, || panic!("Node 2 should be in cache"));
+        assert_eq!(
+            Some((4, 5)),
+            node.map(|n| (n.decimicro_lat, n.decimicro_lon))
+        );
+        assert_eq!(None, osmcache.read_node(1, || None));
+        assert_eq!(
+            None,
+            osmcache.read_node(1, || panic!("Node 1 should be in cache"))
+        );
+
+        let way = osmcache.read_way(12, || {
+            Some(Way {
+                id: 12,
+                nodes: vec![1, 2, 3],
+                ..Default::default()
+            })
+        });
+        assert_eq!(Some(vec![1, 2, 3]), way.map(|w| w.nodes));
+        let way = osmcache.read_way(12, || panic!("Way 12 should be in cache"));
+        assert_eq!(Some(vec![1, 2, 3]), way.map(|w| w.nodes));
+
+        let relation = osmcache.read_relation(23, || Some(rel_23()));
+        assert_eq!(Some(rel_23()), relation);
+        let relation = osmcache.read_relation(23, || panic!("Relation 23 should be in cache"));
+        assert_eq!(Some(rel_23()), relation);
+
+        osmcache.clear();
+        assert_eq!(None, osmcache.read_relation(23, || None));
+    }
 }
@@ -1,44 +1,620 @@
-//! Reader for OpenStreetMap pbf files
+//! Reader and writer for OpenStreetMap pbf files
 
 use chrono;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
 use osmpbfreader;
+use osmpbfreader::{fileformat, osmformat};
+use protobuf::{EnumOrUnknown, Message};
+use rayon::prelude::*;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::num::NonZeroU64;
 use std::path::Path;
 
+use crate::macros::printlnt;
 use crate::osm::{Member, Node, Relation, Way};
 use crate::osm::{OsmCopyTo, OsmWriter};
 
-/// Reader for OpenStreetMap pbf files
+/// Maximum number of elements in a block, as recommended by pbf specification
+const BLOCK_MAX_ELEMENTS: usize = 8000;
+/// Number of blobs decoded in parallel when reading a pbf file
+const READ_BATCH_BLOBS: usize = 64;
+
+/// Reader and writer for OpenStreetMap pbf files
 ///
 /// Only a few fields are kept from pbf file, as we don’t need all fields for OsmBin database.
-///   - nodes: only latitude and longitude
-///   - ways: only list of nodes
-///   - relations: all fields
+///   - nodes: latitude and longitude
+///   - ways: list of nodes
+///   - relations: members and tags
+///
+/// Tags of nodes and ways are also read when target stores them, as told by
+/// [`OsmWriter::stores_metadata`], and version, timestamp, user and changeset of all elements
+/// when target stores metadata or versions, as told by [`OsmWriter::stores_versions`].
+///
+/// All fields are written to pbf file. Elements are expected to be written sorted by type, as
+/// a block is started each time the type of element changes.
 pub struct OsmPbf {
     filename: String,
+    pbfwriter: Option<BufWriter<File>>,
+    block: Option<PbfBlock>,
 }
 
 impl OsmPbf {
-    /// Read a pbf file
+    /// Read or write a pbf file
     pub fn new(filename: &str) -> Result<OsmPbf, Box<dyn Error>> {
         Ok(OsmPbf {
             filename: filename.to_string(),
+            pbfwriter: None,
+            block: None,
         })
     }
+
+    /// Tags of a node or a way from string ids of keys and values, or `None` if it has no tags
+    fn read_tags(
+        keys_vals: impl Iterator<Item = (u32, u32)>,
+        block: &osmformat::PrimitiveBlock,
+    ) -> Option<Vec<(String, String)>> {
+        let tags: Vec<(String, String)> = keys_vals
+            .map(|(k, v)| (Self::string(k, block), Self::string(v, block)))
+            .collect();
+        (!tags.is_empty()).then_some(tags)
+    }
+
+    /// Tags of a dense node, which are a list of key and value ids ended by 0
+    #[allow(clippy::cast_sign_loss)]
+    fn read_dense_tags<'a>(
+        keys_vals: &mut impl Iterator<Item = &'a i32>,
+        block: &osmformat::PrimitiveBlock,
+    ) -> Option<Vec<(String, String)>> {
+        let pairs = std::iter::from_fn(|| match keys_vals.next() {
+            None | Some(0) => None,
+            Some(k) => Some((*k as u32, *keys_vals.next()? as u32)),
+        });
+        Self::read_tags(pairs, block)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn read_coord(c: i64, offset: i64, block: &osmformat::PrimitiveBlock) -> i32 {
+        ((offset + i64::from(block.granularity()) * c) / 100) as i32
+    }
+
+    /// Version, timestamp, user and changeset from the info of an element
+    fn read_info(info: &osmformat::Info, block: &osmformat::PrimitiveBlock) -> PbfInfo {
+        PbfInfo {
+            version: info.version.and_then(|v| Self::non_zero(v.into())),
+            timestamp: info.timestamp.and_then(|t| Self::read_timestamp(t, block)),
+            uid: info.uid.and_then(|u| Self::non_zero(u.into())),
+            user: info
+                .user_sid
+                .and_then(|s| Self::read_string(s.into(), block)),
+            changeset: info.changeset.and_then(Self::non_zero),
+        }
+    }
+
+    /// Version, timestamp, user and changeset of dense nodes, which are delta-coded except
+    /// version
+    fn read_denseinfo(
+        denseinfo: &osmformat::DenseInfo,
+        block: &osmformat::PrimitiveBlock,
+    ) -> Vec<PbfInfo> {
+        let (mut timestamp, mut changeset, mut uid, mut user_sid) = (0, 0, 0, 0);
+        (0..denseinfo.version.len())
+            .map(|i| {
+                timestamp += denseinfo.timestamp.get(i).copied().unwrap_or(0);
+                changeset += denseinfo.changeset.get(i).copied().unwrap_or(0);
+                uid += i64::from(denseinfo.uid.get(i).copied().unwrap_or(0));
+                user_sid += i64::from(denseinfo.user_sid.get(i).copied().unwrap_or(0));
+                PbfInfo {
+                    version: Self::non_zero(denseinfo.version[i].into()),
+                    timestamp: Self::read_timestamp(timestamp, block),
+                    uid: Self::non_zero(uid),
+                    user: Self::read_string(user_sid, block),
+                    changeset: Self::non_zero(changeset),
+                }
+            })
+            .collect()
+    }
+
+    /// Value of an optional field, which is 0 when field is missing
+    fn non_zero(v: i64) -> Option<NonZeroU64> {
+        u64::try_from(v).ok().and_then(NonZeroU64::new)
+    }
+
+    fn read_timestamp(timestamp: i64, block: &osmformat::PrimitiveBlock) -> Option<String> {
+        if timestamp == 0 {
+            return None;
+        }
+        let millis = timestamp * i64::from(block.date_granularity());
+        chrono::DateTime::from_timestamp_millis(millis)
+            .map(|t| t.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+    }
+
+    /// String `sid` of block, or `None` for the empty string at index 0
+    fn read_string(sid: i64, block: &osmformat::PrimitiveBlock) -> Option<String> {
+        let s = Self::string(u32::try_from(sid).ok()?, block);
+        (!s.is_empty()).then_some(s)
+    }
+
+    fn string(sid: u32, block: &osmformat::PrimitiveBlock) -> String {
+        block
+            .stringtable
+            .s
+            .get(sid as usize)
+            .map(|s| String::from_utf8_lossy(s).to_string())
+            .unwrap_or_default()
+    }
+
+    fn read_member(r: &osmpbfreader::Ref) -> Member {
+        let (ref_, type_) = match r.member {
+            osmpbfreader::OsmId::Node(id) => (id.0, "node"),
+            osmpbfreader::OsmId::Way(id) => (id.0, "way"),
+            osmpbfreader::OsmId::Relation(id) => (id.0, "relation"),
+        };
+        Member {
+            ref_: ref_.cast_unsigned(),
+            type_: type_.to_string(),
+            role: r.role.to_string(),
+        }
+    }
+
+    /// Decode elements of a blob, keeping tags of nodes and ways if `tags` is set, and version,
+    /// timestamp, user and changeset of all elements if `info` is set
+    fn read_blob(
+        blob: &fileformat::Blob,
+        tags: bool,
+        info: bool,
+    ) -> Result<Vec<PbfElement>, osmpbfreader::Error> {
+        let block = osmpbfreader::primitive_block_from_blob(blob)?;
+        let mut elements = Vec::new();
+        for group in &block.primitivegroup {
+            Self::read_nodes(group, &block, tags, info, &mut elements);
+            Self::read_ways(group, &block, tags, info, &mut elements);
+            Self::read_relations(group, &block, info, &mut elements);
+        }
+        Ok(elements)
+    }
+
+    fn read_nodes(
+        group: &osmformat::PrimitiveGroup,
+        block: &osmformat::PrimitiveBlock,
+        tags: bool,
+        info: bool,
+        elements: &mut Vec<PbfElement>,
+    ) {
+        for node in &group.nodes {
+            let PbfInfo {
+                version,
+                timestamp,
+                uid,
+                user,
+                changeset,
+            } = match node.info.as_ref() {
+                Some(i) if info => Self::read_info(i, block),
+                _ => PbfInfo::default(),
+            };
+            elements.push(PbfElement::Node(Node {
+                id: node.id().cast_unsigned(),
+                decimicro_lat: Self::read_coord(node.lat(), block.lat_offset(), block),
+                decimicro_lon: Self::read_coord(node.lon(), block.lon_offset(), block),
+                tags: tags
+                    .then(|| {
+                        Self::read_tags(
+                            node.keys.iter().copied().zip(node.vals.iter().copied()),
+                            block,
+                        )
+                    })
+                    .flatten(),
+                version,
+                timestamp,
+                uid,
+                user,
+                changeset,
+            }));
+        }
+
+        let dense = &group.dense;
+        let mut dense_infos = match dense.denseinfo.as_ref() {
+            Some(denseinfo) if info => Self::read_denseinfo(denseinfo, block),
+            _ => Vec::new(),
+        }
+        .into_iter();
+        let mut keys_vals = dense.keys_vals.iter();
+        let (mut id, mut lat, mut lon) = (0, 0, 0);
+        for ((did, dlat), dlon) in dense.id.iter().zip(&dense.lat).zip(&dense.lon) {
+            id += did;
+            lat += dlat;
+            lon += dlon;
+            let PbfInfo {
+                version,
+                timestamp,
+                uid,
+                user,
+                changeset,
+            } = dense_infos.next().unwrap_or_default();
+            elements.push(PbfElement::Node(Node {
+                id: id.cast_unsigned(),
+                decimicro_lat: Self::read_coord(lat, block.lat_offset(), block),
+                decimicro_lon: Self::read_coord(lon, block.lon_offset(), block),
+                tags: tags
+                    .then(|| Self::read_dense_tags(&mut keys_vals, block))
+                    .flatten(),
+                version,
+                timestamp,
+                uid,
+                user,
+                changeset,
+            }));
+        }
+    }
+
+    fn read_ways(
+        group: &osmformat::PrimitiveGroup,
+        block: &osmformat::PrimitiveBlock,
+        tags: bool,
+        info: bool,
+        elements: &mut Vec<PbfElement>,
+    ) {
+        for way in &group.ways {
+            let PbfInfo {
+                version,
+                timestamp,
+                uid,
+                user,
+                changeset,
+            } = match way.info.as_ref() {
+                Some(i) if info => Self::read_info(i, block),
+                _ => PbfInfo::default(),
+            };
+            let mut node_id = 0;
+            elements.push(PbfElement::Way(Way {
+                id: way.id().cast_unsigned(),
+                nodes: way
+                    .refs
+                    .iter()
+                    .map(|r| {
+                        node_id += r;
+                        node_id.cast_unsigned()
+                    })
+                    .collect(),
+                tags: tags
+                    .then(|| {
+                        Self::read_tags(
+                            way.keys.iter().copied().zip(way.vals.iter().copied()),
+                            block,
+                        )
+                    })
+                    .flatten(),
+                version,
+                timestamp,
+                uid,
+                user,
+                changeset,
+                ..Default::default()
+            }));
+        }
+    }
+
+    fn read_relations(
+        group: &osmformat::PrimitiveGroup,
+        block: &osmformat::PrimitiveBlock,
+        info: bool,
+        elements: &mut Vec<PbfElement>,
+    ) {
+        for (relation, pbf_relation) in
+            osmpbfreader::groups::relations(group, block).zip(&group.relations)
+        {
+            let tags = relation
+                .tags
+                .into_inner()
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let PbfInfo {
+                version,
+                timestamp,
+                uid,
+                user,
+                changeset,
+            } = match pbf_relation.info.as_ref() {
+                Some(i) if info => Self::read_info(i, block),
+                _ => PbfInfo::default(),
+            };
+            elements.push(PbfElement::Relation(Relation {
+                id: relation.id.0.cast_unsigned(),
+                members: relation.refs.iter().map(Self::read_member).collect(),
+                tags: Some(tags),
+                version,
+                timestamp,
+                uid,
+                user,
+                changeset,
+                ..Default::default()
+            }));
+        }
+    }
+
+    fn write_blob(&mut self, type_: &str, data: &[u8]) -> Result<(), io::Error> {
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(data)?;
+        let blob = fileformat::Blob {
+            raw_size: Some(i32::try_from(data.len()).map_err(io::Error::other)?),
+            zlib_data: Some(zlib.finish()?),
+            ..Default::default()
+        };
+        let blob = blob.write_to_bytes().map_err(io::Error::other)?;
+        let header = fileformat::BlobHeader {
+            type_: Some(type_.to_string()),
+            datasize: Some(i32::try_from(blob.len()).map_err(io::Error::other)?),
+            ..Default::default()
+        };
+        let header = header.write_to_bytes().map_err(io::Error::other)?;
+
+        let pbfwriter = self.pbfwriter.as_mut().unwrap();
+        pbfwriter.write_all(&u32::try_from(header.len()).unwrap().to_be_bytes())?;
+        pbfwriter.write_all(&header)?;
+        pbfwriter.write_all(&blob)
+    }
+
+    /// Write current block if it can't receive an element of type `type_`
+    fn prepare_block(&mut self, type_: BlockType) -> Result<&mut PbfBlock, io::Error> {
+        if self
+            .block
+            .as_ref()
+            .is_some_and(|b| b.type_ != type_ || b.num_elements >= BLOCK_MAX_ELEMENTS)
+        {
+            self.write_block()?;
+        }
+        Ok(self.block.get_or_insert_with(|| PbfBlock::new(type_)))
+    }
+    fn write_block(&mut self) -> Result<(), io::Error> {
+        if let Some(block) = self.block.take() {
+            let data = block
+                .into_primitive_block()
+                .write_to_bytes()
+                .map_err(io::Error::other)?;
+            self.write_blob("OSMData", &data)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum BlockType {
+    Nodes,
+    Ways,
+    Relations,
 }
 
-macro_rules! printlnt {
-    ($($arg:tt)*) => {
-        println!("{} {}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"), format_args!($($arg)*));
-    };
+/// Block of elements of the same type, being prepared to be written to a pbf file
+struct PbfBlock {
+    type_: BlockType,
+    num_elements: usize,
+    strings: HashMap<String, u32>,
+    stringtable: Vec<Vec<u8>>,
+    group: osmformat::PrimitiveGroup,
+    dense: osmformat::DenseNodes,
+    denseinfo: osmformat::DenseInfo,
+    has_denseinfo: bool,
+}
+
+impl PbfBlock {
+    fn new(type_: BlockType) -> PbfBlock {
+        PbfBlock {
+            type_,
+            num_elements: 0,
+            strings: HashMap::new(),
+            // First string is reserved by pbf format
+            stringtable: vec![Vec::new()],
+            group: osmformat::PrimitiveGroup::new(),
+            dense: osmformat::DenseNodes::new(),
+            denseinfo: osmformat::DenseInfo::new(),
+            has_denseinfo: false,
+        }
+    }
+
+    fn string_id(&mut self, s: &str) -> u32 {
+        if let Some(id) = self.strings.get(s) {
+            return *id;
+        }
+        let id = u32::try_from(self.stringtable.len()).unwrap();
+        self.stringtable.push(s.as_bytes().to_vec());
+        self.strings.insert(s.to_string(), id);
+        id
+    }
+
+    fn tags(&mut self, tags: Option<&Vec<(String, String)>>) -> (Vec<u32>, Vec<u32>) {
+        let mut keys = Vec::new();
+        let mut vals = Vec::new();
+        for (k, v) in tags.into_iter().flatten() {
+            keys.push(self.string_id(k));
+            vals.push(self.string_id(v));
+        }
+        (keys, vals)
+    }
+
+    fn timestamp(timestamp: Option<&String>) -> i64 {
+        timestamp
+            .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+            .map_or(0, |t| t.timestamp())
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_possible_wrap)]
+    fn info(
+        &mut self,
+        version: Option<NonZeroU64>,
+        timestamp: Option<&String>,
+        uid: Option<NonZeroU64>,
+        user: Option<&String>,
+        changeset: Option<NonZeroU64>,
+    ) -> protobuf::MessageField<osmformat::Info> {
+        if version.is_none()
+            && timestamp.is_none()
+            && uid.is_none()
+            && user.is_none()
+            && changeset.is_none()
+        {
+            return protobuf::MessageField::none();
+        }
+        protobuf::MessageField::some(osmformat::Info {
+            version: version.map(|v| v.get() as i32),
+            timestamp: timestamp.map(|t| Self::timestamp(Some(t))),
+            changeset: changeset.map(|c| c.get() as i64),
+            uid: uid.map(|u| u.get() as i32),
+            user_sid: user.map(|u| self.string_id(u)),
+            ..Default::default()
+        })
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_possible_wrap)]
+    fn push_node(&mut self, node: &Node) {
+        self.dense.id.push(node.id as i64);
+        self.dense.lat.push(i64::from(node.decimicro_lat));
+        self.dense.lon.push(i64::from(node.decimicro_lon));
+        for (k, v) in node.tags.iter().flatten() {
+            let k = self.string_id(k);
+            let v = self.string_id(v);
+            self.dense.keys_vals.push(k as i32);
+            self.dense.keys_vals.push(v as i32);
+        }
+        self.dense.keys_vals.push(0);
+
+        self.has_denseinfo |= node.version.is_some();
+        let user_sid = node.user.as_ref().map_or(0, |u| self.string_id(u));
+        let denseinfo = &mut self.denseinfo;
+        denseinfo
+            .version
+            .push(node.version.map_or(0, |v| v.get() as i32));
+        denseinfo
+            .timestamp
+            .push(Self::timestamp(node.timestamp.as_ref()));
+        denseinfo
+            .changeset
+            .push(node.changeset.map_or(0, |c| c.get() as i64));
+        denseinfo.uid.push(node.uid.map_or(0, |u| u.get() as i32));
+        denseinfo.user_sid.push(user_sid as i32);
+        self.num_elements += 1;
+    }
+
+    #[allow(clippy::cast_possible_wrap)]
+    fn push_way(&mut self, way: &Way) {
+        let (keys, vals) = self.tags(way.tags.as_ref());
+        let info = self.info(
+            way.version,
+            way.timestamp.as_ref(),
+            way.uid,
+            way.user.as_ref(),
+            way.changeset,
+        );
+        let mut refs: Vec<i64> = way.nodes.iter().map(|n| *n as i64).collect();
+        Self::delta(&mut refs);
+        self.group.ways.push(osmformat::Way {
+            id: Some(way.id as i64),
+            keys,
+            vals,
+            info,
+            refs,
+            ..Default::default()
+        });
+        self.num_elements += 1;
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_possible_wrap)]
+    fn push_relation(&mut self, relation: &Relation) {
+        let (keys, vals) = self.tags(relation.tags.as_ref());
+        let info = self.info(
+            relation.version,
+            relation.timestamp.as_ref(),
+            relation.uid,
+            relation.user.as_ref(),
+            relation.changeset,
+        );
+        let mut roles_sid = Vec::new();
+        let mut memids = Vec::new();
+        let mut types = Vec::new();
+        for m in &relation.members {
+            roles_sid.push(self.string_id(&m.role) as i32);
+            memids.push(m.ref_ as i64);
+            let t = match m.type_.as_str() {
+                "node" => osmformat::relation::MemberType::NODE,
+                "way" => osmformat::relation::MemberType::WAY,
+                "relation" => osmformat::relation::MemberType::RELATION,
+                t => panic!("{t} not expected"),
+            };
+            types.push(EnumOrUnknown::new(t));
+        }
+        Self::delta(&mut memids);
+        self.group.relations.push(osmformat::Relation {
+            id: Some(relation.id as i64),
+            keys,
+            vals,
+            info,
+            roles_sid,
+            memids,
+            types,
+            ..Default::default()
+        });
+        self.num_elements += 1;
+    }
+
+    /// Replace values by their difference with previous value
+    fn delta<T: Copy + std::ops::Sub<Output = T>>(v: &mut [T]) {
+        for i in (1..v.len()).rev() {
+            v[i] = v[i] - v[i - 1];
+        }
+    }
+
+    fn into_primitive_block(mut self) -> osmformat::PrimitiveBlock {
+        if self.type_ == BlockType::Nodes {
+            Self::delta(&mut self.dense.id);
+            Self::delta(&mut self.dense.lat);
+            Self::delta(&mut self.dense.lon);
+            if self.has_denseinfo {
+                Self::delta(&mut self.denseinfo.timestamp);
+                Self::delta(&mut self.denseinfo.changeset);
+                Self::delta(&mut self.denseinfo.uid);
+                Self::delta(&mut self.denseinfo.user_sid);
+                self.dense.denseinfo = protobuf::MessageField::some(self.denseinfo);
+            }
+            self.group.dense = protobuf::MessageField::some(self.dense);
+        }
+        osmformat::PrimitiveBlock {
+            stringtable: protobuf::MessageField::some(osmformat::StringTable {
+                s: self.stringtable,
+                ..Default::default()
+            }),
+            primitivegroup: vec![self.group],
+            ..Default::default()
+        }
+    }
+}
+
+/// Element decoded from a pbf block
+enum PbfElement {
+    Node(Node),
+    Way(Way),
+    Relation(Relation),
+}
+
+/// Version, timestamp, user and changeset of an element read from pbf
+#[derive(Default)]
+struct PbfInfo {
+    version: Option<NonZeroU64>,
+    timestamp: Option<String>,
+    uid: Option<NonZeroU64>,
+    user: Option<String>,
+    changeset: Option<NonZeroU64>,
 }
 
 impl<T> OsmCopyTo<T> for OsmPbf
 where
     T: OsmWriter,
 {
-    #[allow(clippy::cast_sign_loss)]
     fn copy_to(&mut self, target: &mut T) -> Result<(), Box<dyn Error>> {
         let r = match File::open(Path::new(&self.filename)) {
             Err(e) => {
@@ -52,6 +628,8 @@ where
             Ok(o) => o,
         };
         let mut pbf = osmpbfreader::OsmPbfReader::new(r);
+        let tags = target.stores_metadata();
+        let info = tags || target.stores_versions();
 
         target.write_start(false).unwrap();
         let mut start_way = false;
@@ -59,76 +637,38 @@ where
 
         printlnt!("Starting pbf read");
 
-        for obj in pbf.par_iter() {
-            let obj = obj?;
-            match obj {
-                osmpbfreader::OsmObj::Node(node) => {
-                    target
-                        .write_node(&mut Node {
-                            id: node.id.0 as u64,
-                            decimicro_lat: node.decimicro_lat,
-                            decimicro_lon: node.decimicro_lon,
-                            tags: None,
-                            ..Default::default()
-                        })
-                        .unwrap();
-                }
-                osmpbfreader::OsmObj::Way(way) => {
-                    if !start_way {
-                        printlnt!("Starting ways");
-                        start_way = true;
-                    }
-                    let nodes: Vec<u64> = way.nodes.iter().map(|x| x.0 as u64).collect();
-                    target
-                        .write_way(&mut Way {
-                            id: way.id.0 as u64,
-                            nodes,
-                            tags: None,
-                            ..Default::default()
-                        })
-                        .unwrap();
-                }
-                osmpbfreader::OsmObj::Relation(relation) => {
-                    if !start_relation {
-                        printlnt!("Starting relations");
-                        start_relation = true;
+        let mut blobs = pbf.blobs();
+        loop {
+            let batch: Vec<fileformat::Blob> = blobs
+                .by_ref()
+                .take(READ_BATCH_BLOBS)
+                .collect::<Result<_, _>>()?;
+            if batch.is_empty() {
+                break;
+            }
+            let batch: Vec<Vec<PbfElement>> = batch
+                .par_iter()
+                .map(|blob| Self::read_blob(blob, tags, info))
+                .collect::<Result<_, _>>()?;
+            for element in batch.into_iter().flatten() {
+                match element {
+                    PbfElement::Node(mut node) => {
+                        target.write_node(&mut node).unwrap();
                     }
-                    let mut members: Vec<Member> = Vec::new();
-                    for r in relation.refs {
-                        let ref_: u64;
-                        let type_: String;
-                        match r.member {
-                            osmpbfreader::objects::OsmId::Node(id) => {
-                                ref_ = id.0 as u64;
-                                type_ = String::from("node");
-                            }
-                            osmpbfreader::objects::OsmId::Way(id) => {
-                                ref_ = id.0 as u64;
-                                type_ = String::from("way");
-                            }
-                            osmpbfreader::objects::OsmId::Relation(id) => {
-                                ref_ = id.0 as u64;
-                                type_ = String::from("relation");
-                            }
+                    PbfElement::Way(mut way) => {
+                        if !start_way {
+                            printlnt!("Starting ways");
+                            start_way = true;
                         }
-                        members.push(Member {
-                            ref_,
-                            type_,
-                            role: r.role.to_string(),
-                        });
+                        target.write_way(&mut way).unwrap();
                     }
-                    let mut tags: Vec<(String, String)> = Vec::new();
-                    for (k, v) in relation.tags.into_inner() {
-                        tags.push((k.to_string(), v.to_string()));
+                    PbfElement::Relation(mut relation) => {
+                        if !start_relation {
+                            printlnt!("Starting relations");
+                            start_relation = true;
+                        }
+                        target.write_relation(&mut relation).unwrap();
                     }
-                    target
-                        .write_relation(&mut Relation {
-                            id: relation.id.0 as u64,
-                            members,
-                            tags: Some(tags),
-                            ..Default::default()
-                        })
-                        .unwrap();
                 }
             }
         }
@@ -139,3 +679,144 @@ where
         Ok(())
     }
 }
+
+impl OsmWriter for OsmPbf {
+    fn write_node(&mut self, node: &mut Node) -> Result<(), io::Error> {
+        self.prepare_block(BlockType::Nodes)?.push_node(node);
+        Ok(())
+    }
+    fn write_way(&mut self, way: &mut Way) -> Result<(), io::Error> {
+        self.prepare_block(BlockType::Ways)?.push_way(way);
+        Ok(())
+    }
+    fn write_relation(&mut self, relation: &mut Relation) -> Result<(), io::Error> {
+        self.prepare_block(BlockType::Relations)?
+            .push_relation(relation);
+        Ok(())
+    }
+
+    fn write_start(&mut self, change: bool) -> Result<(), Box<dyn Error>> {
+        if change {
+            return Err(format!("Cannot write a change to pbf file {}", self.filename).into());
+        }
+        self.pbfwriter = Some(BufWriter::new(File::create(&self.filename)?));
+
+        let header = osmformat::HeaderBlock {
+            required_features: vec![String::from("OsmSchema-V0.6"), String::from("DenseNodes")],
+            writingprogram: Some(String::from("osm-replication-rust")),
+            ..Default::default()
+        };
+        self.write_blob("OSMHeader", &header.write_to_bytes()?)?;
+        Ok(())
+    }
+    fn write_end(&mut self, _change: bool) -> Result<(), Box<dyn Error>> {
+        self.write_block()?;
+        if let Some(mut pbfwriter) = self.pbfwriter.take() {
+            pbfwriter.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PBF_SAINT_BARTHELEMY: &str = "tests/resources/saint_barthelemy.osm.pbf";
+
+    fn read_elements(tags: bool, info: bool) -> Vec<PbfElement> {
+        let mut pbf = osmpbfreader::OsmPbfReader::new(File::open(PBF_SAINT_BARTHELEMY).unwrap());
+        pbf.blobs()
+            .flat_map(|blob| OsmPbf::read_blob(&blob.unwrap(), tags, info).unwrap())
+            .collect()
+    }
+
+    fn sorted_tags(tags: &osmpbfreader::Tags) -> Vec<(String, String)> {
+        let mut tags: Vec<(String, String)> = tags
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        tags.sort();
+        tags
+    }
+
+    fn sorted(tags: Option<&Vec<(String, String)>>) -> Vec<(String, String)> {
+        let mut tags = tags.cloned().unwrap_or_default();
+        tags.sort();
+        tags
+    }
+
+    #[test]
+    fn read_like_osmpbfreader() {
+        let elements = read_elements(true, false);
+        let mut pbf = osmpbfreader::OsmPbfReader::new(File::open(PBF_SAINT_BARTHELEMY).unwrap());
+        let objs: Vec<osmpbfreader::OsmObj> = pbf.iter().map(Result::unwrap).collect();
+        assert_eq!(objs.len(), elements.len());
+        for (obj, element) in objs.iter().zip(&elements) {
+            match (obj, element) {
+                (osmpbfreader::OsmObj::Node(expected), PbfElement::Node(node)) => {
+                    assert_eq!(expected.id.0.cast_unsigned(), node.id);
+                    assert_eq!(expected.decimicro_lat, node.decimicro_lat);
+                    assert_eq!(expected.decimicro_lon, node.decimicro_lon);
+                    assert_eq!(sorted_tags(&expected.tags), sorted(node.tags.as_ref()));
+                    assert_eq!(None, node.version);
+                }
+                (osmpbfreader::OsmObj::Way(expected), PbfElement::Way(way)) => {
+                    assert_eq!(expected.id.0.cast_unsigned(), way.id);
+                    let nodes: Vec<u64> =
+                        expected.nodes.iter().map(|n| n.0.cast_unsigned()).collect();
+                    assert_eq!(nodes, way.nodes);
+                    assert_eq!(sorted_tags(&expected.tags), sorted(way.tags.as_ref()));
+                    assert_eq!(None, way.version);
+                }
+                (osmpbfreader::OsmObj::Relation(expected), PbfElement::Relation(relation)) => {
+                    assert_eq!(expected.id.0.cast_unsigned(), relation.id);
+                    let members: Vec<Member> =
+                        expected.refs.iter().map(OsmPbf::read_member).collect();
+                    assert_eq!(members, relation.members);
+                    assert_eq!(sorted_tags(&expected.tags), sorted(relation.tags.as_ref()));
+                    assert_eq!(None, relation.version);
+                }
+                _ => panic!("Element {obj:?} decoded with another type"),
+            }
+        }
+    }
+
+    #[test]
+    fn read_info() {
+        let elements = read_elements(false, true);
+        let node = elements
+            .iter()
+            .find_map(|e| match e {
+                PbfElement::Node(n) if n.id == 2619283351 => Some(n),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(None, node.tags);
+        assert_eq!(Some("Kobow"), node.user.as_deref());
+        assert!(node.version.is_some());
+        assert!(node.timestamp.is_some());
+
+        let way = elements
+            .iter()
+            .find_map(|e| match e {
+                PbfElement::Way(w) if w.id == 255316725 => Some(w),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(None, way.tags);
+        assert_eq!(6, way.nodes.len());
+        assert_eq!(NonZeroU64::new(1), way.version);
+        assert_eq!(Some("2014-01-04T21:56:26Z"), way.timestamp.as_deref());
+        assert_eq!(Some("RedFox"), way.user.as_deref());
+        assert_eq!(NonZeroU64::new(19813735), way.changeset);
+        assert!(way.uid.is_some());
+
+        // All elements of this extract have a version
+        assert!(elements.iter().all(|e| match e {
+            PbfElement::Node(n) => n.version.is_some(),
+            PbfElement::Way(w) => w.version.is_some(),
+            PbfElement::Relation(r) => r.version.is_some(),
+        }));
+    }
+}
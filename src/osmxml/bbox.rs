@@ -30,7 +30,9 @@ impl OsmXmlBBox<osmbin::OsmBin> {
         filename: &str,
         dir_osmbin: &str,
     ) -> Result<OsmXmlBBox<osmbin::OsmBin>, Box<dyn Error>> {
-        let reader = osmbin::OsmBin::new(dir_osmbin).unwrap();
+        let mut reader = osmbin::OsmBin::new(dir_osmbin)?;
+        // Cache is reused to generate sub-diffs, so it must keep all elements read
+        reader.set_cache_record(true);
         Ok(OsmXmlBBox {
             xmlwriter: OsmXml::new(filename).unwrap(),
             reader,
@@ -58,11 +60,16 @@ where
         }
     }
     fn expand_bbox_node_id(&mut self, bbox: &mut Option<BoundingBox>, id: u64) {
+        let node = self.reader.read_node(id);
+        self.expand_bbox_node_read(bbox, id, node.as_ref());
+    }
+    /// Expand bbox with node `id`, already read from reader
+    fn expand_bbox_node_read(&self, bbox: &mut Option<BoundingBox>, id: u64, node: Option<&Node>) {
         if let Some(bb) = self.nodes_modified.get(&id) {
             expand_bbox(bbox, bb);
         }
-        if let Some(node) = self.reader.read_node(id) {
-            Self::expand_bbox_node_only(bbox, &node);
+        if let Some(node) = node {
+            Self::expand_bbox_node_only(bbox, node);
         }
     }
     fn expand_bbox_node(&mut self, bbox: &mut Option<BoundingBox>, node: &Node) {
@@ -71,16 +78,22 @@ where
     }
 
     fn expand_bbox_way_only(&mut self, bbox: &mut Option<BoundingBox>, way: &Way) {
-        for n in &way.nodes {
-            self.expand_bbox_node_id(bbox, *n);
+        let nodes = self.reader.read_nodes(&way.nodes);
+        for (n, node) in way.nodes.iter().zip(&nodes) {
+            self.expand_bbox_node_read(bbox, *n, node.as_ref());
         }
     }
     fn expand_bbox_way_id(&mut self, bbox: &mut Option<BoundingBox>, id: u64) {
+        let way = self.reader.read_way(id);
+        self.expand_bbox_way_read(bbox, id, way.as_ref());
+    }
+    /// Expand bbox with way `id`, already read from reader
+    fn expand_bbox_way_read(&mut self, bbox: &mut Option<BoundingBox>, id: u64, way: Option<&Way>) {
         if let Some(bb) = self.ways_modified.get(&id) {
             expand_bbox(bbox, bb);
         }
-        if let Some(way) = self.reader.read_way(id) {
-            self.expand_bbox_way_only(bbox, &way);
+        if let Some(way) = way {
+            self.expand_bbox_way_only(bbox, way);
         }
     }
     fn expand_bbox_way(&mut self, bbox: &mut Option<BoundingBox>, way: &Way) {
@@ -94,10 +107,20 @@ where
         relation: &Relation,
         prev_relations: &[u64],
     ) {
+        let ids = |type_: &str| -> Vec<u64> {
+            relation
+                .members
+                .iter()
+                .filter(|m| m.type_ == type_)
+                .map(|m| m.ref_)
+                .collect()
+        };
+        let mut nodes = self.reader.read_nodes(&ids("node")).into_iter();
+        let mut ways = self.reader.read_ways(&ids("way")).into_iter();
         for m in &relation.members {
             match m.type_.as_str() {
-                "node" => self.expand_bbox_node_id(bbox, m.ref_),
-                "way" => self.expand_bbox_way_id(bbox, m.ref_),
+                "node" => self.expand_bbox_node_read(bbox, m.ref_, nodes.next().unwrap().as_ref()),
+                "way" => self.expand_bbox_way_read(bbox, m.ref_, ways.next().unwrap().as_ref()),
                 "relation" => self.expand_bbox_relation_id(bbox, m.ref_, prev_relations.to_owned()),
                 _ => panic!("Unsupported relation member: {m:?}"),
             }
@@ -6,7 +6,7 @@ use std::io;
 
 use crate::osm::{self, Action, Member, Node, Relation, Way};
 use crate::osm::{OsmReader, OsmUpdate, OsmWriter};
-use crate::osmbin;
+use crate::osmbin::mmap::OsmBinMmap;
 use crate::osmgeom;
 use crate::osmxml::OsmXml;
 
@@ -55,18 +55,18 @@ fn buffer_polygon(mp: &MultiPolygon<i64>) -> MultiPolygon<i64> {
     convert_multipolygon_f64_to_i64(&poly_buffered)
 }
 
-impl OsmXmlFilter<osmbin::OsmBin> {
+impl OsmXmlFilter<OsmBinMmap> {
     pub fn new_osmbin(
         filename: &str,
         dir_osmbin: &str,
         poly_file: &str,
-    ) -> Result<OsmXmlFilter<osmbin::OsmBin>, Box<dyn Error>> {
+    ) -> Result<OsmXmlFilter<OsmBinMmap>, Box<dyn Error>> {
         let poly = osmgeom::read_multipolygon(poly_file).unwrap().1;
         let poly_buffered = buffer_polygon(&poly.clone());
 
         Ok(OsmXmlFilter {
             xmlwriter: OsmXml::new(filename).unwrap(),
-            reader: osmbin::OsmBin::new(dir_osmbin).unwrap(),
+            reader: OsmBinMmap::new(dir_osmbin)?,
             poly: PolyInfo {
                 poly,
                 nodes_seen_in_poly: HashSet::new(),
@@ -115,22 +115,37 @@ where
 }
 
 impl PolyInfo {
+    fn contains_node(&self, node: &Node) -> bool {
+        let point = point!(x: i64::from(node.decimicro_lon), y: i64::from(node.decimicro_lat));
+        point.intersects(&self.poly)
+    }
     fn node_in_poly<T: OsmReader>(&mut self, reader: &mut T, id: u64) -> bool {
         if self.nodes_seen_in_poly.contains(&id) {
             return true;
         }
-        let node = reader.read_node(id);
-        if let Some(node) = node {
-            let point = point!(x: i64::from(node.decimicro_lon), y: i64::from(node.decimicro_lat));
-            if point.intersects(&self.poly) {
-                self.nodes_seen_in_poly.insert(id);
-                return true;
-            }
+        if let Some(node) = reader.read_node(id)
+            && self.contains_node(&node)
+        {
+            self.nodes_seen_in_poly.insert(id);
+            return true;
         }
         false
     }
     fn nodes_in_poly<T: OsmReader>(&mut self, reader: &mut T, nodes: &[u64]) -> bool {
-        nodes.iter().any(|n| self.node_in_poly(reader, *n))
+        // Nodes don't need to be read if one was already seen in polygon
+        if nodes.iter().any(|n| self.nodes_seen_in_poly.contains(n)) {
+            return true;
+        }
+        let found = reader
+            .read_nodes(nodes)
+            .into_iter()
+            .flatten()
+            .find(|node| self.contains_node(node));
+        if let Some(node) = found {
+            self.nodes_seen_in_poly.insert(node.id);
+            return true;
+        }
+        false
     }
     fn way_in_poly<T: OsmReader>(&mut self, reader: &mut T, id: u64) -> bool {
         if self.ways_seen_in_poly.contains(&id) {
@@ -14,19 +14,16 @@ use thiserror;
 use ureq;
 
 use crate::diffs;
+use crate::macros::printlnt;
 use crate::osm::OsmUpdate;
 use crate::osmbin;
+use crate::osmcache::OsmCache;
 use crate::osmxml;
 
-macro_rules! printlnt {
-    ($($arg:tt)*) => {
-        println!("{} {}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"), format_args!($($arg)*));
-    };
-}
-
 pub struct Update {}
 
 impl Update {
+    #[allow(clippy::too_many_lines)]
     pub fn update(
         dir_osmbin: &str,
         dir_polygon: &str,
@@ -46,6 +43,8 @@ impl Update {
             Ok(o) => o,
         };
 
+        let cur_state = Self::check_osmbin_state(dir_osmbin, cur_state, &state_file)?;
+
         let remote_state = url_diffs.to_string() + "state.txt";
         let remote_state_uri = Uri::from_str(&remote_state)
             .unwrap_or_else(|_| panic!("Invalid state Uri: {remote_state}"));
@@ -73,12 +72,7 @@ impl Update {
         #[allow(clippy::range_plus_one)]
         for n in (cur_state + 1)..(remote_state + 1) {
             printlnt!("{n}");
-            let n_split = format!(
-                "{:03}/{:03}/{:03}",
-                (n / 1_000_000) % 1000,
-                (n / 1_000) % 1000,
-                n % 1000
-            );
+            let n_split = Self::split_state(n);
             let n_split = n_split.as_str();
 
             let orig_state = dir_diffs.to_string() + "planet/minute/" + n_split + ".state.txt";
@@ -105,6 +99,10 @@ impl Update {
             }
             let mut osmxml = osmxml::bbox::OsmXmlBBox::new_osmbin(&bbox_diff, dir_osmbin).unwrap();
             osmxml.update(&orig_diff).unwrap();
+            let mut reader = osmxml.get_reader();
+            let osmcache = reader.get_cache();
+            // Release lock on database before it is updated
+            drop(reader);
 
             match fs::hard_link(&orig_state, &bbox_state) {
                 Err(err) if err.kind() == ErrorKind::AlreadyExists => (),
@@ -121,30 +119,85 @@ impl Update {
 
             printlnt!("  diff generation");
             let dest_modified_time = fs::metadata(&orig_diff).unwrap().modified().unwrap();
-            let osmcache = osmxml.get_reader().get_cache();
-            let diff = diffs::Diff::new_osmcache(
+            // Saved so that this minute can later be split again with the same elements
+            let cache_file = OsmCache::file_for_diff(Path::new(&bbox_diff)).unwrap();
+            osmcache.write_file(&cache_file).unwrap();
+            let diff = diffs::Diff::new_osmcache_fallback(
                 osmcache,
+                dir_osmbin,
                 dir_diffs,
                 &dest_suffix,
                 dest_modified_time,
                 &orig_state,
             );
             diff.generate_diff_recursive(&polys, &bbox_diff, 0).unwrap();
+            let cache_misses = diff.take_cache_misses();
+            if !cache_misses.is_empty() {
+                printlnt!("  {cache_misses}");
+            }
+            // Release lock on database before it is updated
+            drop(diff);
 
             printlnt!("  osmbin update");
             let mut osmbin = osmbin::OsmBin::new_writer(dir_osmbin).unwrap();
-            osmbin.update(&orig_diff).unwrap();
+            let state = osmbin::ReplicationState::from_file(Path::new(&orig_state)).unwrap();
+            osmbin.update_with_state(&orig_diff, &state, false).unwrap();
 
-            let state_file = Path::new(&state_file);
-            match fs::remove_file(state_file) {
-                Err(err) if err.kind() == ErrorKind::NotFound => (),
-                r => r.unwrap(),
-            }
-            unix::fs::symlink(n_split.to_string() + ".state.txt", state_file).unwrap();
+            Self::link_state(&state_file, n_split)?;
+        }
+        Ok(())
+    }
+
+    /// Path of files of state `n`, relative to a minute directory
+    fn split_state(n: u64) -> String {
+        format!(
+            "{:03}/{:03}/{:03}",
+            (n / 1_000_000) % 1000,
+            (n / 1_000) % 1000,
+            n % 1000
+        )
+    }
+
+    /// Point `state_file` to the state file of `n_split`
+    fn link_state(state_file: &str, n_split: &str) -> Result<(), Error> {
+        let state_file = Path::new(state_file);
+        match fs::remove_file(state_file) {
+            Err(err) if err.kind() == ErrorKind::NotFound => (),
+            r => r?,
         }
+        unix::fs::symlink(n_split.to_string() + ".state.txt", state_file)?;
         Ok(())
     }
 
+    /// Check that osmbin database is at the state of diffs, and return the state to update from
+    ///
+    /// Database is one state ahead when an update was interrupted after database was updated,
+    /// but before `state_file` was advanced. Diffs of this state were already generated, as
+    /// database is updated last, so only `state_file` needs to be advanced.
+    fn check_osmbin_state(
+        dir_osmbin: &str,
+        cur_state: u64,
+        state_file: &str,
+    ) -> Result<u64, Error> {
+        let Some(osmbin_state) = osmbin::OsmBin::new(dir_osmbin)?.get_state()? else {
+            return Ok(cur_state);
+        };
+        if osmbin_state.sequence_number == cur_state + 1 {
+            printlnt!("Resuming interrupted update of {}", cur_state + 1);
+            Self::link_state(state_file, &Self::split_state(cur_state + 1))?;
+            return Ok(cur_state + 1);
+        }
+        if osmbin_state.sequence_number != cur_state {
+            let red = anstyle::Style::new().fg_color(Some(anstyle::AnsiColor::Red.into()));
+            eprintln!("{red}Error: osmbin database doesn't match state file {state_file}{red:#}");
+            return Err(Error::StateMismatch(
+                osmbin_state.sequence_number,
+                cur_state,
+            ));
+        }
+        Ok(cur_state)
+    }
+
     fn read_state_from_file(filename: &str) -> Result<u64, Error> {
         let content = match fs::read_to_string(filename) {
             Err(err) if err.kind() == ErrorKind::NotFound => {
@@ -251,9 +304,169 @@ pub enum Error {
     #[error(transparent)]
     IO(#[from] io::Error),
     #[error(transparent)]
+    OsmBin(#[from] osmbin::OsmBinError),
+    #[error(transparent)]
     Network(#[from] Box<ureq::Error>),
     #[error("state file {0} not found")]
     StateNotFound(String),
     #[error("state file {0} has an incorrect format")]
     StateIncorrect(String),
+    #[error("osmbin database is at state {0}, but diffs are at state {1}")]
+    StateMismatch(u64, u64),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osm::OsmWriter;
+    use std::path::PathBuf;
+    use tempfile;
+
+    const PBF_SAINT_BARTHELEMY: &str = "tests/resources/saint_barthelemy.osm.pbf";
+    const OSC_SAINT_BARTHELEMY: &str = "tests/resources/saint_barthelemy.osc.gz";
+    const POLY_SAINT_BARTHELEMY: &str = "tests/resources/saint_barthelemy.poly";
+
+    #[test]
+    fn check_osmbin_state() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path();
+        let dir_osmbin = tmpdir.join("osmbin");
+        let dir_osmbin = dir_osmbin.to_str().unwrap();
+        osmbin::OsmBin::init(dir_osmbin).unwrap();
+        let dir_minute = tmpdir.join("planet/minute");
+        fs::create_dir_all(dir_minute.join("000/000")).unwrap();
+        let state_file = dir_minute.join("state.txt");
+        let state_file = state_file.to_str().unwrap();
+        fs::write(state_file, "sequenceNumber=1\n").unwrap();
+        fs::write(
+            dir_minute.join("000/000/002.state.txt"),
+            "sequenceNumber=2\n",
+        )
+        .unwrap();
+
+        // Database without state can be updated from any state
+        assert_eq!(
+            1,
+            Update::check_osmbin_state(dir_osmbin, 1, state_file).unwrap()
+        );
+
+        let set_state = |sequence_number| {
+            osmbin::OsmBin::new_writer(dir_osmbin)
+                .unwrap()
+                .set_state(&osmbin::ReplicationState {
+                    sequence_number,
+                    timestamp: None,
+                })
+                .unwrap();
+        };
+        set_state(1);
+        assert_eq!(
+            1,
+            Update::check_osmbin_state(dir_osmbin, 1, state_file).unwrap()
+        );
+
+        // Update interrupted before state file was advanced is resumed
+        set_state(2);
+        assert_eq!(
+            2,
+            Update::check_osmbin_state(dir_osmbin, 1, state_file).unwrap()
+        );
+        assert_eq!(2, Update::read_state_from_file(state_file).unwrap());
+        assert_eq!(
+            2,
+            Update::check_osmbin_state(dir_osmbin, 2, state_file).unwrap()
+        );
+
+        set_state(4);
+        assert!(matches!(
+            Update::check_osmbin_state(dir_osmbin, 2, state_file),
+            Err(Error::StateMismatch(4, 2))
+        ));
+        fs::write(
+            Path::new(dir_osmbin).join("state.txt"),
+            "sequenceNumber=a\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            Update::check_osmbin_state(dir_osmbin, 2, state_file),
+            Err(Error::OsmBin(osmbin::OsmBinError::CorruptRecord { .. }))
+        ));
+    }
+
+    #[test]
+    fn update_from_file() {
+        let tmpdir_path = tempfile::tempdir().unwrap();
+        let tmpdir = tmpdir_path.path();
+        let dir = |name: &str| -> PathBuf {
+            let dir = tmpdir.join(name);
+            fs::create_dir_all(&dir).unwrap();
+            dir
+        };
+        let path = |dir: &Path| dir.to_str().unwrap().to_string() + "/";
+
+        let dir_osmbin = dir("osmbin");
+        let dir_osmbin = dir_osmbin.to_str().unwrap();
+        osmbin::OsmBin::init(dir_osmbin).unwrap();
+        let mut osmbin = osmbin::OsmBin::new_writer(dir_osmbin).unwrap();
+        osmbin.import(PBF_SAINT_BARTHELEMY).unwrap();
+        drop(osmbin);
+
+        let dir_polygon = dir("polygons");
+        fs::copy(
+            POLY_SAINT_BARTHELEMY,
+            dir_polygon.join("saint_barthelemy.poly"),
+        )
+        .unwrap();
+
+        let dir_diffs = dir("diffs");
+        fs::create_dir_all(dir_diffs.join("planet/minute")).unwrap();
+        fs::write(
+            dir_diffs.join("planet/minute/state.txt"),
+            "sequenceNumber=1\n",
+        )
+        .unwrap();
+
+        let dir_remote = dir("remote");
+        fs::create_dir_all(dir_remote.join("000/000")).unwrap();
+        let state = "sequenceNumber=2\ntimestamp=2014-01-10T23\\:01\\:02Z\n";
+        fs::write(dir_remote.join("state.txt"), state).unwrap();
+        fs::write(dir_remote.join("000/000/002.state.txt"), state).unwrap();
+        fs::copy(OSC_SAINT_BARTHELEMY, dir_remote.join("000/000/002.osc.gz")).unwrap();
+
+        Update::update(
+            dir_osmbin,
+            dir_polygon.to_str().unwrap(),
+            &path(&dir_diffs),
+            &format!("file:/{}", path(&dir_remote)),
+            None,
+        )
+        .unwrap();
+
+        let state = osmbin::OsmBin::new(dir_osmbin)
+            .unwrap()
+            .get_state()
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, state.sequence_number);
+        assert_eq!(
+            2,
+            Update::read_state_from_file(&(path(&dir_diffs) + "planet/minute/state.txt")).unwrap()
+        );
+        assert!(dir_diffs.join("bbox/minute/000/000/002.osc.gz").exists());
+        assert!(
+            dir_diffs
+                .join("saint_barthelemy/minute/000/000/002.osc.gz")
+                .exists()
+        );
+
+        // Nothing left to update
+        Update::update(
+            dir_osmbin,
+            dir_polygon.to_str().unwrap(),
+            &path(&dir_diffs),
+            &format!("file:/{}", path(&dir_remote)),
+            None,
+        )
+        .unwrap();
+    }
 }